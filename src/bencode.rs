@@ -1,11 +1,47 @@
 //! Decode and encode bencoded values as described by [BEP 003](
 //! http://www.bittorrent.org/beps/bep_0003.html).
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::hash_map::{DefaultHasher, HashMap};
 use std::convert;
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::ops::Range;
+use std::str;
 
 use crate::error;
 
+/// Bounds on what a decoder will accept, so untrusted input can not exhaust memory or the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Deepest nesting of lists and dicts allowed. A value which is not inside any list or dict
+    /// has a depth of 0.
+    pub max_depth: usize,
+    /// Longest string allowed, in bytes
+    pub max_string_len: usize,
+}
+
+impl Default for Limits {
+    /// Allows 256 levels of nesting and strings up to 64 MiB, enough for the "pieces" of any
+    /// real torrent
+    fn default() -> Limits {
+        Limits {
+            max_depth: 256,
+            max_string_len: 64 << 20,
+        }
+    }
+}
+
+/// Where parts of a decoded value were found in its input
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Spans {
+    /// Number of bytes the value took up
+    pub len: usize,
+    /// Bytes of the value under the "info" key, if the value is a dict which has one. Hashing
+    /// these gives the info-hash, even if they are not encoded the way `Benc::encode` would.
+    pub info: Option<Range<usize>>,
+}
+
 /// Indicates type of the Benc node
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum NodeType {
@@ -29,6 +65,15 @@ impl NodeType {
 }
 
 /// The types that can be represented as a bencoded values
+///
+/// `Benc` values are totally ordered: `String < Int < List < Dict`. Values of the same variant
+/// compare their contents; strings and lists lexicographically, ints numerically, and dicts as
+/// their sequence of `(key, value)` pairs sorted by key.
+///
+/// Equality is structural: dicts are equal when they hold the same entries, however they were
+/// built, while lists must also have their items in the same order. This matches the bencoded
+/// form, where dict keys are always sorted, so two values are equal exactly when they encode to
+/// the same bytes. `eq_canonical` spells this out without recursion.
 #[derive(Debug, PartialEq, Eq)]
 pub enum Benc {
     String(Vec<u8>),
@@ -38,16 +83,51 @@ pub enum Benc {
 }
 
 impl Benc {
+    /// Position of the variant in the ordering of `Benc` values
+    fn rank(&self) -> u8 {
+        match *self {
+            Benc::String(_) => 0,
+            Benc::Int(_) => 1,
+            Benc::List(_) => 2,
+            Benc::Dict(_) => 3,
+        }
+    }
+
+    /// Name of the variant's bencoded type
+    crate fn type_name(&self) -> &'static str {
+        match *self {
+            Benc::String(_) => "string",
+            Benc::Int(_) => "int",
+            Benc::List(_) => "list",
+            Benc::Dict(_) => "dict",
+        }
+    }
+
     /// Consumes the Reader and builds a Vec of `Benc` values. The function will return early if
     /// an invalid Benc node is found.
     pub fn new<R>(bytes: &mut io::Bytes<R>) -> error::Result<Vec<Benc>>
     where
         R: io::Read,
     {
+        Benc::new_with(Limits::default(), bytes)
+    }
+
+    /// Same as `new`, but values which exceed `limits` are rejected with `Error::Limit`. The
+    /// input is read into memory and decoded with `slice::Decoder`.
+    pub fn new_with<R>(limits: Limits, bytes: &mut io::Bytes<R>) -> error::Result<Vec<Benc>>
+    where
+        R: io::Read,
+    {
+        let mut buf = Vec::new();
+        for c in bytes {
+            buf.push(c?);
+        }
+
+        let mut decoder = slice::Decoder::new(&buf, limits);
         let mut ast = Vec::new();
 
         loop {
-            let node = match Benc::node(bytes, None) {
+            let node = match decoder.node(None) {
                 Ok(n) => n,
                 Err(error::Error::EndOfFile) => return Ok(ast),
                 Err(error::Error::Delim(_)) => continue,
@@ -57,216 +137,1003 @@ impl Benc {
         }
     }
 
-    /// Consumes as much of `bytes` as needed to read a valid bencoded string. `c` is the first
-    /// byte of the string.
-    fn string<R>(bytes: &mut io::Bytes<R>, c: u8) -> error::Result<Vec<u8>>
-    where
-        R: io::Read,
-    {
-        let err = Err(error::Error::Other("Invalid string bencoding"));
-        let mut len = match c {
-            c @ b'0'..=b'9' => (c - b'0') as usize,
-            _ => return err,
+    /// Decode the first value in `buf`, along with where it and its "info" value were found
+    pub fn new_with_spans(buf: &[u8]) -> error::Result<(Benc, Spans)> {
+        slice::decode_with_spans(buf, Limits::default())
+    }
+
+    pub fn is_string(&self) -> bool {
+        matches!(*self, Benc::String(_))
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(*self, Benc::Int(_))
+    }
+
+    pub fn is_list(&self) -> bool {
+        matches!(*self, Benc::List(_))
+    }
+
+    pub fn is_dict(&self) -> bool {
+        matches!(*self, Benc::Dict(_))
+    }
+
+    /// The bytes of a string
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Benc::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The text of a string, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|s| ::std::str::from_utf8(s).ok())
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Benc::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Benc]> {
+        match *self {
+            Benc::List(ref l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&HashMap<Vec<u8>, Benc>> {
+        match *self {
+            Benc::Dict(ref d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Take the bytes of a string, without copying them
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Benc::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn into_int(self) -> Option<i64> {
+        self.as_int()
+    }
+
+    /// Take the values of a list
+    pub fn into_list(self) -> Option<Vec<Benc>> {
+        match self {
+            Benc::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// Take the entries of a dict
+    pub fn into_dict(self) -> Option<HashMap<Vec<u8>, Benc>> {
+        match self {
+            Benc::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Iterate over the entries of a dict in key order. Values which are not dicts have no
+    /// entries.
+    pub fn entries(&self) -> impl Iterator<Item = (&[u8], &Benc)> {
+        let mut entries: Vec<_> = match *self {
+            Benc::Dict(ref d) => d.iter().map(|(k, v)| (&k[..], v)).collect(),
+            _ => Vec::new(),
         };
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        entries.into_iter()
+    }
+
+    /// Returns true if `self` and `other` have the same canonical bencoding: the same variants
+    /// and contents, with dicts compared entry by entry in sorted key order and lists item by
+    /// item. This agrees with `==`, but walks the trees with a stack so deep trees can not
+    /// overflow the call stack.
+    pub fn eq_canonical(&self, other: &Benc) -> bool {
+        let mut stack = vec![(self, other)];
+
+        while let Some(pair) = stack.pop() {
+            match pair {
+                (Benc::String(s), Benc::String(o)) if s == o => (),
+                (Benc::Int(s), Benc::Int(o)) if s == o => (),
+                (Benc::List(s), Benc::List(o)) if s.len() == o.len() => {
+                    stack.extend(s.iter().zip(o));
+                }
+                (Benc::Dict(s), Benc::Dict(o)) if s.len() == o.len() => {
+                    for (s, o) in pair.0.entries().zip(pair.1.entries()) {
+                        if s.0 != o.0 {
+                            return false;
+                        }
+                        stack.push((s.1, o.1));
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Number of values in the tree, counting `self` and every nested value. Dict keys are not
+    /// counted.
+    pub fn count_nodes(&self) -> usize {
+        let mut count = 0;
+        self.walk(&mut |_| count += 1);
+        count
+    }
+
+    /// Length in bytes of the bencoded form, worked out without encoding
+    pub fn byte_size(&self) -> usize {
+        // length of a string's bencoding, `<len>:<bytes>`
+        let string = |len: usize| decimal_len(len as u64) + 1 + len;
+
+        let mut size = 0;
+        self.walk(&mut |n| {
+            size += match *n {
+                Benc::String(ref s) => string(s.len()),
+                Benc::Int(i) => decimal_len(i.unsigned_abs()) + usize::from(i < 0) + 2,
+                Benc::List(_) => 2,
+                Benc::Dict(ref d) => 2 + d.keys().map(|k| string(k.len())).sum::<usize>(),
+            }
+        });
+        size
+    }
+
+    /// Encode as canonical bencode, with dict keys in sorted order
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.byte_size());
+        self.encode_into(&mut out);
+        out
+    }
 
-        // read numbers until ':' and return early if any other character is read
-        for c in bytes.by_ref() {
-            match c {
-                Ok(c @ b'0'..=b'9') => match len.checked_mul(10)
-                    .and_then(|n| n.checked_add((c - b'0') as usize))
-                {
-                    Some(n) => len = n,
-                    None => return Err(error::Error::Other("Integer overflow")),
-                },
-                Ok(b':') => break,
-                Ok(_) => return err,
-                Err(e) => return Err(error::Error::from(e)),
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match *self {
+            Benc::String(ref s) => {
+                out.extend_from_slice(s.len().to_string().as_bytes());
+                out.push(b':');
+                out.extend_from_slice(s);
+            }
+            Benc::Int(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Benc::List(ref l) => {
+                out.push(b'l');
+                for n in l {
+                    n.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Benc::Dict(_) => {
+                out.push(b'd');
+                for (k, v) in self.entries() {
+                    out.extend_from_slice(k.len().to_string().as_bytes());
+                    out.push(b':');
+                    out.extend_from_slice(k);
+                    v.encode_into(out);
+                }
+                out.push(b'e');
             }
         }
+    }
 
-        if len == 0 {
-            return err;
+    /// Call `f` on every value in the tree depth-first, parents before their children. List
+    /// items are visited in order and dict values in key order. The tree is walked with a stack
+    /// rather than recursion, so deep trees can not overflow the call stack.
+    pub fn walk(&self, f: &mut dyn FnMut(&Benc)) {
+        let mut stack = vec![self];
+
+        while let Some(node) = stack.pop() {
+            f(node);
+            match *node {
+                Benc::List(ref l) => stack.extend(l.iter().rev()),
+                Benc::Dict(_) => {
+                    let values: Vec<_> = node.entries().map(|(_, v)| v).collect();
+                    stack.extend(values.into_iter().rev());
+                }
+                _ => (),
+            }
         }
+    }
 
-        let mut buf = Vec::with_capacity(len);
+    /// Same as `walk`, but `f` may modify each value. Children are visited after `f` has run on
+    /// their parent, so values `f` adds are walked too.
+    pub fn walk_mut(&mut self, f: &mut dyn FnMut(&mut Benc)) {
+        let mut stack = vec![self];
+
+        while let Some(node) = stack.pop() {
+            f(node);
+            match *node {
+                Benc::List(ref mut l) => stack.extend(l.iter_mut().rev()),
+                Benc::Dict(ref mut d) => {
+                    let mut entries: Vec<_> = d.iter_mut().collect();
+                    entries.sort_by(|a, b| a.0.cmp(b.0));
+                    stack.extend(entries.into_iter().rev().map(|(_, v)| v));
+                }
+                _ => (),
+            }
+        }
+    }
 
-        // read `len` bytes, returning any error
-        for c in bytes {
-            match c {
-                Ok(c) => buf.push(c),
-                Err(e) => return Err(error::Error::from(e)),
+    /// Overlay the dict `other` onto the dict `self`. Each key of `other` is inserted into `self`,
+    /// overwriting the value already there, except when both values are dicts: those are merged
+    /// the same way, so nested keys `self` has but `other` lacks are kept. Lists are replaced,
+    /// not concatenated. Fails, leaving `self` unchanged, if either value is not a dict.
+    pub fn merge(&mut self, other: Benc) -> error::Result<()> {
+        let (dict, other) = match (self, other) {
+            (Benc::Dict(d), Benc::Dict(o)) => (d, o),
+            _ => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Only a dict can be merged into a dict",
+                )))
             }
+        };
 
-            len -= 1;
-            if len == 0 {
-                break;
+        // merged with a stack rather than recursion, so deep dicts can not overflow the stack
+        let mut stack = vec![(dict, other)];
+        while let Some((dict, other)) = stack.pop() {
+            let mut nested = HashMap::new();
+            for (k, v) in other {
+                match (dict.get(&k), v) {
+                    (Some(Benc::Dict(_)), Benc::Dict(o)) => {
+                        nested.insert(k, o);
+                    }
+                    (_, v) => {
+                        dict.insert(k, v);
+                    }
+                }
+            }
+
+            for (k, v) in dict.iter_mut() {
+                if let (Benc::Dict(d), Some(o)) = (v, nested.remove(k)) {
+                    stack.push((d, o));
+                }
             }
         }
+        Ok(())
+    }
 
-        match len {
-            0 => Ok(buf),
-            _ => err,
+    /// Renders the tree as indented, human-readable text for debugging. Strings are shown as
+    /// text when they are valid UTF-8 and as `<N bytes: hex>` otherwise, and the `pieces` blob is
+    /// always shown as truncated hex. Dict keys are printed in sorted order.
+    pub fn to_pretty_string(&self) -> String {
+        let mut out = String::new();
+        self.pretty(&mut out, 0, false);
+        out
+    }
+
+    fn pretty(&self, out: &mut String, depth: usize, binary: bool) {
+        const INDENT: &str = "  ";
+
+        match *self {
+            Benc::String(ref s) => Benc::pretty_str(out, s, binary),
+            Benc::Int(i) => {
+                let _ = write!(out, "{}", i);
+            }
+            Benc::List(ref l) if l.is_empty() => out.push_str("[]"),
+            Benc::List(ref l) => {
+                out.push_str("[\n");
+                for v in l {
+                    out.push_str(&INDENT.repeat(depth + 1));
+                    v.pretty(out, depth + 1, false);
+                    out.push_str(",\n");
+                }
+                out.push_str(&INDENT.repeat(depth));
+                out.push(']');
+            }
+            Benc::Dict(ref d) if d.is_empty() => out.push_str("{}"),
+            Benc::Dict(_) => {
+                out.push_str("{\n");
+                for (k, v) in self.entries() {
+                    out.push_str(&INDENT.repeat(depth + 1));
+                    Benc::pretty_str(out, k, false);
+                    out.push_str(": ");
+                    v.pretty(out, depth + 1, k == b"pieces");
+                    out.push_str(",\n");
+                }
+                out.push_str(&INDENT.repeat(depth));
+                out.push('}');
+            }
         }
     }
 
-    /// Consumes as much of `bytes` as needed to read a valid bencoded int
-    fn int<R>(bytes: &mut io::Bytes<R>) -> error::Result<i64>
-    where
-        R: io::Read,
-    {
-        let err = Err(error::Error::Other("Invalid int bencoding"));
-        let mut num = 0;
+    fn pretty_str(out: &mut String, s: &[u8], binary: bool) {
+        // number of bytes shown before a binary string is truncated
+        const MAX_HEX: usize = 20;
 
-        let neg = match bytes.next() {
-            Some(Ok(b'-')) => -1,
-            Some(Ok(c @ b'0'..=b'9')) => {
-                num = i64::from(c - b'0'); //  (c - b'0') as i64;
-                1
+        match ::std::str::from_utf8(s) {
+            Ok(s) if !binary => {
+                let _ = write!(out, "{:?}", s);
             }
-            Some(Ok(_)) | None => return err,
-            Some(Err(e)) => return Err(error::Error::from(e)),
-        };
+            _ => {
+                let _ = write!(out, "<{} bytes: ", s.len());
+                for b in s.iter().take(MAX_HEX) {
+                    let _ = write!(out, "{:02x}", b);
+                }
+                if s.len() > MAX_HEX {
+                    out.push_str("...");
+                }
+                out.push('>');
+            }
+        }
+    }
+}
+
+/// Number of decimal digits in `n`
+fn decimal_len(mut n: u64) -> usize {
+    let mut len = 1;
+    while n >= 10 {
+        n /= 10;
+        len += 1;
+    }
+    len
+}
+
+/// Errors returned while decoding. `offset` is the position in the input of the offending byte,
+/// or the length of the input if it ended early.
+mod errors {
+    use crate::error::{Error, KeyProblem};
+
+    fn parse(message: &'static str, offset: usize) -> Error {
+        Error::new(Error::Parse { message, offset })
+    }
+
+    crate fn invalid_string(offset: usize) -> Error {
+        parse("Invalid string bencoding", offset)
+    }
+
+    crate fn invalid_int(offset: usize) -> Error {
+        parse("Invalid int bencoding", offset)
+    }
+
+    /// The byte at `offset` does not start a bencoded value
+    crate fn invalid_node(offset: usize) -> Error {
+        parse("Invalid bencode value", offset)
+    }
+
+    /// Input ended inside a list or dictionary
+    crate fn unexpected_eof(offset: usize) -> Error {
+        parse("Unexpected end of input", offset)
+    }
+
+    /// The dictionary key starting at `offset` is not a string
+    crate fn non_string_key(offset: usize) -> Error {
+        parse("Expected string key for dictionary", offset)
+    }
+
+    /// `key` is not greater than the key before it
+    crate fn unsorted_key(key: Vec<u8>) -> Error {
+        Error::new(Error::Key {
+            key,
+            problem: KeyProblem::Unsorted,
+        })
+    }
+
+    /// A string length or int does not fit in its type
+    crate fn overflow(offset: usize) -> Error {
+        Error::new(Error::Limit {
+            message: "Integer overflow",
+            offset,
+        })
+    }
+
+    /// A string is longer than `Limits::max_string_len`
+    crate fn string_too_long(offset: usize) -> Error {
+        Error::new(Error::Limit {
+            message: "String too long",
+            offset,
+        })
+    }
+
+    /// The list or dict starting at `offset` is nested deeper than `Limits::max_depth`
+    crate fn too_deep(offset: usize) -> Error {
+        Error::new(Error::Limit {
+            message: "Nested too deeply",
+            offset,
+        })
+    }
+}
+
+/// Decode bencode held in memory. This needs nothing from `std::io`, and reads bytes straight
+/// from the slice rather than through an `io::Result` per byte.
+pub mod slice {
+    use std::cmp;
+    use std::collections::HashMap;
+    use std::ops::Range;
+
+    use super::{errors, Benc, Limits, NodeType, Spans};
+    use crate::error;
+
+    /// Decode the first value in `buf`. Returns the value and the number of bytes it took up;
+    /// anything after it is left for the caller.
+    pub fn decode(buf: &[u8]) -> error::Result<(Benc, usize)> {
+        decode_with(buf, Limits::default())
+    }
+
+    /// Same as `decode`, but values which exceed `limits` are rejected with `Error::Limit`
+    pub fn decode_with(buf: &[u8], limits: Limits) -> error::Result<(Benc, usize)> {
+        let (node, spans) = decode_with_spans(buf, limits)?;
+        Ok((node, spans.len))
+    }
+
+    /// Same as `decode_with`, but also returns where the value and its "info" value were found
+    pub fn decode_with_spans(buf: &[u8], limits: Limits) -> error::Result<(Benc, Spans)> {
+        let mut decoder = Decoder::new(buf, limits);
+
+        match decoder.node(None) {
+            Ok(n) => Ok((n, Spans { len: decoder.pos, info: decoder.info })),
+            Err(error::Error::EndOfFile) => Err(errors::unexpected_eof(decoder.pos)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Position in the bytes being decoded, so errors can report where they occurred
+    crate struct Decoder<'a> {
+        buf: &'a [u8],
+        /// Number of bytes read
+        pos: usize,
+        limits: Limits,
+        /// Number of lists and dicts the decoder is inside
+        depth: usize,
+        /// Bytes of the value under "info" in the outermost dict
+        info: Option<Range<usize>>,
+    }
+
+    impl<'a> Decoder<'a> {
+        crate fn new(buf: &'a [u8], limits: Limits) -> Decoder<'a> {
+            Decoder {
+                buf,
+                pos: 0,
+                limits,
+                depth: 0,
+                info: None,
+            }
+        }
+
+        fn next(&mut self) -> Option<u8> {
+            let c = *self.buf.get(self.pos)?;
+            self.pos += 1;
+            Some(c)
+        }
+
+        /// Offset of the last byte read
+        fn prev(&self) -> usize {
+            self.pos.saturating_sub(1)
+        }
 
-        if neg == -1 {
-            // 1..9 must follow -
-            match bytes.next() {
-                Some(Ok(c @ b'1'..=b'9')) => {
-                    num = i64::from(c - b'0');
+        /// Enter a list or dict, failing if it is nested too deeply
+        fn enter(&mut self) -> error::Result<()> {
+            if self.depth >= self.limits.max_depth {
+                return Err(errors::too_deep(self.prev()));
+            }
+            self.depth += 1;
+            Ok(())
+        }
+
+        /// Read a bencoded string. `c` is the first byte of the string.
+        crate fn string(&mut self, c: u8) -> error::Result<Vec<u8>> {
+            let mut len = match c {
+                c @ b'0'..=b'9' => (c - b'0') as usize,
+                _ => return Err(errors::invalid_string(self.prev())),
+            };
+
+            // read numbers until ':' and return early if any other character is read
+            while let Some(c) = self.next() {
+                match c {
+                    c @ b'0'..=b'9' => match len.checked_mul(10)
+                        .and_then(|n| n.checked_add((c - b'0') as usize))
+                    {
+                        Some(n) => len = n,
+                        None => return Err(errors::overflow(self.prev())),
+                    },
+                    b':' => break,
+                    _ => return Err(errors::invalid_string(self.prev())),
                 }
-                Some(Err(e)) => return Err(error::Error::from(e)),
-                _ => return err,
             }
-        } else if num == 0 {
-            // No digits may follow 0
-            match bytes.next() {
-                Some(Ok(b'e')) => return Ok(num),
-                Some(Err(e)) => return Err(error::Error::from(e)),
-                _ => return err,
+
+            if len == 0 {
+                return Err(errors::invalid_string(self.prev()));
+            }
+            if len > self.limits.max_string_len {
+                return Err(errors::string_too_long(self.prev()));
+            }
+
+            match self.buf.len() - self.pos >= len {
+                true => {
+                    let s = self.buf[self.pos..self.pos + len].to_vec();
+                    self.pos += len;
+                    Ok(s)
+                }
+                // the input ends before the string does
+                false => {
+                    self.pos = self.buf.len();
+                    Err(errors::invalid_string(self.pos))
+                }
             }
         }
 
-        for c in bytes {
-            match c {
-                Ok(c @ b'0'..=b'9') => match num.checked_mul(10)
-                    .and_then(|n| n.checked_add(i64::from(c - b'0')))
-                {
-                    Some(n) => num = n,
-                    None => return Err(error::Error::Other("Integer overflow")),
-                },
-                Ok(b'e') => return Ok(neg * num),
-                Ok(_) => return err,
-                Err(e) => return Err(error::Error::from(e)),
+        /// Read a bencoded int, after its leading 'i'. Digits are read 8 at a time where
+        /// possible, into a `u64` which can hold any 19 digits, so overflow is only checked once.
+        crate fn int(&mut self) -> error::Result<i64> {
+            let neg = self.buf.get(self.pos) == Some(&b'-');
+            let start = self.pos + neg as usize;
+            let rest = &self.buf[start..];
+
+            let max = cmp::min(rest.len(), 19);
+            let mut len = 0;
+            let mut num: u64 = 0;
+            while len + 8 <= max {
+                match parse_8_digits(&rest[len..len + 8]) {
+                    Some(n) => num = num * 100_000_000 + n,
+                    None => break,
+                }
+                len += 8;
+            }
+            while len < max && rest[len].is_ascii_digit() {
+                num = num * 10 + u64::from(rest[len] - b'0');
+                len += 1;
+            }
+
+            match rest.first() {
+                // "-0", and leading zeros
+                Some(b'0') if neg => return Err(errors::invalid_int(start)),
+                Some(b'0') if len > 1 => return Err(errors::invalid_int(start + 1)),
+                _ => (),
+            }
+            // any 18 digits fit in an i64, and a 20th digit never does
+            if num > i64::MAX as u64 {
+                return Err(errors::overflow(start + 18));
+            }
+
+            match rest.get(len) {
+                // no digits, e.g. "ie" or "i-e"
+                _ if len == 0 => Err(errors::invalid_int(start)),
+                Some(c) if c.is_ascii_digit() => Err(errors::overflow(start + len)),
+                Some(b'e') => {
+                    self.pos = start + len + 1;
+                    match neg {
+                        true => Ok(-(num as i64)),
+                        false => Ok(num as i64),
+                    }
+                }
+                _ => Err(errors::invalid_int(start + len)),
             }
         }
 
-        err
+        /// Read a bencoded list, after its leading 'l'
+        crate fn list(&mut self) -> error::Result<Vec<Benc>> {
+            let mut list = Vec::new();
+
+            loop {
+                match self.node(Some(b'e')) {
+                    Ok(n) => list.push(n),
+                    Err(error::Error::Delim(_)) => return Ok(list),
+                    Err(error::Error::EndOfFile) => return Err(errors::unexpected_eof(self.pos)),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        /// Read a bencoded dictionary, after its leading 'd'. Keys must be strings in sorted
+        /// order.
+        crate fn dict(&mut self) -> error::Result<HashMap<Vec<u8>, Benc>> {
+            let mut dict = HashMap::new();
+            let mut prev_key: &[u8] = &[]; // ensure keys are in alphabetical order
+
+            loop {
+                let start = self.pos;
+                let key = match self.node(Some(b'e')) {
+                    Ok(Benc::String(n)) => if &n[..] > prev_key {
+                        n
+                    } else {
+                        return Err(errors::unsorted_key(n));
+                    },
+                    Ok(_) => return Err(errors::non_string_key(start)),
+                    Err(error::Error::Delim(_)) => return Ok(dict),
+                    Err(error::Error::EndOfFile) => return Err(errors::unexpected_eof(self.pos)),
+                    Err(e) => return Err(e),
+                };
+
+                // the key is still in the input, so keep a slice of it rather than a copy
+                prev_key = &self.buf[self.pos - key.len()..self.pos];
+
+                let start = self.pos;
+                let val = match self.node(None) {
+                    Ok(n) => n,
+                    Err(error::Error::EndOfFile) => return Err(errors::unexpected_eof(self.pos)),
+                    Err(e) => return Err(e),
+                };
+                if self.depth == 1 && key == b"info" {
+                    self.info = Some(start..self.pos);
+                }
+
+                dict.insert(key, val);
+            }
+        }
+
+        /// Read a single value. If there is nothing left to read, or a NUL byte is read,
+        /// `Error::EndOfFile` is returned. Reading `delim` returns `Error::Delim`.
+        crate fn node(&mut self, delim: Option<u8>) -> error::Result<Benc> {
+            let c = match self.next() {
+                Some(c) if Some(c) == delim => return Err(error::Error::Delim(c)),
+                Some(0) | None => return Err(error::Error::EndOfFile),
+                Some(c) => c,
+            };
+
+            match NodeType::type_of(c) {
+                Some(NodeType::String) => Ok(Benc::from(self.string(c)?)),
+                Some(NodeType::Int) => Ok(Benc::from(self.int()?)),
+                Some(NodeType::List) => {
+                    self.enter()?;
+                    let list = self.list()?;
+                    self.depth -= 1;
+                    Ok(Benc::from(list))
+                }
+                Some(NodeType::Dict) => {
+                    self.enter()?;
+                    let dict = self.dict()?;
+                    self.depth -= 1;
+                    Ok(Benc::from(dict))
+                }
+                None => Err(errors::invalid_node(self.prev())),
+            }
+        }
     }
 
-    /// Consumes as much of `bytes` as needed to read a valid bencoded list
-    fn list<R>(bytes: &mut io::Bytes<R>) -> error::Result<Vec<Benc>>
-    where
-        R: io::Read,
-    {
-        let mut list = Vec::new();
+    /// Value of `chunk` if it is 8 ASCII digits. The digits are read as one little-endian `u64`
+    /// and combined in pairs, then fours, then eights.
+    fn parse_8_digits(chunk: &[u8]) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(chunk);
+        let v = u64::from_le_bytes(bytes);
+
+        // every byte must be 0x30..=0x39: the high nibble is 3, and adding 6 does not carry
+        if v & 0xf0f0_f0f0_f0f0_f0f0 != 0x3030_3030_3030_3030
+            || (v + 0x0606_0606_0606_0606) & 0xf0f0_f0f0_f0f0_f0f0 != 0x3030_3030_3030_3030
+        {
+            return None;
+        }
 
-        loop {
-            match Benc::node(bytes, Some(b'e')) {
-                Ok(n) => list.push(n),
-                Err(error::Error::Delim(_)) => return Ok(list),
-                Err(e) => return Err(e),
+        let v = v - 0x3030_3030_3030_3030;
+        let v = (v * 10 + (v >> 8)) & 0x00ff_00ff_00ff_00ff;
+        let v = (v * 100 + (v >> 16)) & 0x0000_ffff_0000_ffff;
+        Some((v * 10_000 + (v >> 32)) & 0xffff_ffff)
+    }
+}
+
+/// Remove `key` from `dict`, failing with `KeyProblem::Missing` if it is not present
+crate fn take(dict: &mut HashMap<Vec<u8>, Benc>, key: &[u8]) -> error::Result<Benc> {
+    dict.remove(key).ok_or_else(|| {
+        error::Error::new(error::Error::Key {
+            key: key.to_vec(),
+            problem: error::KeyProblem::Missing,
+        })
+    })
+}
+
+/// Remove the int under `key` from `dict`
+crate fn take_int(dict: &mut HashMap<Vec<u8>, Benc>, key: &[u8]) -> error::Result<i64> {
+    match take(dict, key)? {
+        Benc::Int(i) => Ok(i),
+        n => Err(wrong_type(key, &n)),
+    }
+}
+
+/// Remove the string under `key` from `dict`
+crate fn take_bytes(dict: &mut HashMap<Vec<u8>, Benc>, key: &[u8]) -> error::Result<Vec<u8>> {
+    match take(dict, key)? {
+        Benc::String(s) => Ok(s),
+        n => Err(wrong_type(key, &n)),
+    }
+}
+
+/// Remove the text under `key` from `dict`. Returns `None` if the key is missing, is not a string,
+/// or is not valid UTF-8. Binary fields such as "pieces" should use `take_bytes` instead.
+crate fn take_text(dict: &mut HashMap<Vec<u8>, Benc>, key: &[u8]) -> Option<String> {
+    match dict.remove(key) {
+        Some(Benc::String(s)) => String::from_utf8(s).ok(),
+        _ => None,
+    }
+}
+
+/// Remove `key` from `dict`. Some clients store legacy encoded text under `key` alongside a
+/// "<key>.utf-8" copy; the copy is preferred if all of its text is valid UTF-8, in which case the
+/// returned encoding is `None`.
+crate fn take_preferring_utf8<'a>(
+    dict: &mut HashMap<Vec<u8>, Benc>,
+    key: &[u8],
+    encoding: Option<&'a str>,
+) -> (Option<Benc>, Option<&'a str>) {
+    fn is_utf8(node: &Benc) -> bool {
+        match *node {
+            Benc::String(ref s) => str::from_utf8(s).is_ok(),
+            Benc::List(ref l) => l.iter().all(is_utf8),
+            _ => false,
+        }
+    }
+
+    let mut utf8_key = key.to_vec();
+    utf8_key.extend_from_slice(b".utf-8");
+
+    match dict.remove(&utf8_key) {
+        Some(n) if is_utf8(&n) => {
+            dict.remove(key);
+            (Some(n), None)
+        }
+        _ => (dict.remove(key), encoding),
+    }
+}
+
+/// Error for a `key` whose value is a `found` rather than the expected type
+crate fn wrong_type(key: &[u8], found: &Benc) -> error::Error {
+    error::Error::new(error::Error::Key {
+        key: key.to_vec(),
+        problem: error::KeyProblem::WrongType {
+            found: found.type_name(),
+        },
+    })
+}
+
+// `HashMap` does not implement `Hash`, and the iteration order of a `Dict` is unspecified. Each
+// entry is hashed on its own and the results are summed so that equal dicts hash the same no
+// matter how they were built.
+impl Hash for Benc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Benc::String(ref s) => {
+                state.write_u8(0);
+                s.hash(state);
+            }
+            Benc::Int(i) => {
+                state.write_u8(1);
+                i.hash(state);
+            }
+            Benc::List(ref l) => {
+                state.write_u8(2);
+                l.hash(state);
+            }
+            Benc::Dict(ref d) => {
+                state.write_u8(3);
+                state.write_usize(d.len());
+
+                let sum = d.iter().fold(0u64, |acc, entry| {
+                    let mut h = DefaultHasher::new();
+                    entry.hash(&mut h);
+                    acc.wrapping_add(h.finish())
+                });
+                state.write_u64(sum);
             }
         }
     }
+}
 
-    /// Consumes as much of `bytes` as needed to read a valid bencoded dictionary. Dictionary keys
-    /// should be `Benc::BString`s
-    fn dict<R>(bytes: &mut io::Bytes<R>) -> error::Result<HashMap<Vec<u8>, Benc>>
-    where
-        R: io::Read,
-    {
-        let mut dict = HashMap::new();
-        let mut prev_key = Vec::new(); // ensure keys are in alphabetical order
-        let err = Err(error::Error::Other("Invalid dict bencoding"));
+impl Ord for Benc {
+    fn cmp(&self, other: &Benc) -> Ordering {
+        match (self, other) {
+            (Benc::String(s), Benc::String(o)) => s.cmp(o),
+            (Benc::Int(s), Benc::Int(o)) => s.cmp(o),
+            (Benc::List(s), Benc::List(o)) => s.cmp(o),
+            (Benc::Dict(_), Benc::Dict(_)) => self.entries().cmp(other.entries()),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
 
-        loop {
-            let key = match Benc::node(bytes, Some(b'e')) {
-                Ok(Benc::String(n)) => if n > prev_key {
-                    n
+impl PartialOrd for Benc {
+    fn partial_cmp(&self, other: &Benc) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Trait impl's to consume the value returning a `Benc` type
+impl convert::From<String> for Benc {
+    fn from(s: String) -> Benc {
+        Benc::String(s.into_bytes())
+    }
+}
+
+impl<'a> convert::From<&'a str> for Benc {
+    fn from(s: &'a str) -> Benc {
+        Benc::String(s.as_bytes().to_vec())
+    }
+}
+
+impl convert::From<Vec<u8>> for Benc {
+    fn from(s: Vec<u8>) -> Benc {
+        Benc::String(s)
+    }
+}
+
+impl convert::From<i64> for Benc {
+    fn from(s: i64) -> Benc {
+        Benc::Int(s)
+    }
+}
+
+impl convert::TryFrom<u64> for Benc {
+    type Error = error::Error;
+
+    /// Fails if `s` is larger than `i64::MAX`, the largest int a `Benc` can hold
+    fn try_from(s: u64) -> error::Result<Benc> {
+        if s > i64::MAX as u64 {
+            return Err(error::Error::new(error::Error::Other(
+                "Integer is too large to bencode",
+            )));
+        }
+        Ok(Benc::Int(s as i64))
+    }
+}
+
+impl convert::From<Vec<Benc>> for Benc {
+    fn from(s: Vec<Benc>) -> Benc {
+        Benc::List(s)
+    }
+}
+
+impl convert::From<HashMap<Vec<u8>, Benc>> for Benc {
+    fn from(s: HashMap<Vec<u8>, Benc>) -> Benc {
+        Benc::Dict(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    //! Strings which are valid UTF-8 are (de)serialized as-is. All other strings, and UTF-8
+    //! strings which happen to start with `BASE64_PREFIX`, are base64 encoded and prefixed with
+    //! `BASE64_PREFIX` so they can be told apart. Dictionary keys are handled the same way, since
+    //! formats such as JSON only allow string keys.
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::fmt;
+
+    use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+    use serde::ser::{Serialize, SerializeMap, Serializer};
+
+    use super::Benc;
+
+    const BASE64_PREFIX: &str = "base64:";
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    fn encode_str(s: &[u8]) -> Cow<'_, str> {
+        match ::std::str::from_utf8(s) {
+            Ok(s) if !s.starts_with(BASE64_PREFIX) => Cow::Borrowed(s),
+            _ => Cow::Owned(format!("{}{}", BASE64_PREFIX, base64_encode(s))),
+        }
+    }
+
+    fn decode_str<E: de::Error>(s: &str) -> Result<Vec<u8>, E> {
+        match s.strip_prefix(BASE64_PREFIX) {
+            Some(b) => base64_decode(b).ok_or_else(|| E::custom("invalid base64 string")),
+            None => Ok(s.as_bytes().to_vec()),
+        }
+    }
+
+    fn base64_encode(bytes: &[u8]) -> String {
+        let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
                 } else {
-                    return err;
-                },
-                Ok(_) => return Err(error::Error::Other("Expected `BString` key for dictionary")),
-                Err(error::Error::Delim(_)) => return Ok(dict),
-                Err(e) => return Err(e),
-            };
+                    out.push('=');
+                }
+            }
+        }
 
-            // reuse prev_key's internal buffer if possible
-            prev_key.clear();
-            prev_key.extend(key.iter().cloned());
+        out
+    }
 
-            // value
-            let val = match Benc::node(bytes, None) {
-                Ok(n) => n,
-                Err(e) => return Err(e),
-            };
+    fn base64_decode(s: &str) -> Option<Vec<u8>> {
+        let s = s.as_bytes();
+        if !s.len().is_multiple_of(4) {
+            return None;
+        }
 
-            dict.insert(key, val);
+        let chunks = s.len() / 4;
+        let mut out = Vec::with_capacity(chunks * 3);
+
+        for (i, chunk) in s.chunks(4).enumerate() {
+            let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+            if pad > 2 || (pad > 0 && i + 1 != chunks) {
+                return None;
+            }
+
+            let mut n = 0u32;
+            for &c in &chunk[..4 - pad] {
+                n = n << 6 | ALPHABET.iter().position(|&a| a == c)? as u32;
+            }
+            n <<= 6 * pad;
+
+            out.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..3 - pad]);
         }
+
+        Some(out)
     }
 
-    /// Consumes as much of `bytes` as needed to build a single `Benc`oded value. If `bytes` has
-    /// nothing to read `Error::EOF` is returned
-    fn node<R>(bytes: &mut io::Bytes<R>, delim: Option<u8>) -> error::Result<Benc>
-    where
-        R: io::Read,
-    {
-        let err = Err(error::Error::Other("Parse error"));
+    impl Serialize for Benc {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match *self {
+                Benc::String(ref s) => serializer.serialize_str(&encode_str(s)),
+                Benc::Int(i) => serializer.serialize_i64(i),
+                Benc::List(ref l) => serializer.collect_seq(l),
+                Benc::Dict(ref d) => {
+                    // serialize in key order so the output is deterministic
+                    let mut map = serializer.serialize_map(Some(d.len()))?;
+                    for (k, v) in self.entries() {
+                        map.serialize_entry(&*encode_str(k), v)?;
+                    }
+                    map.end()
+                }
+            }
+        }
+    }
+
+    struct BencVisitor;
+
+    impl<'de> Visitor<'de> for BencVisitor {
+        type Value = Benc;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a string, integer, sequence, or map")
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Benc, E> {
+            Ok(Benc::Int(v))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Benc, E> {
+            match i64::try_from(v) {
+                Ok(v) => Ok(Benc::Int(v)),
+                Err(_) => Err(E::custom("integer out of range")),
+            }
+        }
 
-        let c = match bytes.next() {
-            Some(Ok(c)) if Some(c) == delim => return Err(error::Error::Delim(c)),
-            Some(Ok(0)) | None => return Err(error::Error::EndOfFile),
-            Some(Ok(c)) => c,
-            Some(Err(e)) => return Err(error::Error::Io(e)),
-        };
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Benc, E> {
+            decode_str(v).map(Benc::String)
+        }
 
-        match NodeType::type_of(c) {
-            Some(NodeType::String) => Ok(Benc::from(try!(Benc::string(bytes, c)))),
-            Some(NodeType::Int) => Ok(Benc::from(try!(Benc::int(bytes)))),
-            Some(NodeType::List) => Ok(Benc::from(try!(Benc::list(bytes)))),
-            Some(NodeType::Dict) => Ok(Benc::from(try!(Benc::dict(bytes)))),
-            None => err,
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Benc, E> {
+            Ok(Benc::String(v.to_vec()))
         }
-    }
-}
 
-// Trait impl's to consume the value returning a `Benc` type
-impl convert::From<String> for Benc {
-    fn from(s: String) -> Benc {
-        Benc::String(s.into_bytes())
-    }
-}
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Benc, A::Error> {
+            let mut list = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(v) = seq.next_element()? {
+                list.push(v);
+            }
 
-impl convert::From<Vec<u8>> for Benc {
-    fn from(s: Vec<u8>) -> Benc {
-        Benc::String(s)
-    }
-}
+            Ok(Benc::List(list))
+        }
 
-impl convert::From<i64> for Benc {
-    fn from(s: i64) -> Benc {
-        Benc::Int(s)
-    }
-}
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Benc, A::Error> {
+            let mut dict = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+            while let Some((k, v)) = map.next_entry::<Cow<'de, str>, Benc>()? {
+                dict.insert(decode_str(&k)?, v);
+            }
 
-impl convert::From<Vec<Benc>> for Benc {
-    fn from(s: Vec<Benc>) -> Benc {
-        Benc::List(s)
+            Ok(Benc::Dict(dict))
+        }
     }
-}
 
-impl convert::From<HashMap<Vec<u8>, Benc>> for Benc {
-    fn from(s: HashMap<Vec<u8>, Benc>) -> Benc {
-        Benc::Dict(s)
+    impl<'de> Deserialize<'de> for Benc {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Benc, D::Error> {
+            deserializer.deserialize_any(BencVisitor)
+        }
     }
 }
 
@@ -307,12 +1174,16 @@ mod test_nodetype {
 #[cfg(test)]
 mod test_benc {
     use std::borrow::ToOwned;
+    use std::convert::TryFrom;
     use std::fmt::Debug;
-    use std::io::{self, Read};
+    use std::io::Read;
 
     use super::error;
+    use super::errors;
     use super::Benc;
+    use super::Limits;
     use super::Benc as B;
+    use super::slice::{self, Decoder};
 
     macro_rules! hashmap {
         ($($k:expr => $v:expr),*) => ({
@@ -359,23 +1230,92 @@ mod test_benc {
         assert!(result == expect, "{:?} == {:?}", result, expect);
     }
 
+    #[test]
+    fn truncated() {
+        let data = concat!(
+            "d8:announce40:http://tracker.example.com:8080/announce7:comment17:\"Hello mock data",
+            "\"13:creation datei1234567890e9:httpseedsl31:http://direct.example.com/mock131:http",
+            "://direct.example.com/mock2e4:infod6:lengthi562949953421312e4:name15:",
+            "あいえおう12:piece lengthi536870912e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").as_bytes();
+        assert!(Benc::new(&mut data.bytes()).is_ok());
+
+        // every prefix is an unclosed dict
+        for end in 1..data.len() {
+            let result = Benc::new(&mut data[..end].bytes());
+            assert!(result.is_err(), "{}: {:?}", end, result);
+        }
+        assert!(Benc::new(&mut data[..0].bytes()) == Ok(vec![]));
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            max_depth: 2,
+            max_string_len: 4,
+        };
+        let new = |data: &str| Benc::new_with(limits, &mut data.as_bytes().bytes());
+
+        let expect = Ok(vec![B::List(vec![B::Dict(hashmap!(
+            bytes!("spam") => B::String(bytes!("eggs")),
+        ))])]);
+        let result = new("ld4:spam4:eggsee");
+        assert!(result == expect, "{:?} == {:?}", result, expect);
+
+        for (data, err) in [
+            ("lld4:spam4:eggseee", errors::too_deep(2)),
+            ("d4:spamlleee", errors::too_deep(8)),
+            ("5:hello", errors::string_too_long(1)),
+            ("ld5:spams4:eggsee", errors::string_too_long(3)),
+        ] {
+            let result = new(data).unwrap_err();
+            assert!(result == err, "{}: {:?} == {:?}", data, result, err);
+        }
+
+        // the default limits stop runaway nesting before the stack does
+        let deep = "l".repeat(100_000);
+        let result = Benc::new(&mut deep.as_bytes().bytes()).unwrap_err();
+        assert!(result == errors::too_deep(256), "{:?}", result);
+    }
+
+    #[test]
+    fn truncated_string() {
+        for (data, err) in [
+            ("5:abc", errors::invalid_string(5)),
+            ("5:", errors::invalid_string(2)),
+            ("5", errors::invalid_string(1)),
+            ("l5:abc", errors::invalid_string(6)),
+        ] {
+            let result = Benc::new(&mut data.as_bytes().bytes()).unwrap_err();
+            assert!(result == err, "{}: {:?} == {:?}", data, result, err);
+        }
+    }
+
+    #[test]
+    fn huge_string_length() {
+        // the length alone must not allocate
+        for data in &["99999999999999:abc", "9223372036854775807:"] {
+            let result = Benc::new(&mut data.as_bytes().bytes());
+            assert!(result.is_err(), "{}: {:?}", data, result);
+        }
+    }
+
     #[test]
     fn string() {
         fn is_valid(data: &str, first: u8) {
             let expect = data.splitn(2, |b| b == ':').nth(1).unwrap();
 
             assert(
-                |brd| Benc::string(brd, first),
-                data.as_bytes().bytes(),
+                |d| d.string(first),
+                data.as_bytes(),
                 Ok(bytes!(expect)),
             );
         }
 
-        fn is_invalid(data: &str, first: u8) {
+        fn is_invalid(data: &str, first: u8, err: error::Error) {
             assert(
-                |brd| Benc::string(brd, first),
-                data.as_bytes().bytes(),
-                Err(error::Error::Other("Mock data")),
+                |d| d.string(first),
+                data.as_bytes(),
+                Err(err),
             );
         }
 
@@ -385,54 +1325,71 @@ mod test_benc {
         is_valid("1:hellohello1", b'1');
         is_valid("2:hi", b'0');
 
-        is_invalid(":hello", b'6');
-        is_invalid("5:hallo", b'a');
-        is_invalid("", b'a');
-        is_invalid("8446744073709551616:overflow", b'1') // u64::MAX + 1
+        is_invalid(":hello", b'6', errors::invalid_string(6));
+        is_invalid("5:hallo", b'a', errors::invalid_string(0));
+        is_invalid("", b'a', errors::invalid_string(0));
+        is_invalid("2x:hi", b'1', errors::invalid_string(1));
+        // input ends before the string does
+        is_invalid(":abc", b'5', errors::invalid_string(4));
+        is_invalid(":", b'5', errors::invalid_string(1));
+        is_invalid("", b'5', errors::invalid_string(0));
+        // u64::MAX + 1
+        is_invalid("8446744073709551616:overflow", b'1', errors::overflow(18));
     }
 
     #[test]
     fn int() {
         fn is_valid(expect: i64) {
             assert(
-                Benc::int,
-                format!("{}e", expect).as_bytes().bytes(),
+                Decoder::int,
+                format!("{}e", expect).as_bytes(),
                 Ok(expect),
             );
         }
 
-        fn is_invalid(data: &str) {
-            assert(
-                Benc::int,
-                data.as_bytes().bytes(),
-                Err(error::Error::Other("Mock data")),
-            );
+        fn is_invalid(data: &str, err: error::Error) {
+            assert(Decoder::int, data.as_bytes(), Err(err));
         }
 
         is_valid(2 << 48);
         is_valid(-2 << 48);
         is_valid(0);
-        is_valid(::std::i64::MAX);
-
-        is_invalid("e");
-        is_invalid("-0e");
-        is_invalid("00e");
-        is_invalid("05e");
-        is_invalid(&format!("{}e", ::std::u64::MAX));
+        is_valid(i64::MAX);
+        is_valid(-i64::MAX);
+        is_valid(999_999_999_999_999_999);
+        is_valid(-12_345_678_901_234_567);
+        is_valid(12_345_678);
+
+        is_invalid("e", errors::invalid_int(0));
+        is_invalid("-0e", errors::invalid_int(1));
+        is_invalid("00e", errors::invalid_int(1));
+        is_invalid("05e", errors::invalid_int(1));
+        is_invalid("4x2e", errors::invalid_int(1));
+        is_invalid("42", errors::invalid_int(2));
+        is_invalid("", errors::invalid_int(0));
+        is_invalid("-", errors::invalid_int(1));
+        is_invalid("-e", errors::invalid_int(1));
+        is_invalid("-05e", errors::invalid_int(1));
+        is_invalid("0", errors::invalid_int(1));
+        is_invalid("0x", errors::invalid_int(1));
+        is_invalid("1234567x9e", errors::invalid_int(7));
+        is_invalid("123456789012345678:e", errors::invalid_int(18));
+        // the sign is applied after the digits are read, so i64::MIN overflows
+        is_invalid(&format!("{}e", i64::MIN), errors::overflow(19));
+        is_invalid(&format!("{}e", u64::MAX), errors::overflow(19));
     }
 
     #[test]
     fn list() {
         assert(
-            Benc::list,
-            b"5:helloi42ee".bytes(),
+            Decoder::list,
+            b"5:helloi42ee",
             Ok(vec![B::String(bytes!("hello")), B::Int(42)]),
         );
 
         assert(
-            Benc::list,
-            b"5:helloi42eli2ei3e2:hid4:listli1ei2ei3ee7:yahallo2::)eed2:hi5:hello3:inti15eee"
-                .bytes(),
+            Decoder::list,
+            b"5:helloi42eli2ei3e2:hid4:listli1ei2ei3ee7:yahallo2::)eed2:hi5:hello3:inti15eee",
             Ok(vec![
                 B::String(bytes!("hello")),
                 B::Int(42),
@@ -453,29 +1410,34 @@ mod test_benc {
         );
 
         assert(
-            Benc::list,
-            b"5:helloi4e".bytes(),
-            Err(error::Error::Other("Mock data")),
+            Decoder::list,
+            b"5:helloi4e",
+            Err(errors::unexpected_eof(10)),
+        );
+
+        assert(
+            Decoder::list,
+            b"5:hellox",
+            Err(errors::invalid_node(7)),
         );
     }
 
     #[test]
     fn dict() {
         assert(
-            Benc::dict,
-            b"2:hi5:helloe".bytes(),
+            Decoder::dict,
+            b"2:hi5:helloe",
             Ok(hashmap!(
                 bytes!("hi") => B::String(bytes!("hello")),
             )),
         );
 
         assert(
-            Benc::dict,
+            Decoder::dict,
             concat!(
                 "10:dictionaryd2:hi5:hello3:inti15ee7:integeri42e4:listli2ei3e2:hid4:listli1ei2e",
                 "i3ee7:yahallo2::)ee3:str5:helloe"
-            ).as_bytes()
-                .bytes(),
+            ).as_bytes(),
             Ok(hashmap!(
                 bytes!("str")     => B::String(bytes!("hello")),
                 bytes!("integer") => B::Int(42),
@@ -496,25 +1458,659 @@ mod test_benc {
         );
 
         assert(
-            Benc::dict,
-            b"2:hi5:hello1:ai32ee".bytes(),
-            Err(error::Error::Other("Mock data")),
+            Decoder::dict,
+            b"2:hi5:hello1:ai32ee",
+            Err(errors::unsorted_key(bytes!("a"))),
+        );
+
+        assert(
+            Decoder::dict,
+            b"2:hi5:hello2:hii1ee",
+            Err(errors::unsorted_key(bytes!("hi"))),
+        );
+
+        assert(
+            Decoder::dict,
+            b"2:hi5:helloi1ei2ee",
+            Err(errors::non_string_key(11)),
+        );
+
+        assert(
+            Decoder::dict,
+            b"2:hi5:hello",
+            Err(errors::unexpected_eof(11)),
+        );
+
+        assert(
+            Decoder::dict,
+            b"2:hi",
+            Err(errors::unexpected_eof(4)),
+        );
+    }
+
+    #[test]
+    fn into() {
+        let tree = B::Dict(hashmap!(
+            bytes!("list") => B::List(vec![B::String(bytes!("hello")), B::Int(42)]),
+        ));
+
+        let mut dict = tree.into_dict().unwrap();
+        let mut list = dict.remove(&bytes!("list")).unwrap().into_list().unwrap();
+        assert!(list.len() == 2);
+        assert!(list.pop().unwrap().into_int() == Some(42));
+        assert!(list.pop().unwrap().into_bytes() == Some(bytes!("hello")));
+
+        // the wrong type gives nothing
+        assert!(B::Int(1).into_bytes().is_none());
+        assert!(B::String(bytes!("1")).into_int().is_none());
+        assert!(B::Dict(::std::collections::HashMap::new()).into_list().is_none());
+        assert!(B::List(vec![]).into_dict().is_none());
+    }
+
+    #[test]
+    fn from() {
+        assert!(B::from("hello") == B::String(bytes!("hello")));
+        assert!(B::from("") == B::String(Vec::new()));
+
+        for (n, expect) in [(0, Some(0)), (42, Some(42)), (i64::MAX as u64, Some(i64::MAX))] {
+            let result = B::try_from(n).ok();
+            let expect = expect.map(B::Int);
+            assert!(result == expect, "{:?} == {:?}", result, expect);
+        }
+        for n in [i64::MAX as u64 + 1, u64::MAX] {
+            assert!(B::try_from(n).is_err(), "{}", n);
+        }
+    }
+
+    #[test]
+    fn slice_decode() {
+        // trailing bytes are left for the caller
+        let data = b"d3:fooli1ei2eee4:tail";
+        let expect = B::Dict(hashmap!(bytes!("foo") => B::List(vec![B::Int(1), B::Int(2)])));
+        let (benc, len) = slice::decode(data).unwrap();
+        assert!(benc == expect, "{:?} == {:?}", benc, expect);
+        assert!(len == 15, "{} == 15", len);
+        assert!(&data[len..] == b"4:tail");
+
+        let limits = Limits {
+            max_depth: 1,
+            ..Limits::default()
+        };
+        for (data, expect) in [
+            (&b""[..], errors::unexpected_eof(0)),
+            (&b"li1e"[..], errors::unexpected_eof(4)),
+            (&b"d3:fooi1x2ee"[..], errors::invalid_int(8)),
+            (&b"3:ab"[..], errors::invalid_string(4)),
+        ] {
+            let result = slice::decode(data).err();
+            assert!(result.as_ref() == Some(&expect), "{:?} == {:?}", result, expect);
+        }
+
+        let result = slice::decode_with(b"lli1eee", limits).err();
+        let expect = Some(errors::too_deep(1));
+        assert!(result == expect, "{:?} == {:?}", result, expect);
+    }
+
+    #[test]
+    fn spans() {
+        // "info" is only recorded in the outermost dict, and keeps its original encoding
+        let data = b"d1:ad4:infoi1ee4:infod1:bi1eee4:tail";
+        let (benc, spans) = Benc::new_with_spans(data).unwrap();
+        assert!(benc.is_dict());
+        assert!(spans.len == 30, "{} == 30", spans.len);
+        assert!(spans.info == Some(21..29), "{:?} == Some(21..29)", spans.info);
+        assert!(&data[21..29] == b"d1:bi1ee");
+
+        for data in [&b"li1ee"[..], &b"d4:infoli1eee"[..]] {
+            let (_, spans) = Benc::new_with_spans(data).unwrap();
+            assert!(spans.len == data.len(), "{} == {}", spans.len, data.len());
+        }
+        let (_, spans) = Benc::new_with_spans(b"d3:fooi1ee").unwrap();
+        assert!(spans.info.is_none(), "{:?}", spans.info);
+    }
+
+    #[test]
+    fn new_offset() {
+        // offsets count from the start of the input
+        let result = Benc::new(&mut b"i1ed3:fooi1x2ee".bytes());
+        let expect = Err(errors::invalid_int(11));
+        assert!(result == expect, "{:?} == {:?}", result, expect);
+
+        let err = errors::invalid_int(11);
+        assert!(err.to_string() == "Invalid int bencoding at byte 11", "{}", err);
+    }
+
+    #[test]
+    fn take_text() {
+        let mut dict = hashmap!(
+            bytes!("comment")    => B::String(bytes!("あいえおう")),
+            bytes!("created by") => B::String(vec![0xff, 0xfe]),
+            bytes!("length")     => B::Int(42),
+        );
+
+        let comment = super::take_text(&mut dict, b"comment");
+        assert!(comment == Some("あいえおう".to_owned()), "{:?}", comment);
+        assert!(super::take_text(&mut dict, b"created by").is_none());
+        assert!(super::take_text(&mut dict, b"length").is_none());
+        assert!(super::take_text(&mut dict, b"announce").is_none());
+
+        // taken keys are removed, even if they are not text
+        assert!(dict.is_empty(), "{:?}", dict);
+    }
+
+    #[test]
+    fn take_preferring_utf8() {
+        let mut dict = hashmap!(
+            bytes!("name")          => B::String(vec![0x82, 0xa0]),
+            bytes!("name.utf-8")    => B::String(bytes!("あ")),
+            bytes!("comment")       => B::String(vec![0x82, 0xa0]),
+            bytes!("comment.utf-8") => B::String(vec![0xff, 0xfe]),
+        );
+
+        let name = super::take_preferring_utf8(&mut dict, b"name", Some("Shift_JIS"));
+        assert!(name == (Some(B::String(bytes!("あ"))), None), "{:?}", name);
+        // an invalid copy falls back to the legacy text and its encoding
+        let comment = super::take_preferring_utf8(&mut dict, b"comment", Some("Shift_JIS"));
+        let expect = (Some(B::String(vec![0x82, 0xa0])), Some("Shift_JIS"));
+        assert!(comment == expect, "{:?} == {:?}", comment, expect);
+        assert!(dict.is_empty(), "{:?}", dict);
+    }
+
+    #[test]
+    fn dict_eq() {
+        let entries = || {
+            vec![
+                (bytes!("announce"), B::String(bytes!("http://tracker.example.com"))),
+                (bytes!("comment"), B::String(bytes!("mock"))),
+                (bytes!("creation date"), B::Int(1234567890)),
+                (bytes!("info"), B::Dict(hashmap!(bytes!("length") => B::Int(42)))),
+            ]
+        };
+        let forward: ::std::collections::HashMap<_, _> = entries().into_iter().collect();
+        let reverse: ::std::collections::HashMap<_, _> = entries().into_iter().rev().collect();
+        let (forward, reverse) = (B::Dict(forward), B::Dict(reverse));
+
+        assert!(forward == reverse, "{:?} == {:?}", forward, reverse);
+        assert!(forward.cmp(&reverse) == ::std::cmp::Ordering::Equal);
+
+        // nested in lists, where order matters
+        let list = B::List(vec![forward, B::Int(1)]);
+        assert!(list == B::List(vec![reverse, B::Int(1)]));
+        assert!(list != B::List(vec![B::Int(1), B::Dict(entries().into_iter().collect())]));
+
+        // decoded values compare equal to ones built by hand
+        let decoded = Benc::new(&mut "d1:ai1e1:bi2ee".as_bytes().bytes()).unwrap();
+        let built = B::Dict(hashmap!(bytes!("b") => B::Int(2), bytes!("a") => B::Int(1)));
+        assert!(decoded == [built]);
+    }
+
+    #[test]
+    fn eq_canonical() {
+        let entries = || {
+            vec![
+                (bytes!("announce"), B::String(bytes!("http://tracker.example.com"))),
+                (bytes!("info"), B::Dict(hashmap!(
+                    bytes!("length") => B::Int(42),
+                    bytes!("name")   => B::String(bytes!("file.ext")),
+                    bytes!("files")  => B::List(vec![B::Dict(hashmap!(
+                        bytes!("path")   => B::List(vec![B::String(bytes!("a"))]),
+                        bytes!("length") => B::Int(1),
+                    ))]),
+                ))),
+                (bytes!("url-list"), B::List(vec![B::String(bytes!("http://mirror.example.com"))])),
+            ]
+        };
+        let dicts: Vec<_> = vec![
+            entries(),
+            entries().into_iter().rev().collect(),
+            {
+                let mut e = entries();
+                e.rotate_left(1);
+                e
+            },
+        ]
+        .into_iter()
+        .map(|e| B::Dict(e.into_iter().collect()))
+        .collect();
+
+        for a in &dicts {
+            for b in &dicts {
+                assert!(a == b, "{:?} == {:?}", a, b);
+                assert!(a.eq_canonical(b), "{:?} == {:?}", a, b);
+            }
+        }
+
+        let pairs = vec![
+            (B::Int(1), B::Int(2), false),
+            (B::Int(1), B::String(bytes!("1")), false),
+            (B::String(bytes!("a")), B::String(bytes!("a")), true),
+            (B::List(vec![B::Int(1), B::Int(2)]), B::List(vec![B::Int(2), B::Int(1)]), false),
+            (B::List(vec![B::Int(1)]), B::List(vec![B::Int(1), B::Int(1)]), false),
+            (
+                B::Dict(hashmap!(bytes!("a") => B::Int(1))),
+                B::Dict(hashmap!(bytes!("b") => B::Int(1))),
+                false,
+            ),
+            (
+                B::Dict(hashmap!(bytes!("a") => B::Dict(hashmap!(bytes!("b") => B::Int(1))))),
+                B::Dict(hashmap!(bytes!("a") => B::Dict(hashmap!(bytes!("b") => B::Int(2))))),
+                false,
+            ),
+        ];
+        for (a, b, expect) in &pairs {
+            assert!(a.eq_canonical(b) == *expect, "{:?} == {:?}", a, b);
+            assert!((a == b) == *expect, "{:?} == {:?}", a, b);
+        }
+
+        // deep enough to overflow the stack if compared recursively
+        let deep = || {
+            let mut deep = B::Int(0);
+            for _ in 0..100_000 {
+                deep = B::List(vec![deep]);
+            }
+            deep
+        };
+        let (mut a, mut b) = (deep(), deep());
+        assert!(a.eq_canonical(&b));
+        // dropping is recursive, so take the trees apart first
+        for deep in [&mut a, &mut b] {
+            while let B::List(ref mut l) = *deep {
+                match l.pop() {
+                    Some(inner) => *deep = inner,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn byte_size() {
+        let trees = vec![
+            B::String(Vec::new()),
+            B::String(vec![b'a'; 10]),
+            B::Int(0),
+            B::Int(-7),
+            B::Int(i64::MAX),
+            B::Int(i64::MIN),
+            B::List(Vec::new()),
+            B::Dict(hashmap!()),
+            B::List(vec![B::Int(1), B::List(vec![B::String(bytes!("spam"))])]),
+            B::Dict(hashmap!(
+                bytes!("info") => B::Dict(hashmap!(
+                    bytes!("length")       => B::Int(562949953421312),
+                    bytes!("name")         => B::String(bytes!("あいえおう")),
+                    bytes!("piece length") => B::Int(16384),
+                )),
+                bytes!("announce") => B::String(bytes!("http://tracker.example.com:8080/announce")),
+                bytes!("url-list") => B::List((0..12).map(|_| B::String(vec![0; 100])).collect()),
+            )),
+        ];
+
+        for tree in &trees {
+            let encoded = tree.encode();
+            assert!(tree.byte_size() == encoded.len(), "{:?}", tree);
+        }
+
+        let counts: Vec<_> = trees.iter().map(Benc::count_nodes).collect();
+        let expect = [1, 1, 1, 1, 1, 1, 1, 1, 4, 19];
+        assert!(counts == expect, "{:?} == {:?}", counts, expect);
+    }
+
+    #[test]
+    fn encode() {
+        let tree = B::Dict(hashmap!(
+            bytes!("b") => B::List(vec![B::Int(-1), B::String(bytes!("spam"))]),
+            bytes!("a") => B::Int(0),
+        ));
+        let encoded = tree.encode();
+        assert!(encoded == b"d1:ai0e1:bli-1e4:spamee", "{:?}", String::from_utf8_lossy(&encoded));
+
+        let decoded = Benc::new(&mut encoded.as_slice().bytes()).unwrap();
+        assert!(decoded == [tree]);
+    }
+
+    #[test]
+    fn merge() {
+        let mut tree = B::Dict(hashmap!(
+            bytes!("announce") => B::String(bytes!("http://old.example.com")),
+            bytes!("url-list") => B::List(vec![B::String(bytes!("http://a.example.com"))]),
+            bytes!("info")     => B::Dict(hashmap!(
+                bytes!("name")   => B::String(bytes!("mock")),
+                bytes!("length") => B::Int(42),
+            )),
+        ));
+
+        tree.merge(B::Dict(hashmap!(
+            bytes!("announce") => B::String(bytes!("http://new.example.com")),
+            bytes!("url-list") => B::List(vec![B::String(bytes!("http://b.example.com"))]),
+            bytes!("comment")  => B::String(bytes!("spam")),
+            bytes!("info")     => B::Dict(hashmap!(
+                bytes!("length") => B::Int(7),
+                bytes!("pieces") => B::String(vec![0; 20]),
+            )),
+        )))
+        .unwrap();
+
+        // values are overwritten and lists replaced, while nested dicts keep their other keys
+        let expect = B::Dict(hashmap!(
+            bytes!("announce") => B::String(bytes!("http://new.example.com")),
+            bytes!("url-list") => B::List(vec![B::String(bytes!("http://b.example.com"))]),
+            bytes!("comment")  => B::String(bytes!("spam")),
+            bytes!("info")     => B::Dict(hashmap!(
+                bytes!("name")   => B::String(bytes!("mock")),
+                bytes!("length") => B::Int(7),
+                bytes!("pieces") => B::String(vec![0; 20]),
+            )),
+        ));
+        assert!(tree == expect, "{:?} == {:?}", tree, expect);
+
+        // a dict replaces a value of another type, and the other way around
+        tree.merge(B::Dict(hashmap!(
+            bytes!("comment") => B::Dict(hashmap!(bytes!("en") => B::String(bytes!("spam")))),
+            bytes!("info")    => B::Int(0),
+        )))
+        .unwrap();
+        assert!(tree.as_dict().unwrap()[&bytes!("comment")].is_dict());
+        assert!(tree.as_dict().unwrap()[&bytes!("info")] == B::Int(0));
+    }
+
+    #[test]
+    fn merge_mismatch() {
+        let mut tree = B::Dict(hashmap!(bytes!("a") => B::Int(1)));
+        assert!(tree.merge(B::List(vec![])).is_err());
+        assert!(tree == B::Dict(hashmap!(bytes!("a") => B::Int(1))));
+
+        let mut int = B::Int(1);
+        assert!(int.merge(B::Dict(hashmap!(bytes!("a") => B::Int(2)))).is_err());
+        assert!(int == B::Int(1));
+    }
+
+    #[test]
+    fn walk() {
+        let tree = B::Dict(hashmap!(
+            bytes!("b") => B::List(vec![B::Int(1), B::String(bytes!("spam"))]),
+            bytes!("a") => B::Int(0),
+            bytes!("c") => B::Dict(hashmap!(bytes!("d") => B::String(bytes!("eggs")))),
+        ));
+
+        let mut visited = Vec::new();
+        tree.walk(&mut |n| visited.push(n.type_name()));
+        let expect = ["dict", "int", "list", "int", "string", "dict", "string"];
+        assert!(visited == expect, "{:?} == {:?}", visited, expect);
+
+        let mut strings = Vec::new();
+        tree.walk(&mut |n| strings.extend(n.as_str().map(|s| s.to_owned())));
+        assert!(strings == ["spam", "eggs"], "{:?}", strings);
+
+        // deep enough to overflow the stack if walked recursively
+        let mut deep = B::Int(0);
+        for _ in 0..100_000 {
+            deep = B::List(vec![deep]);
+        }
+        let mut count = 0;
+        deep.walk(&mut |_| count += 1);
+        assert!(count == 100_001, "{}", count);
+        // dropping is recursive, so take the tree apart first
+        while let B::List(ref mut l) = deep {
+            match l.pop() {
+                Some(inner) => deep = inner,
+                None => break,
+            }
+        }
+    }
+
+    #[test]
+    fn walk_mut() {
+        let mut tree = B::List(vec![
+            B::String(bytes!("spam")),
+            B::Dict(hashmap!(bytes!("a") => B::String(bytes!("eggs")), bytes!("b") => B::Int(1))),
+        ]);
+
+        // replaced values are walked too
+        tree.walk_mut(&mut |n| match *n {
+            B::String(ref mut s) if s == b"spam" => *n = B::List(vec![B::Int(2)]),
+            B::Int(ref mut i) => *i *= 10,
+            B::String(ref mut s) => s.make_ascii_uppercase(),
+            _ => (),
+        });
+
+        let expect = B::List(vec![
+            B::List(vec![B::Int(20)]),
+            B::Dict(hashmap!(bytes!("a") => B::String(bytes!("EGGS")), bytes!("b") => B::Int(10))),
+        ]);
+        assert!(tree == expect, "{:?} == {:?}", tree, expect);
+    }
+
+    #[test]
+    fn predicates() {
+        let nodes = [
+            B::String(bytes!("spam")),
+            B::Int(42),
+            B::List(vec![B::Int(1)]),
+            B::Dict(hashmap!(bytes!("a") => B::Int(1))),
+        ];
+
+        for (i, n) in nodes.iter().enumerate() {
+            let is = [n.is_string(), n.is_int(), n.is_list(), n.is_dict()];
+            let expect: Vec<_> = (0..4).map(|j| i == j).collect();
+            assert!(is[..] == expect[..], "{:?}: {:?} == {:?}", n, is, expect);
+
+            assert!(n.as_bytes().is_some() == n.is_string());
+            assert!(n.as_int().is_some() == n.is_int());
+            assert!(n.as_list().is_some() == n.is_list());
+            assert!(n.as_dict().is_some() == n.is_dict());
+        }
+
+        assert!(nodes[0].as_bytes() == Some(&b"spam"[..]));
+        assert!(nodes[0].as_str() == Some("spam"));
+        assert!(B::String(vec![0xff]).as_str().is_none());
+        assert!(nodes[1].as_int() == Some(42));
+        assert!(nodes[2].as_list() == Some(&[B::Int(1)][..]));
+        assert!(nodes[3].as_dict().map(|d| d.len()) == Some(1));
+    }
+
+    #[test]
+    fn entries() {
+        let data = concat!(
+            "d8:announce40:http://tracker.example.com:8080/announce7:comment17:\"Hello mock data",
+            "\"13:creation datei1234567890e9:httpseedsl31:http://direct.example.com/mock131:http",
+            "://direct.example.com/mock2e4:infod6:lengthi562949953421312e4:name15:あいえおう12:p",
+            "iece lengthi536870912eee").as_bytes();
+        let benc = Benc::new(&mut data.bytes()).unwrap();
+
+        let keys: Vec<_> = benc[0].entries().map(|(k, _)| k).collect();
+        let expect: Vec<&[u8]> =
+            vec![b"announce", b"comment", b"creation date", b"httpseeds", b"info"];
+        assert!(keys == expect, "{:?} == {:?}", keys, expect);
+
+        let (_, date) = benc[0].entries().nth(2).unwrap();
+        assert!(*date == B::Int(1234567890), "{:?}", date);
+
+        assert!(B::Int(1).entries().next().is_none());
+        assert!(B::List(vec![B::Int(1)]).entries().next().is_none());
+    }
+
+    #[test]
+    fn to_pretty_string() {
+        let data = concat!(
+            "d8:announce40:http://tracker.example.com:8080/announce4:infod6:lengthi42e4:name3:foo",
+            "12:piece lengthi16384e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb",
+            "ee").as_bytes();
+        let benc = Benc::new(&mut data.bytes()).unwrap();
+        let pretty = benc[0].to_pretty_string();
+
+        assert!(
+            pretty.contains("\"announce\": \"http://tracker.example.com:8080/announce\""),
+            "{}",
+            pretty
         );
+        assert!(pretty.contains("    \"length\": 42,"), "{}", pretty);
+        assert!(
+            pretty.contains(&format!("\"pieces\": <40 bytes: {}...>", "61".repeat(20))),
+            "{}",
+            pretty
+        );
+
+        let pretty = B::List(vec![B::String(vec![0xff, 0x00]), B::List(vec![])]).to_pretty_string();
+        assert!(pretty == "[\n  <2 bytes: ff00>,\n  [],\n]", "{}", pretty);
+    }
+
+    #[test]
+    fn hash() {
+        use std::collections::HashSet;
+
+        let mut set = HashSet::new();
+        set.insert(B::Dict(hashmap!(
+            bytes!("length") => B::Int(42),
+            bytes!("path")   => B::List(vec![B::String(bytes!("a")), B::String(bytes!("b"))]),
+            bytes!("md5sum") => B::String(bytes!("d41d8cd98f00b204e9800998ecf8427e")),
+        )));
+        set.insert(B::Dict(hashmap!(
+            bytes!("md5sum") => B::String(bytes!("d41d8cd98f00b204e9800998ecf8427e")),
+            bytes!("path")   => B::List(vec![B::String(bytes!("a")), B::String(bytes!("b"))]),
+            bytes!("length") => B::Int(42),
+        )));
+        assert!(set.len() == 1, "{} == 1", set.len());
+
+        set.insert(B::Dict(hashmap!(
+            bytes!("length") => B::Int(42),
+            bytes!("path")   => B::List(vec![B::String(bytes!("b")), B::String(bytes!("a"))]),
+            bytes!("md5sum") => B::String(bytes!("d41d8cd98f00b204e9800998ecf8427e")),
+        )));
+        assert!(set.len() == 2, "{} == 2", set.len());
+    }
+
+    #[test]
+    fn ord() {
+        let sorted = vec![
+            B::String(bytes!("a")),
+            B::String(bytes!("ab")),
+            B::String(bytes!("b")),
+            B::Int(-5),
+            B::Int(0),
+            B::Int(42),
+            B::List(vec![]),
+            B::List(vec![B::String(bytes!("z"))]),
+            B::List(vec![B::Int(1)]),
+            B::List(vec![B::Int(1), B::Int(2)]),
+            B::Dict(::std::collections::HashMap::new()),
+            B::Dict(hashmap!(
+                bytes!("a") => B::Int(1),
+                bytes!("b") => B::Int(1),
+            )),
+            B::Dict(hashmap!(
+                bytes!("a") => B::Int(2),
+            )),
+            B::Dict(hashmap!(
+                bytes!("b") => B::Int(0),
+            )),
+        ];
+
+        let mut mixed = vec![
+            B::Dict(hashmap!(bytes!("b") => B::Int(0))),
+            B::Int(42),
+            B::List(vec![B::Int(1), B::Int(2)]),
+            B::String(bytes!("b")),
+            B::Dict(hashmap!(bytes!("a") => B::Int(2))),
+            B::List(vec![]),
+            B::Int(-5),
+            B::Dict(hashmap!(
+                bytes!("b") => B::Int(1),
+                bytes!("a") => B::Int(1),
+            )),
+            B::String(bytes!("ab")),
+            B::List(vec![B::Int(1)]),
+            B::Dict(::std::collections::HashMap::new()),
+            B::Int(0),
+            B::List(vec![B::String(bytes!("z"))]),
+            B::String(bytes!("a")),
+        ];
+        mixed.sort();
+
+        assert!(mixed == sorted, "{:?} == {:?}", mixed, sorted);
     }
 
-    fn assert<R, O, E, F>(func: F, mut data: io::Bytes<R>, expect: Result<O, E>)
+    fn assert<'a, O, E, F>(func: F, data: &'a [u8], expect: Result<O, E>)
     where
-        R: io::Read,
         O: PartialEq + Debug,
         E: PartialEq + Debug,
-        F: Fn(&mut io::Bytes<R>) -> Result<O, E>,
+        F: Fn(&mut Decoder<'a>) -> Result<O, E>,
     {
-        let result = func(&mut data);
+        let result = func(&mut Decoder::new(data, Limits::default()));
 
-        match result {
-            Ok(_) => assert!(result == expect, "{:?} == {:?}", result, expect),
-            Err(_) => assert!(expect.is_err(), "{:?} == {:?}", result, expect),
-        }
+        assert!(result == expect, "{:?} == {:?}", result, expect);
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test_serde {
+    use std::borrow::ToOwned;
+
+    use super::Benc as B;
+
+    macro_rules! hashmap {
+        ($($k:expr => $v:expr),*) => ({
+            let mut d = ::std::collections::HashMap::new();
+            $(d.insert($k, $v);)*
+            d
+        });
+
+        ($($k:expr => $v:expr),+,) => (hashmap!($($k => $v),+));
+    }
+
+    macro_rules! bytes {
+        ($s:expr) => {
+            $s.to_owned().into_bytes()
+        };
+    }
+
+    #[test]
+    fn serialize() {
+        let node = B::Dict(hashmap!(
+            bytes!("name")   => B::String(bytes!("あいえおう")),
+            bytes!("length") => B::Int(-42),
+            bytes!("list")   => B::List(vec![B::Int(1), B::String(bytes!("x"))]),
+            bytes!("pieces") => B::String(vec![0xff, 0x00, 0xfe, 0x01]),
+        ));
+        let expect = concat!(
+            r#"{"length":-42,"list":[1,"x"],"name":"あいえおう","#,
+            r#""pieces":"base64:/wD+AQ=="}"#
+        );
+
+        let json = serde_json::to_string(&node).unwrap();
+        assert!(json == expect, "{} == {}", json, expect);
+    }
+
+    #[test]
+    fn round_trip() {
+        let node = B::List(vec![
+            B::Dict(hashmap!(
+                bytes!("announce")  => B::String(bytes!("http://tracker.example.com:8080/")),
+                vec![0xc3, 0x28]    => B::String(bytes!("non UTF-8 key")),
+                bytes!("base64:x")  => B::String(bytes!("base64:looks encoded")),
+                bytes!("info")      => B::Dict(hashmap!(
+                    bytes!("length") => B::Int(562949953421312),
+                    bytes!("pieces") => B::String((0..=255).collect()),
+                )),
+            )),
+            B::Int(i64::MIN),
+            B::String(vec![0xff]),
+            B::String(vec![0xff, 0xfe]),
+            B::List(vec![]),
+        ]);
+
+        let json = serde_json::to_string(&node).unwrap();
+        let back: B = serde_json::from_str(&json).unwrap();
+        assert!(back == node, "{:?} == {:?}", back, node);
+    }
+
+    #[test]
+    fn deserialize_invalid() {
+        assert!(serde_json::from_str::<B>("1.5").is_err());
+        assert!(serde_json::from_str::<B>("true").is_err());
+        assert!(serde_json::from_str::<B>("18446744073709551615").is_err());
+        assert!(serde_json::from_str::<B>(r#""base64:!!""#).is_err());
+        assert!(serde_json::from_str::<B>(r#""base64:AA==AAAA""#).is_err());
     }
 }
 
@@ -522,6 +2118,37 @@ mod test_benc {
 mod bench {
     extern crate test;
 
+    use std::io::Read;
+
+    use super::slice::{self, Decoder};
+    use super::{Benc, Limits};
+
+    /// A multi-file torrent listing `files` files
+    fn large_torrent(files: usize) -> Vec<u8> {
+        let mut data = concat!(
+            "d8:announce40:http://tracker.example.com:8080/announce",
+            "4:infod5:filesl"
+        ).as_bytes().to_vec();
+        for i in 0..files {
+            let name = format!("file-{}.ext", i);
+            let file = format!("d6:lengthi{}e4:pathl3:sub{}:{}ee", i * 1024, name.len(), name);
+            data.extend_from_slice(file.as_bytes());
+        }
+        let info = format!("e4:name4:mock12:piece lengthi16384e6:pieces{}:", files * 20);
+        data.extend_from_slice(info.as_bytes());
+        data.extend(std::iter::repeat(b'a').take(files * 20));
+        data.extend_from_slice(b"ee");
+        data
+    }
+
+    /// `depth` nested lists around an int
+    fn deep(depth: usize) -> Vec<u8> {
+        let mut data = vec![b'l'; depth];
+        data.extend_from_slice(b"i42e");
+        data.extend(std::iter::repeat(b'e').take(depth));
+        data
+    }
+
     #[bench]
     fn new(b: &mut test::Bencher) {
         let data = concat!(
@@ -533,11 +2160,42 @@ mod bench {
         b.iter(|| Benc::new(&mut data.bytes()));
     }
 
+    #[bench]
+    fn new_large(b: &mut test::Bencher) {
+        let data = large_torrent(5000);
+        b.bytes = data.len() as u64;
+
+        b.iter(|| Benc::new(&mut data.as_slice().bytes()));
+    }
+
+    #[bench]
+    fn spans_large(b: &mut test::Bencher) {
+        let data = large_torrent(5000);
+        b.bytes = data.len() as u64;
+
+        b.iter(|| Benc::new_with_spans(&data));
+    }
+
+    #[bench]
+    fn slice_decode_large(b: &mut test::Bencher) {
+        let data = large_torrent(5000);
+        b.bytes = data.len() as u64;
+
+        b.iter(|| slice::decode(&data));
+    }
+
+    #[bench]
+    fn new_deep(b: &mut test::Bencher) {
+        let data = deep(Limits::default().max_depth);
+
+        b.iter(|| Benc::new(&mut data.as_slice().bytes()));
+    }
+
     #[bench]
     fn string(b: &mut test::Bencher) {
         let data = "5:こんにちわ".as_bytes();
 
-        b.iter(|| Benc::string(&mut data.bytes(), b'1'));
+        b.iter(|| Decoder::new(data, Limits::default()).string(b'1'));
     }
 
     #[bench]
@@ -545,7 +2203,14 @@ mod bench {
         let s = format!("{}e", 2i64 << 48);
         let data = s.as_bytes();
 
-        b.iter(|| Benc::int(&mut data.bytes()));
+        b.iter(|| Decoder::new(data, Limits::default()).int());
+    }
+
+    #[bench]
+    fn int_17_digits(b: &mut test::Bencher) {
+        let data = b"-12345678901234567e";
+
+        b.iter(|| Decoder::new(data, Limits::default()).int());
     }
 
     #[bench]
@@ -555,7 +2220,7 @@ mod bench {
             "e7:yahallo2::)eed2:hi5:hello3:inti15eee"
         ).as_bytes();
 
-        b.iter(|| Benc::list(&mut data.bytes()));
+        b.iter(|| Decoder::new(data, Limits::default()).list());
     }
 
     #[bench]
@@ -566,6 +2231,6 @@ mod bench {
             "1ei2ei3ee7:yahallo2::)ee3:str5:helloe"
         ).as_bytes();
 
-        b.iter(|| Benc::dict(&mut data.bytes()));
+        b.iter(|| Decoder::new(data, Limits::default()).dict());
     }
 }
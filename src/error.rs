@@ -1,9 +1,15 @@
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
 use std::convert;
 use std::error::Error as StdError;
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::result;
 
+use crate::util;
+
 /// A convenient typedef of the return value of any `Benc`ode action
 pub type Result<T> = result::Result<T, Error>;
 
@@ -14,6 +20,54 @@ pub enum Error {
     Io(io::Error),
     /// Generic error
     Other(&'static str),
+    /// Input is not valid bencode. `offset` is the position in the input where decoding failed.
+    Parse {
+        message: &'static str,
+        offset: usize,
+    },
+    /// Input exceeds a limit. `offset` is the position in the input where the limit was
+    /// exceeded.
+    Limit {
+        message: &'static str,
+        offset: usize,
+    },
+    /// A complete value was decoded but more input follows it. `offset` is the position of the
+    /// first byte after the value.
+    TrailingData { offset: usize },
+    /// An HTTP request failed. `status` is the response's status code if one was received.
+    #[cfg(feature = "http")]
+    Http {
+        status: Option<u16>,
+        message: String,
+        retryable: bool,
+    },
+    /// A dictionary key is missing or holds an unusable value
+    Key { key: Vec<u8>, problem: KeyProblem },
+    /// Some files could not be moved. Holds the path of each file that failed and the error
+    /// that occurred.
+    Move { failures: Vec<(PathBuf, io::Error)> },
+    /// Space could not be allocated for some files. Holds the path of each file that failed and
+    /// the error that occurred.
+    Allocate { failures: Vec<(PathBuf, io::Error)> },
+    /// There is not enough free space at `path`. `required` bytes were needed but only
+    /// `available` bytes were free.
+    InsufficientSpace {
+        path: PathBuf,
+        required: u64,
+        available: u64,
+    },
+    /// An error with a description of what was being done when it occurred
+    Context {
+        ctx: Cow<'static, str>,
+        source: Box<Error>,
+    },
+    /// An error with the backtrace captured when it was created. `Display`, `kind`, and
+    /// comparisons see through to the wrapped error.
+    #[cfg(feature = "backtrace")]
+    Traced {
+        error: Box<Error>,
+        backtrace: Backtrace,
+    },
 
     #[doc(hidden)]
     /// For internal use only
@@ -22,13 +76,223 @@ pub enum Error {
     EndOfFile,
 }
 
+/// What is wrong with the value of a dictionary key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyProblem {
+    /// The key is not in the dictionary
+    Missing,
+    /// The value is not of the expected type. `found` is the type of the value.
+    WrongType { found: &'static str },
+    /// The value is not valid UTF-8
+    NotUtf8,
+    /// The key is a duplicate or is not in sorted order
+    Unsorted,
+}
+
+/// Broad classification of an `Error`. New `Error` variants map onto one of these kinds, so
+/// callers can match on the kind without depending on the exact variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Reading or writing failed
+    Io,
+    /// Input is not valid bencode or torrent data
+    Malformed,
+    /// Input exceeds a configured or representable limit
+    LimitExceeded,
+    /// A required field is absent
+    MissingField,
+    /// A field is present but holds an unusable value
+    InvalidValue,
+    /// Input ended early
+    Eof,
+}
+
+impl Error {
+    /// Construct an error. With the `backtrace` feature, a backtrace is captured for errors which
+    /// are not I/O errors; without it this is a no-op.
+    #[cfg(feature = "backtrace")]
+    crate fn new(err: Error) -> Error {
+        match err {
+            Error::Delim(_) | Error::EndOfFile | Error::Context { .. } | Error::Traced { .. } => {
+                err
+            }
+            ref e if e.is_io() => err,
+            err => Error::Traced {
+                error: Box::new(err),
+                backtrace: Backtrace::force_capture(),
+            },
+        }
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    #[inline]
+    crate fn new(err: Error) -> Error {
+        err
+    }
+
+    /// The backtrace captured when the error was created. Backtraces are only captured with the
+    /// `backtrace` feature.
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match *self {
+            Error::Traced { ref backtrace, .. } => Some(backtrace),
+            Error::Context { ref source, .. } => source.backtrace(),
+            _ => None,
+        }
+    }
+
+    /// The backtrace captured when the error was created. Backtraces are only captured with the
+    /// `backtrace` feature.
+    #[cfg(not(feature = "backtrace"))]
+    pub fn backtrace(&self) -> Option<&::std::backtrace::Backtrace> {
+        None
+    }
+
+    /// Classify the error
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::Io(_)
+            | Error::Move { .. }
+            | Error::Allocate { .. }
+            | Error::InsufficientSpace { .. } => ErrorKind::Io,
+            #[cfg(feature = "http")]
+            Error::Http { .. } => ErrorKind::Io,
+            Error::Other(_)
+            | Error::Parse { .. }
+            | Error::TrailingData { .. }
+            | Error::Delim(_) => ErrorKind::Malformed,
+            Error::Limit { .. } => ErrorKind::LimitExceeded,
+            Error::EndOfFile => ErrorKind::Eof,
+            Error::Key {
+                problem: KeyProblem::Missing,
+                ..
+            } => ErrorKind::MissingField,
+            Error::Key {
+                problem: KeyProblem::Unsorted,
+                ..
+            } => ErrorKind::Malformed,
+            Error::Key { .. } => ErrorKind::InvalidValue,
+            Error::Context { ref source, .. } => source.kind(),
+            #[cfg(feature = "backtrace")]
+            Error::Traced { ref error, .. } => error.kind(),
+        }
+    }
+
+    /// Returns true if the error was caused by an `io::Error`
+    pub fn is_io(&self) -> bool {
+        self.kind() == ErrorKind::Io
+    }
+
+    /// Returns true if the input was malformed
+    pub fn is_malformed(&self) -> bool {
+        self.kind() == ErrorKind::Malformed
+    }
+
+    /// Returns true if the error is transient and the operation may succeed if retried
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            Error::Io(ref e) => matches!(
+                e.kind(),
+                io::ErrorKind::Interrupted
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ),
+            #[cfg(feature = "http")]
+            Error::Http { retryable, .. } => retryable,
+            Error::Context { ref source, .. } => source.is_retryable(),
+            #[cfg(feature = "backtrace")]
+            Error::Traced { ref error, .. } => error.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
 impl PartialEq for Error {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            #[cfg(feature = "backtrace")]
+            (Error::Traced { error, .. }, o) => **error == *o,
+            #[cfg(feature = "backtrace")]
+            (s, Error::Traced { error, .. }) => *s == **error,
             (&Error::Delim(s), &Error::Delim(o)) => s == o,
             (&Error::Other(s), &Error::Other(o)) => s == o,
+            (
+                Error::Parse {
+                    message: sm,
+                    offset: so,
+                },
+                Error::Parse {
+                    message: om,
+                    offset: oo,
+                },
+            )
+            | (
+                Error::Limit {
+                    message: sm,
+                    offset: so,
+                },
+                Error::Limit {
+                    message: om,
+                    offset: oo,
+                },
+            ) => sm == om && so == oo,
+            (Error::TrailingData { offset: s }, Error::TrailingData { offset: o }) => s == o,
             (&Error::Io(ref s), &Error::Io(ref o)) => s.kind() == o.kind(),
             (&Error::EndOfFile, &Error::EndOfFile) => true,
+            #[cfg(feature = "http")]
+            (
+                Error::Http {
+                    status: ss,
+                    message: sm,
+                    retryable: sr,
+                },
+                Error::Http {
+                    status: os,
+                    message: om,
+                    retryable: or,
+                },
+            ) => ss == os && sm == om && sr == or,
+            (
+                Error::Key {
+                    key: sk,
+                    problem: sp,
+                },
+                Error::Key {
+                    key: ok,
+                    problem: op,
+                },
+            ) => sk == ok && sp == op,
+            (Error::Move { failures: s }, Error::Move { failures: o })
+            | (Error::Allocate { failures: s }, Error::Allocate { failures: o }) => {
+                s.len() == o.len()
+                    && s.iter()
+                        .zip(o)
+                        .all(|((sp, se), (op, oe))| sp == op && se.kind() == oe.kind())
+            }
+            (
+                Error::InsufficientSpace {
+                    path: sp,
+                    required: sr,
+                    available: sa,
+                },
+                Error::InsufficientSpace {
+                    path: op,
+                    required: or,
+                    available: oa,
+                },
+            ) => sp == op && sr == or && sa == oa,
+            (
+                Error::Context {
+                    ctx: sc,
+                    source: ss,
+                },
+                Error::Context {
+                    ctx: oc,
+                    source: os,
+                },
+            ) => sc == oc && ss == os,
             _ => false,
         }
     }
@@ -36,24 +300,223 @@ impl PartialEq for Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(self.description())
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Other(e) => f.write_str(e),
+            Error::Parse { message, offset } | Error::Limit { message, offset } => {
+                write!(f, "{} at byte {}", message, offset)
+            }
+            Error::TrailingData { offset } => write!(f, "Trailing data at byte {}", offset),
+            Error::Delim(c) => write!(f, "Delimiter reached: {:?}", c as char),
+            Error::EndOfFile => f.write_str("End of file"),
+            #[cfg(feature = "http")]
+            Error::Http {
+                status: Some(status),
+                ref message,
+                ..
+            } => write!(f, "HTTP error {}: {}", status, message),
+            #[cfg(feature = "http")]
+            Error::Http {
+                status: None,
+                ref message,
+                ..
+            } => write!(f, "HTTP error: {}", message),
+            Error::Key {
+                ref key,
+                ref problem,
+            } => {
+                // keys are shown as text if possible, otherwise as hex
+                let key = match ::std::str::from_utf8(key) {
+                    Ok(k) => format!("{:?}", k),
+                    Err(_) => format!("0x{}", util::hex(key)),
+                };
+
+                match *problem {
+                    KeyProblem::Missing => write!(f, "Missing key {}", key),
+                    KeyProblem::WrongType { found } => {
+                        write!(f, "Unexpected {} for key {}", found, key)
+                    }
+                    KeyProblem::NotUtf8 => write!(f, "Invalid UTF-8 for key {}", key),
+                    KeyProblem::Unsorted => write!(f, "Unsorted or duplicate key {}", key),
+                }
+            }
+            Error::Move { ref failures } | Error::Allocate { ref failures } => {
+                let verb = match *self {
+                    Error::Move { .. } => "move",
+                    _ => "allocate",
+                };
+                write!(f, "Failed to {} {} file(s)", verb, failures.len())?;
+                write_failures(f, ": ", failures.iter().map(|(path, e)| (&**path, e)))
+            }
+            Error::InsufficientSpace {
+                ref path,
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough space at {}: {} bytes required, {} available ({} short)",
+                path.display(),
+                required,
+                available,
+                required.saturating_sub(available)
+            ),
+            Error::Context {
+                ref ctx,
+                ref source,
+            } => write!(f, "{}: {}", ctx, source),
+            #[cfg(feature = "backtrace")]
+            Error::Traced { ref error, .. } => error.fmt(f),
+        }
     }
 }
 
 impl StdError for Error {
-    fn description(&self) -> &str {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
-            Error::Io(ref e) => e.description(),
-            Error::Other(e) => e,
-            Error::Delim(_) => "Delimiter reached",
-            Error::EndOfFile => "End of file",
+            Error::Io(ref e) => Some(e),
+            Error::Move { ref failures } | Error::Allocate { ref failures } => failures
+                .first()
+                .map(|(_, e)| e as &(dyn StdError + 'static)),
+            Error::Context { ref source, .. } => Some(&**source),
+            #[cfg(feature = "backtrace")]
+            Error::Traced { ref error, .. } => error.source(),
+            _ => None,
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn StdError> {
-        match *self {
-            Error::Io(ref e) => Some(e),
-            _ => None,
+/// Attach context to the error of a `Result`
+pub trait ResultExt<T> {
+    /// Wrap the error in `Error::Context` with `ctx`
+    fn context<C: Into<Cow<'static, str>>>(self, ctx: C) -> Result<T>;
+
+    /// Wrap the error in `Error::Context` with the string returned by `f`, which is only called
+    /// if `self` is an error
+    fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn context<C: Into<Cow<'static, str>>>(self, ctx: C) -> Result<T> {
+        self.map_err(|e| Error::Context {
+            ctx: ctx.into(),
+            source: Box::new(e),
+        })
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T> {
+        self.map_err(|e| Error::Context {
+            ctx: Cow::Owned(f()),
+            source: Box::new(e),
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use std::io;
+    use std::path::Path;
+
+    use serde::ser::{Serialize, SerializeMap, Serializer};
+
+    use super::{Error, ErrorKind};
+
+    impl Serialize for ErrorKind {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(match *self {
+                ErrorKind::Io => "io",
+                ErrorKind::Malformed => "malformed",
+                ErrorKind::LimitExceeded => "limit_exceeded",
+                ErrorKind::MissingField => "missing_field",
+                ErrorKind::InvalidValue => "invalid_value",
+                ErrorKind::Eof => "eof",
+            })
+        }
+    }
+
+    /// `io::Error`s are serialized as their kind and message
+    struct IoError<'a>(&'a io::Error);
+
+    impl<'a> Serialize for IoError<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(Some(2))?;
+            map.serialize_entry("kind", &format!("{:?}", self.0.kind()))?;
+            map.serialize_entry("message", &self.0.to_string())?;
+            map.end()
+        }
+    }
+
+    /// A file that could not be moved or allocated is serialized as its path and the error that
+    /// occurred
+    struct MoveFailure<'a>(&'a Path, &'a io::Error);
+
+    impl<'a> Serialize for MoveFailure<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(Some(2))?;
+            map.serialize_entry("path", &self.0.display().to_string())?;
+            map.serialize_entry("source", &IoError(self.1))?;
+            map.end()
+        }
+    }
+
+    /// Errors are serialized as a map of their `kind`, a `message`, and the `source` error if
+    /// there is one
+    impl Serialize for Error {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            #[cfg(feature = "backtrace")]
+            {
+                if let Error::Traced { ref error, .. } = *self {
+                    return error.serialize(serializer);
+                }
+            }
+
+            let mut map = serializer.serialize_map(None)?;
+            map.serialize_entry("kind", &self.kind())?;
+
+            match *self {
+                Error::Io(ref e) => {
+                    map.serialize_entry("message", "I/O error")?;
+                    map.serialize_entry("source", &IoError(e))?;
+                }
+                #[cfg(feature = "http")]
+                Error::Http { status, .. } => {
+                    map.serialize_entry("message", &self.to_string())?;
+                    map.serialize_entry("status", &status)?;
+                }
+                Error::Parse { offset, .. }
+                | Error::Limit { offset, .. }
+                | Error::TrailingData { offset } => {
+                    map.serialize_entry("message", &self.to_string())?;
+                    map.serialize_entry("offset", &offset)?;
+                }
+                Error::Move { ref failures } | Error::Allocate { ref failures } => {
+                    let failures: Vec<_> = failures
+                        .iter()
+                        .map(|(path, e)| MoveFailure(path, e))
+                        .collect();
+
+                    map.serialize_entry("message", &self.to_string())?;
+                    map.serialize_entry("failures", &failures)?;
+                }
+                Error::InsufficientSpace {
+                    required,
+                    available,
+                    ..
+                } => {
+                    map.serialize_entry("message", &self.to_string())?;
+                    map.serialize_entry("required", &required)?;
+                    map.serialize_entry("available", &available)?;
+                }
+                Error::Context {
+                    ref ctx,
+                    ref source,
+                } => {
+                    map.serialize_entry("message", ctx)?;
+                    map.serialize_entry("source", source)?;
+                }
+                _ => map.serialize_entry("message", &self.to_string())?,
+            }
+
+            map.end()
         }
     }
 }
@@ -70,8 +533,361 @@ impl convert::From<io::Error> for Error {
     }
 }
 
+impl convert::From<Error> for io::Error {
+    /// `Error::Io` is unwrapped, any other error is wrapped in an `io::Error` of kind
+    /// `InvalidData`. The original error can be recovered with `io::Error::into_inner`.
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl convert::From<reqwest::Error> for Error {
+    /// Timeouts, failed connections, reset connections, and server errors are retryable
+    fn from(err: reqwest::Error) -> Error {
+        let status = err.status().map(|s| s.as_u16());
+
+        // look for a reset connection in the chain of errors
+        let mut source = err.source();
+        let mut reset = false;
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                reset |= matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                );
+            }
+            source = e.source();
+        }
+
+        Error::Http {
+            status,
+            message: err.to_string(),
+            retryable: err.is_timeout()
+                || err.is_connect()
+                || reset
+                || status.is_some_and(http_retryable),
+        }
+    }
+}
+
+/// Returns true if a request which failed with HTTP status code `status` may succeed if retried
+#[cfg(feature = "http")]
+crate fn http_retryable(status: u16) -> bool {
+    // Request Timeout, Too Many Requests, and server errors
+    status == 408 || status == 429 || (500..600).contains(&status)
+}
+
+/// Write each failed file's path and error, separated by "; " and starting with `first`
+crate fn write_failures<'a>(
+    f: &mut fmt::Formatter<'_>,
+    first: &str,
+    failures: impl Iterator<Item = (&'a Path, &'a io::Error)>,
+) -> fmt::Result {
+    for (i, (path, e)) in failures.enumerate() {
+        let sep = if i == 0 { first } else { "; " };
+        write!(f, "{}{}: {}", sep, path.display(), e)?;
+    }
+    Ok(())
+}
+
 impl convert::From<&'static str> for Error {
     fn from(err: &'static str) -> Error {
-        Error::Other(err)
+        Error::new(Error::Other(err))
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use std::error::Error as StdError;
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::{Error, ErrorKind, KeyProblem, ResultExt};
+
+    #[test]
+    fn display() {
+        let io = Error::Io(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+
+        for (err, expect) in [
+            (io, "I/O error: no such file"),
+            (
+                Error::Other("Invalid int bencoding"),
+                "Invalid int bencoding",
+            ),
+            (Error::Delim(b'e'), "Delimiter reached: 'e'"),
+            (Error::EndOfFile, "End of file"),
+            (
+                Error::Parse {
+                    message: "Invalid int bencoding",
+                    offset: 42,
+                },
+                "Invalid int bencoding at byte 42",
+            ),
+            (
+                Error::Limit {
+                    message: "Integer overflow",
+                    offset: 7,
+                },
+                "Integer overflow at byte 7",
+            ),
+            (
+                Error::TrailingData { offset: 12 },
+                "Trailing data at byte 12",
+            ),
+        ] {
+            assert!(err.to_string() == expect, "{} == {}", err, expect);
+        }
+    }
+
+    #[test]
+    fn display_move() {
+        let err = Error::Move {
+            failures: vec![
+                (
+                    PathBuf::from("/mock/a.ext"),
+                    io::Error::new(io::ErrorKind::NotFound, "no such file"),
+                ),
+                (
+                    PathBuf::from("/mock/b.ext"),
+                    io::Error::new(io::ErrorKind::PermissionDenied, "permission denied"),
+                ),
+            ],
+        };
+        let msg = err.to_string();
+
+        assert!(msg.starts_with("Failed to move 2 file(s)"), "{}", msg);
+        assert!(msg.contains("/mock/a.ext: no such file"), "{}", msg);
+        assert!(msg.contains("/mock/b.ext: permission denied"), "{}", msg);
+        assert!(err.is_io());
+
+        let source = err.source().map(|e| e.to_string());
+        assert!(source == Some("no such file".to_owned()), "{:?}", source);
+
+        let err = Error::Allocate {
+            failures: vec![(
+                PathBuf::from("/mock/a.ext"),
+                io::Error::new(io::ErrorKind::WriteZero, "no space left"),
+            )],
+        };
+        let expect = "Failed to allocate 1 file(s): /mock/a.ext: no space left";
+        assert!(err.to_string() == expect, "{} == {}", err, expect);
+        assert!(err.is_io());
+
+        let err = Error::InsufficientSpace {
+            path: PathBuf::from("/mock"),
+            required: 100,
+            available: 40,
+        };
+        let expect = "Not enough space at /mock: 100 bytes required, 40 available (60 short)";
+        assert!(err.to_string() == expect, "{} == {}", err, expect);
+        assert!(err.is_io());
+    }
+
+    #[test]
+    fn display_key() {
+        for (key, problem, expect, kind) in [
+            (
+                &b"piece length"[..],
+                KeyProblem::Missing,
+                "Missing key \"piece length\"",
+                ErrorKind::MissingField,
+            ),
+            (
+                &b"pieces"[..],
+                KeyProblem::WrongType { found: "int" },
+                "Unexpected int for key \"pieces\"",
+                ErrorKind::InvalidValue,
+            ),
+            (
+                &[0xde, 0xad, 0xbe, 0xef][..],
+                KeyProblem::NotUtf8,
+                "Invalid UTF-8 for key 0xdeadbeef",
+                ErrorKind::InvalidValue,
+            ),
+        ] {
+            let err = Error::Key {
+                key: key.to_vec(),
+                problem,
+            };
+            assert!(err.to_string() == expect, "{} == {}", err, expect);
+            assert!(err.kind() == kind, "{:?} == {:?}", err.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn source() {
+        let io = Error::Io(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert!(io.source().is_some());
+
+        assert!(Error::Other("Mock data").source().is_none());
+        assert!(Error::Delim(b'e').source().is_none());
+        assert!(Error::EndOfFile.source().is_none());
+    }
+
+    #[test]
+    fn kind() {
+        let io = Error::Io(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+
+        for (err, kind) in [
+            (io, ErrorKind::Io),
+            (Error::Other("Invalid int bencoding"), ErrorKind::Malformed),
+            (Error::Delim(b'e'), ErrorKind::Malformed),
+            (Error::EndOfFile, ErrorKind::Eof),
+            (
+                Error::Parse {
+                    message: "Invalid int bencoding",
+                    offset: 0,
+                },
+                ErrorKind::Malformed,
+            ),
+            (
+                Error::Limit {
+                    message: "Integer overflow",
+                    offset: 0,
+                },
+                ErrorKind::LimitExceeded,
+            ),
+            (Error::TrailingData { offset: 0 }, ErrorKind::Malformed),
+        ] {
+            assert!(err.kind() == kind, "{:?} == {:?}", err.kind(), kind);
+            assert!(err.is_io() == (kind == ErrorKind::Io));
+            assert!(err.is_malformed() == (kind == ErrorKind::Malformed));
+        }
+    }
+
+    #[test]
+    fn is_retryable() {
+        for (kind, retry) in [
+            (io::ErrorKind::Interrupted, true),
+            (io::ErrorKind::TimedOut, true),
+            (io::ErrorKind::ConnectionReset, true),
+            (io::ErrorKind::NotFound, false),
+            (io::ErrorKind::PermissionDenied, false),
+            (io::ErrorKind::InvalidData, false),
+        ] {
+            let err = Error::Io(io::Error::new(kind, "mock"));
+            assert!(err.is_retryable() == retry, "{:?} == {}", kind, retry);
+        }
+
+        assert!(!Error::Other("Mock data").is_retryable());
+        assert!(!Error::EndOfFile.is_retryable());
+    }
+
+    #[test]
+    fn context() {
+        let res: super::Result<()> = Err(Error::Other("Expected int for \"length\""));
+        let err = res
+            .with_context(|| format!("file {}", 37))
+            .context("info dict")
+            .unwrap_err();
+
+        let expect = "info dict: file 37: Expected int for \"length\"";
+        assert!(err.to_string() == expect, "{} == {}", err, expect);
+        assert!(err.kind() == ErrorKind::Malformed);
+
+        let source = err.source().and_then(|e| e.downcast_ref::<Error>());
+        assert!(source.map(|e| e.to_string()) == Some(expect[11..].to_owned()));
+
+        let io = Error::Io(io::Error::new(io::ErrorKind::TimedOut, "mock"));
+        let err = Err::<(), _>(io).context("tracker").unwrap_err();
+        assert!(err.is_io() && err.is_retryable());
+    }
+
+    #[test]
+    fn into_io() {
+        for kind in [
+            io::ErrorKind::NotFound,
+            io::ErrorKind::UnexpectedEof,
+            io::ErrorKind::TimedOut,
+        ] {
+            let err = Error::from(io::Error::new(kind, "mock"));
+            let io = io::Error::from(err);
+            assert!(io.kind() == kind, "{:?} == {:?}", io.kind(), kind);
+        }
+
+        let io = io::Error::from(Error::Other("Invalid int bencoding"));
+        assert!(io.kind() == io::ErrorKind::InvalidData);
+
+        let inner = io.get_ref().and_then(|e| e.downcast_ref::<Error>());
+        assert!(inner == Some(&Error::Other("Invalid int bencoding")));
+    }
+
+    #[test]
+    fn send_sync() {
+        fn is_send_sync<T: StdError + Send + Sync + 'static>() {}
+
+        is_send_sync::<Error>();
+    }
+}
+
+#[cfg(all(test, feature = "backtrace"))]
+mod test_backtrace {
+    use std::backtrace::BacktraceStatus;
+    use std::io::Read;
+
+    use super::{Error, ResultExt};
+    use crate::bencode::Benc;
+
+    #[test]
+    fn parse_failure() {
+        let err = Benc::new(&mut b"i12xe".bytes()).unwrap_err();
+        let expect = Error::Parse {
+            message: "Invalid int bencoding",
+            offset: 3,
+        };
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+
+        let bt = err.backtrace().map(|bt| bt.status());
+        assert!(bt == Some(BacktraceStatus::Captured), "{:?}", bt);
+
+        // context keeps the backtrace of the original error
+        let err = Err::<(), _>(err).context("mock").unwrap_err();
+        assert!(err.backtrace().is_some());
+    }
+
+    #[test]
+    fn io_not_traced() {
+        let err = Error::new(Error::Io(::std::io::ErrorKind::NotFound.into()));
+        assert!(err.backtrace().is_none());
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test_serde {
+    use std::io;
+
+    use super::{Error, ResultExt};
+
+    #[test]
+    fn serialize() {
+        let err = Error::Other("Invalid int bencoding");
+        let json = serde_json::to_string(&err).unwrap();
+        let expect = r#"{"kind":"malformed","message":"Invalid int bencoding"}"#;
+        assert!(json == expect, "{} == {}", json, expect);
+
+        let err = Error::Limit {
+            message: "Integer overflow",
+            offset: 7,
+        };
+        let json = serde_json::to_string(&err).unwrap();
+        let expect = concat!(
+            r#"{"kind":"limit_exceeded","message":"Integer overflow at byte 7","#,
+            r#""offset":7}"#
+        );
+        assert!(json == expect, "{} == {}", json, expect);
+
+        let err = Err::<(), _>(Error::Io(io::Error::new(io::ErrorKind::NotFound, "mock")))
+            .context("info dict")
+            .unwrap_err();
+        let json = serde_json::to_string(&err).unwrap();
+        let expect = concat!(
+            r#"{"kind":"io","message":"info dict","source":{"kind":"io","message":"I/O error","#,
+            r#""source":{"kind":"NotFound","message":"mock"}}}"#
+        );
+        assert!(json == expect, "{} == {}", json, expect);
     }
 }
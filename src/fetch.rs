@@ -0,0 +1,156 @@
+//! Download torrent files and other data. HTTP support is only built with the `http` feature;
+//! without it, implement `TorrentFetcher` to bring your own client.
+use std::convert::TryFrom;
+use std::ops::Range;
+#[cfg(feature = "http")]
+use std::thread;
+#[cfg(feature = "http")]
+use std::time::Duration;
+
+use crate::error;
+
+/// Something which can download the contents of a URL
+pub trait TorrentFetcher {
+    /// Download the whole body of `url`
+    fn fetch(&self, url: &str) -> error::Result<Vec<u8>>;
+
+    /// Download bytes `range` of `url`. By default the whole body is downloaded and sliced;
+    /// fetchers which support it should request only the range, e.g. with an HTTP `Range` header.
+    fn fetch_range(&self, url: &str, range: Range<u64>) -> error::Result<Vec<u8>> {
+        slice(self.fetch(url)?, range)
+    }
+}
+
+/// Cut `range` out of `body`, failing if `body` is too short
+fn slice(mut body: Vec<u8>, range: Range<u64>) -> error::Result<Vec<u8>> {
+    let start = usize::try_from(range.start);
+    let end = usize::try_from(range.end);
+    match (start, end) {
+        (Ok(start), Ok(end)) if start <= end && end <= body.len() => {
+            body.truncate(end);
+            Ok(body.split_off(start))
+        }
+        _ => Err(error::Error::new(error::Error::Other(
+            "Response does not hold the requested range",
+        ))),
+    }
+}
+
+impl<F> TorrentFetcher for F
+where
+    F: Fn(&str) -> error::Result<Vec<u8>>,
+{
+    fn fetch(&self, url: &str) -> error::Result<Vec<u8>> {
+        self(url)
+    }
+}
+
+/// How `HttpFetcher` makes requests
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchOptions {
+    /// Time allowed to connect to the host
+    pub connect_timeout: Duration,
+    /// Time allowed for the whole request, including reading the response
+    pub timeout: Duration,
+    /// Number of times a request which failed with a retryable error is tried again
+    pub retries: u32,
+    /// Time to wait before the first retry. The wait doubles after each attempt.
+    pub backoff: Duration,
+}
+
+#[cfg(feature = "http")]
+impl Default for FetchOptions {
+    fn default() -> FetchOptions {
+        FetchOptions {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            retries: 2,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Blocking HTTP(S) fetcher. Requests which time out, fail to connect, or get a server error are
+/// retried as set in its `FetchOptions`.
+#[cfg(feature = "http")]
+#[derive(Debug)]
+pub struct HttpFetcher {
+    client: reqwest::blocking::Client,
+    opts: FetchOptions,
+}
+
+#[cfg(feature = "http")]
+impl HttpFetcher {
+    pub fn new(opts: FetchOptions) -> error::Result<HttpFetcher> {
+        let client = reqwest::blocking::Client::builder()
+            .connect_timeout(opts.connect_timeout)
+            .timeout(opts.timeout)
+            .build()?;
+
+        Ok(HttpFetcher { client, opts })
+    }
+
+    /// Make a single request for `url`, or only `range` of it, failing if the response is not a
+    /// success
+    fn get(&self, url: &str, range: Option<&Range<u64>>) -> error::Result<Vec<u8>> {
+        let mut req = self.client.get(url);
+        if let Some(r) = range {
+            // an empty range can not be expressed in a header
+            if r.start >= r.end {
+                return Ok(Vec::new());
+            }
+            let value = format!("bytes={}-{}", r.start, r.end - 1);
+            req = req.header(reqwest::header::RANGE, value);
+        }
+        let res = req.send()?;
+
+        let status = res.status();
+        if !status.is_success() {
+            return Err(error::Error::Http {
+                status: Some(status.as_u16()),
+                message: status.canonical_reason().unwrap_or("").to_owned(),
+                retryable: error::http_retryable(status.as_u16()),
+            });
+        }
+
+        let body = res.bytes()?.to_vec();
+        match range {
+            // the server ignored the range and sent the whole body
+            Some(r) if status != reqwest::StatusCode::PARTIAL_CONTENT => slice(body, r.clone()),
+            _ => Ok(body),
+        }
+    }
+
+    /// Call `f` until it succeeds, fails with an error which is not retryable, or runs out of
+    /// retries
+    fn retry<F>(&self, f: F) -> error::Result<Vec<u8>>
+    where
+        F: Fn() -> error::Result<Vec<u8>>,
+    {
+        let mut backoff = self.opts.backoff;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Ok(body) => return Ok(body),
+                Err(ref e) if e.is_retryable() && attempt < self.opts.retries => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl TorrentFetcher for HttpFetcher {
+    fn fetch(&self, url: &str) -> error::Result<Vec<u8>> {
+        self.retry(|| self.get(url, None))
+    }
+
+    fn fetch_range(&self, url: &str, range: Range<u64>) -> error::Result<Vec<u8>> {
+        self.retry(|| self.get(url, Some(&range)))
+    }
+}
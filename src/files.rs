@@ -1,14 +1,22 @@
+use std::cmp;
 use std::collections;
 use std::convert;
 use std::default;
-use std::env;
+use std::error::Error as StdError;
 use std::ffi;
+use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Seek, Write};
 use std::mem;
+use std::ops::{self, ControlFlow};
 use std::path;
+use std::slice;
+use std::str;
+use std::vec;
 
-use crate::bencode::Benc;
+use crate::bencode::{self, Benc};
+use crate::error::{self, ResultExt};
+use crate::torrent::Info;
 use crate::util;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -17,6 +25,8 @@ pub enum Status {
     Downloading,
     Stopped,
     Seeding,
+    /// Equivalent to `Priority::Skip`; a file with this status has that priority
+    #[deprecated(note = "use `File::set_priority(Priority::Skip)` instead")]
     Skip,
     Done,
     /// Can contian the last known location of the file
@@ -31,27 +41,885 @@ impl default::Default for Status {
     }
 }
 
+/// How eagerly a file is downloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Do not download the file
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Attributes of a file from its "attr" key, as in BEP 47. Combine them with `|`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FileAttrs(u8);
+
+impl FileAttrs {
+    /// Filler so the next file starts on a piece boundary. It holds only zeros and is not part of
+    /// the torrent's content.
+    pub const PADDING: FileAttrs = FileAttrs(1);
+    pub const EXECUTABLE: FileAttrs = FileAttrs(1 << 1);
+    pub const HIDDEN: FileAttrs = FileAttrs(1 << 2);
+    /// The file is a symlink to its `link_target`
+    pub const SYMLINK: FileAttrs = FileAttrs(1 << 3);
+
+    /// No attributes
+    pub fn empty() -> FileAttrs {
+        FileAttrs(0)
+    }
+
+    /// Parse an "attr" string, e.g. "px". Unknown characters are ignored.
+    pub fn parse(attr: &str) -> FileAttrs {
+        attr.chars().fold(FileAttrs::empty(), |attrs, c| {
+            attrs
+                | match c {
+                    'p' => FileAttrs::PADDING,
+                    'x' => FileAttrs::EXECUTABLE,
+                    'h' => FileAttrs::HIDDEN,
+                    'l' => FileAttrs::SYMLINK,
+                    _ => FileAttrs::empty(),
+                }
+        })
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns true if every attribute in `other` is set
+    pub fn contains(self, other: FileAttrs) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl ops::BitOr for FileAttrs {
+    type Output = FileAttrs;
+
+    fn bitor(self, other: FileAttrs) -> FileAttrs {
+        FileAttrs(self.0 | other.0)
+    }
+}
+
+impl ops::BitOrAssign for FileAttrs {
+    fn bitor_assign(&mut self, other: FileAttrs) {
+        self.0 |= other.0;
+    }
+}
+
+/// How space is reserved for a file before it is downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationMode {
+    /// Reserve every byte on disk, so the download can not run out of space
+    Full,
+    /// Only set the file's length. On filesystems with sparse files no space is used until data
+    /// is written, and regions never written read back as zeros.
+    Sparse,
+}
+
+/// How `Directory::set_location_with` puts files at their new location. Every mode but `Move`
+/// leaves the originals in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocateMode {
+    /// Rename the files, or copy and delete them across filesystems
+    Move,
+    /// Hard link the new paths to the originals, so no data is copied. Both must be on the same
+    /// filesystem.
+    HardLink,
+    /// Clone the files, sharing their data until either copy is written to. This needs a
+    /// filesystem such as Btrfs, XFS, or APFS; elsewhere the files are copied.
+    Reflink,
+    /// Copy the files
+    Copy,
+}
+
+/// What `Directory::set_location_progress` is doing with a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocatePhase {
+    /// Nothing has been touched yet; reported once for each file before any is moved
+    Planning,
+    /// The file is about to be renamed, which takes no time
+    Moving,
+    /// The file is being copied to another filesystem, reported as it starts and then every
+    /// `PROGRESS_INTERVAL` bytes
+    Copying,
+    /// The file is at its new location
+    Done,
+}
+
+/// Progress of one file in a relocation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelocateProgress {
+    /// Index of the file in the `Directory`
+    pub index: usize,
+    /// Name of the file as described in the torrent
+    pub name: String,
+    /// Bytes of the file put at the new location so far
+    pub bytes_done: u64,
+    /// Bytes in the file
+    pub bytes_total: u64,
+    pub phase: RelocatePhase,
+}
+
+/// Bytes copied between each `RelocatePhase::Copying` report
+pub const PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// A file `Directory::set_location` could not move. The file is left at `path` with the status it
+/// had, so the move can be tried again with `Directory::retry_failed`.
+#[derive(Debug)]
+pub struct MoveFailure {
+    /// Index of the file in the `Directory`
+    pub index: usize,
+    /// Name of the file as described in the torrent
+    pub name: String,
+    /// Where the file still is
+    pub path: path::PathBuf,
+    /// Where the file was being put
+    pub to: path::PathBuf,
+    /// How the file was being put there
+    pub mode: RelocateMode,
+    pub error: io::Error,
+}
+
+impl MoveFailure {
+    /// The path and error, as listed in `error::Error::Move`
+    fn pair(&self) -> (&path::Path, &io::Error) {
+        (&self.path, &self.error)
+    }
+}
+
 #[derive(Debug)]
-pub enum MvError<'a> {
+pub enum MvError {
     /// A generic IoError
     Io(io::Error),
-    /// Errors while moving `File`'s. Tuple of a reference to the `File` and the `IoError` that
-    /// occoured
-    MoveErrors(Vec<(&'a File, io::Error)>),
+    /// Errors while moving `File`'s, one for each file which failed
+    MoveErrors(Vec<MoveFailure>),
+    /// Nothing was moved, e.g. as there was not enough space
+    Other(error::Error),
+    /// The progress callback stopped the relocation. Files already moved were put back, except
+    /// for those listed.
+    Cancelled(Vec<MoveFailure>),
+}
+
+impl fmt::Display for MvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MvError::Io(ref e) => write!(f, "I/O error: {}", e),
+            MvError::MoveErrors(ref errs) => {
+                write!(f, "Failed to move {} file(s)", errs.len())?;
+                error::write_failures(f, ": ", errs.iter().map(MoveFailure::pair))
+            }
+            MvError::Other(ref e) => e.fmt(f),
+            MvError::Cancelled(ref errs) => {
+                write!(f, "Relocation was cancelled")?;
+                error::write_failures(f, "; not put back: ", errs.iter().map(MoveFailure::pair))
+            }
+        }
+    }
+}
+
+impl StdError for MvError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            MvError::Io(ref e) => Some(e),
+            MvError::MoveErrors(ref errs) | MvError::Cancelled(ref errs) => errs
+                .first()
+                .map(|failure| &failure.error as &(dyn StdError + 'static)),
+            MvError::Other(ref e) => e.source(),
+        }
+    }
+}
+
+impl convert::From<MvError> for error::Error {
+    /// Only the path of each file which failed to move is kept
+    fn from(e: MvError) -> error::Error {
+        match e {
+            MvError::Io(e) => error::Error::Io(e),
+            MvError::MoveErrors(errs) => error::Error::Move {
+                failures: errs.into_iter().map(|f| (f.path, f.error)).collect(),
+            },
+            MvError::Other(e) => e,
+            MvError::Cancelled(ref errs) if errs.is_empty() => {
+                error::Error::from("Relocation was cancelled")
+            }
+            MvError::Cancelled(errs) => error::Error::Move {
+                failures: errs.into_iter().map(|f| (f.path, f.error)).collect(),
+            },
+        }
+    }
 }
 
-impl<'a> convert::From<io::Error> for MvError<'a> {
-    fn from(e: io::Error) -> MvError<'a> {
+impl convert::From<io::Error> for MvError {
+    fn from(e: io::Error) -> MvError {
         MvError::Io(e)
     }
 }
 
-impl<'a> convert::From<Vec<(&'a File, io::Error)>> for MvError<'a> {
-    fn from(e: Vec<(&'a File, io::Error)>) -> MvError<'a> {
+impl convert::From<error::Error> for MvError {
+    fn from(e: error::Error) -> MvError {
+        MvError::Other(e)
+    }
+}
+
+impl convert::From<Vec<MoveFailure>> for MvError {
+    fn from(e: Vec<MoveFailure>) -> MvError {
         MvError::MoveErrors(e)
     }
 }
 
+/// Reasons a path component taken from a torrent can not be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// The component is made only of dots, such as `..`, which names a folder rather than a file
+    Dots,
+    /// The component holds a path separator, so it is not a single component
+    Separator,
+    /// The component is not valid UTF-8. Names should be decoded with the torrent's encoding
+    /// before they are sanitized.
+    NotUtf8,
+    /// A path has no components
+    Empty,
+}
+
+impl PathError {
+    fn message(self) -> &'static str {
+        match self {
+            PathError::Dots | PathError::Separator => {
+                "Path component would leave the download folder"
+            }
+            PathError::NotUtf8 => "Path component is not valid UTF-8",
+            PathError::Empty => "Expected a filename in \"path\"",
+        }
+    }
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl StdError for PathError {}
+
+impl convert::From<PathError> for error::Error {
+    fn from(e: PathError) -> error::Error {
+        error::Error::from(e.message())
+    }
+}
+
+/// Filesystem operations used to move files. Tests implement this to inject failures.
+crate trait FileSystem {
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()>;
+    /// Copy `from` to a new file `to`. Fails with `ErrorKind::AlreadyExists` if `to` exists.
+    fn copy(&self, from: &path::Path, to: &path::Path) -> io::Result<u64>;
+    /// Same as `copy`, calling `progress` with the bytes copied so far as the copy goes. If it
+    /// returns `Break` the copy stops with `ErrorKind::Interrupted`. By default `progress` is only
+    /// called once the whole file is copied.
+    fn copy_progress(
+        &self,
+        from: &path::Path,
+        to: &path::Path,
+        progress: &mut dyn FnMut(u64) -> ControlFlow<()>,
+    ) -> io::Result<u64> {
+        let len = self.copy(from, to)?;
+        match progress(len) {
+            ControlFlow::Continue(()) => Ok(len),
+            ControlFlow::Break(()) => Err(io::Error::from(io::ErrorKind::Interrupted)),
+        }
+    }
+    fn remove_file(&self, path: &path::Path) -> io::Result<()>;
+    fn hard_link(&self, from: &path::Path, to: &path::Path) -> io::Result<()>;
+    /// Clone `from` to a new file `to` without copying its data. Fails if the filesystem does
+    /// not support it, see `reflink_unsupported`, or with `ErrorKind::AlreadyExists` if `to`
+    /// exists.
+    fn reflink(&self, from: &path::Path, to: &path::Path) -> io::Result<()>;
+    fn available_space(&self, path: &path::Path) -> io::Result<u64>;
+    /// Returns true if `a` and `b` are on the same filesystem, so moving between them is a
+    /// rename which needs no space
+    fn same_device(&self, a: &path::Path, b: &path::Path) -> bool;
+}
+
+/// The real filesystem
+crate struct StdFs;
+
+impl FileSystem for StdFs {
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    /// `io::copy` between files uses `copy_file_range` on Linux, so the kernel copies the data
+    /// where it can. Unlike `fs::copy`, an existing `to` is never replaced.
+    fn copy(&self, from: &path::Path, to: &path::Path) -> io::Result<u64> {
+        let mut src = fs::File::open(from)?;
+        let mut dst = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(to)?;
+        let len = io::copy(&mut src, &mut dst)?;
+        dst.set_permissions(src.metadata()?.permissions())?;
+        Ok(len)
+    }
+
+    /// Copies through a buffer rather than with `fs::copy`, so progress can be reported every
+    /// `PROGRESS_INTERVAL` bytes
+    fn copy_progress(
+        &self,
+        from: &path::Path,
+        to: &path::Path,
+        progress: &mut dyn FnMut(u64) -> ControlFlow<()>,
+    ) -> io::Result<u64> {
+        let mut src = fs::File::open(from)?;
+        let mut dst = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(to)?;
+        let mut buf = vec![0; 64 * 1024];
+        let (mut done, mut reported) = (0u64, 0u64);
+        loop {
+            let n = match src.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            dst.write_all(&buf[..n])?;
+            done += n as u64;
+            if done - reported >= PROGRESS_INTERVAL {
+                reported = done;
+                if progress(done).is_break() {
+                    return Err(io::Error::from(io::ErrorKind::Interrupted));
+                }
+            }
+        }
+        dst.set_permissions(src.metadata()?.permissions())?;
+        // the end of the file, unless it was just reported
+        if (reported != done || done == 0) && progress(done).is_break() {
+            return Err(io::Error::from(io::ErrorKind::Interrupted));
+        }
+        Ok(done)
+    }
+
+    fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn hard_link(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn reflink(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        reflink(from, to)
+    }
+
+    fn available_space(&self, path: &path::Path) -> io::Result<u64> {
+        available_space(path)
+    }
+
+    #[cfg(unix)]
+    fn same_device(&self, a: &path::Path, b: &path::Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        let dev = |p: &path::Path| fs::metadata(existing_ancestor(p)).map(|m| m.dev());
+        match (dev(a), dev(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Paths on the same drive or share are taken to be on the same volume
+    #[cfg(windows)]
+    fn same_device(&self, a: &path::Path, b: &path::Path) -> bool {
+        match (a.components().next(), b.components().next()) {
+            (Some(path::Component::Prefix(a)), Some(path::Component::Prefix(b))) => a == b,
+            _ => false,
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn same_device(&self, _a: &path::Path, _b: &path::Path) -> bool {
+        false
+    }
+}
+
+/// The closest ancestor of `p` which exists, or `p` itself if none do
+fn existing_ancestor(p: &path::Path) -> &path::Path {
+    p.ancestors().find(|a| a.exists()).unwrap_or(p)
+}
+
+/// Number of bytes free for the current user on the filesystem holding `path`. `path` need not
+/// exist yet; the space is read from its closest existing parent.
+#[cfg(unix)]
+pub fn available_space(path: &path::Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = match ffi::CString::new(existing_ancestor(path).as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+    };
+
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    match unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } {
+        0 => Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Number of bytes free for the current user on the volume holding `path`. `path` need not exist
+/// yet; the space is read from its closest existing parent.
+#[cfg(windows)]
+pub fn available_space(path: &path::Path) -> io::Result<u64> {
+    use std::iter;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use winapi::shared::ntdef::ULARGE_INTEGER;
+    use winapi::um::fileapi::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = existing_ancestor(path)
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+
+    let mut available: ULARGE_INTEGER = unsafe { mem::zeroed() };
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+
+    match ok {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(unsafe { *available.QuadPart() }),
+    }
+}
+
+/// Free space can not be queried on other platforms, so it is reported as unlimited
+#[cfg(not(any(unix, windows)))]
+pub fn available_space(_path: &path::Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Fail with `Error::InsufficientSpace` if fewer than `required` bytes are free at `path`
+fn check_space(fs: &dyn FileSystem, path: &path::Path, required: u64) -> error::Result<()> {
+    if required == 0 {
+        return Ok(());
+    }
+
+    let available = fs.available_space(path)?;
+    match required > available {
+        true => Err(error::Error::new(error::Error::InsufficientSpace {
+            path: path.to_path_buf(),
+            required,
+            available,
+        })),
+        false => Ok(()),
+    }
+}
+
+/// Returns true if `e` is the error `rename` returns when `from` and `to` are on different
+/// filesystems; `EXDEV`, or `ERROR_NOT_SAME_DEVICE` on Windows.
+fn crosses_devices(e: &io::Error) -> bool {
+    #[cfg(windows)]
+    const EXDEV: i32 = 17;
+    #[cfg(not(windows))]
+    const EXDEV: i32 = 18;
+
+    e.raw_os_error() == Some(EXDEV)
+}
+
+/// Clone `from` to `to` with the `FICLONE` ioctl. Fails with `ErrorKind::AlreadyExists` if `to`
+/// exists.
+#[cfg(target_os = "linux")]
+crate fn reflink(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src = fs::File::open(from)?;
+    let dst = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+
+    match unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } {
+        0 => Ok(()),
+        _ => {
+            let e = io::Error::last_os_error();
+            // `to` was created above, so is ours to remove
+            drop(dst);
+            let _ = fs::remove_file(to);
+            Err(e)
+        }
+    }
+}
+
+/// Clone `from` to `to` with `clonefile`, which fails with `ErrorKind::AlreadyExists` if `to`
+/// exists.
+#[cfg(target_os = "macos")]
+crate fn reflink(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |p: &path::Path| {
+        ffi::CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (src, dst) = (c_path(from)?, c_path(to)?);
+
+    match unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Files can not be cloned on other platforms
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+crate fn reflink(_from: &path::Path, _to: &path::Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Reflinks are not supported on this platform",
+    ))
+}
+
+/// Returns true if `e` is an error `reflink` returns when the files can not be cloned, but could
+/// be copied; the filesystem does not support it, or the files are on different filesystems.
+fn reflink_unsupported(e: &io::Error) -> bool {
+    if crosses_devices(e) {
+        return true;
+    }
+
+    #[cfg(unix)]
+    {
+        // ENOTSUP and EOPNOTSUPP are the same value on some platforms, so can't both be patterns
+        match e.raw_os_error() {
+            Some(c) => [
+                libc::EOPNOTSUPP,
+                libc::ENOTSUP,
+                libc::EINVAL,
+                libc::ENOTTY,
+                libc::ENOSYS,
+            ]
+            .contains(&c),
+            None => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        e.raw_os_error().is_none()
+    }
+}
+
+/// Copy `from` to `to`, removing anything written to `to` if the copy fails
+fn copy(fs: &dyn FileSystem, from: &path::Path, to: &path::Path) -> io::Result<()> {
+    // only a file this call created is removed on failure
+    if to.symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Destination already exists",
+        ));
+    }
+    match fs.copy(from, to) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                let _ = fs.remove_file(to);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Copy `from` to `to` and delete `from`, for moves `rename` can not do. On failure anything
+/// written to `to` is removed, leaving the file at `from`.
+fn copy_and_remove(fs: &dyn FileSystem, from: &path::Path, to: &path::Path) -> io::Result<()> {
+    copy(fs, from, to)?;
+
+    if let Err(e) = fs.remove_file(from) {
+        // keep the original, since it may be on a read-only filesystem
+        let _ = fs.remove_file(to);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Create the folders `path` is put in, adding each one which did not exist to `created`
+fn create_parents(path: &path::Path, created: &mut Vec<path::PathBuf>) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let mut missing: Vec<_> = parent
+        .ancestors()
+        .take_while(|p| !p.exists())
+        .map(path::Path::to_path_buf)
+        .collect();
+
+    fs::create_dir_all(parent)?;
+    missing.reverse();
+    created.extend(missing);
+    Ok(())
+}
+
+/// Reserve `len` bytes on disk for `f` with `fallocate`. Filesystems which do not support it are
+/// left to `set_len`.
+#[cfg(target_os = "linux")]
+fn preallocate(f: &fs::File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    match unsafe { libc::fallocate(f.as_raw_fd(), 0, 0, len as libc::off_t) } {
+        0 => Ok(()),
+        _ => match io::Error::last_os_error() {
+            ref e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            e => Err(e),
+        },
+    }
+}
+
+/// Reserve space past the end of `f` with `F_PREALLOCATE`, preferring contiguous space. This does
+/// not change the file's length.
+#[cfg(target_os = "macos")]
+fn preallocate(f: &fs::File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let current = f.metadata()?.len();
+    if len <= current {
+        return Ok(());
+    }
+
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATECONTIG | libc::F_ALLOCATEALL,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: (len - current) as libc::off_t,
+        fst_bytesalloc: 0,
+    };
+
+    unsafe {
+        if libc::fcntl(f.as_raw_fd(), libc::F_PREALLOCATE, &store) == -1 {
+            store.fst_flags = libc::F_ALLOCATEALL;
+            if libc::fcntl(f.as_raw_fd(), libc::F_PREALLOCATE, &store) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `set_len` allocates the file's space on Windows, and is all other platforms offer
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn preallocate(_f: &fs::File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Mark `f` as sparse, so NTFS does not write zeros when its length is set
+#[cfg(windows)]
+fn set_sparse(f: &fs::File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use winapi::um::ioapiset::DeviceIoControl;
+    use winapi::um::winioctl::FSCTL_SET_SPARSE;
+
+    let mut returned = 0;
+    let ok = unsafe {
+        DeviceIoControl(
+            f.as_raw_handle() as _,
+            FSCTL_SET_SPARSE,
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+
+    match ok {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Files are sparse by default on other platforms
+#[cfg(not(windows))]
+fn set_sparse(_f: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Parse a hex encoded md5 digest, in either case
+#[cfg(feature = "md5")]
+fn md5_digest(sum: &str) -> Option<[u8; 16]> {
+    let sum = sum.as_bytes();
+    if sum.len() != 32 {
+        return None;
+    }
+
+    let mut digest = [0; 16];
+    for (d, pair) in digest.iter_mut().zip(sum.chunks(2)) {
+        let hi = (pair[0] as char).to_digit(16)?;
+        let lo = (pair[1] as char).to_digit(16)?;
+        *d = (hi << 4 | lo) as u8;
+    }
+    Some(digest)
+}
+
+/// Error for a `key` missing from a dict
+fn missing_key(key: &[u8]) -> error::Error {
+    error::Error::new(error::Error::Key {
+        key: key.to_vec(),
+        problem: error::KeyProblem::Missing,
+    })
+}
+
+/// The bytes of `node`, the value of `key`, failing if it is missing or not a string
+fn take_string(key: &[u8], node: Option<Benc>) -> error::Result<Vec<u8>> {
+    match node {
+        Some(Benc::String(s)) => Ok(s),
+        Some(n) => Err(bencode::wrong_type(key, &n)),
+        None => Err(missing_key(key)),
+    }
+}
+
+/// Decode `bytes`, the value of `key`, with `encoding`
+fn decode_key(key: &[u8], bytes: &[u8], encoding: Option<&str>) -> error::Result<String> {
+    util::decode(bytes, encoding).ok_or_else(|| {
+        error::Error::new(error::Error::Key {
+            key: key.to_vec(),
+            problem: error::KeyProblem::NotUtf8,
+        })
+    })
+}
+
+/// Which platform's filename rules `sanitize_component_with` applies
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SanitizeMode {
+    /// Rules of the platform the library was built for
+    #[default]
+    Native,
+    /// Rules of Windows as well as the native platform, for paths which may later be used from
+    /// Windows, e.g. files saved to an SMB share by a Linux seedbox
+    Portable,
+}
+
+/// Longest component `sanitize_component` returns, in bytes. Most filesystems limit names to 255
+/// bytes or UTF-16 units.
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+/// Make a single path component from a torrent safe to create on disk, so it can be joined to a
+/// folder without leaving it. Bytes the platform does not allow in filenames are removed, a
+/// component left empty is replaced with `_`, and one longer than `MAX_COMPONENT_LEN` is cut
+/// short, keeping its extension. On Windows, reserved device names are prefixed with `_` and
+/// trailing dots and spaces are trimmed.
+///
+/// Components made only of dots or holding a separator are rejected, as are components which are
+/// not UTF-8; decode names with the torrent's encoding first.
+pub fn sanitize_component(component: &[u8]) -> Result<ffi::OsString, PathError> {
+    sanitize_component_with(component, SanitizeMode::Native)
+}
+
+/// Same as `sanitize_component`, with the filename rules of `mode`. With
+/// `SanitizeMode::Portable`, the characters Windows forbids are removed, reserved device names
+/// such as `CON` or `aux.txt` are prefixed with `_`, and trailing dots and spaces are trimmed,
+/// whatever the platform.
+pub fn sanitize_component_with(
+    component: &[u8],
+    mode: SanitizeMode,
+) -> Result<ffi::OsString, PathError> {
+    let c = util::sanitize_path(component);
+
+    if !c.is_empty() && c.iter().all(|&b| b == b'.') {
+        return Err(PathError::Dots);
+    }
+    if c.iter().any(|&b| path::is_separator(b as char)) {
+        return Err(PathError::Separator);
+    }
+
+    let c = util::sanitize_component(&c, mode == SanitizeMode::Portable);
+    let mut c = match str::from_utf8(&c) {
+        Ok("") => String::from("_"),
+        Ok(c) => c.to_owned(),
+        Err(_) => return Err(PathError::NotUtf8),
+    };
+
+    if c.len() > MAX_COMPONENT_LEN {
+        let ext = match c.rfind('.') {
+            Some(i) if c.len() - i <= MAX_COMPONENT_LEN / 16 => c.split_off(i),
+            _ => String::new(),
+        };
+        let mut end = MAX_COMPONENT_LEN - ext.len();
+        while !c.is_char_boundary(end) {
+            end -= 1;
+        }
+        c.truncate(end);
+        c.push_str(&ext);
+    }
+
+    Ok(ffi::OsString::from(c))
+}
+
+/// `sanitize_component_with` for a name already decoded to `String`
+fn sanitize_name(name: &str, mode: SanitizeMode) -> error::Result<String> {
+    let name = sanitize_component_with(name.as_bytes(), mode)?;
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// `path` as compared by `Directory::verify_layout`. Windows and macOS filesystems are usually
+/// case-insensitive.
+fn path_key(path: &path::Path) -> String {
+    match cfg!(any(windows, target_os = "macos")) {
+        true => path.to_string_lossy().to_lowercase(),
+        false => path.to_string_lossy().into_owned(),
+    }
+}
+
+/// Create a symlink at `path` pointing to `target`
+#[cfg(unix)]
+fn symlink(target: &path::Path, path: &path::Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+/// Creating symlinks needs a privilege most users lack, so without it the target is written to a
+/// plain file
+#[cfg(windows)]
+fn symlink(target: &path::Path, path: &path::Path) -> io::Result<()> {
+    const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+    match std::os::windows::fs::symlink_file(target, path) {
+        Err(ref e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => {
+            fs::write(path, target.to_string_lossy().as_bytes())
+        }
+        r => r,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(target: &path::Path, path: &path::Path) -> io::Result<()> {
+    fs::write(path, target.to_string_lossy().as_bytes())
+}
+
+/// Returns true if `name` is one BEP 47 gives padding files, ".pad/" followed by their length
+fn is_padding_name(name: &str) -> bool {
+    match name.strip_prefix(".pad/") {
+        Some(n) => !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// `filename` with ` (n)` appended before its extension. A leading dot does not start an
+/// extension.
+fn numbered(filename: &str, n: usize) -> String {
+    match filename.rfind('.') {
+        Some(i) if i > 0 => format!("{} ({}){}", &filename[..i], n, &filename[i..]),
+        _ => format!("{} ({})", filename, n),
+    }
+}
+
 /// Single File
 #[derive(Debug, PartialEq, Eq)]
 pub struct File {
@@ -66,6 +934,18 @@ pub struct File {
     pub md5sum: Option<String>,
     /// Status of File
     pub status: Status,
+    /// How the file was allocated, if it was
+    allocation: Option<AllocationMode>,
+    priority: Priority,
+    /// Number of bytes downloaded
+    completed: u64,
+    /// Downloaded byte ranges as `start => end`. Ranges never overlap or touch.
+    ranges: collections::BTreeMap<u64, u64>,
+    attrs: FileAttrs,
+    /// Where a `FileAttrs::SYMLINK` file points, relative to the folder holding it
+    link_target: Option<path::PathBuf>,
+    /// Path components as the torrent gives them, before sanitizing
+    torrent_path: Vec<String>,
 }
 
 impl File {
@@ -81,51 +961,154 @@ impl File {
         assert!(path.is_absolute());
 
         File {
+            torrent_path: name.split('/').map(str::to_owned).collect(),
             name,
             path,
             length,
             md5sum: None,
             status: Status::NotCreated,
+            allocation: None,
+            priority: Priority::Normal,
+            completed: 0,
+            ranges: collections::BTreeMap::new(),
+            attrs: FileAttrs::empty(),
+            link_target: None,
         }
     }
 
-    /// Create a new `File` from a HashMap; the hashmap must contain "name" and "length" keys with
-    /// an optional "md5sum" key
-    pub fn from_dict(dict: &mut collections::HashMap<Vec<u8>, Benc>) -> Option<File> {
-        let md5sum = match dict.remove(&b"md5sum"[..]) {
-            // TODO - Check if it is a valid hash
-            Some(Benc::String(s)) => String::from_utf8(s).ok(),
-            _ => None,
-        };
+    /// Create a new `File` from an entry of a multi-file torrent's "files" list. The entry must
+    /// contain "length" and "path" keys with an optional "md5sum" key, where "path" is a list of
+    /// folders ending with the filename. The file is placed under the download folder; see
+    /// `Directory::from_dict` for files placed under their torrent's folder. Names are decoded
+    /// with `encoding` if the torrent declares one.
+    pub fn from_dict(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+    ) -> error::Result<File> {
+        File::from_dict_in(dict, encoding, util::download_dir(), SanitizeMode::Native)
+    }
+
+    /// Create a new `File` from the info dict of a single-file torrent, which must contain "name"
+    /// and "length" keys with an optional "md5sum" key. "name" is the filename, and the file is
+    /// placed under the download folder.
+    pub fn from_info(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+    ) -> error::Result<File> {
+        File::from_info_with(dict, encoding, SanitizeMode::Native)
+    }
 
-        // name_raw should be a Vec<String>, where each element is a subfolder
-        let name_raw = unwrap_opt!(Benc::List, dict.remove(&b"name"[..]));
-        let mut name = String::new();
-        let mut path = util::download_dir().unwrap_or_else(env::temp_dir);
+    /// Same as `from_info`, with the filename sanitized for `mode`
+    pub fn from_info_with(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+        mode: SanitizeMode,
+    ) -> error::Result<File> {
+        let (name, name_encoding) = bencode::take_preferring_utf8(dict, b"name", encoding);
+        let name = take_string(b"name", name)?;
+        let name = decode_key(b"name", &name, name_encoding)?;
+        let component = sanitize_name(&name, mode)?;
 
-        for part in name_raw {
-            let part = unwrap!(Benc::String, part);
-            let part_str = unwrap!(Ok, ::std::str::from_utf8(&part));
+        let mut path = util::download_dir();
+        path.push(component);
+
+        File::from_parts(dict, encoding, vec![name.clone()], name, path)
+    }
+
+    /// Like `from_dict`, with "path" relative to `root` and sanitized for `mode`
+    fn from_dict_in(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+        mut path: path::PathBuf,
+        mode: SanitizeMode,
+    ) -> error::Result<File> {
+        let (parts, path_encoding) = bencode::take_preferring_utf8(dict, b"path", encoding);
+        let parts = match parts {
+            Some(Benc::List(l)) if !l.is_empty() => l,
+            Some(Benc::List(_)) => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Expected at least one component in \"path\"",
+                )))
+            }
+            Some(n) => return Err(bencode::wrong_type(b"path", &n)),
+            None => return Err(missing_key(b"path")),
+        };
 
-            name.push_str(part_str);
+        let mut names = Vec::with_capacity(parts.len());
+        let mut torrent_path = Vec::with_capacity(parts.len());
+        for part in parts {
+            let part = take_string(b"path", Some(part))?;
+            let part = decode_key(b"path", &part, path_encoding)?;
+            torrent_path.push(part.clone());
 
-            if part_str != ".." || part_str != "." {
-                path.push(part_str);
+            // empty and "." components do not name a folder
+            if part.is_empty() || part == "." {
+                continue;
             }
+            let part = sanitize_name(&part, mode)?;
+
+            path.push(&part);
+            names.push(part);
         }
 
-        let length = unwrap_opt!(Benc::Int, dict.remove(&b"length"[..]));
+        if names.is_empty() {
+            return Err(error::Error::from(PathError::Empty));
+        }
 
-        Some(File {
-            name,
-            path,
-            length: if length < 0 {
-                return None;
-            } else {
-                length as u64
-            },
+        File::from_parts(dict, encoding, torrent_path, names.join("/"), path)
+    }
+
+    /// Finish a `File` named `name` at `path` from the "length", "md5sum", "attr" and "symlink
+    /// path" keys of `dict`. `torrent_path` is the path as the torrent gave it.
+    fn from_parts(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+        torrent_path: Vec<String>,
+        name: String,
+        path: path::PathBuf,
+    ) -> error::Result<File> {
+        // TODO - Check if it is a valid hash
+        let md5sum = bencode::take_text(dict, b"md5sum");
+        let attrs = match bencode::take_text(dict, b"attr") {
+            Some(attr) => FileAttrs::parse(&attr),
+            None => FileAttrs::empty(),
+        };
+
+        let link_target = match bencode::take_preferring_utf8(dict, b"symlink path", encoding) {
+            (Some(Benc::List(parts)), encoding) => {
+                let mut target = path::PathBuf::new();
+                for part in parts {
+                    let part = take_string(b"symlink path", Some(part))?;
+                    target.push(decode_key(b"symlink path", &part, encoding)?);
+                }
+                Some(target)
+            }
+            (Some(n), _) => return Err(bencode::wrong_type(b"symlink path", &n)),
+            (None, _) => None,
+        };
+
+        let length = match bencode::take_int(dict, b"length")? {
+            l if l >= 0 => l as u64,
+            _ => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Negative \"length\"",
+                )))
+            }
+        };
+
+        Ok(File {
+            name,
+            path,
+            length,
             md5sum,
             status: Status::NotCreated,
+            allocation: None,
+            priority: Priority::Normal,
+            completed: 0,
+            ranges: collections::BTreeMap::new(),
+            attrs,
+            link_target,
+            torrent_path,
         })
     }
 
@@ -134,15 +1117,368 @@ impl File {
         &self.path
     }
 
+    /// Filename as described in the torrent file
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size of the file in bytes
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Path components as the torrent gives them, before they are sanitized for the filesystem.
+    /// Unlike `path`, this does not change when the file is moved.
+    pub fn torrent_path(&self) -> &[String] {
+        &self.torrent_path
+    }
+
+    /// Read a single-file torrent's file as it downloads, given whether each piece is verified.
+    /// See `FileReader`.
+    pub fn reader<'a>(&'a self, piece_length: u64, verified: &'a [bool]) -> FileReader<'a> {
+        FileReader {
+            file: self,
+            start: 0,
+            piece_length,
+            verified,
+            pos: 0,
+        }
+    }
+
+    /// Attributes from the torrent's "attr" key
+    pub fn attrs(&self) -> FileAttrs {
+        self.attrs
+    }
+
+    /// Returns true if the file only pads the next one to a piece boundary
+    pub fn is_padding(&self) -> bool {
+        self.attrs.contains(FileAttrs::PADDING)
+    }
+
+    /// Where the file links to if it is a symlink, from the torrent's "symlink path" key
+    pub fn link_target(&self) -> Option<&path::Path> {
+        self.link_target.as_deref()
+    }
+
+    /// Create the file as a symlink to its `link_target`, which is relative to `root`, the folder
+    /// the torrent is downloaded to. Targets which are absolute or contain `..` are refused, so a
+    /// torrent can not link to anything outside `root`. The link is made relative, so it still
+    /// works once the folder is moved. Where symlinks can not be created, e.g. on Windows without
+    /// the privilege, a plain file holding the target path is written instead.
+    pub fn materialize_symlink(&self, root: &path::Path) -> io::Result<()> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        let target = match self.link_target {
+            Some(ref t) => t,
+            None => return invalid("File is not a symlink"),
+        };
+        let escapes = target
+            .components()
+            .any(|c| !matches!(c, path::Component::Normal(_) | path::Component::CurDir));
+        if escapes || target.as_os_str().is_empty() {
+            return invalid("Symlink target is outside the torrent");
+        }
+
+        // climb from the folder holding the link back up to `root`
+        let depth = match self.path.parent().map(|p| p.strip_prefix(root)) {
+            Some(Ok(rel)) => rel.components().count(),
+            _ => return invalid("File is not under the torrent's folder"),
+        };
+        let mut link: path::PathBuf = (0..depth).map(|_| path::Component::ParentDir).collect();
+        link.push(target);
+
+        if let Some(p) = self.path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        symlink(&link, &self.path)
+    }
+
+    /// Check the file on disk against its md5sum. Returns `None` if the torrent has no md5sum for
+    /// the file. On a mismatch the status is set to `Status::Other`.
+    #[cfg(feature = "md5")]
+    pub fn verify_md5(&mut self) -> error::Result<Option<bool>> {
+        use md5::{Digest, Md5};
+
+        let expect = match self.md5sum {
+            Some(ref sum) => match md5_digest(sum) {
+                Some(d) => d,
+                None => return Err(error::Error::new(error::Error::Other("Invalid md5sum"))),
+            },
+            None => return Ok(None),
+        };
+
+        let mut f = fs::File::open(&self.path)?;
+        let mut md5 = Md5::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match f.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => md5.update(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let matches = md5.finalize()[..] == expect[..];
+        if !matches {
+            self.status = Status::Other(Some("md5 mismatch".to_owned()));
+        }
+        Ok(Some(matches))
+    }
+
+    #[cfg(not(feature = "md5"))]
+    pub fn verify_md5(&mut self) -> error::Result<Option<bool>> {
+        Err(error::Error::new(error::Error::Other(
+            "Verifying md5sums requires the `md5` feature",
+        )))
+    }
+
+    /// How eagerly the file is downloaded. A file with the deprecated `Status::Skip` is
+    /// `Priority::Skip`, whatever priority was set.
+    #[allow(deprecated)]
+    pub fn priority(&self) -> Priority {
+        match self.status {
+            Status::Skip => Priority::Skip,
+            _ => self.priority,
+        }
+    }
+
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Number of bytes downloaded, see `mark_range_complete`
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    /// Fraction of the file downloaded, from 0 to 1. An empty file is always complete.
+    pub fn progress(&self) -> f64 {
+        if self.length == 0 {
+            return 1.0;
+        }
+        self.completed as f64 / self.length as f64
+    }
+
+    /// Record that `len` bytes starting at `offset` have been downloaded. Bytes past the end of
+    /// the file are ignored, as are bytes already recorded. Once every byte is downloaded the
+    /// file is marked `Done`.
+    pub fn mark_range_complete(&mut self, offset: u64, len: u64) {
+        let mut start = offset;
+        let mut end = cmp::min(offset.saturating_add(len), self.length);
+        if start >= end {
+            return;
+        }
+
+        // merge with every range which overlaps or touches [start, end)
+        let merged: Vec<_> = self
+            .ranges
+            .range(..=end)
+            .rev()
+            .take_while(|&(_, &e)| e >= start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in merged {
+            self.ranges.remove(&s);
+            self.completed -= e - s;
+            start = cmp::min(start, s);
+            end = cmp::max(end, e);
+        }
+        self.ranges.insert(start, end);
+        self.completed += end - start;
+
+        if self.completed == self.length {
+            self.status = Status::Done;
+        }
+    }
+
+    /// Mark the file as completely downloaded
+    pub fn mark_done(&mut self) {
+        self.status = Status::Done;
+    }
+
+    /// Mark the file as being uploaded to peers
+    pub fn mark_seeding(&mut self) {
+        self.status = Status::Seeding;
+    }
+
+    /// Check the file on disk and update `status` to match:
+    ///     * A missing file which was created becomes `Missing`, holding its last known path
+    ///     * A directory or a file longer than `length` becomes `Other`
+    ///     * A file shorter than `length` which was `Done`, `Seeding`, `Missing`, or `NotCreated`
+    ///       becomes `Downloading`
+    ///     * A file of exactly `length` bytes which was `Missing` or `NotCreated` becomes
+    ///       `Stopped`, since its contents are unverified
+    ///
+    /// Any other status is left alone, as are files with `Priority::Skip`.
+    pub fn sync_status(&mut self) -> io::Result<&Status> {
+        if self.priority() == Priority::Skip {
+            return Ok(&self.status);
+        }
+
+        let meta = match fs::metadata(&self.path) {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                if !matches!(self.status, Status::NotCreated | Status::Missing(_)) {
+                    self.status = Status::Missing(Some(self.path.clone()));
+                }
+                return Ok(&self.status);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let found = meta.len();
+        if meta.is_dir() {
+            self.status = Status::Other(Some("Expected a file, found a directory".to_owned()));
+        } else if found > self.length {
+            self.status = Status::Other(Some("File is longer than expected".to_owned()));
+        } else {
+            let missing = matches!(self.status, Status::NotCreated | Status::Missing(_));
+            let finished = matches!(self.status, Status::Done | Status::Seeding);
+            if found < self.length && (missing || finished) {
+                self.status = Status::Downloading;
+            } else if found == self.length && missing {
+                self.status = Status::Stopped;
+            }
+        }
+
+        Ok(&self.status)
+    }
+
+    /// How the file was allocated. `Sparse` files may have holes in regions which were never
+    /// written, so integrity checks should not expect them to hold data.
+    pub fn allocation(&self) -> Option<AllocationMode> {
+        self.allocation
+    }
+
+    /// Create the file and its parent folders and set its length. With `AllocationMode::Full`,
+    /// `length` bytes are reserved on disk so a full disk is found before downloading rather
+    /// than part way through. Files which are not yet created become `Stopped`. Padding files
+    /// are never created.
+    pub fn allocate(&mut self, mode: AllocationMode) -> io::Result<()> {
+        if self.is_padding() {
+            return Ok(());
+        }
+        if let Some(p) = self.path.parent() {
+            fs::create_dir_all(p)?;
+        }
+
+        let f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        match mode {
+            AllocationMode::Full => preallocate(&f, self.length)?,
+            AllocationMode::Sparse => set_sparse(&f)?,
+        }
+        f.set_len(self.length)?;
+
+        self.allocation = Some(mode);
+        if self.status == Status::NotCreated {
+            self.status = Status::Stopped;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset` bytes into the file, creating the file and its parent folders if
+    /// needed. Writes which would extend past `length` are rejected. The status is set to
+    /// `Downloading` on success. Writes to padding files are dropped.
+    pub fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= self.length => (),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Block extends past the end of the file",
+                ))
+            }
+        }
+        if self.is_padding() {
+            return Ok(());
+        }
+
+        if let Some(p) = self.path.parent() {
+            fs::create_dir_all(p)?;
+        }
+
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        f.seek(io::SeekFrom::Start(offset))?;
+        f.write_all(data)?;
+
+        self.status = Status::Downloading;
+        Ok(())
+    }
+
+    /// Read up to `len` bytes starting `offset` bytes into the file. Fewer bytes are returned if
+    /// the file on disk is shorter than `length`. Reads which would extend past `length` are
+    /// rejected, and reading a file which has not been created is a `NotFound` error. Padding
+    /// files read as zeros.
+    pub fn read_block(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.length => (),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Block extends past the end of the file",
+                ))
+            }
+        }
+        if self.is_padding() {
+            return Ok(vec![0; len]);
+        }
+
+        if let Status::NotCreated = self.status {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "File has not been created",
+            ));
+        }
+
+        let mut f = fs::File::open(&self.path)?;
+        f.seek(io::SeekFrom::Start(offset))?;
+
+        let mut buf = Vec::with_capacity(len);
+        f.take(len as u64).read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+
     /// Move `File` to an absolute path `p`. If the status is `NotCreated` or `Missing` the path
-    /// is set without attempting to move the file.
-    pub fn set_location(&mut self, mut p: path::PathBuf) -> io::Result<()> {
+    /// is set without attempting to move the file. Files moved to another filesystem are copied,
+    /// and the original is deleted once the copy succeeds.
+    pub fn set_location(&mut self, p: path::PathBuf) -> io::Result<()> {
+        self.set_location_in(&StdFs, p)
+    }
+
+    crate fn set_location_in(
+        &mut self,
+        fs: &dyn FileSystem,
+        p: path::PathBuf,
+    ) -> io::Result<()> {
+        self.relocate_in(fs, p, RelocateMode::Move)
+    }
+
+    /// Put the file at `p` as `mode` says, see `Directory::set_location_with`
+    crate fn relocate_in(
+        &mut self,
+        fs: &dyn FileSystem,
+        mut p: path::PathBuf,
+        mode: RelocateMode,
+    ) -> io::Result<()> {
         if !p.is_absolute() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Not an absolute path",
             ));
         }
+        // copying a file onto itself would truncate it
+        if p == self.path {
+            return Ok(());
+        }
 
         match self.status {
             Status::NotCreated | Status::Missing(_) => {
@@ -164,18 +1500,135 @@ impl File {
             }
         }
 
+        let result = match mode {
+            RelocateMode::Move => None,
+            RelocateMode::HardLink => Some(fs.hard_link(&self.path, &p)),
+            RelocateMode::Reflink => match fs.reflink(&self.path, &p) {
+                Err(ref e) if reflink_unsupported(e) => Some(copy(fs, &self.path, &p)),
+                r => Some(r),
+            },
+            RelocateMode::Copy => Some(copy(fs, &self.path, &p)),
+        };
+        // the original is untouched, so only the path changes
+        if let Some(result) = result {
+            return result.map(|()| self.path = p);
+        }
+
         mem::swap(&mut self.path, &mut p);
-        // TODO - This will not work if the new name is on a different mount point.
-        match fs::rename(&p, &self.path) {
-            e @ Ok(_) => e,
-            e @ Err(_) => {
+        match fs.rename(&p, &self.path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if crosses_devices(e) => match copy_and_remove(fs, &p, &self.path) {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    self.path = p;
+                    Err(e)
+                }
+            },
+            Err(e) => {
                 self.status = Status::Missing(Some(p));
-                e
+                Err(e)
             }
         }
     }
 }
 
+/// Bytes of a piece stored in one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    /// Index of the file in its `Directory`
+    pub file_index: usize,
+    /// Offset into the file where the bytes start
+    pub offset: u64,
+    /// Number of bytes
+    pub len: u64,
+}
+
+/// A file's status before and after `Directory::sync_all`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusChange {
+    /// Index of the file in the `Directory`
+    pub file_index: usize,
+    pub old: Status,
+    pub new: Status,
+}
+
+/// State of a file's data found by `Directory::verify_all`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyResult {
+    /// Every piece the file is part of matches its hash
+    Complete,
+    /// Some of the file's data is missing or unverified, as in a download which was interrupted
+    Partial,
+    /// The file is its full length on disk, but a piece it is part of was read in full, holds
+    /// more than zeros and does not match its hash
+    Corrupt,
+    /// The file does not exist
+    Missing,
+}
+
+/// See `Directory::map_piece`
+fn map_piece(files: &[File], piece_index: usize, piece_length: u64) -> Vec<(usize, u64, u64)> {
+    let start = match (piece_index as u64).checked_mul(piece_length) {
+        Some(s) => s,
+        None => return Vec::new(),
+    };
+    let end = start.saturating_add(piece_length);
+
+    let mut pieces = Vec::new();
+    let mut offset: u64 = 0;
+
+    for (i, f) in files.iter().enumerate() {
+        // no byte past u64::MAX can be addressed, so neither can later files
+        let (f_start, f_end) = match offset.checked_add(f.length) {
+            Some(end) => (offset, end),
+            None => break,
+        };
+        offset = f_end;
+
+        if f_end <= start || f.length == 0 {
+            continue;
+        } else if f_start >= end {
+            break;
+        }
+
+        let from = cmp::max(start, f_start);
+        let to = cmp::min(end, f_end);
+        pieces.push((i, from - f_start, to - from));
+    }
+
+    pieces
+}
+
+/// See `Directory::piece_ranges`
+fn piece_ranges(
+    files: &[File],
+    piece_length: u64,
+    total_length: u64,
+    index: u32,
+) -> Vec<FileSlice> {
+    let start = u64::from(index).saturating_mul(piece_length);
+    if start >= total_length {
+        return Vec::new();
+    }
+    let mut remaining = cmp::min(piece_length, total_length - start);
+
+    let mut slices = Vec::new();
+    for (file_index, offset, len) in map_piece(files, index as usize, piece_length) {
+        let len = cmp::min(len, remaining);
+        if len == 0 {
+            break;
+        }
+        remaining -= len;
+        slices.push(FileSlice {
+            file_index,
+            offset,
+            len,
+        });
+    }
+
+    slices
+}
+
 /// Multi-file structure
 #[derive(Debug, PartialEq, Eq)]
 pub struct Directory {
@@ -183,8 +1636,14 @@ pub struct Directory {
     path: path::PathBuf,
     /// Vector of `File`'s
     files: Vec<File>,
+    /// Index into `files` of the first file with each name
+    names: collections::HashMap<String, usize>,
+    /// Index into `files` of the first file at each path
+    paths: collections::HashMap<path::PathBuf, usize>,
     /// Status of the Directory, independant from the files owned by Self
     pub status: Status,
+    /// Whether `set_location` and `allocate_all` check for free space first
+    check_space: bool,
 }
 
 impl Directory {
@@ -208,313 +1667,4016 @@ impl Directory {
         Directory {
             path,
             files: Vec::with_capacity(cap),
+            names: collections::HashMap::with_capacity(cap),
+            paths: collections::HashMap::with_capacity(cap),
             status: Status::NotCreated,
+            check_space: true,
+        }
+    }
+
+    /// Create a new `Directory` from the info dict of a multi-file torrent. The dict must contain
+    /// a "name" key, the name of the root folder, and a "files" list whose entries match
+    /// `File::from_dict()` requirements. Files are placed under the root folder.
+    pub fn from_dict(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+    ) -> error::Result<Directory> {
+        Directory::from_dict_with(dict, encoding, SanitizeMode::Native)
+    }
+
+    /// Same as `from_dict`, with the folder and file names sanitized for `mode`
+    pub fn from_dict_with(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+        mode: SanitizeMode,
+    ) -> error::Result<Directory> {
+        // a "name.utf-8" copy says nothing about the encoding of the file paths
+        let (name, name_encoding) = bencode::take_preferring_utf8(dict, b"name", encoding);
+        let name = take_string(b"name", name)?;
+
+        let name = decode_key(b"name", &name, name_encoding)?;
+        let name = sanitize_name(&name, mode)?;
+
+        let mut path = util::download_dir();
+        path.push(name);
+
+        let fs = match bencode::take(dict, b"files")? {
+            Benc::List(l) => l,
+            n => return Err(bencode::wrong_type(b"files", &n)),
+        };
+        let mut files = Vec::with_capacity(fs.len());
+
+        for (i, f) in fs.into_iter().enumerate() {
+            let file = match f {
+                Benc::Dict(mut f) => File::from_dict_in(&mut f, encoding, path.clone(), mode),
+                _ => Err(error::Error::new(error::Error::Other("Expected dict"))),
+            };
+            files.push(file.with_context(|| format!("file {}", i))?);
+        }
+
+        for f in &mut files {
+            if is_padding_name(&f.name) {
+                f.attrs |= FileAttrs::PADDING;
+            }
+            // padding is never written to disk, so there is nothing to download
+            if f.is_padding() {
+                f.priority = Priority::Skip;
+            }
+        }
+
+        let mut dir = Directory::with_capacity(path, files.len());
+        dir.add_files(files);
+        // names which differ in the torrent may have been sanitized to the same path
+        dir.dedupe_paths();
+
+        Ok(dir)
+    }
+
+    /// Check that no two files are stored at the same path, which can happen when different
+    /// names in the torrent are sanitized to the same name. Paths are compared case-insensitively
+    /// on Windows and macOS, whose filesystems usually are. This should be checked before
+    /// downloading, so one file does not overwrite another. Returns each pair of files that
+    /// collide, with the earlier file first.
+    pub fn verify_layout(&self) -> Result<(), Vec<(&File, &File)>> {
+        let mut seen = collections::HashMap::with_capacity(self.files.len());
+        let mut collisions = Vec::new();
+
+        for f in &self.files {
+            match seen.entry(path_key(&f.path)) {
+                collections::hash_map::Entry::Occupied(e) => collisions.push((*e.get(), f)),
+                collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(f);
+                }
+            }
+        }
+
+        match collisions.is_empty() {
+            true => Ok(()),
+            false => Err(collisions),
+        }
+    }
+
+    /// Rename files stored at the same path as an earlier file, by appending ` (1)`, ` (2)`, and
+    /// so on to the filename, before its extension. Paths are compared as in `verify_layout`, and
+    /// a new name is never one another file already has. Files should not have been created yet.
+    /// Returns the number of files renamed.
+    pub fn dedupe_paths(&mut self) -> usize {
+        let mut taken: collections::HashSet<_> =
+            self.files.iter().map(|f| path_key(&f.path)).collect();
+        let mut seen = collections::HashSet::with_capacity(self.files.len());
+        let mut renamed = 0;
+
+        for f in self.files.iter_mut() {
+            if seen.insert(path_key(&f.path)) {
+                continue;
+            }
+
+            let filename = match f.path.file_name() {
+                Some(n) => n.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let mut path = f.path.clone();
+            for n in 1.. {
+                path.set_file_name(numbered(&filename, n));
+                if !taken.contains(&path_key(&path)) {
+                    break;
+                }
+            }
+
+            let filename = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            f.name = match f.name.rfind('/') {
+                Some(i) => format!("{}/{}", &f.name[..i], filename),
+                None => filename,
+            };
+            f.path = path;
+
+            taken.insert(path_key(&f.path));
+            seen.insert(path_key(&f.path));
+            renamed += 1;
+        }
+
+        if renamed > 0 {
+            self.reindex();
+        }
+        renamed
+    }
+
+    /// Rebuild the name and path indexes, after files were removed or moved
+    fn reindex(&mut self) {
+        self.names.clear();
+        self.paths.clear();
+        for (i, f) in self.files.iter().enumerate() {
+            self.names.entry(f.name.clone()).or_insert(i);
+            self.paths.entry(f.path.clone()).or_insert(i);
+        }
+    }
+
+    /// Root directory the files are stored under
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// Number of files in the `Directory`
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Iterate over the files in the order they appear in the torrent
+    pub fn iter(&self) -> impl Iterator<Item = &File> {
+        self.files.iter()
+    }
+
+    /// Same as `iter`, without padding files, for listing the files to a user
+    pub fn iter_visible(&self) -> impl Iterator<Item = &File> {
+        self.files.iter().filter(|f| !f.is_padding())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut File> {
+        self.files.iter_mut()
+    }
+
+    /// The file at `idx`, in the order files appear in the torrent
+    pub fn file(&self, idx: usize) -> Option<&File> {
+        self.files.get(idx)
+    }
+
+    pub fn file_mut(&mut self, idx: usize) -> Option<&mut File> {
+        self.files.get_mut(idx)
+    }
+
+    /// The file stored at `rel_path` under the directory's root
+    pub fn find_file(&self, rel_path: &path::Path) -> Option<&File> {
+        let root = &self.path;
+        self.files
+            .iter()
+            .find(|f| f.path.strip_prefix(root).ok() == Some(rel_path))
+    }
+
+    pub fn find_file_mut(&mut self, rel_path: &path::Path) -> Option<&mut File> {
+        let root = &self.path;
+        self.files
+            .iter_mut()
+            .find(|f| f.path.strip_prefix(root).ok() == Some(rel_path))
+    }
+
+    /// The file whose name, as given in the torrent, is `name`
+    pub fn find(&self, name: &str) -> Option<&File> {
+        self.find_by_name(name).map(|(_, f)| f)
+    }
+
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut File> {
+        let (idx, _) = self.find_by_name(name)?;
+        self.files.get_mut(idx)
+    }
+
+    /// Index and file whose name, as given in the torrent, is `name`, without searching every
+    /// file. If several files share a name, the first is returned.
+    pub fn find_by_name(&self, name: &str) -> Option<(usize, &File)> {
+        let idx = *self.names.get(name)?;
+        self.files.get(idx).map(|f| (idx, f))
+    }
+
+    /// Index and file stored at the absolute `path`, without searching every file. If several
+    /// files share a path, the first is returned. A file moved with `File::set_location`, through
+    /// `file_mut` or `iter_mut`, is found by searching every file instead.
+    pub fn find_by_path(&self, path: &path::Path) -> Option<(usize, &File)> {
+        match self.paths.get(path) {
+            Some(&idx) if self.files.get(idx).map(|f| f.path == path) == Some(true) => {
+                Some((idx, &self.files[idx]))
+            }
+            _ => self.files.iter().enumerate().find(|(_, f)| f.path == path),
+        }
+    }
+
+    /// Combined size in bytes of all files in the `Directory`. Saturates at `u64::MAX` rather
+    /// than overflowing.
+    pub fn total_size(&self) -> u64 {
+        self.files
+            .iter()
+            .fold(0u64, |total, f| total.saturating_add(f.length))
+    }
+
+    /// Map piece `piece_index` onto the files it covers. Each entry is a tuple of the index of a
+    /// file in the `Directory`, the offset into that file, and the number of bytes of the piece
+    /// stored there. Returns an empty `Vec` if the piece is past the end of the last file.
+    pub fn map_piece(&self, piece_index: usize, piece_length: u64) -> Vec<(usize, u64, u64)> {
+        map_piece(&self.files, piece_index, piece_length)
+    }
+
+    /// Map piece `index` onto the files it covers, in order. Zero-length files cover no bytes and
+    /// never appear. The last piece is cut short at `total_length`, and pieces past it map to
+    /// nothing.
+    pub fn piece_ranges(&self, piece_length: u64, total_length: u64, index: u32) -> Vec<FileSlice> {
+        piece_ranges(&self.files, piece_length, total_length, index)
+    }
+
+    /// Find the piece holding byte `offset` of the file at `file_index`. Returns the index of the
+    /// piece and the offset into it, or `None` if the file has no such byte.
+    pub fn file_offset_to_piece(
+        &self,
+        file_index: usize,
+        offset: u64,
+        piece_length: u64,
+    ) -> Option<(u32, u64)> {
+        let file = self.files.get(file_index)?;
+        if offset >= file.length || piece_length == 0 {
+            return None;
+        }
+
+        let start = self.files[..file_index]
+            .iter()
+            .try_fold(0u64, |start, f| start.checked_add(f.length))?;
+        let pos = start.checked_add(offset)?;
+        let index = convert::TryFrom::try_from(pos / piece_length).ok()?;
+        Some((index, pos % piece_length))
+    }
+
+    /// Read the file at `index` as it downloads, given whether each piece is verified. See
+    /// `FileReader`.
+    pub fn reader<'a>(
+        &'a self,
+        index: usize,
+        piece_length: u64,
+        verified: &'a [bool],
+    ) -> Option<FileReader<'a>> {
+        let file = self.files.get(index)?;
+        let start = self.files[..index]
+            .iter()
+            .try_fold(0u64, |start, f| start.checked_add(f.length))?;
+        Some(FileReader {
+            file,
+            start,
+            piece_length,
+            verified,
+            pos: 0,
+        })
+    }
+
+    /// Add a `File` to be managed by the `Directory`. See `add_files` for more details.
+    pub fn add_file(&mut self, file: File) {
+        let idx = self.files.len();
+        self.names.entry(file.name.clone()).or_insert(idx);
+        self.paths.entry(file.path.clone()).or_insert(idx);
+        self.files.push(file)
+    }
+
+    /// Add a file of `length` bytes at `path`, a list of folders ending with the filename, relative
+    /// to the `Directory`. Each component is passed through `sanitize_component`, so `path` may
+    /// come from an untrusted source such as a torrent or a peer.
+    pub fn add_file_named(&mut self, path: &[&[u8]], length: u64) -> Result<(), PathError> {
+        let mut location = self.path.clone();
+        let mut names = Vec::with_capacity(path.len());
+
+        for part in path {
+            let part = sanitize_component(part)?;
+            location.push(&part);
+            names.push(part.to_string_lossy().into_owned());
+        }
+
+        if names.is_empty() {
+            return Err(PathError::Empty);
+        }
+
+        self.add_file(File::new(names.join("/"), location, length));
+        Ok(())
+    }
+
+    /// Move `files` to be owned by the `Directory`. Location of Files will not be changed.
+    pub fn add_files(&mut self, files: Vec<File>) {
+        self.files.reserve(files.len());
+        for f in files {
+            self.add_file(f);
+        }
+    }
+
+    /// Stop managing the file at `idx`, returning it. Files after it move down one index. Data on
+    /// disk is left alone.
+    pub fn remove_file(&mut self, idx: usize) -> Option<File> {
+        if idx < self.files.len() {
+            let file = self.files.remove(idx);
+            self.reindex();
+            Some(file)
+        } else {
+            None
+        }
+    }
+
+    /// Same as `remove_file`, but if `delete_data` is set the file is deleted from disk too,
+    /// along with any folders under the `Directory`'s root it leaves empty. The root itself is
+    /// kept. A file stored outside the root is never deleted; that fails with
+    /// `ErrorKind::InvalidInput`. The file is removed from the `Directory` even if deleting its
+    /// data fails.
+    pub fn remove_file_with(&mut self, idx: usize, delete_data: bool) -> io::Result<Option<File>> {
+        let file = match self.remove_file(idx) {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        if delete_data {
+            self.delete_data(&file)?;
+        }
+        Ok(Some(file))
+    }
+
+    /// Stop managing the file stored at `path`, either its full path or one relative to the
+    /// directory's root. Data on disk is left alone.
+    pub fn remove_by_path(&mut self, path: &path::Path) -> Option<File> {
+        let (idx, _) = self.find_by_path(&self.path.join(path))?;
+        self.remove_file(idx)
+    }
+
+    /// Stop managing the file named `name`, as in `find`. Data on disk is left alone.
+    pub fn remove_named(&mut self, name: &str) -> Option<File> {
+        let (idx, _) = self.find_by_name(name)?;
+        self.remove_file(idx)
+    }
+
+    /// Keep only the files `pred` returns true for, in their current order. Data on disk is left
+    /// alone.
+    pub fn retain<F>(&mut self, pred: F)
+    where
+        F: FnMut(&File) -> bool,
+    {
+        self.files.retain(pred);
+        self.reindex();
+    }
+
+    /// Same as `retain`, but if `delete_data` is set the data of each file dropped is deleted as
+    /// in `remove_file_with`. Every file is tried, and the first error is returned.
+    pub fn retain_with<F>(&mut self, mut pred: F, delete_data: bool) -> io::Result<()>
+    where
+        F: FnMut(&File) -> bool,
+    {
+        let (keep, removed) = self.files.drain(..).partition(|f| pred(f));
+        self.files = keep;
+        self.reindex();
+
+        let mut result = Ok(());
+        if delete_data {
+            for f in &removed {
+                if let Err(e) = self.delete_data(f) {
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        result
+    }
+
+    /// Delete `file` from disk, then each parent folder it leaves empty, stopping at the root
+    fn delete_data(&self, file: &File) -> io::Result<()> {
+        if !file.path.starts_with(&self.path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "File is outside the directory",
+            ));
+        }
+        match fs::remove_file(&file.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            r => r?,
+        }
+
+        let mut dir = file.path.parent();
+        while let Some(d) = dir {
+            // a folder which is not empty, or is in use, is left alone
+            if d == self.path || !d.starts_with(&self.path) || fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+        Ok(())
+    }
+
+    /// Set the priority of every file `pred` returns true for, e.g. to select files by extension.
+    /// Returns the number of files matched.
+    pub fn set_priority_by<F>(&mut self, pred: F, priority: Priority) -> usize
+    where
+        F: Fn(&File) -> bool,
+    {
+        let mut count = 0;
+        for f in self.files.iter_mut().filter(|f| pred(f)) {
+            f.set_priority(priority);
+            count += 1;
+        }
+        count
+    }
+
+    /// A single status for the directory derived from its files, ignoring skipped files:
+    ///     * The first `Missing` or `Other` status, so problems are not hidden
+    ///     * `Downloading` if any file is downloading
+    ///     * `Seeding` if every file is `Done` or `Seeding`
+    ///     * `NotCreated` if no file has been created
+    ///     * `Stopped` otherwise
+    ///
+    /// A directory with no files is `NotCreated`, and one where every file is skipped is `Skip`.
+    #[allow(deprecated)]
+    pub fn aggregate_status(&self) -> Status {
+        let status: Vec<_> = self
+            .files
+            .iter()
+            .filter(|f| f.priority() != Priority::Skip)
+            .map(|f| &f.status)
+            .collect();
+
+        let problem = status
+            .iter()
+            .find(|s| matches!(**s, Status::Missing(_) | Status::Other(_)));
+        if let Some(s) = problem {
+            return (*s).clone();
+        }
+
+        if status.iter().any(|s| **s == Status::Downloading) {
+            Status::Downloading
+        } else if status.is_empty() && !self.files.is_empty() {
+            Status::Skip
+        } else if status.iter().all(|s| **s == Status::NotCreated) {
+            Status::NotCreated
+        } else if status
+            .iter()
+            .all(|s| matches!(**s, Status::Done | Status::Seeding))
+        {
+            Status::Seeding
+        } else {
+            Status::Stopped
+        }
+    }
+
+    /// Fraction of the directory downloaded, from 0 to 1, ignoring skipped files. A directory
+    /// with nothing to download is complete.
+    pub fn progress(&self) -> f64 {
+        let (completed, length) = self
+            .files
+            .iter()
+            .filter(|f| f.priority() != Priority::Skip)
+            .fold((0u64, 0u64), |(c, l), f| {
+                (c.saturating_add(f.completed), l.saturating_add(f.length))
+            });
+
+        if length == 0 {
+            return 1.0;
+        }
+        completed as f64 / length as f64
+    }
+
+    /// Run `File::sync_status` on every file. Returns the files whose status changed.
+    pub fn sync_all(&mut self) -> io::Result<Vec<StatusChange>> {
+        let mut changes = Vec::new();
+        for (file_index, f) in self.files.iter_mut().enumerate() {
+            let old = f.status.clone();
+            if *f.sync_status()? != old {
+                changes.push(StatusChange {
+                    file_index,
+                    old,
+                    new: f.status.clone(),
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Hash every piece on disk and compare it against `pieces`, rebuilding each file's
+    /// completed ranges from the pieces which match. A piece fails if any of its files is
+    /// missing or too short. Files whose pieces all match are marked `Done`; files with only
+    /// some matching pieces, or which were finished but no longer match, become `Downloading`.
+    /// Padding files are never on disk and are hashed as zeros. Returns whether each piece
+    /// matched.
+    pub fn verify(&mut self, pieces: &[[u8; 20]], piece_length: u64) -> error::Result<Vec<bool>> {
+        self.recheck(pieces, piece_length).map(|(passed, _)| passed)
+    }
+
+    /// See `verify`. Also returns, for each file, whether it is part of a piece which was read in
+    /// full but does not match its hash. Unwritten parts of sparse files read as zeros, so a
+    /// piece of only zeros is taken to not have been downloaded yet rather than to be corrupt.
+    fn recheck(
+        &mut self,
+        pieces: &[[u8; 20]],
+        piece_length: u64,
+    ) -> error::Result<(Vec<bool>, Vec<bool>)> {
+        let total_length = self.total_size();
+        let expect = match piece_length {
+            0 => None,
+            l => match total_length % l {
+                0 => Some(total_length / l),
+                _ => Some(total_length / l + 1),
+            },
+        };
+        if expect != Some(pieces.len() as u64) {
+            return Err(error::Error::new(error::Error::Other(
+                "Number of pieces does not match the files' length",
+            )));
+        }
+
+        for f in &mut self.files {
+            f.completed = 0;
+            f.ranges.clear();
+        }
+
+        // the open file, kept across pieces; `None` if it could not be opened
+        let mut open: Option<(usize, Option<fs::File>)> = None;
+        let mut buf = vec![0; 64 * 1024];
+        let mut passed = Vec::with_capacity(pieces.len());
+        let mut corrupt = vec![false; self.files.len()];
+
+        for (index, hash) in pieces.iter().enumerate() {
+            let slices = piece_ranges(&self.files, piece_length, total_length, index as u32);
+            let mut sha1 = util::Sha1::new();
+            let mut ok = true;
+            let mut written = false;
+
+            for s in &slices {
+                if self.files[s.file_index].is_padding() {
+                    let zeros = [0; 4096];
+                    let mut left = s.len;
+                    while left > 0 {
+                        let n = cmp::min(left, zeros.len() as u64) as usize;
+                        sha1.update(&zeros[..n]);
+                        left -= n as u64;
+                    }
+                    continue;
+                }
+                if open.as_ref().map(|o| o.0) != Some(s.file_index) {
+                    let f = match fs::File::open(&self.files[s.file_index].path) {
+                        Ok(f) => Some(f),
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                        Err(e) => return Err(e.into()),
+                    };
+                    open = Some((s.file_index, f));
+                }
+                let f = match open {
+                    Some((_, Some(ref mut f))) => f,
+                    _ => {
+                        ok = false;
+                        break;
+                    }
+                };
+
+                f.seek(io::SeekFrom::Start(s.offset))?;
+                let mut left = s.len;
+                while left > 0 {
+                    let n = cmp::min(left, buf.len() as u64) as usize;
+                    match f.read_exact(&mut buf[..n]) {
+                        Ok(()) => {
+                            sha1.update(&buf[..n]);
+                            written = written || buf[..n].iter().any(|&b| b != 0);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                    left -= n as u64;
+                }
+                if left > 0 {
+                    ok = false;
+                    break;
+                }
+            }
+
+            let read = ok;
+            ok = ok && sha1.finish() == *hash;
+            if ok {
+                for s in &slices {
+                    self.files[s.file_index].mark_range_complete(s.offset, s.len);
+                }
+            } else if read && written {
+                for s in &slices {
+                    corrupt[s.file_index] = true;
+                }
+            }
+            passed.push(ok);
+        }
+
+        for f in self.files.iter_mut().filter(|f| !f.is_padding()) {
+            let finished = matches!(f.status, Status::Done | Status::Seeding);
+            if f.completed < f.length && (f.completed > 0 || finished) {
+                f.status = Status::Downloading;
+            }
+        }
+
+        Ok((passed, corrupt))
+    }
+
+    /// Recheck every file as in `verify`, then report the state of each file's data along with
+    /// its index. Files which were created but are gone are marked `Missing`. Padding files are
+    /// left out of the report. The report depends only on what is on disk, so checking again
+    /// without changing any file gives the same result.
+    pub fn verify_all(&mut self, info: &Info) -> error::Result<Vec<(usize, VerifyResult)>> {
+        let pieces: Vec<[u8; 20]> = (0..info.piece_count())
+            .filter_map(|i| info.piece_hash(i))
+            .filter_map(|h| convert::TryFrom::try_from(h).ok())
+            .collect();
+        let (_, corrupt) = self.recheck(&pieces, info.piece_length())?;
+
+        let mut report = Vec::with_capacity(self.files.len());
+        for (i, f) in self.files.iter_mut().enumerate() {
+            if f.is_padding() {
+                continue;
+            }
+            let on_disk = match fs::metadata(&f.path) {
+                Ok(m) => Some(m.len()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            let result = match on_disk {
+                None => {
+                    if f.status != Status::NotCreated {
+                        f.status = Status::Missing(Some(f.path.clone()));
+                    }
+                    VerifyResult::Missing
+                }
+                // zero-length files have no pieces to pass
+                Some(_) if f.completed == f.length => {
+                    if !matches!(f.status, Status::Done | Status::Seeding) {
+                        f.status = Status::Done;
+                    }
+                    VerifyResult::Complete
+                }
+                Some(len) if corrupt[i] && len >= f.length => VerifyResult::Corrupt,
+                Some(_) => VerifyResult::Partial,
+            };
+            report.push((i, result));
+        }
+
+        Ok(report)
+    }
+
+    /// Set whether `set_location` and `allocate_all` first check there is enough free space,
+    /// failing with `Error::InsufficientSpace` before touching any file if there is not. The
+    /// check is on by default; turn it off for filesystems which misreport their free space.
+    pub fn set_check_space(&mut self, check: bool) {
+        self.check_space = check;
+    }
+
+    /// Allocate every file with `mode`, see `File::allocate`. Errors are accumulated and returned
+    /// as `Error::Allocate`; files which failed keep their status. With `AllocationMode::Full`,
+    /// the bytes not yet on disk are checked against the free space first.
+    pub fn allocate_all(&mut self, mode: AllocationMode) -> error::Result<()> {
+        self.allocate_all_in(&StdFs, mode)
+    }
+
+    crate fn allocate_all_in(
+        &mut self,
+        fs: &dyn FileSystem,
+        mode: AllocationMode,
+    ) -> error::Result<()> {
+        if self.check_space && mode == AllocationMode::Full {
+            let required = self.iter_visible().fold(0u64, |sum, f| {
+                let on_disk = fs::metadata(&f.path).map(|m| m.len()).unwrap_or(0);
+                sum.saturating_add(f.length.saturating_sub(on_disk))
+            });
+            check_space(fs, &self.path, required)?;
+        }
+
+        let failures: Vec<_> = self
+            .files
+            .iter_mut()
+            .filter_map(|f| f.allocate(mode).err().map(|e| (f.path.clone(), e)))
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(error::Error::Allocate { failures })
+        }
+    }
+
+    /// Renames root folder
+    /// From: /path/to/original/file.ext
+    /// To:   /path/to/changed/file.ext
+    pub fn rename<P>(&mut self, p: P) -> Result<(), MvError>
+    where
+        P: convert::AsRef<ffi::OsStr>,
+    {
+        let dir = (&self.path).with_file_name(p);
+        self.set_location(dir)
+    }
+
+    /// Move all files under `self.path` to `dir`. `dir` must be an absolute path. Errors while
+    /// moving files are accumulated and returned as `MvError::MoveErrors`; those files are left
+    /// where they were with the status they had. Moving to another filesystem first checks that
+    /// the files fit, see `set_check_space`.
+    pub fn set_location(&mut self, dir: path::PathBuf) -> Result<(), MvError> {
+        self.set_location_with(dir, RelocateMode::Move)
+    }
+
+    /// Same as `set_location`, with files put in `dir` as `mode` says. Every mode but
+    /// `RelocateMode::Move` leaves the files under the old location in place, e.g. to seed the
+    /// same data from two folders; the `Directory` then refers to the new copies. Files which are
+    /// `NotCreated` or `Missing` only have their path changed. Copies, and reflinks which may fall
+    /// back to copies, always check that the files fit. Other than with `RelocateMode::Move`, a
+    /// file already at the new path is never replaced; that file fails to relocate.
+    pub fn set_location_with(
+        &mut self,
+        dir: path::PathBuf,
+        mode: RelocateMode,
+    ) -> Result<(), MvError> {
+        self.relocate_in(&StdFs, dir, mode)
+    }
+
+    /// Move all files under `self.path` to `dir`, as in `set_location`, but all or nothing. If
+    /// any file fails to move, the files already moved are put back and nothing about the
+    /// `Directory` changes. Moves to another filesystem copy every file before deleting any
+    /// original, so going back only deletes the copies. Nothing is moved if any file's new path
+    /// already exists; each of those fails with `io::ErrorKind::AlreadyExists`. Otherwise the
+    /// error holds the file which failed, followed by any which could not be put back.
+    pub fn set_location_atomic(&mut self, dir: path::PathBuf) -> Result<(), MvError> {
+        self.set_location_atomic_in(&StdFs, dir, &mut |_| ControlFlow::Continue(()))
+    }
+
+    /// Same as `set_location_atomic`, calling `progress` as each file is planned, moved or
+    /// copied, and put in place. Copies report every `PROGRESS_INTERVAL` bytes. If `progress`
+    /// returns `Break`, the files already moved are put back as if a move had failed, and
+    /// `MvError::Cancelled` is returned.
+    pub fn set_location_progress<F>(
+        &mut self,
+        dir: path::PathBuf,
+        mut progress: F,
+    ) -> Result<(), MvError>
+    where
+        F: FnMut(RelocateProgress) -> ControlFlow<()>,
+    {
+        self.set_location_atomic_in(&StdFs, dir, &mut progress)
+    }
+
+    crate fn set_location_atomic_in(
+        &mut self,
+        fs: &dyn FileSystem,
+        dir: path::PathBuf,
+        progress: &mut dyn FnMut(RelocateProgress) -> ControlFlow<()>,
+    ) -> Result<(), MvError> {
+        if !dir.is_absolute() {
+            return Err(MvError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Must be an absolute path",
+            )));
+        }
+        if dir == self.path {
+            return Ok(());
+        }
+
+        // the new path of each file under the root, and whether it has data to move
+        let plan: Vec<_> = self
+            .files
+            .iter()
+            .enumerate()
+            .filter_map(|(i, f)| {
+                let rel = f.path.strip_prefix(&self.path).ok()?;
+                let exists = !matches!(f.status, Status::NotCreated | Status::Missing(_));
+                Some((i, dir.join(rel), exists))
+            })
+            .collect();
+
+        let files = &self.files;
+        let mut report = |i: usize, bytes_done, phase| {
+            progress(RelocateProgress {
+                index: i,
+                name: files[i].name.clone(),
+                bytes_done,
+                bytes_total: files[i].length,
+                phase,
+            })
+        };
+        let mut cancelled = plan
+            .iter()
+            .any(|&(i, _, _)| report(i, 0, RelocatePhase::Planning).is_break());
+
+        // never overwrite, nor later download into, a file which is not ours
+        let taken: Vec<_> = plan
+            .iter()
+            .filter(|&(_, to, _)| !cancelled && to.symlink_metadata().is_ok())
+            .map(|&(i, ref to, _)| MoveFailure {
+                index: i,
+                name: files[i].name.clone(),
+                path: files[i].path.clone(),
+                to: to.clone(),
+                mode: RelocateMode::Move,
+                error: io::Error::new(io::ErrorKind::AlreadyExists, "Destination already exists"),
+            })
+            .collect();
+        if !taken.is_empty() {
+            return Err(MvError::MoveErrors(taken));
+        }
+
+        let copies = !fs.same_device(&self.path, &dir);
+        if self.check_space && copies && !cancelled {
+            let required = plan
+                .iter()
+                .filter(|&&(_, _, exists)| exists)
+                .fold(0u64, |sum, &(i, _, _)| sum.saturating_add(files[i].length));
+            check_space(fs, &dir, required)?;
+        }
+
+        let mut created = Vec::new();
+        let mut done = Vec::new();
+        let mut failure = None;
+        for &(i, ref to, exists) in &plan {
+            if cancelled {
+                break;
+            }
+            if !exists {
+                cancelled = report(i, 0, RelocatePhase::Done).is_break();
+                continue;
+            }
+            let from = &files[i].path;
+            let result = create_parents(to, &mut created).and_then(|()| {
+                if copies {
+                    cancelled = report(i, 0, RelocatePhase::Copying).is_break();
+                    if cancelled {
+                        return Ok(());
+                    }
+                    let mut on_copy = |n| {
+                        let flow = report(i, n, RelocatePhase::Copying);
+                        cancelled = flow.is_break();
+                        flow
+                    };
+                    let copied = fs.copy_progress(from, to, &mut on_copy);
+                    match copied {
+                        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                        Err(_) => {
+                            let _ = fs.remove_file(to);
+                        }
+                        Ok(_) => (),
+                    }
+                    match copied {
+                        Err(_) if cancelled => Ok(()),
+                        r => r.map(|_| ()),
+                    }
+                } else {
+                    cancelled = report(i, 0, RelocatePhase::Moving).is_break();
+                    match cancelled {
+                        true => Ok(()),
+                        false => fs.rename(from, to),
+                    }
+                }
+            });
+            match result {
+                Ok(()) if cancelled => (),
+                Ok(()) => {
+                    done.push((i, to));
+                    cancelled = report(i, files[i].length, RelocatePhase::Done).is_break();
+                }
+                Err(error) => {
+                    failure = Some(MoveFailure {
+                        index: i,
+                        name: files[i].name.clone(),
+                        path: from.clone(),
+                        to: to.clone(),
+                        mode: RelocateMode::Move,
+                        error,
+                    });
+                    break;
+                }
+            }
+        }
+
+        if cancelled || failure.is_some() {
+            let mut errs: Vec<_> = failure.into_iter().collect();
+            for &(i, to) in done.iter().rev() {
+                let f = &mut self.files[i];
+                let undo = match copies {
+                    true => fs.remove_file(to),
+                    false => fs.rename(to, &f.path),
+                };
+                if let Err(error) = undo {
+                    // a rename which can not be undone leaves the file at its new path
+                    let (path, to) = match copies {
+                        true => (f.path.clone(), to.clone()),
+                        false => (to.clone(), mem::replace(&mut f.path, to.clone())),
+                    };
+                    errs.push(MoveFailure {
+                        index: i,
+                        name: f.name.clone(),
+                        path,
+                        to,
+                        mode: RelocateMode::Move,
+                        error,
+                    });
+                }
+            }
+            for d in created.iter().rev() {
+                let _ = fs::remove_dir(d);
+            }
+            self.reindex();
+            return Err(match cancelled {
+                true => MvError::Cancelled(errs),
+                false => MvError::MoveErrors(errs),
+            });
+        }
+
+        for &(i, _) in &done {
+            // every copy is in place, so the originals are no longer needed
+            if copies {
+                let _ = fs.remove_file(&self.files[i].path);
+            }
+            // then the folders left empty, up to and including the old root
+            let mut parent = self.files[i].path.parent();
+            while let Some(p) = parent {
+                if !p.starts_with(&self.path) || fs::remove_dir(p).is_err() {
+                    break;
+                }
+                parent = p.parent();
+            }
+        }
+        for (i, to, _) in plan {
+            self.files[i].path = to;
+        }
+        let _ = fs::remove_dir(&self.path);
+        self.path = dir;
+        self.reindex();
+
+        Ok(())
+    }
+
+    /// Try again to put each file in `failures` where it was being put, e.g. once a full disk has
+    /// been cleared. Files which have since been moved or removed are skipped.
+    pub fn retry_failed(&mut self, failures: &[MoveFailure]) -> Result<(), MvError> {
+        let mut errs = Vec::new();
+        for failure in failures {
+            let f = match self.files.get_mut(failure.index) {
+                Some(f) if f.name == failure.name && f.path == failure.path => f,
+                _ => continue,
+            };
+            if let Err(e) = move_file(&StdFs, failure.index, f, failure.to.clone(), failure.mode) {
+                errs.push(e);
+            }
+        }
+        self.reindex();
+
+        match errs.is_empty() {
+            true => Ok(()),
+            false => Err(MvError::MoveErrors(errs)),
+        }
+    }
+
+    crate fn relocate_in(
+        &mut self,
+        fs: &dyn FileSystem,
+        dir: path::PathBuf,
+        mode: RelocateMode,
+    ) -> Result<(), MvError> {
+        if !dir.is_absolute() {
+            return Err(MvError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Must be an absolute path",
+            )));
+        }
+
+        if dir == self.path {
+            return Ok(());
+        }
+
+        // only files which exist are moved, and a rename or hard link needs no space
+        let needs_space = match mode {
+            RelocateMode::Move => !fs.same_device(&self.path, &dir),
+            RelocateMode::HardLink => false,
+            RelocateMode::Reflink | RelocateMode::Copy => true,
+        };
+        if self.check_space && needs_space {
+            let required = self
+                .files
+                .iter()
+                .filter(|f| f.path.starts_with(&self.path))
+                .filter(|f| !matches!(f.status, Status::NotCreated | Status::Missing(_)))
+                .fold(0u64, |sum, f| sum.saturating_add(f.length));
+            check_space(fs, &dir, required)?;
+        }
+
+        try!(fs::create_dir_all(&dir));
+        let mut errs = Vec::new();
+
+        let path_len = match self.path.to_str() {
+            Some(p) => p.len() + 1,
+            None => {
+                return Err(MvError::Io(io::Error::other(
+                    "`self.path` is not a valid string",
+                )))
+            }
+        };
+
+        // move files under self.path
+        for (i, f) in self.files.iter_mut().enumerate() {
+            if !f.path.starts_with(&self.path) {
+                continue;
+            }
+
+            let new_path = match f.path.to_str() {
+                Some(f) => dir.join(&f[path_len..]),
+                None => continue,
+            };
+
+            if let Err(e) = move_file(fs, i, f, new_path, mode) {
+                errs.push(e);
+            }
+        }
+
+        // Deletes the folder if possible, will fail if `self.path` is not empty. We should be
+        // able to continue regardless of error
+        // TODO - Should we report something if this fails?
+        if mode == RelocateMode::Move {
+            let _ = fs::remove_dir(&self.path);
+        }
+        self.path = dir;
+        self.reindex();
+
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(MvError::MoveErrors(errs))
+        }
+    }
+}
+
+/// Put `f`, the file at `index`, at `to` as `mode` says. If that fails the file keeps its path
+/// and status, rather than being marked `Missing`, so the move can be retried.
+fn move_file(
+    fs: &dyn FileSystem,
+    index: usize,
+    f: &mut File,
+    to: path::PathBuf,
+    mode: RelocateMode,
+) -> Result<(), MoveFailure> {
+    let (path, status) = (f.path.clone(), f.status.clone());
+    match f.relocate_in(fs, to.clone(), mode) {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            f.path = path;
+            f.status = status;
+            Err(MoveFailure {
+                index,
+                name: f.name.clone(),
+                path: f.path.clone(),
+                to,
+                mode,
+                error,
+            })
+        }
+    }
+}
+
+impl IntoIterator for Directory {
+    type Item = File;
+    type IntoIter = vec::IntoIter<File>;
+
+    fn into_iter(self) -> vec::IntoIter<File> {
+        self.files.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Directory {
+    type Item = &'a File;
+    type IntoIter = slice::Iter<'a, File>;
+
+    fn into_iter(self) -> slice::Iter<'a, File> {
+        self.files.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Directory {
+    type Item = &'a mut File;
+    type IntoIter = slice::IterMut<'a, File>;
+
+    fn into_iter(self) -> slice::IterMut<'a, File> {
+        self.files.iter_mut()
+    }
+}
+
+/// Files a `Storage` reads and writes
+#[derive(Debug)]
+enum Layout {
+    File(File),
+    Directory(Directory),
+}
+
+/// Reads and writes piece data, which may span several files. Files are opened as they are
+/// needed, and created with their parent folders on their first write.
+#[derive(Debug)]
+pub struct Storage {
+    layout: Layout,
+    piece_length: u64,
+    /// Sum of the file lengths
+    total_length: u64,
+}
+
+impl Storage {
+    /// Storage for a single-file torrent
+    pub fn from_file(file: File, piece_length: u64) -> Storage {
+        let total_length = file.length;
+        Storage {
+            layout: Layout::File(file),
+            piece_length,
+            total_length,
+        }
+    }
+
+    /// Storage for a multi-file torrent
+    pub fn from_directory(dir: Directory, piece_length: u64) -> Storage {
+        let total_length = dir.total_size();
+        Storage {
+            layout: Layout::Directory(dir),
+            piece_length,
+            total_length,
+        }
+    }
+
+    /// Files in the order their data appears in pieces
+    pub fn files(&self) -> &[File] {
+        match self.layout {
+            Layout::File(ref f) => ::std::slice::from_ref(f),
+            Layout::Directory(ref d) => &d.files,
+        }
+    }
+
+    fn files_mut(&mut self) -> &mut [File] {
+        match self.layout {
+            Layout::File(ref mut f) => ::std::slice::from_mut(f),
+            Layout::Directory(ref mut d) => &mut d.files,
+        }
+    }
+
+    /// Read `buf.len()` bytes starting `offset` bytes into `piece`. Bytes past the end of a file
+    /// on disk, which have not been written yet, are read as zeros; reading from a file which
+    /// has not been created is a `NotFound` error, as in `File::read_block`.
+    pub fn read_block(&self, piece: u32, offset: u32, buf: &mut [u8]) -> io::Result<()> {
+        for (s, pos) in self.block_ranges(piece, offset, buf.len())? {
+            let dst = &mut buf[pos..pos + s.len as usize];
+            let data = self.files()[s.file_index].read_block(s.offset, s.len as usize)?;
+            dst[..data.len()].copy_from_slice(&data);
+            for b in &mut dst[data.len()..] {
+                *b = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write `data` starting `offset` bytes into `piece`. Files written to become `Downloading`.
+    pub fn write_block(&mut self, piece: u32, offset: u32, data: &[u8]) -> io::Result<()> {
+        for (s, pos) in self.block_ranges(piece, offset, data.len())? {
+            let src = &data[pos..pos + s.len as usize];
+            self.files_mut()[s.file_index].write_block(s.offset, src)?;
+        }
+
+        Ok(())
+    }
+
+    /// The parts of files holding the `len` bytes starting `offset` bytes into `piece`, each
+    /// paired with its position in the block
+    fn block_ranges(
+        &self,
+        piece: u32,
+        offset: u32,
+        len: usize,
+    ) -> io::Result<Vec<(FileSlice, usize)>> {
+        let ranges = piece_ranges(self.files(), self.piece_length, self.total_length, piece);
+        let piece_size: u64 = ranges.iter().map(|s| s.len).sum();
+
+        let start = u64::from(offset);
+        let end = match start.checked_add(len as u64) {
+            Some(end) if end <= piece_size => end,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Block extends past the end of the piece",
+                ))
+            }
+        };
+
+        let mut blocks = Vec::new();
+        let mut piece_pos = 0;
+        for s in ranges {
+            let (from, to) = (cmp::max(start, piece_pos), cmp::min(end, piece_pos + s.len));
+            if from < to {
+                let slice = FileSlice {
+                    file_index: s.file_index,
+                    offset: s.offset + (from - piece_pos),
+                    len: to - from,
+                };
+                blocks.push((slice, (from - start) as usize));
+            }
+            piece_pos += s.len;
+        }
+
+        Ok(blocks)
+    }
+}
+
+/// Reads a file of a torrent as it downloads, e.g. to play it while the rest is fetched. Only
+/// bytes in verified pieces are read; reading anywhere else fails with `ErrorKind::WouldBlock`
+/// until the piece is verified and the reader is made again with the new set of pieces.
+#[derive(Debug)]
+pub struct FileReader<'a> {
+    file: &'a File,
+    /// Offset of the file's first byte in the torrent's data
+    start: u64,
+    piece_length: u64,
+    /// Whether each piece is verified, as returned by `Directory::verify`
+    verified: &'a [bool],
+    pos: u64,
+}
+
+impl<'a> FileReader<'a> {
+    /// Number of bytes which can be read from the current position without blocking
+    pub fn available(&self) -> u64 {
+        let mut pos = self.pos;
+        while pos < self.file.length {
+            match self.piece_end(pos) {
+                Some(end) => pos = end,
+                None => break,
+            }
+        }
+        // the last piece may end past the end of the file
+        cmp::min(pos, self.file.length).saturating_sub(self.pos)
+    }
+
+    /// The position in the file where the piece holding `pos` ends, if it is verified
+    fn piece_end(&self, pos: u64) -> Option<u64> {
+        let offset = self.start.checked_add(pos)?;
+        let piece = offset.checked_div(self.piece_length)?;
+        let index: usize = convert::TryFrom::try_from(piece).ok()?;
+        match self.verified.get(index) {
+            Some(true) => Some((piece + 1).checked_mul(self.piece_length)? - self.start),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> io::Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.file.length {
+            return Ok(0);
+        }
+        let end = match self.piece_end(self.pos) {
+            Some(end) => cmp::min(end, self.file.length),
+            None if self.piece_length == 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Piece length is zero",
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Piece has not been downloaded",
+                ))
+            }
+        };
+
+        let len = cmp::min(buf.len() as u64, end - self.pos) as usize;
+        let data = self.file.read_block(self.pos, len)?;
+        if data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File is shorter on disk than in the torrent",
+            ));
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl<'a> io::Seek for FileReader<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            io::SeekFrom::Start(p) => Some(p),
+            io::SeekFrom::End(p) => offset(self.file.length, p),
+            io::SeekFrom::Current(p) => offset(self.pos, p),
+        };
+        match pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// `base` moved by `delta` bytes, or `None` if that is before the start or overflows
+fn offset(base: u64, delta: i64) -> Option<u64> {
+    match delta {
+        d if d >= 0 => base.checked_add(d as u64),
+        d => base.checked_sub(d.unsigned_abs()),
+    }
+}
+
+#[cfg(test)]
+mod test_file {
+    use std::borrow::ToOwned;
+    use std::collections;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path;
+
+    use super::{
+        sanitize_component, AllocationMode, File, FileAttrs, FileSystem, PathError, Priority,
+        Status, MAX_COMPONENT_LEN,
+    };
+    use crate::bencode::Benc;
+    use crate::error::{Error, KeyProblem};
+    use crate::util;
+
+    fn name() -> String {
+        "こんにちは".to_owned()
+    }
+    fn path_abs() -> path::PathBuf {
+        env::temp_dir().join(name())
+    }
+    fn path_rel() -> path::PathBuf {
+        path::PathBuf::from(name())
+    }
+    static LEN: u64 = 256;
+
+    #[test]
+    fn new() {
+        let name = name();
+        let path = path_abs();
+        let f = File::new(name.clone(), path.clone(), LEN);
+
+        assert!(f.name() == name, "{} == {}", f.name(), name);
+        assert!(f.path() == path, "{:?} == {:?}", f.path(), path);
+        assert!(f.length() == LEN, "{} == {}", f.length(), LEN);
+        assert!(f.md5sum == None, "{:?} == None", f.md5sum);
+        assert!(
+            f.status == Status::NotCreated,
+            "{:?} == {:?}",
+            f.status,
+            Status::NotCreated
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_fail() {
+        File::new(name(), path_rel(), LEN);
+    }
+
+    fn path(parts: &[&str]) -> Benc {
+        Benc::List(
+            parts
+                .iter()
+                .map(|p| Benc::String(p.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn from_dict() {
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"length".to_vec(), Benc::Int(LEN as i64));
+        dict.insert(b"path".to_vec(), path(&["sub", "file.ext"]));
+        dict.insert(
+            b"md5sum".to_vec(),
+            Benc::String(b"d41d8cd98f00b204e9800998ecf8427e".to_vec()),
+        );
+
+        let f = File::from_dict(&mut dict, None).unwrap();
+        let expect = util::download_dir().join("sub").join("file.ext");
+        assert!(f.path() == expect, "{:?} == {:?}", f.path(), expect);
+        assert!(f.name() == "sub/file.ext", "{} == sub/file.ext", f.name());
+        assert!(f.length() == LEN);
+        assert!(f.md5sum.as_deref() == Some("d41d8cd98f00b204e9800998ecf8427e"));
+        assert!(f.status == Status::NotCreated);
+        assert!(dict.is_empty(), "{:?}", dict);
+
+        // "name" is only used by single-file torrents
+        for (entries, key, problem) in [
+            (vec![("length", Benc::Int(1))], "path", KeyProblem::Missing),
+            (
+                vec![
+                    ("length", Benc::Int(1)),
+                    ("path", Benc::String(b"a".to_vec())),
+                ],
+                "path",
+                KeyProblem::WrongType { found: "string" },
+            ),
+            (
+                vec![
+                    ("length", Benc::Int(1)),
+                    ("path", Benc::List(vec![Benc::Int(0)])),
+                ],
+                "path",
+                KeyProblem::WrongType { found: "int" },
+            ),
+            (
+                vec![("name", path(&["file.ext"])), ("length", Benc::Int(1))],
+                "path",
+                KeyProblem::Missing,
+            ),
+            (
+                vec![("path", path(&["file.ext"]))],
+                "length",
+                KeyProblem::Missing,
+            ),
+        ] {
+            let mut dict: collections::HashMap<_, _> = entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect();
+            let expect = Error::Key {
+                key: key.as_bytes().to_vec(),
+                problem,
+            };
+
+            let err = File::from_dict(&mut dict, None).unwrap_err();
+            assert!(err == expect, "{:?} == {:?}", err, expect);
+        }
+
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"length".to_vec(), Benc::Int(1));
+        dict.insert(b"path".to_vec(), path(&[]));
+        assert!(File::from_dict(&mut dict, None).is_err());
+    }
+
+    #[test]
+    fn materialize_symlink() {
+        let root = env::temp_dir().join("materialize_symlink");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("data")).unwrap();
+        fs::write(root.join("data").join("target.ext"), b"hello").unwrap();
+
+        let link = |target: &str| {
+            let mut f = File::new("sub/link".to_owned(), root.join("sub").join("link"), 0);
+            f.attrs = FileAttrs::SYMLINK;
+            f.link_target = Some(path::PathBuf::from(target));
+            f
+        };
+
+        for target in ["../../etc/passwd", "data/../../x", "/etc/passwd", ""] {
+            let err = link(target).materialize_symlink(&root).unwrap_err();
+            assert!(
+                err.kind() == io::ErrorKind::InvalidInput,
+                "{}: {:?}",
+                target,
+                err
+            );
+            assert!(!root.join("sub").exists(), "{}", target);
+        }
+        let plain = File::new(name(), root.join("plain"), 0);
+        assert!(plain.materialize_symlink(&root).is_err());
+        // the link must be inside the torrent's folder
+        let outside = link("data/target.ext");
+        assert!(outside.materialize_symlink(&root.join("data")).is_err());
+
+        let f = link("data/target.ext");
+        f.materialize_symlink(&root).unwrap();
+        #[cfg(unix)]
+        {
+            let target = fs::read_link(f.path()).unwrap();
+            let expect = path::Path::new("..").join("data").join("target.ext");
+            assert!(target == expect, "{:?} == {:?}", target, expect);
+            assert!(fs::read(f.path()).unwrap() == b"hello");
+        }
+    }
+
+    #[test]
+    fn from_dict_attrs() {
+        let parse = |entries: Vec<(&str, Benc)>| {
+            let mut dict: collections::HashMap<_, _> = entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect();
+            dict.insert(b"length".to_vec(), Benc::Int(LEN as i64));
+            dict.insert(b"path".to_vec(), path(&["file.ext"]));
+            File::from_dict(&mut dict, None).unwrap()
+        };
+
+        let f = parse(vec![]);
+        assert!(f.attrs().is_empty() && !f.is_padding(), "{:?}", f.attrs());
+        assert!(f.link_target().is_none());
+
+        let f = parse(vec![("attr", Benc::String(b"x".to_vec()))]);
+        assert!(f.attrs() == FileAttrs::EXECUTABLE, "{:?}", f.attrs());
+        assert!(!f.is_padding());
+
+        // unknown attributes are ignored
+        let f = parse(vec![("attr", Benc::String(b"hpz".to_vec()))]);
+        let expect = FileAttrs::PADDING | FileAttrs::HIDDEN;
+        assert!(f.attrs() == expect, "{:?} == {:?}", f.attrs(), expect);
+        assert!(f.is_padding());
+
+        let f = parse(vec![
+            ("attr", Benc::String(b"l".to_vec())),
+            ("symlink path", path(&["sub", "target.ext"])),
+        ]);
+        assert!(f.attrs().contains(FileAttrs::SYMLINK));
+        let expect = path::Path::new("sub").join("target.ext");
+        assert!(f.link_target() == Some(&*expect), "{:?}", f.link_target());
+
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"length".to_vec(), Benc::Int(1));
+        dict.insert(b"path".to_vec(), path(&["file.ext"]));
+        dict.insert(b"symlink path".to_vec(), Benc::Int(1));
+        assert!(File::from_dict(&mut dict, None).is_err());
+    }
+
+    #[test]
+    fn from_dict_hostile() {
+        let parse = |parts: &[&str]| {
+            let mut dict = collections::HashMap::new();
+            dict.insert(b"length".to_vec(), Benc::Int(1));
+            dict.insert(b"path".to_vec(), path(parts));
+            File::from_dict(&mut dict, None)
+        };
+        let root = util::download_dir();
+
+        for parts in &[
+            &["..", "..", "etc", "passwd"][..],
+            &["a", "..", "..", "b"],
+            &["/etc", "passwd"],
+            &["a/../../b"],
+            &["a", "/"],
+        ] {
+            let f = parse(parts);
+            assert!(f.is_err(), "{:?}: {:?}", parts, f);
+        }
+
+        // "." and empty components are dropped
+        let f = parse(&[".", "a", "", "b.ext"]).unwrap();
+        assert!(f.path() == root.join("a").join("b.ext"), "{:?}", f.path());
+        assert!(f.name() == "a/b.ext", "{} == a/b.ext", f.name());
+        assert!(parse(&["", "."]).is_err());
+
+        #[cfg(target_os = "linux")]
+        {
+            let f = parse(&["a\0", "\0b.ext"]).unwrap();
+            assert!(f.path() == root.join("a").join("b.ext"), "{:?}", f.path());
+        }
+
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"length".to_vec(), Benc::Int(1));
+        dict.insert(b"name".to_vec(), Benc::String(b"..".to_vec()));
+        assert!(File::from_info(&mut dict, None).is_err());
+    }
+
+    #[test]
+    fn sanitize() {
+        let sanitize = |c: &[u8]| sanitize_component(c).map(|c| c.into_string().unwrap());
+
+        for (c, expect) in [
+            (&b"file.ext"[..], Ok("file.ext")),
+            (b".hidden", Ok(".hidden")),
+            (b"..", Err(PathError::Dots)),
+            (b".", Err(PathError::Dots)),
+            (b"....", Err(PathError::Dots)),
+            (b"a/b", Err(PathError::Separator)),
+            (b"/", Err(PathError::Separator)),
+            (b"\xff\xfe", Err(PathError::NotUtf8)),
+            (b"", Ok("_")),
+        ] {
+            let result = sanitize(c);
+            let expect = expect.map(str::to_owned);
+            assert!(result == expect, "{:?}: {:?} == {:?}", c, result, expect);
+        }
+
+        #[cfg(not(target_os = "macos"))]
+        {
+            assert!(sanitize(b"a\0b.ext") == Ok("ab.ext".to_owned()));
+            // nothing is left once NUL is removed
+            assert!(sanitize(b"\0\0") == Ok("_".to_owned()));
+            // removing NUL must not reveal a traversal
+            assert!(sanitize(b".\0.") == Err(PathError::Dots));
+        }
+
+        #[cfg(windows)]
+        {
+            assert!(sanitize(b"a:b*c?.ext") == Ok("abc.ext".to_owned()));
+            assert!(sanitize(b"<>|") == Ok("_".to_owned()));
+            assert!(sanitize(b"con.txt") == Ok("_con.txt".to_owned()));
+        }
+        #[cfg(target_os = "linux")]
+        assert!(sanitize(b"a:b*c?.ext") == Ok("a:b*c?.ext".to_owned()));
+
+        // long names keep their extension, and are not cut inside a character
+        let long = format!("{}.ext", "あ".repeat(100));
+        let c = sanitize(long.as_bytes()).unwrap();
+        assert!(
+            c.len() <= MAX_COMPONENT_LEN,
+            "{} <= {}",
+            c.len(),
+            MAX_COMPONENT_LEN
+        );
+        assert!(c.ends_with("あ.ext"), "{}", c);
+
+        let long = "a".repeat(300);
+        let c = sanitize(long.as_bytes()).unwrap();
+        assert!(
+            c.len() == MAX_COMPONENT_LEN,
+            "{} == {}",
+            c.len(),
+            MAX_COMPONENT_LEN
+        );
+    }
+
+    #[test]
+    fn from_info() {
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"length".to_vec(), Benc::Int(LEN as i64));
+        dict.insert(b"name".to_vec(), Benc::String(name().into_bytes()));
+
+        let f = File::from_info(&mut dict, None).unwrap();
+        let expect = util::download_dir().join(name());
+        assert!(f.path() == expect, "{:?} == {:?}", f.path(), expect);
+        assert!(f.name() == name(), "{} == {}", f.name(), name());
+        assert!(f.length() == LEN);
+
+        dict.insert(b"length".to_vec(), Benc::Int(LEN as i64));
+        dict.insert(b"name".to_vec(), path(&["file.ext"]));
+        let err = File::from_info(&mut dict, None).unwrap_err();
+        let expect = Error::Key {
+            key: b"name".to_vec(),
+            problem: KeyProblem::WrongType { found: "list" },
+        };
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+    }
+
+    #[test]
+    fn from_dict_utf8() {
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"length".to_vec(), Benc::Int(LEN as i64));
+        dict.insert(
+            b"path".to_vec(),
+            Benc::List(vec![Benc::String(vec![0x82, 0xa0, 0xff])]),
+        );
+        dict.insert(b"path.utf-8".to_vec(), path(&[&name()]));
+
+        let f = File::from_dict(&mut dict, None).unwrap();
+        assert!(f.name() == name(), "{} == {}", f.name(), name());
+        assert!(f.path().ends_with(name()), "{:?}", f.path());
+
+        // fall back to "path" if "path.utf-8" is not valid UTF-8
+        dict.insert(b"length".to_vec(), Benc::Int(LEN as i64));
+        dict.insert(b"path".to_vec(), path(&[&name()]));
+        dict.insert(
+            b"path.utf-8".to_vec(),
+            Benc::List(vec![Benc::String(vec![0xff])]),
+        );
+
+        let f = File::from_dict(&mut dict, None).unwrap();
+        assert!(f.name() == name(), "{} == {}", f.name(), name());
+        assert!(dict.is_empty());
+    }
+
+    #[test]
+    fn mark_range_complete() {
+        let mut f = File::new(name(), path_abs(), LEN);
+        assert!(f.progress() == 0.0);
+
+        f.mark_range_complete(0, 64);
+        f.mark_range_complete(32, 64);
+        f.mark_range_complete(128, 16);
+        assert!(f.completed() == 112, "{} == 112", f.completed());
+        assert!(f.status == Status::NotCreated);
+
+        // ranges which touch are merged, and ranges past the end are clamped
+        f.mark_range_complete(96, 32);
+        assert!(f.ranges.len() == 1, "{:?}", f.ranges);
+        f.mark_range_complete(LEN - 16, 1 << 20);
+        f.mark_range_complete(LEN, 16);
+        f.mark_range_complete(0, u64::MAX);
+        assert!(f.completed() == LEN, "{} == {}", f.completed(), LEN);
+        assert!(f.progress() == 1.0, "{} == 1", f.progress());
+        assert!(f.status == Status::Done, "{:?} == Done", f.status);
+
+        let mut f = File::new(name(), path_abs(), LEN);
+        f.mark_range_complete(0, 64);
+        f.mark_range_complete(0, 64);
+        assert!(f.progress() == 0.25, "{} == 0.25", f.progress());
+        assert!(File::new(name(), path_abs(), 0).progress() == 1.0);
+    }
+
+    #[test]
+    fn sync_status() {
+        let dir = env::temp_dir().join("sync_status");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.ext");
+        let mut f = File::new(name(), path.clone(), 4);
+
+        // nothing on disk yet
+        assert!(*f.sync_status().unwrap() == Status::NotCreated);
+
+        fs::write(&path, b"ab").unwrap();
+        assert!(*f.sync_status().unwrap() == Status::Downloading);
+
+        fs::write(&path, b"abcd").unwrap();
+        f.mark_done();
+        assert!(*f.sync_status().unwrap() == Status::Done);
+
+        // truncated after it was finished
+        fs::write(&path, b"a").unwrap();
+        assert!(*f.sync_status().unwrap() == Status::Downloading);
+
+        fs::remove_file(&path).unwrap();
+        let missing = Status::Missing(Some(path.clone()));
+        assert!(*f.sync_status().unwrap() == missing);
+
+        // restored, but unverified
+        fs::write(&path, b"abcd").unwrap();
+        assert!(*f.sync_status().unwrap() == Status::Stopped);
+
+        fs::write(&path, b"abcde").unwrap();
+        assert!(matches!(*f.sync_status().unwrap(), Status::Other(_)));
+
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        f.mark_seeding();
+        assert!(matches!(*f.sync_status().unwrap(), Status::Other(_)));
+
+        // skipped files are left alone
+        fs::remove_dir(&path).unwrap();
+        f.mark_done();
+        f.set_priority(Priority::Skip);
+        assert!(*f.sync_status().unwrap() == Status::Done);
+    }
+
+    #[test]
+    fn allocate() {
+        let dir = env::temp_dir().join("allocate");
+        let _ = fs::remove_dir_all(&dir);
+
+        for len in &[0, 1, 1 << 20] {
+            let path = dir.join(len.to_string()).join(name());
+            let mut f = File::new(name(), path.clone(), *len);
+
+            f.allocate(AllocationMode::Full).unwrap();
+            assert!(f.status == Status::Stopped, "{:?}", f.status);
+            assert!(f.allocation() == Some(AllocationMode::Full));
+            let size = fs::metadata(&path).unwrap().len();
+            assert!(size == *len, "{} == {}", size, len);
+        }
+
+        // existing data is kept, and the status of a created file is unchanged
+        let path = dir.join("existing").join(name());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"hello").unwrap();
+        let mut f = File::new(name(), path.clone(), 8);
+        f.status = Status::Downloading;
+
+        f.allocate(AllocationMode::Full).unwrap();
+        assert!(f.status == Status::Downloading, "{:?}", f.status);
+        assert!(fs::read(&path).unwrap() == b"hello\0\0\0");
+    }
+
+    #[test]
+    fn allocate_sparse() {
+        let path = env::temp_dir().join("allocate_sparse").join(name());
+        let _ = fs::remove_file(&path);
+        let mut f = File::new(name(), path.clone(), 64 << 20);
+        assert!(f.allocation().is_none());
+
+        f.allocate(AllocationMode::Sparse).unwrap();
+        assert!(f.status == Status::Stopped, "{:?}", f.status);
+        assert!(f.allocation() == Some(AllocationMode::Sparse));
+
+        let meta = fs::metadata(&path).unwrap();
+        assert!(meta.len() == 64 << 20, "{} == {}", meta.len(), 64 << 20);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // 512 byte blocks; allow for some metadata
+            assert!(meta.blocks() < 64, "{}", meta.blocks());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "md5")]
+    fn verify_md5() {
+        let path = env::temp_dir().join("verify_md5").join(name());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"The quick brown fox jumps over the lazy dog").unwrap();
+
+        let mut f = File::new(name(), path.clone(), 43);
+        f.status = Status::Done;
+        assert!(f.verify_md5().unwrap().is_none());
+
+        for sum in &[
+            "9e107d9d372bb6826bd81d3542a419d6",
+            "9E107D9D372BB6826BD81D3542A419D6",
+        ] {
+            f.md5sum = Some(sum.to_string());
+            assert!(f.verify_md5().unwrap() == Some(true), "{}", sum);
+            assert!(f.status == Status::Done, "{:?}", f.status);
+        }
+
+        f.md5sum = Some("d41d8cd98f00b204e9800998ecf8427e".to_owned());
+        assert!(f.verify_md5().unwrap() == Some(false));
+        let expect = Status::Other(Some("md5 mismatch".to_owned()));
+        assert!(f.status == expect, "{:?} == {:?}", f.status, expect);
+
+        for sum in &[
+            "9e107d9d",
+            "9e107d9d372bb6826bd81d3542a419dx",
+            "+e107d9d372bb6826bd81d3542a419d6",
+        ] {
+            f.md5sum = Some(sum.to_string());
+            assert!(f.verify_md5().is_err(), "{}", sum);
+        }
+    }
+
+    #[test]
+    fn write_block() {
+        let path = env::temp_dir().join("write_block").join(name());
+        let _ = fs::remove_file(&path);
+        let mut f = File::new(name(), path.clone(), 16);
+
+        f.write_block(8, b"world!").unwrap();
+        f.write_block(0, b"hello, ").unwrap();
+        assert!(f.status == Status::Downloading);
+
+        let data = fs::read(&path).unwrap();
+        assert!(data == b"hello, \0world!", "{:?}", data);
+
+        assert!(f.write_block(12, b"overflow").is_err());
+        assert!(f.write_block(u64::MAX, b"overflow").is_err());
+        assert!(fs::read(&path).unwrap() == data);
+    }
+
+    #[test]
+    fn read_block() {
+        let path = env::temp_dir().join("read_block").join(name());
+        let _ = fs::remove_file(&path);
+        let mut f = File::new(name(), path.clone(), 16);
+
+        let err = f.read_block(0, 4).unwrap_err();
+        assert!(err.kind() == io::ErrorKind::NotFound, "{:?}", err);
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"hello, world").unwrap();
+        f.status = Status::Downloading;
+
+        assert!(f.read_block(0, 5).unwrap() == b"hello");
+        assert!(f.read_block(7, 5).unwrap() == b"world");
+        // not yet written to disk
+        assert!(f.read_block(10, 6).unwrap() == b"ld");
+
+        assert!(f.read_block(12, 8).is_err());
+        assert!(f.read_block(u64::MAX, 1).is_err());
+    }
+
+    /// Renames fail as if across filesystems; copies fail if `fail_copy` is set.
+    struct CrossDevice {
+        fail_copy: bool,
+    }
+
+    impl FileSystem for CrossDevice {
+        fn rename(&self, _: &path::Path, _: &path::Path) -> io::Result<()> {
+            #[cfg(windows)]
+            let code = 17;
+            #[cfg(not(windows))]
+            let code = 18;
+            Err(io::Error::from_raw_os_error(code))
+        }
+
+        fn copy(&self, from: &path::Path, to: &path::Path) -> io::Result<u64> {
+            if self.fail_copy {
+                fs::write(to, b"partial")?;
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "No space left"));
+            }
+            fs::copy(from, to)
+        }
+
+        fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+            fs::remove_file(path)
+        }
+
+        fn hard_link(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            fs::hard_link(from, to)
+        }
+
+        fn reflink(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            super::reflink(from, to)
+        }
+
+        fn available_space(&self, _: &path::Path) -> io::Result<u64> {
+            Ok(u64::MAX)
+        }
+
+        fn same_device(&self, _: &path::Path, _: &path::Path) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn set_location_cross_device() {
+        let dir = env::temp_dir().join("set_location_cross_device");
+        let (from, to) = (dir.join("from").join(name()), dir.join("to").join(name()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, b"hello, world").unwrap();
+
+        let mut f = File::new(name(), from.clone(), 12);
+        f.status = Status::Downloading;
+        let fs = CrossDevice { fail_copy: false };
+
+        f.set_location_in(&fs, to.clone()).unwrap();
+        assert!(f.path() == to.as_path(), "{:?} == {:?}", f.path(), to);
+        assert!(f.status == Status::Downloading, "{:?}", f.status);
+        assert!(fs::read(&to).unwrap() == b"hello, world");
+        assert!(!from.exists());
+    }
+
+    #[test]
+    fn set_location_cross_device_failed() {
+        let dir = env::temp_dir().join("set_location_cross_device_failed");
+        let (from, to) = (dir.join("from").join(name()), dir.join("to").join(name()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, b"hello, world").unwrap();
+
+        let mut f = File::new(name(), from.clone(), 12);
+        f.status = Status::Downloading;
+        let fs = CrossDevice { fail_copy: true };
+
+        assert!(f.set_location_in(&fs, to.clone()).is_err());
+        assert!(f.path() == from.as_path(), "{:?} == {:?}", f.path(), from);
+        assert!(f.status == Status::Downloading, "{:?}", f.status);
+        assert!(fs::read(&from).unwrap() == b"hello, world");
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn set_location() {
+        let mut f = File::new(name(), path_abs(), LEN);
+        let p = env::temp_dir().join("あ");
+
+        if let Err(e) = f.set_location(p.clone()) {
+            panic!("Could not move file. {}", e)
+        }
+        assert!(f.path() == p.as_path());
+
+        if let Ok(_) = f.set_location(path::PathBuf::from("あ")) {
+            panic!("Moved file to relative path")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_directory {
+    use std::borrow::ToOwned;
+    use std::collections;
+    use std::env;
+    use std::ffi;
+    use std::fs;
+    use std::io;
+    use std::ops::ControlFlow;
+    use std::path;
+
+    use super::{
+        sanitize_component, AllocationMode, Directory, File, FileAttrs, FileSlice, FileSystem,
+        MoveFailure, MvError, PathError, Priority, RelocateMode, RelocatePhase, SanitizeMode,
+        Status, StatusChange, StdFs, VerifyResult, PROGRESS_INTERVAL,
+    };
+    use crate::bencode::Benc;
+    use crate::error;
+    use crate::torrent::Torrent;
+    use crate::util;
+
+    fn name() -> String {
+        "こんにちは".to_owned()
+    }
+    fn path_abs() -> path::PathBuf {
+        env::temp_dir().join(name())
+    }
+    static LEN: u64 = 256;
+    static CAP: usize = 8;
+
+    #[test]
+    fn new() {
+        let path = path_abs();
+        let d = Directory::with_capacity(path.clone(), CAP);
+
+        assert!(d.path() == path, "{:?} == {:?}", d.path(), path);
+        assert!(
+            d.files.capacity() == CAP,
+            "{} == {}",
+            d.files.capacity(),
+            CAP
+        );
+        assert!(d.is_empty(), "{} == 0", d.len());
+        assert!(
+            d.status == Status::NotCreated,
+            "{:?} == {:?}",
+            d.status,
+            Status::NotCreated
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_fail() {
+        Directory::new(path::PathBuf::from(""));
+    }
+
+    #[test]
+    fn from_dict() {
+        let entry = |len: i64, parts: &[&str]| {
+            let mut f = collections::HashMap::new();
+            f.insert(b"length".to_vec(), Benc::Int(len));
+            f.insert(
+                b"path".to_vec(),
+                Benc::List(
+                    parts
+                        .iter()
+                        .map(|p| Benc::String(p.as_bytes().to_vec()))
+                        .collect(),
+                ),
+            );
+            Benc::Dict(f)
+        };
+
+        // shaped like the info dict of a real torrent, less "piece length" and "pieces"
+        let mut dict = collections::HashMap::new();
+        dict.insert(
+            b"name".to_vec(),
+            Benc::String(b"Album (2009) [FLAC]".to_vec()),
+        );
+        dict.insert(
+            b"files".to_vec(),
+            Benc::List(vec![
+                entry(31_457_280, &["CD1", "01 - Intro.flac"]),
+                entry(524_288, &["Artwork", "Scans", "front.jpg"]),
+            ]),
+        );
+
+        let d = Directory::from_dict(&mut dict, None).unwrap();
+        let root = util::download_dir().join("Album (2009) [FLAC]");
+        assert!(d.path() == root, "{:?} == {:?}", d.path(), root);
+        assert!(d.status == Status::NotCreated);
+        assert!(dict.is_empty(), "{:?}", dict);
+
+        let paths: Vec<_> = d.iter().map(|f| f.path().to_path_buf()).collect();
+        let expect = vec![
+            root.join("CD1").join("01 - Intro.flac"),
+            root.join("Artwork").join("Scans").join("front.jpg"),
+        ];
+        assert!(paths == expect, "{:?} == {:?}", paths, expect);
+        assert!(d.file(1).map(File::name) == Some("Artwork/Scans/front.jpg"));
+        assert!(d.total_size() == 31_457_280 + 524_288);
+
+        // the root folder and every file must stay inside the download folder
+        for (name, parts) in &[
+            ("..", &["a.ext"][..]),
+            ("/", &["a.ext"]),
+            ("mock", &["..", "a.ext"]),
+            ("mock", &["/tmp", "a.ext"]),
+        ] {
+            let mut dict = collections::HashMap::new();
+            dict.insert(b"name".to_vec(), Benc::String(name.as_bytes().to_vec()));
+            dict.insert(b"files".to_vec(), Benc::List(vec![entry(1, parts)]));
+            let d = Directory::from_dict(&mut dict, None);
+            assert!(d.is_err(), "{:?} {:?}: {:?}", name, parts, d);
+        }
+
+        // an empty root folder name is replaced rather than dropped
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"name".to_vec(), Benc::String(Vec::new()));
+        dict.insert(b"files".to_vec(), Benc::List(vec![entry(1, &["a.ext"])]));
+        let d = Directory::from_dict(&mut dict, None).unwrap();
+        let expect = util::download_dir().join("_");
+        assert!(d.path() == expect, "{:?} == {:?}", d.path(), expect);
+
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"name".to_vec(), Benc::String(b"mock".to_vec()));
+        let err = Directory::from_dict(&mut dict, None).unwrap_err();
+        let expect = error::Error::Key {
+            key: b"files".to_vec(),
+            problem: error::KeyProblem::Missing,
+        };
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+    }
+
+    #[test]
+    fn add_file() {
+        let mut d = Directory::new(path_abs());
+
+        let name = name();
+        let path = path_abs().join("file");
+        d.add_file(File::new(name.clone(), path.clone(), LEN));
+
+        assert!(d.len() == 1);
+        assert!(d.iter().eq(&[File::new(name, path, LEN)]));
+    }
+
+    #[test]
+    fn add_files() {
+        let path = path_abs();
+
+        let mut dir = Directory::new(path.clone());
+        let mut files = Vec::with_capacity(CAP);
+        let mut copy = Vec::with_capacity(CAP);
+
+        for i in 0..CAP as u64 {
+            let name = format!("file-{}.ext", i);
+            let fpath = path.join(name.clone());
+
+            files.push(File::new(name.clone(), fpath.clone(), i * i));
+            copy.push(File::new(name, fpath, i * i));
+        }
+
+        dir.add_files(files);
+        assert!(dir.iter().eq(&copy));
+    }
+
+    #[test]
+    fn from_dict_portable() {
+        let parse = |mode| {
+            let mut f = collections::HashMap::new();
+            f.insert(b"length".to_vec(), Benc::Int(1));
+            f.insert(
+                b"path".to_vec(),
+                Benc::List(vec![
+                    Benc::String(b"aux".to_vec()),
+                    Benc::String(b"CON.txt".to_vec()),
+                ]),
+            );
+            let mut dict = collections::HashMap::new();
+            dict.insert(b"name".to_vec(), Benc::String(b"report. ".to_vec()));
+            dict.insert(b"files".to_vec(), Benc::List(vec![Benc::Dict(f)]));
+            Directory::from_dict_with(&mut dict, None, mode).unwrap()
+        };
+
+        let d = parse(SanitizeMode::Portable);
+        let root = util::download_dir().join("report");
+        assert!(d.path() == root, "{:?} == {:?}", d.path(), root);
+        let expect = root.join("_aux").join("_CON.txt");
+        let f = d.file(0).unwrap();
+        assert!(f.path() == expect, "{:?} == {:?}", f.path(), expect);
+        assert!(f.name() == "_aux/_CON.txt", "{}", f.name());
+
+        #[cfg(not(windows))]
+        {
+            let d = parse(SanitizeMode::Native);
+            let expect = util::download_dir()
+                .join("report. ")
+                .join("aux")
+                .join("CON.txt");
+            let f = d.file(0).unwrap();
+            assert!(f.path() == expect, "{:?} == {:?}", f.path(), expect);
+        }
+    }
+
+    #[test]
+    fn add_file_named() {
+        let path = path_abs();
+        let mut dir = Directory::new(path.clone());
+
+        dir.add_file_named(&[b"sub", b"file.ext"], LEN).unwrap();
+        let f = dir.iter().last().unwrap();
+        assert!(f.name() == "sub/file.ext", "{} == sub/file.ext", f.name());
+        assert!(
+            f.path() == path.join("sub").join("file.ext"),
+            "{:?}",
+            f.path()
+        );
+
+        #[cfg(not(target_os = "macos"))]
+        {
+            dir.add_file_named(&[b"\0", b"file\0.ext"], LEN).unwrap();
+            let f = dir.iter().last().unwrap();
+            assert!(
+                f.path() == path.join("_").join("file.ext"),
+                "{:?}",
+                f.path()
+            );
+        }
+
+        for (p, err) in [
+            (&[&b".."[..], b"file.ext"][..], PathError::Dots),
+            (&[b"a/../../b"], PathError::Separator),
+            (&[], PathError::Empty),
+        ] {
+            let result = dir.add_file_named(p, LEN);
+            assert!(result == Err(err), "{:?}: {:?} == {:?}", p, result, err);
+        }
+    }
+
+    #[test]
+    fn total_size() {
+        let mut d = Directory::new(path_abs());
+        assert!(d.total_size() == 0);
+
+        for i in 0..CAP as u64 {
+            d.add_file(File::new(
+                name(),
+                path_abs().join(format!("file-{}", i)),
+                i * LEN,
+            ));
+        }
+        assert!(
+            d.total_size() == 28 * LEN,
+            "{} == {}",
+            d.total_size(),
+            28 * LEN
+        );
+
+        d.add_file(File::new(name(), path_abs().join("huge"), u64::MAX));
+        assert!(d.total_size() == u64::MAX);
+    }
+
+    #[test]
+    fn find_file() {
+        let mut d = Directory::new(path_abs());
+        for n in &["a.ext", "sub/b.ext"] {
+            d.add_file(File::new(n.to_string(), path_abs().join(n), LEN));
+        }
+
+        assert!(d.file(1).map(File::name) == Some("sub/b.ext"));
+        assert!(d.file(2).is_none());
+
+        let rel = path::Path::new("sub").join("b.ext");
+        assert!(d.find_file(&rel).map(File::name) == Some("sub/b.ext"));
+        assert!(d.find_file(path::Path::new("b.ext")).is_none());
+        assert!(d.find_file(&path_abs().join("a.ext")).is_none());
+
+        d.find_file_mut(path::Path::new("a.ext"))
+            .unwrap()
+            .mark_done();
+        d.file_mut(1).unwrap().mark_seeding();
+        assert!(d.file(0).unwrap().status == Status::Done);
+        assert!(d.file(1).unwrap().status == Status::Seeding);
+    }
+
+    #[test]
+    fn iter() {
+        let mut d = Directory::new(path_abs());
+        for n in &["a.ext", "b.ext"] {
+            d.add_file(File::new(n.to_string(), path_abs().join(n), LEN));
+        }
+
+        let names: Vec<_> = (&d).into_iter().map(File::name).collect();
+        assert!(names == ["a.ext", "b.ext"], "{:?}", names);
+
+        for f in &mut d {
+            f.mark_done();
+        }
+        assert!(d.iter().all(|f| f.status == Status::Done));
+
+        let files: Vec<File> = d.into_iter().collect();
+        assert!(files.len() == 2);
+    }
+
+    #[test]
+    fn padding() {
+        let entry = |len: i64, parts: &[&str], attr: Option<&str>| {
+            let mut f = collections::HashMap::new();
+            f.insert(b"length".to_vec(), Benc::Int(len));
+            let parts = parts.iter().map(|p| Benc::String(p.as_bytes().to_vec()));
+            f.insert(b"path".to_vec(), Benc::List(parts.collect()));
+            if let Some(attr) = attr {
+                f.insert(b"attr".to_vec(), Benc::String(attr.as_bytes().to_vec()));
+            }
+            Benc::Dict(f)
+        };
+
+        // 16 byte pieces, with each file after the first starting on a piece boundary. The
+        // second padding file has no "attr" and is only known by its name.
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"name".to_vec(), Benc::String(b"mock".to_vec()));
+        dict.insert(
+            b"files".to_vec(),
+            Benc::List(vec![
+                entry(10, &["a.ext"], None),
+                entry(6, &[".pad", "6"], Some("p")),
+                entry(20, &["b.ext"], Some("x")),
+                entry(12, &[".pad", "12"], None),
+                entry(3, &["c.ext"], None),
+            ]),
+        );
+        let d = Directory::from_dict(&mut dict, None).unwrap();
+
+        let names: Vec<_> = d.iter_visible().map(File::name).collect();
+        assert!(names == ["a.ext", "b.ext", "c.ext"], "{:?}", names);
+        let padding: Vec<_> = d.iter().map(File::is_padding).collect();
+        assert!(
+            padding == [false, true, false, true, false],
+            "{:?}",
+            padding
+        );
+        assert!(d.file(3).unwrap().priority() == Priority::Skip);
+        assert!(d.total_size() == 51, "{} == 51", d.total_size());
+
+        // the padding bytes still take up space in pieces
+        let expect = vec![(0, 0, 10), (1, 0, 6)];
+        assert!(d.map_piece(0, 16) == expect, "{:?}", d.map_piece(0, 16));
+        let expect = vec![(2, 16, 4), (3, 0, 12)];
+        assert!(d.map_piece(2, 16) == expect, "{:?}", d.map_piece(2, 16));
+        for (i, expect) in [(2, (1, 0)), (4, (3, 0))] {
+            let piece = d.file_offset_to_piece(i, 0, 16);
+            assert!(piece == Some(expect), "{}: {:?} == {:?}", i, piece, expect);
+        }
+    }
+
+    #[test]
+    fn map_piece() {
+        let mut d = Directory::new(path_abs());
+        for (i, &len) in [10, 5, 0, 3, 20].iter().enumerate() {
+            d.add_file(File::new(
+                name(),
+                path_abs().join(format!("file-{}", i)),
+                len,
+            ));
+        }
+
+        // within a single file
+        assert!(d.map_piece(0, 8) == vec![(0, 0, 8)]);
+        // straddles three files, skipping the empty one
+        assert!(d.map_piece(1, 8) == vec![(0, 8, 2), (1, 0, 5), (3, 0, 1)]);
+        // straddles two files
+        assert!(d.map_piece(2, 8) == vec![(3, 1, 2), (4, 0, 6)]);
+        assert!(d.map_piece(3, 8) == vec![(4, 6, 8)]);
+        // short last piece
+        assert!(d.map_piece(4, 8) == vec![(4, 14, 6)]);
+        // out of range
+        assert!(d.map_piece(5, 8).is_empty());
+        assert!(d.map_piece(usize::MAX, 8).is_empty());
+    }
+
+    #[test]
+    fn map_piece_large() {
+        const GIB: u64 = 1 << 30;
+        const MIB: u64 = 1 << 20;
+
+        let mut d = Directory::new(path_abs());
+        for (i, &len) in [3 * GIB + MIB / 2, 3 * GIB].iter().enumerate() {
+            d.add_file(File::new(
+                name(),
+                path_abs().join(format!("file-{}", i)),
+                len,
+            ));
+        }
+        let total = d.total_size();
+
+        // the piece at 3 GiB straddles both files
+        let expect = vec![(0, 3 * GIB, MIB / 2), (1, 0, MIB / 2)];
+        assert!(
+            d.map_piece(3072, MIB) == expect,
+            "{:?}",
+            d.map_piece(3072, MIB)
+        );
+
+        let last = (total / MIB) as u32;
+        let ranges = d.piece_ranges(MIB, total, last);
+        assert!(ranges.len() == 1 && ranges[0].file_index == 1);
+        assert!(ranges[0].offset == 3 * GIB - MIB / 2, "{:?}", ranges);
+        assert!(ranges[0].len == MIB / 2, "{:?}", ranges);
+
+        let piece = d.file_offset_to_piece(1, 2 * GIB, MIB);
+        assert!(piece == Some((5120, MIB / 2)), "{:?}", piece);
+
+        // offsets past u64::MAX can not be mapped, but must not overflow
+        let mut d = Directory::new(path_abs());
+        for (i, &len) in [1 << 63, 1 << 62, 1 << 63].iter().enumerate() {
+            d.add_file(File::new(
+                name(),
+                path_abs().join(format!("file-{}", i)),
+                len,
+            ));
+        }
+        let piece = d.file_offset_to_piece(2, 0, 1 << 40);
+        assert!(piece == Some((3 << 22, 0)), "{:?}", piece);
+        assert!(d.file_offset_to_piece(2, 1 << 62, 1 << 40).is_none());
+        // nor can piece indexes past u32::MAX
+        assert!(d.file_offset_to_piece(1, 0, MIB).is_none());
+        assert!(d.map_piece((u64::MAX >> 40) as usize, 1 << 40).is_empty());
+        assert!(d.map_piece(1 << 23, 1 << 40) == vec![(1, 0, 1 << 40)]);
+    }
+
+    #[test]
+    fn verify_layout() {
+        let mut d = Directory::new(path_abs());
+        let file = |n: &[u8]| {
+            let n = sanitize_component(n).unwrap().into_string().unwrap();
+            File::new(n.clone(), path_abs().join(&n), LEN)
+        };
+        d.add_files(vec![file(b"a.ext"), file(b"b.ext"), file(b"c.ext")]);
+        assert!(d.verify_layout().is_ok());
+
+        // NUL is stripped from names everywhere but macOS
+        #[cfg(not(target_os = "macos"))]
+        {
+            d.add_file(file(b"a\0.ext"));
+            d.add_file(file(b"\0b.ext"));
+            d.add_file(file(b"a.e\0xt"));
+
+            let collisions = d.verify_layout().unwrap_err();
+            let names: Vec<_> = collisions
+                .iter()
+                .map(|(a, b)| (a.name(), b.path().to_path_buf()))
+                .collect();
+            let expect = vec![
+                ("a.ext", path_abs().join("a.ext")),
+                ("b.ext", path_abs().join("b.ext")),
+                ("a.ext", path_abs().join("a.ext")),
+            ];
+            assert!(names == expect, "{:?} == {:?}", names, expect);
+        }
+
+        let mut d = Directory::new(path_abs());
+        d.add_files(vec![file(b"a.ext"), file(b"A.EXT")]);
+        let case_insensitive = cfg!(any(windows, target_os = "macos"));
+        assert!(d.verify_layout().is_err() == case_insensitive);
+    }
+
+    #[test]
+    fn dedupe_paths() {
+        let mut d = Directory::new(path_abs());
+        for n in [
+            "a.ext",
+            "sub/a.ext",
+            "a.ext",
+            "a (1).ext",
+            "a.ext",
+            ".hidden",
+            ".hidden",
+        ] {
+            d.add_file(File::new(n.to_owned(), path_abs().join(n), LEN));
+        }
+
+        assert!(d.dedupe_paths() == 3);
+        assert!(d.verify_layout().is_ok());
+        let names: Vec<_> = d.iter().map(File::name).collect();
+        let expect = [
+            "a.ext",
+            "sub/a.ext",
+            "a (2).ext",
+            "a (1).ext",
+            "a (3).ext",
+            ".hidden",
+            ".hidden (1)",
+        ];
+        assert!(names == expect, "{:?} == {:?}", names, expect);
+        assert!(d.file(2).unwrap().path() == path_abs().join("a (2).ext"));
+        assert!(d.file(3).unwrap().path() == path_abs().join("a (1).ext"));
+
+        assert!(d.dedupe_paths() == 0);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn from_dict_collisions() {
+        // names which only differ by bytes removed while sanitizing
+        let entry = |name: &[u8]| {
+            let mut f = collections::HashMap::new();
+            f.insert(b"length".to_vec(), Benc::Int(1));
+            f.insert(
+                b"path".to_vec(),
+                Benc::List(vec![
+                    Benc::String(b"sub".to_vec()),
+                    Benc::String(name.to_vec()),
+                ]),
+            );
+            Benc::Dict(f)
+        };
+        let mut dict = collections::HashMap::new();
+        dict.insert(b"name".to_vec(), Benc::String(b"mock".to_vec()));
+        dict.insert(
+            b"files".to_vec(),
+            Benc::List(vec![entry(b"file.ext"), entry(b"file\0.ext")]),
+        );
+
+        let d = Directory::from_dict(&mut dict, None).unwrap();
+        let root = util::download_dir().join("mock").join("sub");
+        let paths: Vec<_> = d.iter().map(|f| f.path().to_path_buf()).collect();
+        let expect = vec![root.join("file.ext"), root.join("file (1).ext")];
+        assert!(paths == expect, "{:?} == {:?}", paths, expect);
+        assert!(d.file(1).unwrap().name() == "sub/file (1).ext");
+    }
+
+    #[test]
+    fn piece_ranges() {
+        let mut d = Directory::new(path_abs());
+        for (i, &len) in [3, 0, 10].iter().enumerate() {
+            d.add_file(File::new(
+                name(),
+                path_abs().join(format!("file-{}", i)),
+                len,
+            ));
+        }
+        let slice = |file_index, offset, len| FileSlice {
+            file_index,
+            offset,
+            len,
+        };
+
+        // straddles the empty file
+        let ranges = d.piece_ranges(8, 13, 0);
+        let expect = vec![slice(0, 0, 3), slice(2, 0, 5)];
+        assert!(ranges == expect, "{:?} == {:?}", ranges, expect);
+        // short last piece
+        let ranges = d.piece_ranges(8, 13, 1);
+        let expect = vec![slice(2, 5, 5)];
+        assert!(ranges == expect, "{:?} == {:?}", ranges, expect);
+        // out of range
+        assert!(d.piece_ranges(8, 13, 2).is_empty());
+        assert!(d.piece_ranges(8, 13, u32::MAX).is_empty());
+        assert!(d.piece_ranges(8, 0, 0).is_empty());
+        // `total_length` cuts the piece short even if the files are longer
+        let ranges = d.piece_ranges(8, 4, 0);
+        let expect = vec![slice(0, 0, 3), slice(2, 0, 1)];
+        assert!(ranges == expect, "{:?} == {:?}", ranges, expect);
+
+        for (file, offset, expect) in [
+            (0, 0, Some((0, 0))),
+            (0, 2, Some((0, 2))),
+            (2, 0, Some((0, 3))),
+            (2, 4, Some((0, 7))),
+            (2, 5, Some((1, 0))),
+            (2, 9, Some((1, 4))),
+            (0, 3, None),
+            (1, 0, None),
+            (2, 10, None),
+            (3, 0, None),
+        ] {
+            let result = d.file_offset_to_piece(file, offset, 8);
+            assert!(
+                result == expect,
+                "{}, {}: {:?} == {:?}",
+                file,
+                offset,
+                result,
+                expect
+            );
+        }
+        assert!(d.file_offset_to_piece(0, 0, 0).is_none());
+
+        // every byte maps back into the piece that covers it
+        for index in 0..2 {
+            for s in d.piece_ranges(8, 13, index) {
+                let (piece, _) = d.file_offset_to_piece(s.file_index, s.offset, 8).unwrap();
+                assert!(piece == index, "{:?}: {} == {}", s, piece, index);
+            }
+        }
+    }
+
+    #[test]
+    fn rename() {
+        let path = path_abs();
+        let mut d = Directory::new(path.join("old"));
+
+        if let Err(_) = d.rename("new") {
+            panic!("Error while renaming directory");
+        }
+
+        assert!(d.path() == path.join("new"));
+    }
+
+    #[test]
+    fn remove_file() {
+        let mut d = Directory::new(path_abs());
+        for name in ["a.ext", "sub/b.ext", "c.ext"] {
+            d.add_file(File::new(name.to_owned(), path_abs().join(name), LEN));
+        }
+
+        assert!(d.remove_file(3).is_none());
+        let f = d.remove_file(1).unwrap();
+        assert!(f.path() == path_abs().join("sub/b.ext"), "{:?}", f.path());
+        assert!(d.file(1).unwrap().path() == path_abs().join("c.ext"));
+
+        // full and relative paths
+        assert!(d.remove_by_path(&path_abs().join("c.ext")).is_some());
+        assert!(d.remove_by_path(path::Path::new("sub/b.ext")).is_none());
+        assert!(d.remove_by_path(path::Path::new("a.ext")).is_some());
+        assert!(d.is_empty());
+    }
+
+    #[test]
+    fn find_named() {
+        let mut d = Directory::new(path_abs());
+        for name in ["a.ext", "sub/b.ext"] {
+            d.add_file(File::new(name.to_owned(), path_abs().join(name), LEN));
+        }
+
+        assert!(d.find("sub/b.ext").unwrap().path() == path_abs().join("sub/b.ext"));
+        assert!(d.find("b.ext").is_none());
+        d.find_mut("a.ext").unwrap().set_priority(Priority::Skip);
+        assert!(d.file(0).unwrap().priority() == Priority::Skip);
+
+        let f = d.remove_named("a.ext").unwrap();
+        assert!(f.name() == "a.ext");
+        assert!(d.remove_named("a.ext").is_none());
+        assert!(d.remove_named("missing.ext").is_none());
+        assert!(d.len() == 1, "{} == 1", d.len());
+        assert!(d.find("a.ext").is_none());
+    }
+
+    #[test]
+    fn find_by() {
+        let root = env::temp_dir().join("find_by");
+        let _ = fs::remove_dir_all(&root);
+        let mut d = Directory::new(root.join("from"));
+        for name in ["a.ext", "sub/b.ext", "c.ext"] {
+            d.add_file(File::new(
+                name.to_owned(),
+                root.join("from").join(name),
+                LEN,
+            ));
+        }
+        // a duplicate name finds the first file
+        d.add_files(vec![File::new("a.ext".to_owned(), root.join("a.ext"), LEN)]);
+
+        let (idx, f) = d.find_by_name("sub/b.ext").unwrap();
+        assert!(idx == 1 && f.name() == "sub/b.ext", "{} {:?}", idx, f);
+        let (idx, f) = d.find_by_name("a.ext").unwrap();
+        assert!(
+            idx == 0 && f.path() == root.join("from/a.ext"),
+            "{} {:?}",
+            idx,
+            f
+        );
+        assert!(d.find_by_name("b.ext").is_none());
+        let idx = d.find_by_path(&root.join("a.ext")).map(|(i, _)| i);
+        assert!(idx == Some(3), "{:?} == Some(3)", idx);
+
+        // every path under the root changes
+        d.set_location(root.join("to")).unwrap();
+        assert!(d.find_by_path(&root.join("from/c.ext")).is_none());
+        for (i, name) in ["a.ext", "sub/b.ext", "c.ext"].iter().enumerate() {
+            let idx = d.find_by_path(&root.join("to").join(name)).map(|(i, _)| i);
+            assert!(idx == Some(i), "{:?} == Some({})", idx, i);
+        }
+
+        // later files move down
+        d.remove_file(0);
+        let idx = d.find_by_name("c.ext").map(|(i, _)| i);
+        assert!(idx == Some(1), "{:?} == Some(1)", idx);
+        let (idx, f) = d.find_by_name("a.ext").unwrap();
+        assert!(
+            idx == 2 && f.path() == root.join("a.ext"),
+            "{} {:?}",
+            idx,
+            f
+        );
+
+        // a file moved on its own is still found
+        let to = root.join("elsewhere.ext");
+        d.file_mut(0).unwrap().set_location(to.clone()).unwrap();
+        let idx = d.find_by_path(&to).map(|(i, _)| i);
+        assert!(idx == Some(0), "{:?} == Some(0)", idx);
+    }
+
+    #[test]
+    fn retain() {
+        let mut d = Directory::new(path_abs());
+        for i in 0..CAP {
+            let name = format!("file-{}.ext", i);
+            d.add_file(File::new(name.clone(), path_abs().join(name), i as u64));
+        }
+
+        d.retain(|f| f.length % 2 == 0);
+        let lengths: Vec<_> = d.iter().map(|f| f.length).collect();
+        assert!(lengths == [0, 2, 4, 6], "{:?}", lengths);
+    }
+
+    #[test]
+    fn remove_file_data() {
+        let root = env::temp_dir().join("remove_file_data");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join("c")).unwrap();
+
+        let mut d = Directory::new(root.clone());
+        for name in ["a/b/1.ext", "a/2.ext", "c/3.ext"] {
+            fs::write(root.join(name), b"mock").unwrap();
+            d.add_file(File::new(name.to_owned(), root.join(name), 4));
+        }
+        // never created, so there is nothing to delete
+        d.add_file(File::new("4.ext".to_owned(), root.join("4.ext"), 4));
+
+        // "a" still holds 2.ext
+        d.remove_file_with(0, true).unwrap();
+        assert!(!root.join("a/b").exists());
+        assert!(root.join("a/2.ext").exists());
+
+        d.retain_with(|f| f.path().ends_with("3.ext"), true)
+            .unwrap();
+        assert!(!root.join("a").exists());
+        assert!(root.join("c/3.ext").exists());
+
+        // the last file goes, but the root stays
+        d.remove_file_with(0, true).unwrap();
+        assert!(d.is_empty());
+        assert!(root.exists());
+        assert!(fs::read_dir(&root).unwrap().next().is_none());
+
+        // without `delete_data` nothing is touched
+        fs::write(root.join("5.ext"), b"mock").unwrap();
+        d.add_file(File::new("5.ext".to_owned(), root.join("5.ext"), 4));
+        d.remove_file_with(0, false).unwrap();
+        assert!(root.join("5.ext").exists());
+
+        // nor is a file stored outside the root
+        let outside = env::temp_dir().join("remove_file_data.ext");
+        fs::write(&outside, b"mock").unwrap();
+        d.add_file(File::new("6.ext".to_owned(), outside.clone(), 4));
+        let err = d.remove_file_with(0, true).unwrap_err();
+        assert!(err.kind() == io::ErrorKind::InvalidInput, "{:?}", err);
+        assert!(d.is_empty());
+        assert!(outside.exists());
+        fs::remove_file(&outside).unwrap();
+    }
+
+    #[test]
+    fn set_location() {
+        let tmp = env::temp_dir().join("root");
+        // Default `path` for `dir`
+        let path = tmp.join("default");
+        // Path to place files not owned by `dir`
+        let other = tmp.join("somewhere_else");
+        // Path to move files to
+        let moved = env::temp_dir().join("moved");
+
+        let mut dir = Directory::new(path.clone());
+
+        for i in 0..CAP as u64 {
+            let name = format!("file-{}.ext", i);
+            let fpath = if i % 2 == 0 { &path } else { &other }.join(name.clone());
+
+            dir.add_file(File::new(name, fpath, i * i));
+        }
+
+        if let Err(e) = dir.set_location(moved.clone()) {
+            panic!("Failed to moved files. {:?}", e);
+        }
+
+        // Check if only files under `root` were moved to `moved`
+        for (i, f) in dir.iter().enumerate() {
+            let p = if i % 2 == 0 { &moved } else { &other };
+
+            assert!(f.path().parent() == Some(p));
+            assert!(f.path().file_name().unwrap() == ffi::OsStr::new(&format!("file-{}.ext", i)));
+        }
+    }
+
+    #[test]
+    fn move_errors() {
+        let a = File::new("a.ext".to_owned(), path_abs().join("a.ext"), 1);
+        let b = File::new("b.ext".to_owned(), path_abs().join("b.ext"), 2);
+        let failure = |index, f: &File, error| MoveFailure {
+            index,
+            name: f.name().to_owned(),
+            path: f.path().to_path_buf(),
+            to: env::temp_dir().join(f.name()),
+            mode: RelocateMode::Move,
+            error,
+        };
+        let errs = vec![
+            failure(
+                0,
+                &a,
+                io::Error::new(io::ErrorKind::NotFound, "no such file"),
+            ),
+            failure(
+                1,
+                &b,
+                io::Error::new(io::ErrorKind::PermissionDenied, "permission denied"),
+            ),
+        ];
+
+        // the error does not borrow the files, so it can be boxed as `'static`
+        let err: Box<dyn std::error::Error + Send + Sync + 'static> = Box::new(MvError::from(errs));
+        let msg = err.to_string();
+        for f in &[&a, &b] {
+            assert!(msg.contains(&*f.path().to_string_lossy()), "{}", msg);
+        }
+
+        let errs = vec![failure(
+            0,
+            &a,
+            io::Error::new(io::ErrorKind::NotFound, "no such file"),
+        )];
+        let err = error::Error::from(MvError::MoveErrors(errs));
+        let expect = error::Error::Move {
+            failures: vec![(
+                a.path().to_path_buf(),
+                io::Error::from(io::ErrorKind::NotFound),
+            )],
+        };
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+    }
+
+    #[test]
+    fn retry_failed() {
+        let root = env::temp_dir().join("retry_failed");
+        let (from, to) = (root.join("from"), root.join("to"));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(from.join("sub")).unwrap();
+        // a file is in the way of the folder "sub/b.ext" goes in
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("sub"), b"").unwrap();
+
+        let mut d = Directory::new(from.clone());
+        for name in ["a.ext", "sub/b.ext"] {
+            fs::write(from.join(name), b"mock").unwrap();
+            let mut f = File::new(name.to_owned(), from.join(name), 4);
+            f.status = Status::Done;
+            d.add_file(f);
+        }
+
+        let failures = match d.set_location(to.clone()) {
+            Err(MvError::MoveErrors(failures)) => failures,
+            r => panic!("{:?}", r),
+        };
+        assert!(failures.len() == 1, "{:?}", failures);
+        let failure = &failures[0];
+        assert!(
+            failure.index == 1 && failure.name == "sub/b.ext",
+            "{:?}",
+            failure
+        );
+        assert!(failure.path == from.join("sub/b.ext"), "{:?}", failure);
+        assert!(failure.to == to.join("sub/b.ext"), "{:?}", failure);
+        // the file is left as it was, while the directory can still be changed
+        let f = d.file(1).unwrap();
+        assert!(
+            f.path() == from.join("sub/b.ext") && f.status == Status::Done,
+            "{:?}",
+            f
+        );
+        d.file_mut(0).unwrap().set_priority(Priority::High);
+
+        fs::remove_file(to.join("sub")).unwrap();
+        d.retry_failed(&failures).unwrap();
+        assert!(fs::read(to.join("sub/b.ext")).unwrap() == b"mock");
+        let idx = d.find_by_path(&to.join("sub/b.ext")).map(|(i, _)| i);
+        assert!(idx == Some(1), "{:?} == Some(1)", idx);
+
+        // the file has moved since, so is not moved again
+        d.retry_failed(&failures).unwrap();
+        assert!(d.file(1).unwrap().path() == to.join("sub/b.ext"));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn aggregate_status() {
+        let dir = |status: Vec<Status>| {
+            let mut d = Directory::new(path_abs());
+            for (i, s) in status.into_iter().enumerate() {
+                let mut f = File::new(i.to_string(), path_abs().join(i.to_string()), LEN);
+                f.status = s;
+                d.add_file(f);
+            }
+            d
+        };
+
+        let missing = Status::Missing(Some(path_abs()));
+        let other = Status::Other(Some("md5 mismatch".to_owned()));
+        for (status, expect) in vec![
+            (vec![], Status::NotCreated),
+            (vec![Status::Skip, Status::Skip], Status::Skip),
+            (vec![Status::NotCreated, Status::Skip], Status::NotCreated),
+            (
+                vec![Status::Done, Status::Seeding, Status::Skip],
+                Status::Seeding,
+            ),
+            (vec![Status::Done, Status::Done], Status::Seeding),
+            (vec![Status::Done, Status::Downloading], Status::Downloading),
+            (vec![Status::Done, Status::NotCreated], Status::Stopped),
+            (vec![Status::Stopped, Status::Seeding], Status::Stopped),
+            (vec![Status::Downloading, missing.clone()], missing.clone()),
+            (vec![Status::Seeding, other.clone(), missing], other),
+        ] {
+            let d = dir(status.clone());
+            let result = d.aggregate_status();
+            assert!(
+                result == expect,
+                "{:?}: {:?} == {:?}",
+                status,
+                result,
+                expect
+            );
+        }
+
+        let mut d = dir(vec![Status::Downloading, Status::Done]);
+        d.files[0].mark_done();
+        assert!(d.aggregate_status() == Status::Seeding);
+        d.files[1].mark_seeding();
+        assert!(d.files[1].status == Status::Seeding);
+        assert!(d.aggregate_status() == Status::Seeding);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn priority() {
+        let mut d = Directory::new(path_abs());
+        for n in &["movie.nfo", "movie.mkv", "extras.mkv", "sample.txt"] {
+            d.add_file(File::new(n.to_string(), path_abs().join(n), LEN));
+        }
+        let priorities = |d: &Directory| d.iter().map(File::priority).collect::<Vec<_>>();
+        assert!(priorities(&d) == [Priority::Normal; 4]);
+
+        let n = d.set_priority_by(|f| f.name().ends_with(".mkv"), Priority::Low);
+        assert!(n == 2, "{} == 2", n);
+        d.set_priority_by(|f| f.name().starts_with("movie"), Priority::High);
+        d.set_priority_by(|f| f.name().ends_with(".txt"), Priority::Skip);
+        let expect = [
+            Priority::High,
+            Priority::High,
+            Priority::Low,
+            Priority::Skip,
+        ];
+        assert!(
+            priorities(&d) == expect,
+            "{:?} == {:?}",
+            priorities(&d),
+            expect
+        );
+
+        // skipped files do not count towards the directory's status
+        d.files[3].status = Status::Downloading;
+        for f in &mut d.files[..3] {
+            f.mark_done();
+        }
+        assert!(d.aggregate_status() == Status::Seeding);
+
+        // the old status still skips a file, whatever its priority
+        d.files[2].status = Status::Skip;
+        assert!(d.files[2].priority() == Priority::Skip);
+        d.files[2].status = Status::NotCreated;
+        assert!(d.files[2].priority() == Priority::Low);
+    }
+
+    #[test]
+    fn progress() {
+        let mut d = Directory::new(path_abs());
+        assert!(d.progress() == 1.0);
+
+        for n in &["a.ext", "b.ext", "c.ext"] {
+            d.add_file(File::new(n.to_string(), path_abs().join(n), LEN));
+        }
+        d.files[0].mark_range_complete(0, LEN);
+        d.files[1].mark_range_complete(0, LEN / 2);
+        assert!(d.progress() == 0.5, "{} == 0.5", d.progress());
+
+        // a skipped file is not part of the total
+        d.files[2].set_priority(Priority::Skip);
+        assert!(d.progress() == 0.75, "{} == 0.75", d.progress());
+        d.files[2].set_priority(Priority::Normal);
+        d.files[2].mark_range_complete(0, LEN / 2);
+        assert!(d.progress() == 2.0 / 3.0, "{} == 2/3", d.progress());
+
+        // lengths too large to add up do not overflow
+        let mut d = Directory::new(path_abs());
+        for n in &["a.ext", "b.ext"] {
+            d.add_file(File::new(n.to_string(), path_abs().join(n), u64::MAX));
+        }
+        assert!(d.progress() == 0.0, "{} == 0", d.progress());
+    }
+
+    #[test]
+    fn sync_all() {
+        let dir = env::temp_dir().join("sync_all");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut d = Directory::new(dir.clone());
+        for n in &["a.ext", "b.ext", "c.ext"] {
+            fs::write(dir.join(n), b"abcd").unwrap();
+            d.add_file(File::new(n.to_string(), dir.join(n), 4));
+        }
+        for f in &mut d {
+            f.mark_done();
+        }
+        assert!(d.sync_all().unwrap().is_empty());
+
+        fs::remove_file(dir.join("b.ext")).unwrap();
+        fs::write(dir.join("c.ext"), b"ab").unwrap();
+        let changes = d.sync_all().unwrap();
+        let expect = vec![
+            StatusChange {
+                file_index: 1,
+                old: Status::Done,
+                new: Status::Missing(Some(dir.join("b.ext"))),
+            },
+            StatusChange {
+                file_index: 2,
+                old: Status::Done,
+                new: Status::Downloading,
+            },
+        ];
+        assert!(changes == expect, "{:?} == {:?}", changes, expect);
+        assert!(d.sync_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn verify() {
+        use sha1::{Digest, Sha1};
+
+        let dir = env::temp_dir().join("verify");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..50).collect();
+        let pieces: Vec<[u8; 20]> = data.chunks(16).map(|p| Sha1::digest(p).into()).collect();
+        fs::write(dir.join("a.ext"), &data[..20]).unwrap();
+        fs::write(dir.join("b.ext"), &data[20..]).unwrap();
+
+        let mut d = Directory::new(dir.clone());
+        d.add_file(File::new("a.ext".to_owned(), dir.join("a.ext"), 20));
+        d.add_file(File::new("b.ext".to_owned(), dir.join("b.ext"), 30));
+        let status = |d: &Directory| d.iter().map(|f| f.status.clone()).collect::<Vec<_>>();
+
+        assert!(d.verify(&pieces, 16).unwrap() == [true; 4]);
+        assert!(
+            status(&d) == [Status::Done, Status::Done],
+            "{:?}",
+            status(&d)
+        );
+        assert!(d.progress() == 1.0);
+
+        // byte 25 of the torrent is in the second piece
+        let mut corrupt = data[20..].to_vec();
+        corrupt[5] ^= 0xff;
+        fs::write(dir.join("b.ext"), &corrupt).unwrap();
+        let passed = d.verify(&pieces, 16).unwrap();
+        assert!(passed == [true, false, true, true], "{:?}", passed);
+        let expect = [Status::Downloading, Status::Downloading];
+        assert!(status(&d) == expect, "{:?}", status(&d));
+        assert!(d.file(0).unwrap().completed() == 16);
+        assert!(d.file(1).unwrap().completed() == 18);
+
+        // missing and short files fail their pieces
+        fs::remove_file(dir.join("b.ext")).unwrap();
+        let passed = d.verify(&pieces, 16).unwrap();
+        assert!(passed == [true, false, false, false], "{:?}", passed);
+        fs::write(dir.join("a.ext"), &data[..10]).unwrap();
+        assert!(d.verify(&pieces, 16).unwrap() == [false; 4]);
+        assert!(status(&d) == expect, "{:?}", status(&d));
+
+        assert!(d.verify(&pieces[..3], 16).is_err());
+        assert!(d.verify(&pieces, 0).is_err());
+    }
+
+    /// A torrent of `files`, given as their paths, lengths and attributes, and the pieces of
+    /// `data`
+    fn torrent(files: &[(&[&str], usize, &str)], data: &[u8], piece_length: usize) -> Torrent {
+        use sha1::{Digest, Sha1};
+
+        let text = |s: &str| Benc::String(s.as_bytes().to_vec());
+        let files = files
+            .iter()
+            .map(|&(parts, len, attr)| {
+                let mut f = collections::HashMap::new();
+                f.insert(b"length".to_vec(), Benc::Int(len as i64));
+                f.insert(
+                    b"path".to_vec(),
+                    Benc::List(parts.iter().map(|p| text(p)).collect()),
+                );
+                f.insert(b"attr".to_vec(), text(attr));
+                Benc::Dict(f)
+            })
+            .collect();
+        let pieces = data.chunks(piece_length).flat_map(Sha1::digest).collect();
+
+        let mut info = collections::HashMap::new();
+        info.insert(b"name".to_vec(), text("torrent"));
+        info.insert(b"piece length".to_vec(), Benc::Int(piece_length as i64));
+        info.insert(b"pieces".to_vec(), Benc::String(pieces));
+        info.insert(b"files".to_vec(), Benc::List(files));
+        let mut t = collections::HashMap::new();
+        t.insert(
+            b"announce".to_vec(),
+            text("http://tracker.example/announce"),
+        );
+        t.insert(b"info".to_vec(), Benc::Dict(info));
+        Torrent::from_benc(Benc::Dict(t)).unwrap()
+    }
+
+    #[test]
+    fn verify_all() {
+        let dir = env::temp_dir().join("verify_all");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // pieces of 16 bytes cross from one file into the next, and d.ext is the last piece
+        let data: Vec<u8> = (0..64).collect();
+        let names = ["a.ext", "b.ext", "c.ext", "d.ext"];
+        let bounds = [0, 10, 30, 48, 64];
+        let files: Vec<(&[&str], usize, &str)> = (0..names.len())
+            .map(|i| (&names[i..=i], bounds[i + 1] - bounds[i], ""))
+            .collect();
+        let t = torrent(&files, &data, 16);
+
+        let mut d = Directory::new(dir.clone());
+        for (i, name) in names.iter().enumerate() {
+            let part = &data[bounds[i]..bounds[i + 1]];
+            fs::write(dir.join(name), part).unwrap();
+            d.add_file(File::new(
+                name.to_string(),
+                dir.join(name),
+                part.len() as u64,
+            ));
+        }
+        let all = |r: VerifyResult| (0..names.len()).map(|i| (i, r)).collect::<Vec<_>>();
+
+        let report = d.verify_all(t.info()).unwrap();
+        assert!(report == all(VerifyResult::Complete), "{:?}", report);
+        assert!(d.iter().all(|f| f.status == Status::Done));
+
+        // byte 35 is in the last piece of c.ext
+        let mut corrupt = data[30..48].to_vec();
+        corrupt[5] ^= 0xff;
+        fs::write(dir.join("c.ext"), &corrupt).unwrap();
+        // an interrupted download of d.ext
+        fs::write(dir.join("d.ext"), &data[48..54]).unwrap();
+        fs::remove_file(dir.join("a.ext")).unwrap();
+
+        // b.ext is intact, but its first piece can not be checked without a.ext
+        let report = d.verify_all(t.info()).unwrap();
+        let expect = vec![
+            (0, VerifyResult::Missing),
+            (1, VerifyResult::Partial),
+            (2, VerifyResult::Corrupt),
+            (3, VerifyResult::Partial),
+        ];
+        assert!(report == expect, "{:?} == {:?}", report, expect);
+        let status: Vec<_> = d.iter().map(|f| f.status.clone()).collect();
+        let expect = [
+            Status::Missing(Some(dir.join("a.ext"))),
+            Status::Downloading,
+            Status::Downloading,
+            Status::Downloading,
+        ];
+        assert!(status == expect, "{:?} == {:?}", status, expect);
+
+        // checking again gives the same report
+        let again = d.verify_all(t.info()).unwrap();
+        assert!(again == report, "{:?} == {:?}", again, report);
+
+        // a file allocated to its full length but not yet written is not corrupt
+        fs::write(dir.join("d.ext"), [0; 16]).unwrap();
+        let report = d.verify_all(t.info()).unwrap();
+        assert!(report[3] == (3, VerifyResult::Partial), "{:?}", report);
+
+        // but a piece which is only partly zeros is
+        let mut part = [0; 16];
+        part[15] = 1;
+        fs::write(dir.join("d.ext"), part).unwrap();
+        let report = d.verify_all(t.info()).unwrap();
+        assert!(report[3] == (3, VerifyResult::Corrupt), "{:?}", report);
+    }
+
+    #[test]
+    fn verify_padding() {
+        use sha1::{Digest, Sha1};
+
+        let dir = env::temp_dir().join("verify_padding");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // the padding file ends the first piece, and is never written to disk
+        let data = [1, 2, 3, 4, 5, 0, 0, 0, 6, 7, 8, 9];
+        let pieces: Vec<[u8; 20]> = data.chunks(8).map(|p| Sha1::digest(p).into()).collect();
+        fs::write(dir.join("a.ext"), &data[..5]).unwrap();
+        fs::write(dir.join("b.ext"), &data[8..]).unwrap();
+
+        let mut d = Directory::new(dir.clone());
+        for (name, len) in [("a.ext", 5), (".pad/3", 3), ("b.ext", 4)] {
+            let mut f = File::new(name.to_owned(), dir.join(name), len);
+            if name.starts_with(".pad") {
+                f.attrs = FileAttrs::PADDING;
+                f.priority = Priority::Skip;
+            }
+            d.add_file(f);
+        }
+
+        let passed = d.verify(&pieces, 8).unwrap();
+        assert!(passed == [true, true], "{:?}", passed);
+        let t = torrent(
+            &[
+                (&["a.ext"], 5, ""),
+                (&[".pad", "3"], 3, "p"),
+                (&["b.ext"], 4, ""),
+            ],
+            &data,
+            8,
+        );
+        let report = d.verify_all(t.info()).unwrap();
+        let expect = vec![(0, VerifyResult::Complete), (2, VerifyResult::Complete)];
+        assert!(report == expect, "{:?} == {:?}", report, expect);
+        assert!(!dir.join(".pad").exists());
+    }
+
+    #[test]
+    fn allocate_all() {
+        let dir = env::temp_dir().join("allocate_all");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // a file can not be created under another file
+        fs::write(dir.join("file"), b"").unwrap();
+
+        let mut d = Directory::new(dir.clone());
+        d.add_files(vec![
+            File::new("a.ext".to_owned(), dir.join("a.ext"), 4),
+            File::new("b.ext".to_owned(), dir.join("file").join("b.ext"), 4),
+            File::new("c.ext".to_owned(), dir.join("c").join("c.ext"), 0),
+        ]);
+
+        match d.allocate_all(AllocationMode::Full).unwrap_err() {
+            error::Error::Allocate { ref failures } => {
+                assert!(failures.len() == 1, "{:?}", failures);
+                assert!(failures[0].0 == dir.join("file").join("b.ext"));
+            }
+            e => panic!("Expected allocate error, got {:?}", e),
+        }
+
+        let status: Vec<_> = d.iter().map(|f| f.status.clone()).collect();
+        let expect = vec![Status::Stopped, Status::NotCreated, Status::Stopped];
+        assert!(status == expect, "{:?} == {:?}", status, expect);
+        assert!(fs::metadata(dir.join("a.ext")).unwrap().len() == 4);
+        assert!(fs::metadata(dir.join("c").join("c.ext")).unwrap().len() == 0);
+    }
+
+    /// The real filesystem, except every path is on another device with `available` bytes free
+    struct FullDisk {
+        available: u64,
+    }
+
+    impl FileSystem for FullDisk {
+        fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            fs::rename(from, to)
+        }
+
+        fn copy(&self, from: &path::Path, to: &path::Path) -> io::Result<u64> {
+            fs::copy(from, to)
+        }
+
+        fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+            fs::remove_file(path)
+        }
+
+        fn hard_link(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            fs::hard_link(from, to)
+        }
+
+        fn reflink(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            super::reflink(from, to)
+        }
+
+        fn available_space(&self, _: &path::Path) -> io::Result<u64> {
+            Ok(self.available)
+        }
+
+        fn same_device(&self, _: &path::Path, _: &path::Path) -> bool {
+            false
+        }
+    }
+
+    /// The real filesystem, except the `fail_at`th rename or copy fails
+    struct FailAt {
+        fail_at: usize,
+        calls: std::cell::Cell<usize>,
+        same_device: bool,
+    }
+
+    impl FailAt {
+        fn call(&self) -> io::Result<()> {
+            self.calls.set(self.calls.get() + 1);
+            match self.calls.get() == self.fail_at {
+                true => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl FileSystem for FailAt {
+        fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            self.call()?;
+            fs::rename(from, to)
+        }
+
+        fn copy(&self, from: &path::Path, to: &path::Path) -> io::Result<u64> {
+            self.call()?;
+            fs::copy(from, to)
+        }
+
+        fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+            fs::remove_file(path)
+        }
+
+        fn hard_link(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            fs::hard_link(from, to)
+        }
+
+        fn reflink(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            super::reflink(from, to)
+        }
+
+        fn available_space(&self, _: &path::Path) -> io::Result<u64> {
+            Ok(u64::MAX)
+        }
+
+        fn same_device(&self, _: &path::Path, _: &path::Path) -> bool {
+            self.same_device
+        }
+    }
+
+    #[test]
+    fn set_location_atomic() {
+        let root = env::temp_dir().join("set_location_atomic");
+        let (from, to) = (root.join("from"), root.join("to"));
+        let names = ["1.ext", "sub/2.ext", "3.ext", "sub/4.ext", "5.ext"];
+        let setup = || {
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(from.join("sub")).unwrap();
+            let mut d = Directory::new(from.clone());
+            for name in &names {
+                fs::write(from.join(name), name.as_bytes()).unwrap();
+                let mut f = File::new(name.to_string(), from.join(name), name.len() as u64);
+                f.status = Status::Done;
+                d.add_file(f);
+            }
+            d
+        };
+
+        for same_device in [true, false] {
+            let mut d = setup();
+            let fs = FailAt {
+                fail_at: 3,
+                calls: std::cell::Cell::new(0),
+                same_device,
+            };
+            let failures = match d
+                .set_location_atomic_in(&fs, to.clone(), &mut |_| ControlFlow::Continue(()))
+            {
+                Err(MvError::MoveErrors(failures)) => failures,
+                r => panic!("{:?}", r),
+            };
+            assert!(failures.len() == 1, "{:?}", failures);
+            assert!(failures[0].index == 2, "{:?}", failures);
+
+            // every file is back where it started, and nothing is left at the new location
+            assert!(d.path() == from, "{:?}", d.path());
+            for (f, name) in d.iter().zip(&names) {
+                assert!(f.path() == from.join(name), "{:?}", f);
+                assert!(f.status == Status::Done, "{:?}", f);
+                assert!(fs::read(f.path()).unwrap() == name.as_bytes(), "{:?}", f);
+            }
+            assert!(!to.exists(), "{:?}", to);
+        }
+
+        // a file already at the new location stops the move before anything is touched
+        let mut d = setup();
+        fs::create_dir_all(to.join("sub")).unwrap();
+        fs::write(to.join("sub/4.ext"), b"keep").unwrap();
+        let mut phases = Vec::new();
+        let failures = match d.set_location_progress(to.clone(), |p| {
+            phases.push(p.phase);
+            ControlFlow::Continue(())
+        }) {
+            Err(MvError::MoveErrors(failures)) => failures,
+            r => panic!("{:?}", r),
+        };
+        assert!(
+            failures.len() == 1 && failures[0].index == 3,
+            "{:?}",
+            failures
+        );
+        let kind = failures[0].error.kind();
+        assert!(kind == io::ErrorKind::AlreadyExists, "{:?}", kind);
+        assert!(
+            phases.iter().all(|p| *p == RelocatePhase::Planning),
+            "{:?}",
+            phases
+        );
+        assert!(fs::read(to.join("sub/4.ext")).unwrap() == b"keep");
+        assert!(!to.join("1.ext").exists());
+        assert!(d.path() == from, "{:?}", d.path());
+        for (f, name) in d.iter().zip(&names) {
+            assert!(fs::read(f.path()).unwrap() == name.as_bytes(), "{:?}", f);
         }
+        fs::remove_dir_all(&to).unwrap();
+
+        d.add_file(File::new("6.ext".to_owned(), from.join("6.ext"), 1));
+        d.set_location_atomic(to.clone()).unwrap();
+        assert!(d.path() == to && !from.exists());
+        for (f, name) in d.iter().zip(&names) {
+            assert!(
+                fs::read(to.join(name)).unwrap() == name.as_bytes(),
+                "{:?}",
+                f
+            );
+        }
+        assert!(d.file(5).unwrap().path() == to.join("6.ext"));
     }
 
-    /// Create a new `Directory` from a HashMap. The HashMap must contain a "name" key and "files"
-    /// list which should match `Files::from_dict()` requirements
-    pub fn from_dict(dict: &mut collections::HashMap<Vec<u8>, Benc>) -> Option<Directory> {
-        let mut path = util::download_dir().unwrap_or_else(env::temp_dir);
-        for p in unwrap_opt!(Benc::String, dict.remove(&b"name"[..]))
-            .split(|&c| c == b'/')
-            .filter(|&p| p == b".." || p == b".")
-        {
-            if let Ok(s) = ::std::str::from_utf8(&util::sanitize_path(p)) {
-                path.push(s);
+    #[test]
+    fn set_location_progress() {
+        use super::RelocatePhase::*;
+
+        let root = env::temp_dir().join("set_location_progress");
+        let (from, to) = (root.join("from"), root.join("to"));
+        let names = ["1.ext", "sub/2.ext", "3.ext"];
+        let setup = || {
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(from.join("sub")).unwrap();
+            let mut d = Directory::new(from.clone());
+            for name in &names {
+                fs::write(from.join(name), name.as_bytes()).unwrap();
+                let mut f = File::new(name.to_string(), from.join(name), name.len() as u64);
+                f.status = Status::Done;
+                d.add_file(f);
             }
-        }
+            d
+        };
 
-        let fs = unwrap_opt!(Benc::List, dict.remove(&b"files"[..]));
-        let mut files = Vec::with_capacity(fs.len());
+        for (same_device, phase) in [(true, Moving), (false, Copying)] {
+            let mut d = setup();
+            let fs = FailAt {
+                fail_at: 0,
+                calls: std::cell::Cell::new(0),
+                same_device,
+            };
+            let mut events = Vec::new();
+            d.set_location_atomic_in(&fs, to.clone(), &mut |p| {
+                assert!(p.name == names[p.index], "{:?}", p);
+                events.push((p.index, p.phase, p.bytes_done));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
 
-        for f in fs {
-            let mut f = unwrap!(Benc::Dict, f);
-            files.push(unwrap!(Some, File::from_dict(&mut f)));
+            let mut expected = vec![(0, Planning, 0), (1, Planning, 0), (2, Planning, 0)];
+            for (i, name) in names.iter().enumerate() {
+                let len = name.len() as u64;
+                expected.push((i, phase, 0));
+                // the default `copy_progress` reports once the file is copied
+                if phase == Copying {
+                    expected.push((i, Copying, len));
+                }
+                expected.push((i, Done, len));
+            }
+            assert!(events == expected, "{:?} == {:?}", events, expected);
+            assert!(d.path() == to && !from.exists());
         }
 
-        Some(Directory {
-            path,
-            status: Status::NotCreated,
-            files,
-        })
+        // cancelling once the first file is moved puts it back before the others are touched
+        let mut d = setup();
+        let mut events = Vec::new();
+        let r = d.set_location_progress(to.clone(), |p| {
+            events.push((p.index, p.phase));
+            match p.phase {
+                Done => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        match r {
+            Err(MvError::Cancelled(ref errs)) if errs.is_empty() => (),
+            r => panic!("{:?}", r),
+        }
+        let expected = vec![
+            (0, Planning),
+            (1, Planning),
+            (2, Planning),
+            (0, Moving),
+            (0, Done),
+        ];
+        assert!(events == expected, "{:?} == {:?}", events, expected);
+        assert!(d.path() == from, "{:?}", d.path());
+        for (f, name) in d.iter().zip(&names) {
+            assert!(f.path() == from.join(name), "{:?}", f);
+            assert!(fs::read(f.path()).unwrap() == name.as_bytes(), "{:?}", f);
+        }
+        assert!(!to.exists(), "{:?}", to);
     }
 
-    /// Add a `File` to be managed by the `Directory`. See `add_files` for more details.
-    pub fn add_file(&mut self, file: File) {
-        self.files.push(file)
-    }
+    #[test]
+    fn copy_progress() {
+        let root = env::temp_dir().join("copy_progress");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let (from, to) = (root.join("from.ext"), root.join("to.ext"));
+        let len = PROGRESS_INTERVAL * 5 / 2;
+        fs::write(&from, vec![7; len as usize]).unwrap();
 
-    /// Move `files` to be owned by the `Directory`. Location of Files will not be changed.
-    pub fn add_files(&mut self, files: Vec<File>) {
-        self.files.extend(files.into_iter());
+        let mut reports = Vec::new();
+        let copied = StdFs
+            .copy_progress(&from, &to, &mut |n| {
+                reports.push(n);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert!(copied == len, "{} == {}", copied, len);
+        let expected = vec![PROGRESS_INTERVAL, PROGRESS_INTERVAL * 2, len];
+        assert!(reports == expected, "{:?} == {:?}", reports, expected);
+        assert!(fs::read(&to).unwrap() == fs::read(&from).unwrap());
+
+        fs::remove_file(&to).unwrap();
+        let e = StdFs
+            .copy_progress(&from, &to, &mut |_| ControlFlow::Break(()))
+            .unwrap_err();
+        assert!(e.kind() == io::ErrorKind::Interrupted, "{:?}", e);
     }
 
-    /// Renames root folder
-    /// From: /path/to/original/file.ext
-    /// To:   /path/to/changed/file.ext
-    pub fn rename<P>(&mut self, p: P) -> Result<(), MvError<'_>>
-    where
-        P: convert::AsRef<ffi::OsStr>,
-    {
-        let dir = (&self.path).with_file_name(p);
-        self.set_location(dir)
+    /// Lay out a download of two files under `root/from`, one of which was never created
+    fn relocate_fixture(root: &path::Path) -> Directory {
+        let from = root.join("from");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join("a.ext"), b"hello, world").unwrap();
+
+        let mut d = Directory::new(from.clone());
+        let mut a = File::new("a.ext".to_owned(), from.join("a.ext"), 12);
+        a.status = Status::Done;
+        d.add_file(a);
+        d.add_file(File::new("b.ext".to_owned(), from.join("b.ext"), 4));
+        d
     }
 
-    /// Move all files under `self.path` to `dir`. `dir` must be an absolute path. Errors while
-    /// moving files are accumulated and returned as `MvError::MoveErrors`. Status of files in
-    /// `MvError::MoveErrors` are independent from the error.
-    pub fn set_location(&mut self, dir: path::PathBuf) -> Result<(), MvError<'_>> {
-        if !dir.is_absolute() {
-            return Err(MvError::Io(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Must be an absolute path",
-            )));
+    #[test]
+    fn set_location_copy() {
+        let root = env::temp_dir().join("set_location_copy");
+        for mode in [RelocateMode::Copy, RelocateMode::Reflink] {
+            let mut d = relocate_fixture(&root);
+            let to = root.join("to");
+
+            d.set_location_with(to.clone(), mode).unwrap();
+            assert!(d.path() == to, "{:?} == {:?}", d.path(), to);
+            assert!(fs::read(to.join("a.ext")).unwrap() == b"hello, world");
+            // the original is left in place
+            assert!(fs::read(root.join("from").join("a.ext")).unwrap() == b"hello, world");
+            // a file which was never created only has its path changed
+            assert!(d.file(1).unwrap().path() == to.join("b.ext"));
+            assert!(!to.join("b.ext").exists());
+
+            // like hard links, an existing file is neither replaced nor removed
+            let mut d = relocate_fixture(&root);
+            fs::create_dir_all(&to).unwrap();
+            fs::write(to.join("a.ext"), b"mock").unwrap();
+            match d.set_location_with(to.clone(), mode).unwrap_err() {
+                MvError::MoveErrors(ref errs) => {
+                    let kind = errs[0].error.kind();
+                    assert!(kind == io::ErrorKind::AlreadyExists, "{:?}", kind);
+                }
+                e => panic!("{:?}", e),
+            }
+            assert!(fs::read(to.join("a.ext")).unwrap() == b"mock");
+            assert!(d.file(0).unwrap().path() == root.join("from").join("a.ext"));
         }
 
-        if dir == self.path {
-            return Ok(());
+        let (from, to) = (
+            root.join("from").join("a.ext"),
+            root.join("to").join("a.ext"),
+        );
+        let e = StdFs.copy(&from, &to).unwrap_err();
+        assert!(e.kind() == io::ErrorKind::AlreadyExists, "{:?}", e);
+        let e = StdFs
+            .copy_progress(&from, &to, &mut |_| ControlFlow::Continue(()))
+            .unwrap_err();
+        assert!(e.kind() == io::ErrorKind::AlreadyExists, "{:?}", e);
+        #[cfg(target_os = "linux")]
+        {
+            let e = super::reflink(&from, &to).unwrap_err();
+            assert!(e.kind() == io::ErrorKind::AlreadyExists, "{:?}", e);
         }
+        assert!(fs::read(&to).unwrap() == b"mock");
+    }
 
-        try!(fs::create_dir_all(&dir));
-        let mut errs = Vec::new();
+    #[test]
+    #[cfg(unix)]
+    fn set_location_hard_link() {
+        use std::os::unix::fs::MetadataExt;
 
-        let path_len = match self.path.to_str() {
-            Some(p) => p.len() + 1,
-            None => {
-                return Err(MvError::Io(io::Error::new(
-                    io::ErrorKind::Other,
-                    "`self.path` is not a valid string",
-                )))
-            }
-        };
+        let root = env::temp_dir().join("set_location_hard_link");
+        let mut d = relocate_fixture(&root);
+        let (from, to) = (root.join("from"), root.join("to"));
 
-        // move files under self.path
-        for f in &mut self.files {
-            if !f.path.starts_with(&self.path) {
-                continue;
-            }
+        d.set_location_with(to.clone(), RelocateMode::HardLink)
+            .unwrap();
+        let (old, new) = (
+            fs::metadata(from.join("a.ext")).unwrap(),
+            fs::metadata(to.join("a.ext")).unwrap(),
+        );
+        assert!(old.ino() == new.ino(), "{} == {}", old.ino(), new.ino());
+        assert!(new.nlink() == 2);
+        assert!(d.file(0).unwrap().path() == to.join("a.ext"));
 
-            let new_path = match f.path.to_str() {
-                Some(f) => dir.join(&f[path_len..]),
-                None => continue,
-            };
+        // an existing file is not replaced
+        let mut d = relocate_fixture(&root);
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("a.ext"), b"mock").unwrap();
+        assert!(d
+            .set_location_with(to.clone(), RelocateMode::HardLink)
+            .is_err());
+        assert!(fs::read(to.join("a.ext")).unwrap() == b"mock");
+        assert!(d.file(0).unwrap().path() == from.join("a.ext"));
+    }
 
-            if let Err(e) = f.set_location(new_path) {
-                errs.push((&*f, e));
-            }
+    /// The real filesystem, except reflinks are never supported. Copies are counted.
+    struct NoReflink {
+        copies: std::cell::Cell<usize>,
+    }
+
+    impl FileSystem for NoReflink {
+        fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            fs::rename(from, to)
         }
 
-        // Deletes the folder if possible, will fail if `self.path` is not empty. We should be
-        // able to continue regardless of error
-        // TODO - Should we report something if this fails?
-        let _ = fs::remove_dir(&self.path);
-        self.path = dir;
+        fn copy(&self, from: &path::Path, to: &path::Path) -> io::Result<u64> {
+            self.copies.set(self.copies.get() + 1);
+            fs::copy(from, to)
+        }
 
-        if errs.is_empty() {
-            Ok(())
-        } else {
-            Err(MvError::MoveErrors(errs))
+        fn remove_file(&self, path: &path::Path) -> io::Result<()> {
+            fs::remove_file(path)
         }
-    }
-}
 
-#[cfg(test)]
-mod test_file {
-    use std::borrow::ToOwned;
-    use std::env;
-    use std::path;
+        fn hard_link(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+            fs::hard_link(from, to)
+        }
 
-    use super::{File, Status};
+        #[cfg(unix)]
+        fn reflink(&self, _: &path::Path, _: &path::Path) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
 
-    fn name() -> String {
-        "こんにちは".to_owned()
-    }
-    fn path_abs() -> path::PathBuf {
-        env::temp_dir().join(name())
-    }
-    fn path_rel() -> path::PathBuf {
-        path::PathBuf::from(name())
+        #[cfg(not(unix))]
+        fn reflink(&self, _: &path::Path, _: &path::Path) -> io::Result<()> {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Reflinks are not supported",
+            ))
+        }
+
+        fn available_space(&self, _: &path::Path) -> io::Result<u64> {
+            Ok(u64::MAX)
+        }
+
+        fn same_device(&self, _: &path::Path, _: &path::Path) -> bool {
+            true
+        }
     }
-    static LEN: u64 = 256;
 
     #[test]
-    fn new() {
-        let name = name();
-        let path = path_abs();
-        let f = File::new(name.clone(), path.clone(), LEN);
+    fn set_location_reflink_fallback() {
+        let root = env::temp_dir().join("set_location_reflink_fallback");
+        let mut d = relocate_fixture(&root);
+        let to = root.join("to");
+        let fs = NoReflink {
+            copies: std::cell::Cell::new(0),
+        };
 
-        assert!(f.name == name, "{} == {}", f.name, name);
-        assert!(f.path == path, "{:?} == {:?}", f.path, path);
-        assert!(f.length == LEN, "{} == {}", f.length, LEN);
-        assert!(f.md5sum == None, "{:?} == None", f.md5sum);
-        assert!(
-            f.status == Status::NotCreated,
-            "{:?} == {:?}",
-            f.status,
-            Status::NotCreated
-        );
+        d.relocate_in(&fs, to.clone(), RelocateMode::Reflink)
+            .unwrap();
+        assert!(fs.copies.get() == 1, "{} == 1", fs.copies.get());
+        assert!(std::fs::read(to.join("a.ext")).unwrap() == b"hello, world");
+        assert!(root.join("from").join("a.ext").exists());
     }
 
     #[test]
-    #[should_panic]
-    fn new_fail() {
-        File::new(name(), path_rel(), LEN);
+    fn available_space() {
+        // missing folders are measured from their closest existing parent
+        let missing = env::temp_dir().join("available_space").join("missing");
+        assert!(super::available_space(&missing).unwrap() > 0);
     }
 
     #[test]
-    fn from_dict() {
-        unimplemented!()
+    fn set_location_no_space() {
+        let root = env::temp_dir().join("set_location_no_space");
+        let (from, to) = (root.join("from"), root.join("to"));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join("a.ext"), vec![0; 10]).unwrap();
+        fs::write(from.join("b.ext"), vec![0; 20]).unwrap();
+
+        let mut d = Directory::new(from.clone());
+        for (name, len) in &[("a.ext", 10), ("b.ext", 20), ("c.ext", 1000)] {
+            d.add_file(File::new(name.to_string(), from.join(name), *len));
+        }
+        d.files[0].status = Status::Downloading;
+        d.files[1].status = Status::Done;
+
+        // files which were never created are not moved, so need no space
+        let err = d
+            .relocate_in(&FullDisk { available: 25 }, to.clone(), RelocateMode::Move)
+            .map_err(error::Error::from)
+            .unwrap_err();
+        let expect = error::Error::InsufficientSpace {
+            path: to.clone(),
+            required: 30,
+            available: 25,
+        };
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+        assert!(d.path() == from.as_path());
+        assert!(from.join("a.ext").exists() && !to.exists());
+
+        d.relocate_in(&FullDisk { available: 30 }, to.clone(), RelocateMode::Move)
+            .unwrap();
+        assert!(d.path() == to.as_path());
+        assert!(to.join("b.ext").exists());
+
+        d.set_check_space(false);
+        d.relocate_in(&FullDisk { available: 0 }, from.clone(), RelocateMode::Move)
+            .unwrap();
+        assert!(from.join("a.ext").exists());
     }
 
     #[test]
-    fn set_location() {
-        let mut f = File::new(name(), path_abs(), LEN);
-        let p = env::temp_dir().join("あ");
+    fn allocate_all_no_space() {
+        let dir = env::temp_dir().join("allocate_all_no_space");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.ext"), vec![0; 4]).unwrap();
 
-        if let Err(e) = f.set_location(p.clone()) {
-            panic!("Could not move file. {}", e)
-        }
-        assert!(f.path() == p.as_path());
+        let mut d = Directory::new(dir.clone());
+        d.add_files(vec![
+            File::new("a.ext".to_owned(), dir.join("a.ext"), 10),
+            File::new("b.ext".to_owned(), dir.join("b.ext"), 20),
+        ]);
 
-        if let Ok(_) = f.set_location(path::PathBuf::from("あ")) {
-            panic!("Moved file to relative path")
+        // bytes already on disk are not counted
+        let err = d
+            .allocate_all_in(&FullDisk { available: 25 }, AllocationMode::Full)
+            .unwrap_err();
+        let expect = error::Error::InsufficientSpace {
+            path: dir.clone(),
+            required: 26,
+            available: 25,
+        };
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+        assert!(!dir.join("b.ext").exists());
+
+        // sparse files reserve nothing
+        d.allocate_all_in(&FullDisk { available: 0 }, AllocationMode::Sparse)
+            .unwrap();
+        assert!(fs::metadata(dir.join("b.ext")).unwrap().len() == 20);
+    }
+
+    #[test]
+    fn set_location_rel_path() {
+        let mut d = Directory::new(path_abs());
+
+        if let Ok(_) = d.set_location(path::PathBuf::from("")) {
+            panic!("Moved directory to relative path");
         }
     }
 }
 
 #[cfg(test)]
-mod test_directory {
-    use std::borrow::ToOwned;
+mod test_storage {
     use std::env;
-    use std::ffi;
-    use std::path;
+    use std::fs;
+    use std::io;
 
-    use super::{Directory, File, Status};
+    use super::{AllocationMode, Directory, File, FileAttrs, Status, Storage};
 
-    fn name() -> String {
-        "こんにちは".to_owned()
+    static PIECE_LEN: u32 = 6;
+
+    /// Storage over files 5, 7, and 4 bytes long
+    fn storage(name: &str) -> Storage {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut d = Directory::new(dir.clone());
+        for (i, &len) in [5, 7, 4].iter().enumerate() {
+            let name = format!("file-{}", i);
+            d.add_file(File::new(name.clone(), dir.join(name), len));
+        }
+        Storage::from_directory(d, u64::from(PIECE_LEN))
     }
-    fn path_abs() -> path::PathBuf {
-        env::temp_dir().join(name())
+
+    fn data() -> Vec<u8> {
+        (0..16).collect()
     }
-    static LEN: u64 = 256;
-    static CAP: usize = 8;
 
     #[test]
-    fn new() {
-        let path = path_abs();
-        let d = Directory::with_capacity(path.clone(), CAP);
+    fn write_read() {
+        let mut s = storage("storage_write_read");
+        let data = data();
 
-        assert!(d.path == path, "{:?} == {:?}", d.path, path);
-        assert!(
-            d.files.capacity() == CAP,
-            "{} == {}",
-            d.files.capacity(),
-            CAP
-        );
-        assert!(d.files.len() == 0, "{} == 0", d.files.len());
-        assert!(
-            d.status == Status::NotCreated,
-            "{:?} == {:?}",
-            d.status,
-            Status::NotCreated
-        );
+        // 3 byte blocks, with a 1 byte block at the end of the short last piece
+        for (i, block) in data.chunks(3).enumerate() {
+            let (piece, offset) = ((i * 3) as u32 / PIECE_LEN, (i * 3) as u32 % PIECE_LEN);
+            s.write_block(piece, offset, block).unwrap();
+        }
+
+        for f in s.files() {
+            assert!(f.status == Status::Downloading, "{:?}", f.status);
+        }
+        for (f, expect) in s
+            .files()
+            .iter()
+            .zip(&[&data[..5], &data[5..12], &data[12..]])
+        {
+            assert!(fs::read(f.path()).unwrap() == *expect);
+        }
+
+        for (piece, expect) in data.chunks(PIECE_LEN as usize).enumerate() {
+            let mut buf = vec![0; expect.len()];
+            s.read_block(piece as u32, 0, &mut buf).unwrap();
+            assert!(buf == expect, "{}: {:?} == {:?}", piece, buf, expect);
+        }
+
+        let mut buf = [0; 4];
+        s.read_block(0, 2, &mut buf).unwrap();
+        assert!(buf == data[2..6]);
     }
 
     #[test]
-    #[should_panic]
-    fn new_fail() {
-        Directory::new(path::PathBuf::from(""));
+    fn padding() {
+        let dir = env::temp_dir().join("storage_padding");
+        let _ = fs::remove_dir_all(&dir);
+
+        // the second file starts the second piece
+        let mut d = Directory::new(dir.clone());
+        for (name, len) in [("a.ext", 4), (".pad/2", 2), ("b.ext", 5)] {
+            let mut f = File::new(name.to_owned(), dir.join(name), len);
+            if name.starts_with(".pad") {
+                f.attrs = FileAttrs::PADDING;
+            }
+            d.add_file(f);
+        }
+        d.allocate_all(AllocationMode::Sparse).unwrap();
+        assert!(!dir.join(".pad").exists());
+        let mut s = Storage::from_directory(d, u64::from(PIECE_LEN));
+
+        let data = [1, 2, 3, 4, 0, 0, 5, 6, 7, 8, 9];
+        s.write_block(0, 0, &data[..6]).unwrap();
+        s.write_block(1, 0, &data[6..]).unwrap();
+        assert!(fs::read(dir.join("a.ext")).unwrap() == data[..4]);
+        assert!(fs::read(dir.join("b.ext")).unwrap() == data[6..]);
+        assert!(!dir.join(".pad").exists());
+
+        let mut buf = [0xff; 6];
+        s.read_block(0, 0, &mut buf).unwrap();
+        assert!(buf == data[..6], "{:?} == {:?}", buf, &data[..6]);
     }
 
     #[test]
-    fn from_dict() {
-        unimplemented!()
+    fn unwritten() {
+        let mut s = storage("storage_unwritten");
+        s.write_block(1, 0, &[0xff, 0xff]).unwrap();
+        assert!(s.files()[0].status == Status::NotCreated);
+        assert!(s.files()[1].status == Status::Downloading);
+
+        // the rest of the piece has not been written to disk, so is read as zeros
+        let mut buf = [0xab; 6];
+        s.read_block(1, 0, &mut buf).unwrap();
+        assert!(buf == [0xff, 0xff, 0, 0, 0, 0], "{:?}", buf);
+
+        // the first file has not been created
+        let err = s.read_block(0, 0, &mut [0; 4]).unwrap_err();
+        assert!(err.kind() == io::ErrorKind::NotFound, "{:?}", err);
     }
 
     #[test]
-    fn add_file() {
-        let mut d = Directory::new(path_abs());
+    fn out_of_bounds() {
+        let mut s = storage("storage_out_of_bounds");
 
-        let name = name();
-        let path = path_abs().join("file");
-        d.add_file(File::new(name.clone(), path.clone(), LEN));
+        for &(piece, offset, len) in &[(0, 0, 7), (0, 6, 1), (2, 0, 5), (2, 3, 2), (3, 0, 1)] {
+            let err = s.write_block(piece, offset, &vec![0; len]).unwrap_err();
+            assert!(err.kind() == io::ErrorKind::InvalidInput, "{:?}", err);
+            let err = s.read_block(piece, offset, &mut vec![0; len]).unwrap_err();
+            assert!(err.kind() == io::ErrorKind::InvalidInput, "{:?}", err);
+        }
 
-        assert!(d.files.len() == 1);
-        assert!(d.files[0] == File::new(name, path, LEN));
+        for f in s.files() {
+            assert!(f.status == Status::NotCreated, "{:?}", f.status);
+        }
     }
 
     #[test]
-    fn add_files() {
-        let path = path_abs();
+    fn single_file() {
+        let path = env::temp_dir().join("storage_single_file").join("file");
+        let _ = fs::remove_file(&path);
+        let mut s = Storage::from_file(File::new("file".to_owned(), path.clone(), 10), 4);
 
-        let mut dir = Directory::new(path.clone());
-        let mut files = Vec::with_capacity(CAP);
-        let mut copy = Vec::with_capacity(CAP);
+        s.write_block(2, 0, b"89").unwrap();
+        s.write_block(0, 0, b"0123").unwrap();
+        s.write_block(1, 0, b"4567").unwrap();
+        assert!(fs::read(&path).unwrap() == b"0123456789");
 
-        for i in 0..CAP as u64 {
-            let name = format!("file-{}.ext", i);
-            let fpath = path.join(name.clone());
+        let mut buf = [0; 2];
+        s.read_block(2, 0, &mut buf).unwrap();
+        assert!(&buf == b"89");
+        assert!(s.read_block(2, 1, &mut buf).is_err());
+    }
+}
 
-            files.push(File::new(name.clone(), fpath.clone(), i * i));
-            copy.push(File::new(name, fpath, i * i));
-        }
+#[cfg(test)]
+mod test_reader {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Read, Seek, SeekFrom};
 
-        dir.add_files(files);
-        assert!(dir.files == copy);
-    }
+    use super::{Directory, File, Status};
 
-    #[test]
-    fn rename() {
-        let path = path_abs();
-        let mut d = Directory::new(path.join("old"));
+    /// Files 5 and 11 bytes long holding bytes 0 to 15, in 4 byte pieces
+    fn directory(name: &str) -> Directory {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
 
-        if let Err(_) = d.rename("new") {
-            panic!("Error while renaming directory");
+        let mut d = Directory::new(dir.clone());
+        for (name, data) in [("a.ext", 0..5u8), ("b.ext", 5..16)] {
+            fs::write(dir.join(name), data.clone().collect::<Vec<_>>()).unwrap();
+            let mut f = File::new(name.to_owned(), dir.join(name), data.len() as u64);
+            f.status = Status::Done;
+            d.add_file(f);
         }
-
-        assert!(d.path == path.join("new"));
+        d
     }
 
     #[test]
-    fn set_location() {
-        let tmp = env::temp_dir().join("root");
-        // Default `path` for `dir`
-        let path = tmp.join("default");
-        // Path to place files not owned by `dir`
-        let other = tmp.join("somewhere_else");
-        // Path to move files to
-        let moved = env::temp_dir().join("moved");
+    fn seek_read() {
+        let d = directory("reader_seek_read");
+        let verified = [true; 4];
+        let mut r = d.reader(1, 4, &verified).unwrap();
 
-        let mut dir = Directory::new(path.clone());
+        r.seek(SeekFrom::Start(3)).unwrap();
+        let mut buf = [0; 5];
+        r.read_exact(&mut buf).unwrap();
+        assert!(buf == [8, 9, 10, 11, 12], "{:?}", buf);
 
-        for i in 0..CAP as u64 {
-            let name = format!("file-{}.ext", i);
-            let fpath = if i % 2 == 0 { &path } else { &other }.join(name.clone());
+        assert!(r.seek(SeekFrom::End(-2)).unwrap() == 9);
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest).unwrap();
+        assert!(rest == [14, 15], "{:?}", rest);
 
-            dir.add_file(File::new(name, fpath, i * i));
-        }
+        assert!(r.seek(SeekFrom::Current(-12)).is_err());
+        assert!(d.reader(2, 4, &verified).is_none());
+    }
 
-        if let Err(e) = dir.set_location(moved.clone()) {
-            panic!("Failed to moved files. {:?}", e);
-        }
+    #[test]
+    fn would_block() {
+        let d = directory("reader_would_block");
+        // the third piece, bytes 8 to 11, is missing
+        let verified = [true, true, false, true];
+        let mut r = d.reader(1, 4, &verified).unwrap();
+        assert!(r.available() == 3, "{} == 3", r.available());
 
-        // Check if only files under `root` were moved to `moved`
-        for (i, f) in dir.files.iter().enumerate() {
-            let p = if i % 2 == 0 { &moved } else { &other };
+        let mut buf = [0; 8];
+        let n = r.read(&mut buf).unwrap();
+        assert!(buf[..n] == [5, 6, 7], "{:?}", &buf[..n]);
+        let err = r.read(&mut buf).unwrap_err();
+        assert!(err.kind() == io::ErrorKind::WouldBlock, "{:?}", err);
 
-            assert!(f.path().parent() == Some(p));
-            assert!(f.path().file_name().unwrap() == ffi::OsStr::new(&format!("file-{}.ext", i)));
-        }
+        // past the missing piece
+        r.seek(SeekFrom::Start(7)).unwrap();
+        assert!(r.available() == 4, "{} == 4", r.available());
+        r.read_exact(&mut buf[..4]).unwrap();
+        assert!(buf[..4] == [12, 13, 14, 15], "{:?}", &buf[..4]);
     }
 
     #[test]
-    fn set_location_rel_path() {
-        let mut d = Directory::new(path_abs());
+    fn available_to_end() {
+        let d = directory("reader_available_to_end");
+        // a.ext ends one byte into the second piece
+        let mut r = d.reader(0, 4, &[true; 4]).unwrap();
+        assert!(r.available() == 5, "{} == 5", r.available());
 
-        if let Ok(_) = d.set_location(path::PathBuf::from("")) {
-            panic!("Moved directory to relative path");
-        }
+        r.seek(SeekFrom::Start(4)).unwrap();
+        assert!(r.available() == 1, "{} == 1", r.available());
+        r.seek(SeekFrom::End(0)).unwrap();
+        assert!(r.available() == 0, "{} == 0", r.available());
     }
 }
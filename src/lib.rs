@@ -13,5 +13,8 @@ mod util;
 
 pub mod bencode;
 pub mod error;
+pub mod fetch;
 pub mod files;
+pub mod peer;
 pub mod torrent;
+pub mod verify;
@@ -0,0 +1,1157 @@
+//! Peer wire protocol as described by [BEP 003](http://www.bittorrent.org/beps/bep_0003.html).
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+
+use crate::bencode::{self, Benc};
+use crate::error;
+use crate::util;
+
+/// Protocol string sent at the start of every handshake
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length of an encoded `Handshake` in bytes
+pub const HANDSHAKE_LEN: usize = 68;
+
+/// The first message sent by both ends of a peer connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    /// Reserved bytes, used to advertise support for extensions
+    pub reserved: [u8; 8],
+    /// SHA1 hash of the torrent's info dict
+    pub info_hash: [u8; 20],
+    /// Id the peer chose for itself
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// Create a `Handshake` which advertises no extensions
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+        Handshake {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Encode as `<pstrlen><pstr><reserved><info_hash><peer_id>`
+    pub fn encode(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0; HANDSHAKE_LEN];
+
+        buf[0] = PROTOCOL.len() as u8;
+        buf[1..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..68].copy_from_slice(&self.peer_id);
+
+        buf
+    }
+
+    /// Decode a handshake, failing if it is not for the BitTorrent protocol
+    pub fn decode(buf: &[u8; HANDSHAKE_LEN]) -> error::Result<Handshake> {
+        if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+            return Err(errors::invalid_protocol(0));
+        }
+
+        let mut hs = Handshake::new([0; 20], [0; 20]);
+        hs.reserved.copy_from_slice(&buf[20..28]);
+        hs.info_hash.copy_from_slice(&buf[28..48]);
+        hs.peer_id.copy_from_slice(&buf[48..68]);
+
+        Ok(hs)
+    }
+
+    /// Returns true if the peer supports the DHT ([BEP 005](
+    /// http://www.bittorrent.org/beps/bep_0005.html))
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    /// Returns true if the peer supports the Fast Extension ([BEP 006](
+    /// http://www.bittorrent.org/beps/bep_0006.html))
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    /// Returns true if the peer supports the extension protocol ([BEP 010](
+    /// http://www.bittorrent.org/beps/bep_0010.html))
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+}
+
+/// Extended message id of the extension handshake
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The extension handshake ([BEP 010](http://www.bittorrent.org/beps/bep_0010.html)), sent as
+/// the payload of a `Message::Extended` with id `EXTENDED_HANDSHAKE_ID` to peers whose
+/// `Handshake` supports extensions. It tells the peer which extended message id each extension,
+/// such as `ut_metadata` or `ut_pex`, is sent with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the extended message id the sender expects for them. An id of 0
+    /// means the extension is disabled.
+    pub extensions: HashMap<String, u8>,
+    /// Name and version of the sender's client
+    pub version: Option<String>,
+    /// Port the sender is listening on
+    pub port: Option<u16>,
+    /// Number of outstanding requests the sender allows
+    pub reqq: Option<u32>,
+    /// Size of the info dict in bytes, sent by peers supporting `ut_metadata`
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    /// Id the sender expects for messages of extension `name`, or `None` if it does not support
+    /// the extension or has disabled it
+    pub fn id(&self, name: &str) -> Option<u8> {
+        match self.extensions.get(name) {
+            Some(&0) | None => None,
+            Some(&id) => Some(id),
+        }
+    }
+
+    /// Encode as the payload of a `Message::Extended`
+    pub fn encode(&self) -> Vec<u8> {
+        let m = self
+            .extensions
+            .iter()
+            .map(|(name, &id)| (name.clone().into_bytes(), Benc::Int(i64::from(id))))
+            .collect();
+
+        let mut dict = HashMap::new();
+        dict.insert(b"m".to_vec(), Benc::Dict(m));
+        if let Some(ref v) = self.version {
+            dict.insert(b"v".to_vec(), Benc::from(v.clone()));
+        }
+        if let Some(p) = self.port {
+            dict.insert(b"p".to_vec(), Benc::Int(i64::from(p)));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), Benc::Int(i64::from(reqq)));
+        }
+        if let Some(size) = self.metadata_size {
+            dict.insert(b"metadata_size".to_vec(), Benc::Int(size as i64));
+        }
+
+        Benc::Dict(dict).encode()
+    }
+
+    /// Decode the payload of a `Message::Extended`. Every key but "m" is optional, and values of
+    /// the wrong type or out of range are ignored, since clients differ in what they send.
+    pub fn decode(buf: &[u8]) -> error::Result<ExtendedHandshake> {
+        let mut dict = match bencode::slice::decode(buf)? {
+            (Benc::Dict(d), _) => d,
+            _ => return Err(errors::invalid_extended(0)),
+        };
+
+        let mut hs = ExtendedHandshake::default();
+        let m = match bencode::take(&mut dict, b"m")? {
+            Benc::Dict(m) => m,
+            n => return Err(bencode::wrong_type(b"m", &n)),
+        };
+        for (name, id) in m {
+            let id = match id {
+                Benc::Int(id) => u8::try_from(id).ok(),
+                _ => None,
+            };
+            match (String::from_utf8(name), id) {
+                (Ok(name), Some(id)) => hs.extensions.insert(name, id),
+                _ => return Err(errors::invalid_extended(0)),
+            };
+        }
+
+        hs.version = bencode::take_text(&mut dict, b"v");
+        hs.port = take_opt_int(&mut dict, b"p");
+        hs.reqq = take_opt_int(&mut dict, b"reqq");
+        hs.metadata_size = take_opt_int(&mut dict, b"metadata_size");
+
+        Ok(hs)
+    }
+}
+
+/// Remove the int under `key` from `dict`, if it is present and fits in a `T`
+fn take_opt_int<T: TryFrom<i64>>(dict: &mut HashMap<Vec<u8>, Benc>, key: &[u8]) -> Option<T> {
+    match dict.remove(key) {
+        Some(Benc::Int(i)) => T::try_from(i).ok(),
+        _ => None,
+    }
+}
+
+/// Messages exchanged after the handshake. Every message except `KeepAlive` is framed as a 4-byte
+/// big-endian length prefix, a 1-byte id, and a payload; `KeepAlive` is just a length of zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// The sender has the piece at this index
+    Have(u32),
+    /// Pieces the sender has, with the high bit of the first byte being piece 0
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// Port the sender's DHT node is listening on
+    Port(u16),
+    /// Message of an extension negotiated with the extension protocol ([BEP 010](
+    /// http://www.bittorrent.org/beps/bep_0010.html)). `id` is the extended message id, 0 for the
+    /// extension handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Encode as a length prefixed message
+    pub fn encode(&self) -> Vec<u8> {
+        let (id, payload) = match *self {
+            Message::KeepAlive => return vec![0; 4],
+            Message::Choke => (0, Vec::new()),
+            Message::Unchoke => (1, Vec::new()),
+            Message::Interested => (2, Vec::new()),
+            Message::NotInterested => (3, Vec::new()),
+            Message::Have(index) => (4, index.to_be_bytes().to_vec()),
+            Message::Bitfield(ref bits) => (5, bits.clone()),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => (6, u32s(&[index, begin, length])),
+            Message::Piece {
+                index,
+                begin,
+                ref block,
+            } => {
+                let mut payload = u32s(&[index, begin]);
+                payload.extend_from_slice(block);
+                (7, payload)
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => (8, u32s(&[index, begin, length])),
+            Message::Port(port) => (9, port.to_be_bytes().to_vec()),
+            Message::Extended { id, ref payload } => {
+                let mut buf = Vec::with_capacity(1 + payload.len());
+                buf.push(id);
+                buf.extend_from_slice(payload);
+                (20, buf)
+            }
+        };
+
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        buf.push(id);
+        buf.extend_from_slice(&payload);
+
+        buf
+    }
+
+    /// Decode the message at the start of `buf`, returning it and the number of bytes it used.
+    /// Bytes after the message are ignored; an error is returned if `buf` holds less than a whole
+    /// message.
+    pub fn decode(buf: &[u8]) -> error::Result<(Message, usize)> {
+        if buf.len() < 4 {
+            return Err(errors::incomplete(buf.len()));
+        }
+
+        let len = read_u32(&buf[..4]) as usize;
+        if len == 0 {
+            return Ok((Message::KeepAlive, 4));
+        }
+
+        let end = match len.checked_add(4) {
+            Some(end) if end <= buf.len() => end,
+            _ => return Err(errors::incomplete(buf.len())),
+        };
+        let payload = &buf[5..end];
+
+        let msg = match (buf[4], payload.len()) {
+            (0, 0) => Message::Choke,
+            (1, 0) => Message::Unchoke,
+            (2, 0) => Message::Interested,
+            (3, 0) => Message::NotInterested,
+            (4, 4) => Message::Have(read_u32(payload)),
+            (5, _) => Message::Bitfield(payload.to_vec()),
+            (6, 12) => Message::Request {
+                index: read_u32(&payload[0..4]),
+                begin: read_u32(&payload[4..8]),
+                length: read_u32(&payload[8..12]),
+            },
+            (7, n) if n >= 8 => Message::Piece {
+                index: read_u32(&payload[0..4]),
+                begin: read_u32(&payload[4..8]),
+                block: payload[8..].to_vec(),
+            },
+            (8, 12) => Message::Cancel {
+                index: read_u32(&payload[0..4]),
+                begin: read_u32(&payload[4..8]),
+                length: read_u32(&payload[8..12]),
+            },
+            (9, 2) => Message::Port(u16::from_be_bytes([payload[0], payload[1]])),
+            (20, n) if n >= 1 => Message::Extended {
+                id: payload[0],
+                payload: payload[1..].to_vec(),
+            },
+            (0..=9, _) | (20, _) => return Err(errors::invalid_length(0)),
+            _ => return Err(errors::unknown_id(4)),
+        };
+
+        Ok((msg, end))
+    }
+
+    /// Read one message from `r`, such as a blocking socket. Messages longer than `max_len`
+    /// bytes are rejected without reading them.
+    pub fn read_from<R: io::Read>(r: &mut R, max_len: usize) -> error::Result<Message> {
+        let frame = util::read_frame(r, max_len)?;
+        let mut buf = Vec::with_capacity(4 + frame.len());
+        buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&frame);
+        Message::decode(&buf).map(|(msg, _)| msg)
+    }
+
+    /// Write the message to `w` with its length prefix
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        util::write_frame(w, &self.encode()[4..])
+    }
+}
+
+/// Default limit on the length of messages other than `Piece`
+pub const MAX_MESSAGE_LEN: usize = 1 << 16;
+
+/// Largest block a `Piece` message may carry. Clients request 16 KiB blocks, but some accept
+/// requests for up to 128 KiB.
+pub const MAX_BLOCK_LEN: usize = 1 << 17;
+
+/// Decodes messages from a stream of bytes, such as reads from a socket. Bytes are buffered until
+/// a whole message has arrived. Messages longer than the limit are rejected as soon as their
+/// length prefix is read, rather than buffered.
+#[derive(Debug, Clone)]
+pub struct MessageDecoder {
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl Default for MessageDecoder {
+    fn default() -> MessageDecoder {
+        MessageDecoder::with_max_len(MAX_MESSAGE_LEN)
+    }
+}
+
+impl MessageDecoder {
+    /// Create a decoder which accepts messages up to `MAX_MESSAGE_LEN` bytes long
+    pub fn new() -> MessageDecoder {
+        MessageDecoder::default()
+    }
+
+    /// Create a decoder which accepts messages up to `max_len` bytes long. `Piece` messages
+    /// carrying up to `MAX_BLOCK_LEN` bytes are always accepted.
+    pub fn with_max_len(max_len: usize) -> MessageDecoder {
+        MessageDecoder {
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    /// Buffer bytes read from the peer
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes buffered which are not yet part of a decoded message
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns an error if a message with length prefix `len` is too long. `None` is returned if
+    /// more bytes are needed to decide.
+    fn check_len(&self, len: usize) -> Option<error::Result<()>> {
+        if len <= self.max_len {
+            return Some(Ok(()));
+        }
+
+        // <id><index><begin><block>
+        if len > MAX_BLOCK_LEN + 9 {
+            return Some(Err(errors::too_long(0)));
+        }
+
+        match self.buf.get(4) {
+            Some(7) => Some(Ok(())),
+            Some(_) => Some(Err(errors::too_long(0))),
+            None => None,
+        }
+    }
+}
+
+impl Iterator for MessageDecoder {
+    type Item = error::Result<Message>;
+
+    /// Decode the next buffered message. `None` is returned until a whole message has been fed.
+    /// After a message is rejected for being too long the buffer is cleared, since there is no
+    /// way to find the start of the next message; the connection should be dropped.
+    fn next(&mut self) -> Option<error::Result<Message>> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+
+        let len = read_u32(&self.buf[..4]) as usize;
+        if let Err(e) = self.check_len(len)? {
+            self.buf.clear();
+            return Some(Err(e));
+        }
+
+        let end = len + 4;
+        if self.buf.len() < end {
+            return None;
+        }
+
+        let result = Message::decode(&self.buf[..end]).map(|(msg, _)| msg);
+        self.buf.drain(..end);
+        Some(result)
+    }
+}
+
+/// Size of each piece of metadata exchanged with `ut_metadata`. Only the last piece may be shorter.
+pub const METADATA_PIECE_LEN: usize = 1 << 14;
+
+/// Largest info dict `MetadataAssembler` will accept
+pub const MAX_METADATA_LEN: u64 = 1 << 24;
+
+/// Messages of the `ut_metadata` extension ([BEP 009](
+/// http://www.bittorrent.org/beps/bep_0009.html)), used to fetch a torrent's info dict from peers
+/// when only its info hash is known, e.g. from a magnet link. Each is a bencoded dict, sent as the
+/// payload of a `Message::Extended`; `Data` is followed by the piece itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    /// Ask for the piece at this index
+    Request(u32),
+    /// A piece of the info dict. `total_size` is the length of the whole info dict in bytes.
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    /// The peer does not have, or will not send, the piece at this index
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    /// Encode as the payload of a `Message::Extended`
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match *self {
+            MetadataMessage::Request(piece) => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject(piece) => (2, piece),
+        };
+
+        let mut dict = HashMap::new();
+        dict.insert(b"msg_type".to_vec(), Benc::Int(msg_type));
+        dict.insert(b"piece".to_vec(), Benc::Int(i64::from(piece)));
+        if let MetadataMessage::Data { total_size, .. } = *self {
+            dict.insert(b"total_size".to_vec(), Benc::Int(total_size as i64));
+        }
+
+        let mut buf = Benc::Dict(dict).encode();
+        if let MetadataMessage::Data { ref data, .. } = *self {
+            buf.extend_from_slice(data);
+        }
+
+        buf
+    }
+
+    /// Decode the payload of a `Message::Extended`. Only `Data` may have bytes after its dict.
+    pub fn decode(buf: &[u8]) -> error::Result<MetadataMessage> {
+        let (dict, len) = bencode::slice::decode(buf)?;
+        let mut dict = match dict {
+            Benc::Dict(d) => d,
+            _ => return Err(errors::invalid_metadata(0)),
+        };
+
+        let msg_type = bencode::take_int(&mut dict, b"msg_type")?;
+        let piece = match u32::try_from(bencode::take_int(&mut dict, b"piece")?) {
+            Ok(piece) => piece,
+            Err(_) => return Err(errors::invalid_metadata(0)),
+        };
+
+        match (msg_type, len == buf.len()) {
+            (0, true) => Ok(MetadataMessage::Request(piece)),
+            (1, _) => match u64::try_from(bencode::take_int(&mut dict, b"total_size")?) {
+                Ok(total_size) => Ok(MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data: buf[len..].to_vec(),
+                }),
+                Err(_) => Err(errors::invalid_metadata(0)),
+            },
+            (2, true) => Ok(MetadataMessage::Reject(piece)),
+            (0, false) | (2, false) => Err(errors::invalid_metadata(len)),
+            _ => Err(errors::invalid_metadata(0)),
+        }
+    }
+}
+
+/// Collects the pieces of an info dict received with `ut_metadata`. The info dict is only
+/// returned once every piece has arrived and it hashes to the expected info hash.
+#[derive(Debug, Clone)]
+pub struct MetadataAssembler {
+    info_hash: [u8; 20],
+    total_size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataAssembler {
+    /// Create an assembler for the info dict with `info_hash`. `total_size` is taken from the
+    /// first `Data` message, and must not be over `MAX_METADATA_LEN`.
+    pub fn new(info_hash: [u8; 20], total_size: u64) -> error::Result<MetadataAssembler> {
+        if total_size == 0 || total_size > MAX_METADATA_LEN {
+            return Err(error::Error::new(error::Error::Other(
+                "Invalid metadata size",
+            )));
+        }
+
+        let total_size = total_size as usize;
+        let count = match total_size % METADATA_PIECE_LEN {
+            0 => total_size / METADATA_PIECE_LEN,
+            _ => total_size / METADATA_PIECE_LEN + 1,
+        };
+
+        Ok(MetadataAssembler {
+            info_hash,
+            total_size,
+            pieces: vec![None; count],
+        })
+    }
+
+    /// Number of pieces the info dict is split into
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Indexes of the pieces which have not been received, to be requested from peers
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.is_none())
+            .map(|(i, _)| i as u32)
+    }
+
+    /// Returns true once every piece has been received
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// Add the piece at `index`. Every piece must be `METADATA_PIECE_LEN` bytes long, except the
+    /// last which holds the rest of the info dict.
+    pub fn add_piece(&mut self, index: u32, data: Vec<u8>) -> error::Result<()> {
+        let index = index as usize;
+        if index >= self.pieces.len() {
+            return Err(error::Error::new(error::Error::Other(
+                "Invalid metadata piece index",
+            )));
+        }
+
+        let expect = match index + 1 == self.pieces.len() {
+            true => self.total_size - index * METADATA_PIECE_LEN,
+            false => METADATA_PIECE_LEN,
+        };
+        if data.len() != expect {
+            return Err(error::Error::new(error::Error::Other(
+                "Invalid metadata piece length",
+            )));
+        }
+
+        self.pieces[index] = Some(data);
+        Ok(())
+    }
+
+    /// Join the pieces into the info dict, failing if any are missing or it does not hash to the
+    /// info hash. After a hash mismatch every piece is dropped so they can be requested again,
+    /// possibly from other peers.
+    pub fn finish(&mut self) -> error::Result<Vec<u8>> {
+        if !self.is_complete() {
+            return Err(error::Error::new(error::Error::Other(
+                "Metadata is missing pieces",
+            )));
+        }
+
+        let mut info = Vec::with_capacity(self.total_size);
+        for piece in self.pieces.iter_mut() {
+            info.extend(piece.take().unwrap_or_default());
+        }
+
+        match util::sha1(&info) == self.info_hash {
+            true => Ok(info),
+            false => Err(error::Error::new(error::Error::Other(
+                "Metadata does not match the info hash",
+            ))),
+        }
+    }
+}
+
+/// Big-endian bytes of each int in `ints`
+fn u32s(ints: &[u32]) -> Vec<u8> {
+    ints.iter().flat_map(|i| i.to_be_bytes().to_vec()).collect()
+}
+
+/// Read a big-endian int from the first 4 bytes of `buf`
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// Errors returned while decoding handshakes and messages. `offset` is the position in the
+/// message where decoding failed.
+mod errors {
+    use crate::error::Error;
+
+    fn parse(message: &'static str, offset: usize) -> Error {
+        Error::new(Error::Parse { message, offset })
+    }
+
+    /// The handshake is not for the BitTorrent protocol
+    crate fn invalid_protocol(offset: usize) -> Error {
+        parse("Invalid handshake protocol string", offset)
+    }
+
+    /// The buffer ends before the message does
+    crate fn incomplete(offset: usize) -> Error {
+        parse("Incomplete message", offset)
+    }
+
+    /// The length prefix does not match the message id
+    crate fn invalid_length(offset: usize) -> Error {
+        parse("Invalid message length", offset)
+    }
+
+    /// The message id is not one this library knows
+    crate fn unknown_id(offset: usize) -> Error {
+        parse("Unknown message id", offset)
+    }
+
+    /// The extension handshake is not a dict, or its "m" dict maps a name to an invalid id
+    crate fn invalid_extended(offset: usize) -> Error {
+        parse("Invalid extension handshake", offset)
+    }
+
+    /// A `ut_metadata` message has an unknown type, or an invalid field
+    crate fn invalid_metadata(offset: usize) -> Error {
+        parse("Invalid ut_metadata message", offset)
+    }
+
+    /// The length prefix is over the decoder's limit
+    crate fn too_long(offset: usize) -> Error {
+        Error::new(Error::Limit {
+            message: "Message too long",
+            offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_handshake {
+    use super::{errors, Handshake, HANDSHAKE_LEN};
+
+    fn handshake() -> Handshake {
+        let mut hs = Handshake::new([0xab; 20], *b"-LB0001-123456789012");
+        hs.reserved[5] = 0x10;
+        hs.reserved[7] = 0x05;
+        hs
+    }
+
+    #[test]
+    fn encode() {
+        let buf = handshake().encode();
+
+        assert!(buf[0] == 19);
+        assert!(&buf[1..20] == b"BitTorrent protocol");
+        assert!(buf[20..28] == [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert!(buf[28..48] == [0xab; 20]);
+        assert!(&buf[48..] == b"-LB0001-123456789012");
+    }
+
+    #[test]
+    fn round_trip() {
+        let hs = handshake();
+        let decoded = Handshake::decode(&hs.encode()).unwrap();
+
+        assert!(decoded == hs, "{:?} == {:?}", decoded, hs);
+        assert!(decoded.supports_dht());
+        assert!(decoded.supports_fast());
+        assert!(decoded.supports_extensions());
+
+        let plain = Handshake::decode(&Handshake::new([0; 20], [0; 20]).encode()).unwrap();
+        assert!(!plain.supports_dht());
+        assert!(!plain.supports_fast());
+        assert!(!plain.supports_extensions());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let expect = errors::invalid_protocol(0);
+
+        let mut buf = handshake().encode();
+        buf[0] = 18;
+        assert!(Handshake::decode(&buf).unwrap_err() == expect);
+
+        let mut buf = handshake().encode();
+        buf[1] = b'b';
+        assert!(Handshake::decode(&buf).unwrap_err() == expect);
+
+        assert!(Handshake::decode(&[0; HANDSHAKE_LEN]).unwrap_err() == expect);
+    }
+}
+
+#[cfg(test)]
+mod test_extended {
+    use std::borrow::ToOwned;
+
+    use super::{errors, ExtendedHandshake, Message, EXTENDED_HANDSHAKE_ID};
+    use crate::error::{Error, KeyProblem};
+
+    fn handshake() -> ExtendedHandshake {
+        let mut hs = ExtendedHandshake {
+            version: Some("libbittorrent 0.1.0".to_owned()),
+            port: Some(6881),
+            reqq: Some(250),
+            metadata_size: Some(31_235),
+            ..ExtendedHandshake::default()
+        };
+        hs.extensions.insert("ut_metadata".to_owned(), 3);
+        hs.extensions.insert("ut_pex".to_owned(), 1);
+        hs.extensions.insert("lt_donthave".to_owned(), 0);
+        hs
+    }
+
+    #[test]
+    fn encode() {
+        let buf = ExtendedHandshake::default().encode();
+        assert!(buf == b"d1:mdee", "{:?}", String::from_utf8_lossy(&buf));
+
+        let mut hs = ExtendedHandshake::default();
+        hs.extensions.insert("ut_metadata".to_owned(), 3);
+        hs.port = Some(6881);
+        let buf = hs.encode();
+        let expect = &b"d1:md11:ut_metadatai3ee1:pi6881ee"[..];
+        assert!(buf == expect, "{:?}", String::from_utf8_lossy(&buf));
+    }
+
+    #[test]
+    fn round_trip() {
+        let hs = handshake();
+        let msg = Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: hs.encode(),
+        };
+
+        let payload = match Message::decode(&msg.encode()).unwrap() {
+            (Message::Extended { id: 0, payload }, _) => payload,
+            m => panic!("{:?}", m),
+        };
+        let decoded = ExtendedHandshake::decode(&payload).unwrap();
+        assert!(decoded == hs, "{:?} == {:?}", decoded, hs);
+
+        assert!(decoded.id("ut_metadata") == Some(3));
+        assert!(decoded.id("ut_pex") == Some(1));
+        // disabled, and unknown
+        assert!(decoded.id("lt_donthave").is_none());
+        assert!(decoded.id("ut_holepunch").is_none());
+    }
+
+    #[test]
+    fn decode_lenient() {
+        // unknown keys and optional values of the wrong type or range are ignored
+        let buf = b"d1:md6:ut_pexi1ee1:pi-1e4:reqq3:abc1:v4:mock6:yourip4:\x7f\0\0\x01e";
+        let hs = ExtendedHandshake::decode(buf).unwrap();
+
+        assert!(hs.id("ut_pex") == Some(1));
+        assert!(hs.version.as_deref() == Some("mock"));
+        assert!(hs.port.is_none());
+        assert!(hs.reqq.is_none());
+        assert!(hs.metadata_size.is_none());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        for buf in [
+            &b"li1ee"[..],
+            b"d1:md6:ut_pexi256eee",
+            b"d1:md6:ut_pexi-1eee",
+            b"d1:md6:ut_pex1:aee",
+        ] {
+            let result = ExtendedHandshake::decode(buf).unwrap_err();
+            let expect = errors::invalid_extended(0);
+            assert!(result == expect, "{:?}: {:?} == {:?}", buf, result, expect);
+        }
+
+        let result = ExtendedHandshake::decode(b"d1:pi6881ee").unwrap_err();
+        let expect = Error::Key {
+            key: b"m".to_vec(),
+            problem: KeyProblem::Missing,
+        };
+        assert!(result == expect, "{:?} == {:?}", result, expect);
+
+        let result = ExtendedHandshake::decode(b"d1:mi1ee").unwrap_err();
+        let expect = Error::Key {
+            key: b"m".to_vec(),
+            problem: KeyProblem::WrongType { found: "int" },
+        };
+        assert!(result == expect, "{:?} == {:?}", result, expect);
+    }
+}
+
+#[cfg(test)]
+mod test_message {
+    use super::{errors, Message};
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0x0102_0304),
+            Message::Bitfield(vec![0b1010_0000, 0xff]),
+            Message::Bitfield(vec![]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 1,
+                begin: 16384,
+                block: b"mock data".to_vec(),
+            },
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Port(6881),
+            Message::Extended {
+                id: 0,
+                payload: b"d1:v4:mocke".to_vec(),
+            },
+            Message::Extended {
+                id: 3,
+                payload: vec![],
+            },
+        ]
+    }
+
+    #[test]
+    fn encode() {
+        assert!(Message::KeepAlive.encode() == [0, 0, 0, 0]);
+        assert!(Message::Choke.encode() == [0, 0, 0, 1, 0]);
+        assert!(Message::Have(0x0102_0304).encode() == [0, 0, 0, 5, 4, 1, 2, 3, 4]);
+        assert!(Message::Port(6881).encode() == [0, 0, 0, 3, 9, 0x1a, 0xe1]);
+
+        let piece = Message::Piece {
+            index: 1,
+            begin: 2,
+            block: vec![0xab, 0xcd],
+        };
+        let expect = [0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 2, 0xab, 0xcd];
+        assert!(
+            piece.encode() == expect,
+            "{:?} == {:?}",
+            piece.encode(),
+            expect
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for msg in messages() {
+            let buf = msg.encode();
+            let decoded = Message::decode(&buf).unwrap();
+
+            assert!(
+                decoded == (msg.clone(), buf.len()),
+                "{:?} == {:?}",
+                decoded,
+                msg
+            );
+        }
+    }
+
+    #[test]
+    fn decode_stream() {
+        let mut buf = Vec::new();
+        for msg in messages() {
+            buf.extend(msg.encode());
+        }
+
+        let mut pos = 0;
+        for msg in messages() {
+            let (decoded, used) = Message::decode(&buf[pos..]).unwrap();
+            assert!(decoded == msg, "{:?} == {:?}", decoded, msg);
+            pos += used;
+        }
+        assert!(pos == buf.len());
+    }
+
+    #[test]
+    fn read_write() {
+        let mut buf = Vec::new();
+        for msg in messages() {
+            msg.write_to(&mut buf).unwrap();
+        }
+        let encoded: Vec<u8> = messages().iter().flat_map(Message::encode).collect();
+        assert!(buf == encoded);
+
+        let mut r = &buf[..];
+        for msg in messages() {
+            let read = Message::read_from(&mut r, 64).unwrap();
+            assert!(read == msg, "{:?} == {:?}", read, msg);
+        }
+        assert!(r.is_empty());
+
+        let long = Message::Bitfield(vec![0; 64]).encode();
+        assert!(Message::read_from(&mut &long[..], 64).is_err());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        for (buf, err) in [
+            (vec![], errors::incomplete(0)),
+            (vec![0, 0, 0], errors::incomplete(3)),
+            (vec![0, 0, 0, 5, 4, 0, 0], errors::incomplete(7)),
+            (vec![0xff, 0xff, 0xff, 0xff, 7], errors::incomplete(5)),
+            (vec![0, 0, 0, 1, 10], errors::unknown_id(4)),
+            (vec![0, 0, 0, 2, 0, 0], errors::invalid_length(0)),
+            (vec![0, 0, 0, 3, 4, 0, 0], errors::invalid_length(0)),
+            (vec![0, 0, 0, 5, 7, 0, 0, 0, 1], errors::invalid_length(0)),
+            (vec![0, 0, 0, 1, 20], errors::invalid_length(0)),
+        ] {
+            let result = Message::decode(&buf).unwrap_err();
+            assert!(result == err, "{:?}: {:?} == {:?}", buf, result, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_decoder {
+    use super::{errors, Message, MessageDecoder, MAX_BLOCK_LEN};
+
+    #[test]
+    fn partial() {
+        let msgs = vec![
+            Message::Have(3),
+            Message::KeepAlive,
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                block: b"mock data".to_vec(),
+            },
+        ];
+        let bytes: Vec<u8> = msgs.iter().flat_map(|m| m.encode()).collect();
+
+        let mut dec = MessageDecoder::new();
+        let mut decoded = Vec::new();
+        for b in &bytes {
+            assert!(dec.next().is_none());
+            dec.feed(&[*b]);
+            decoded.extend(dec.by_ref().map(|m| m.unwrap()));
+        }
+
+        assert!(decoded == msgs, "{:?} == {:?}", decoded, msgs);
+        assert!(dec.buffered() == 0);
+        assert!(dec.next().is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut dec = MessageDecoder::new();
+        dec.feed(&[0, 0, 0, 1, 10]);
+        dec.feed(&Message::Choke.encode());
+
+        assert!(dec.next().unwrap().unwrap_err() == errors::unknown_id(4));
+        assert!(dec.next().unwrap().unwrap() == Message::Choke);
+        assert!(dec.next().is_none());
+    }
+
+    #[test]
+    fn too_long() {
+        let mut dec = MessageDecoder::with_max_len(16);
+        dec.feed(&Message::Bitfield(vec![0xff; 16]).encode());
+        dec.feed(&Message::Choke.encode());
+
+        assert!(dec.next().unwrap().unwrap_err() == errors::too_long(0));
+        assert!(dec.buffered() == 0);
+        assert!(dec.next().is_none());
+
+        // a length prefix alone is enough to reject anything larger than a piece
+        dec.feed(&(MAX_BLOCK_LEN as u32 + 10).to_be_bytes());
+        assert!(dec.next().unwrap().unwrap_err() == errors::too_long(0));
+
+        // pieces may be longer than the limit, and are only checked once their id is read
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0xab; MAX_BLOCK_LEN],
+        };
+        let bytes = piece.encode();
+        dec.feed(&bytes[..4]);
+        assert!(dec.next().is_none());
+        dec.feed(&bytes[4..]);
+        assert!(dec.next().unwrap().unwrap() == piece);
+    }
+}
+
+#[cfg(test)]
+mod test_metadata {
+    use sha1::{Digest, Sha1};
+
+    use super::{errors, MetadataAssembler, MetadataMessage, METADATA_PIECE_LEN};
+
+    /// An info dict long enough to be split over 3 pieces
+    fn info() -> Vec<u8> {
+        let pieces = vec![b'a'; 2 * METADATA_PIECE_LEN];
+        let mut info = format!(
+            "d6:lengthi1024e4:name8:file.ext12:piece lengthi16384e6:pieces{}:",
+            pieces.len()
+        )
+        .into_bytes();
+        info.extend(pieces);
+        info.push(b'e');
+        info
+    }
+
+    #[test]
+    fn round_trip() {
+        for (msg, encoded) in [
+            (
+                MetadataMessage::Request(0),
+                &b"d8:msg_typei0e5:piecei0ee"[..],
+            ),
+            (
+                MetadataMessage::Reject(2),
+                &b"d8:msg_typei2e5:piecei2ee"[..],
+            ),
+            (
+                MetadataMessage::Data {
+                    piece: 1,
+                    total_size: 16390,
+                    data: b"mock data".to_vec(),
+                },
+                &b"d8:msg_typei1e5:piecei1e10:total_sizei16390eemock data"[..],
+            ),
+        ] {
+            let buf = msg.encode();
+            assert!(buf == encoded, "{:?} == {:?}", buf, encoded);
+
+            let decoded = MetadataMessage::decode(&buf).unwrap();
+            assert!(decoded == msg, "{:?} == {:?}", decoded, msg);
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        for (buf, err) in [
+            (
+                &b"d8:msg_typei3e5:piecei0ee"[..],
+                errors::invalid_metadata(0),
+            ),
+            (
+                &b"d8:msg_typei0e5:piecei-1ee"[..],
+                errors::invalid_metadata(0),
+            ),
+            (
+                &b"d8:msg_typei0e5:piecei0eemock"[..],
+                errors::invalid_metadata(25),
+            ),
+            (
+                &b"d8:msg_typei1e5:piecei0e10:total_sizei-1ee"[..],
+                errors::invalid_metadata(0),
+            ),
+            (&b"li0ee"[..], errors::invalid_metadata(0)),
+        ] {
+            let result = MetadataMessage::decode(buf).unwrap_err();
+            assert!(result == err, "{:?}: {:?} == {:?}", buf, result, err);
+        }
+
+        // missing keys
+        assert!(MetadataMessage::decode(b"d5:piecei0ee").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei1e5:piecei0eemock").is_err());
+    }
+
+    #[test]
+    fn assemble() {
+        let info = info();
+        let hash = Sha1::digest(&info);
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&hash);
+
+        let mut asm = MetadataAssembler::new(info_hash, info.len() as u64).unwrap();
+        assert!(asm.piece_count() == 3, "{} == 3", asm.piece_count());
+
+        // pieces are sent through the wire format, out of order
+        for &index in &[2, 0, 1] {
+            let start = index as usize * METADATA_PIECE_LEN;
+            let end = std::cmp::min(start + METADATA_PIECE_LEN, info.len());
+            let msg = MetadataMessage::Data {
+                piece: index,
+                total_size: info.len() as u64,
+                data: info[start..end].to_vec(),
+            };
+
+            match MetadataMessage::decode(&msg.encode()).unwrap() {
+                MetadataMessage::Data { piece, data, .. } => asm.add_piece(piece, data).unwrap(),
+                m => panic!("{:?}", m),
+            }
+        }
+        assert!(asm.is_complete());
+        assert!(asm.missing().next().is_none());
+
+        let result = asm.finish().unwrap();
+        assert!(result == info);
+    }
+
+    #[test]
+    fn assemble_invalid() {
+        let info = info();
+        let mut asm = MetadataAssembler::new([0; 20], info.len() as u64).unwrap();
+
+        // wrong index or length
+        assert!(asm.add_piece(3, vec![0; 10]).is_err());
+        assert!(asm.add_piece(0, vec![0; 10]).is_err());
+        assert!(asm.add_piece(2, vec![0; METADATA_PIECE_LEN]).is_err());
+
+        assert!(asm
+            .add_piece(0, info[..METADATA_PIECE_LEN].to_vec())
+            .is_ok());
+        let missing: Vec<u32> = asm.missing().collect();
+        assert!(missing == [1, 2], "{:?} == [1, 2]", missing);
+        assert!(asm.finish().is_err());
+
+        // a hash mismatch drops every piece
+        for (i, piece) in info.chunks(METADATA_PIECE_LEN).enumerate() {
+            asm.add_piece(i as u32, piece.to_vec()).unwrap();
+        }
+        assert!(asm.finish().is_err());
+        assert!(asm.missing().count() == 3);
+
+        assert!(MetadataAssembler::new([0; 20], 0).is_err());
+        assert!(MetadataAssembler::new([0; 20], super::MAX_METADATA_LEN + 1).is_err());
+    }
+}
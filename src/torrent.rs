@@ -1,40 +1,42 @@
-/*
-//! TODO - module documentation
-extern crate time;
-extern crate hyper;
-
+//! Parse torrent files as described by [BEP 003](http://www.bittorrent.org/beps/bep_0003.html).
+use std::cmp;
 use std::collections;
+use std::convert::TryFrom;
 use std::fs;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::str;
 
-use error;
-use files;
-use bencode::{self, Benc};
+use crate::bencode::{self, Benc};
+use crate::error::{self, ResultExt};
+use crate::fetch::TorrentFetcher;
+#[cfg(feature = "http")]
+use crate::fetch::{FetchOptions, HttpFetcher};
+use crate::files;
+use crate::util;
 
 // Enum to represent a `File` or `Directory`
+#[derive(Debug)]
 pub enum FileOrDir {
     File(files::File),
     Directory(files::Directory),
 }
 
 // Tracker(s) to announce to
-type AnnounceList = Vec<String>;
+pub type AnnounceList = Vec<String>;
 
 fn announce_list(dict: &mut collections::HashMap<Vec<u8>, Benc>) -> Option<Vec<AnnounceList>> {
     // Torrent must have "announce" even if "announce-list" is found. Don't abort if "announce"
     // is not found, try "announce-list".
     // RFC - This is not BEP 003 compliant
-    let announce = match dict.remove(&b"announce"[..]) {
-        Some(Benc::String(s)) => String::from_utf8(s).ok().map(|s| vec![ vec![s] ]),
-        _                     => None,
-    };
+    let announce = bencode::take_text(dict, b"announce").map(|s| vec![vec![s]]);
 
     // try "announce-list", fall back to `announce` if any errors while parsing "announce-list"
-    let lists        = unwrap_opt!(Benc::List, dict.remove(&b"announce-list"[..]), announce);
+    let lists = unwrap_opt!(Benc::List, dict.remove(&b"announce-list"[..]), announce);
     let mut trackers = Vec::with_capacity(lists.len());
 
     for list in lists {
-        let list          = unwrap!(Benc::List, list, announce);
+        let list = unwrap!(Benc::List, list, announce);
         let mut announcer = Vec::with_capacity(list.len());
 
         for l in list {
@@ -47,9 +49,129 @@ fn announce_list(dict: &mut collections::HashMap<Vec<u8>, Benc>) -> Option<Vec<A
     Some(trackers)
 }
 
+/// Returns true if `url` uses a scheme trackers can be announced to over
+fn valid_tracker(url: &str) -> bool {
+    ["http://", "https://", "udp://"]
+        .iter()
+        .any(|scheme| matches!(url.get(..scheme.len()), Some(s) if s.eq_ignore_ascii_case(scheme)))
+}
+
+fn invalid_tracker() -> error::Error {
+    error::Error::new(error::Error::Other(
+        "Tracker URL must use http, https or udp",
+    ))
+}
+
+/// Collect web seed URLs from "httpseeds" ([BEP 017](
+/// http://www.bittorrent.org/beps/bep_0017.html)) and "url-list" ([BEP 019](
+/// http://www.bittorrent.org/beps/bep_0019.html)). Either key may hold a single string or a list
+/// of strings; malformed entries are skipped.
+fn web_seeds(dict: &mut collections::HashMap<Vec<u8>, Benc>) -> Vec<String> {
+    let mut seeds = Vec::new();
+
+    for key in &[&b"httpseeds"[..], &b"url-list"[..]] {
+        let urls = match dict.remove(*key) {
+            Some(Benc::List(l)) => l,
+            Some(s @ Benc::String(_)) => vec![s],
+            _ => continue,
+        };
+
+        seeds.extend(urls.into_iter().filter_map(|url| match url {
+            Benc::String(s) => String::from_utf8(s).ok(),
+            _ => None,
+        }));
+    }
+
+    seeds
+}
+
+/// Collect DHT bootstrap nodes from "nodes" ([BEP 005](
+/// http://www.bittorrent.org/beps/bep_0005.html)), a list of `[host, port]` pairs. Entries which
+/// are not a string and a valid port are skipped.
+fn dht_nodes(dict: &mut collections::HashMap<Vec<u8>, Benc>) -> Vec<(String, u16)> {
+    let list = match dict.remove(&b"nodes"[..]) {
+        Some(Benc::List(l)) => l,
+        _ => return Vec::new(),
+    };
+
+    list.into_iter()
+        .filter_map(|node| match node {
+            Benc::List(pair) => match pair.as_slice() {
+                [Benc::String(host), Benc::Int(port)] => {
+                    let host = String::from_utf8(host.clone()).ok()?;
+                    let port = u16::try_from(*port).ok()?;
+                    Some((host, port))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Remove the text under `key` from `dict`. A "<key>.utf-8" copy is preferred if it is valid
+/// UTF-8, otherwise the text is decoded with `encoding`. Text which can not be decoded falls back
+/// to its raw bytes, with invalid sequences replaced.
+fn take_text(
+    dict: &mut collections::HashMap<Vec<u8>, Benc>,
+    key: &[u8],
+    encoding: Option<&str>,
+) -> Option<String> {
+    match bencode::take_preferring_utf8(dict, key, encoding) {
+        (Some(Benc::String(raw)), encoding) => match util::decode(&raw, encoding) {
+            Some(text) => Some(text),
+            None => Some(String::from_utf8_lossy(&raw).into_owned()),
+        },
+        _ => None,
+    }
+}
+
+/// Read the torrent's name from the info dict without removing it, so `File` and `Directory` can
+/// still parse it. "name.utf-8" is preferred if it is valid UTF-8.
+fn info_name(
+    dict: &collections::HashMap<Vec<u8>, Benc>,
+    encoding: Option<&str>,
+) -> error::Result<String> {
+    if let Some(Benc::String(s)) = dict.get(&b"name.utf-8"[..]) {
+        if let Ok(name) = str::from_utf8(s) {
+            return Ok(name.to_owned());
+        }
+    }
+
+    let problem = match dict.get(&b"name"[..]) {
+        Some(Benc::String(s)) => match util::decode(s, encoding) {
+            Some(name) => return Ok(name),
+            None => error::KeyProblem::NotUtf8,
+        },
+        Some(n) => return Err(bencode::wrong_type(b"name", n)),
+        None => error::KeyProblem::Missing,
+    };
+    Err(error::Error::new(error::Error::Key {
+        key: b"name".to_vec(),
+        problem,
+    }))
+}
+
+/// Largest "piece length" accepted. Real torrents rarely use pieces larger than 16 MiB.
+const MAX_PIECE_LENGTH: u64 = 1 << 29;
+
+/// How strictly a torrent is checked while it is parsed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Reject torrents whose "piece length" is not a power of two. Every common client creates
+    /// power of two pieces, but the spec does not require it.
+    pub strict: bool,
+    /// Filename rules file and folder names are sanitized with
+    pub sanitize: files::SanitizeMode,
+}
+
 // UTF-8 encoded
 // TODO - Inline `Info` to `Torrent?
-struct Info {
+/// The torrent's "info" dict; its files and the hashes of its pieces
+#[derive(Debug)]
+pub struct Info {
+    /// Name of the file in single-file mode, or of the root directory in multi-file mode
+    name: String,
     /// Number of bytes in each piece
     piece_length: u64,
     /// SHA1 hashes mapped to each `piece_length` piece
@@ -61,40 +183,159 @@ struct Info {
 }
 
 impl Info {
-    fn from_dict(dict: &mut collections::HashMap<Vec<u8>, Benc>) -> Option<Info> {
-        let pieces    = unwrap_opt!(Benc::String, dict.remove(&b"pieces"[..]));
+    fn from_dict(
+        dict: &mut collections::HashMap<Vec<u8>, Benc>,
+        encoding: Option<&str>,
+        opts: &ParseOptions,
+    ) -> error::Result<Info> {
+        let pieces = bencode::take_bytes(dict, b"pieces")?;
         let piece_len = 20;
         if pieces.len() % piece_len != 0 {
-            return None;
+            return Err(error::Error::new(error::Error::Other(
+                "Invalid \"pieces\" length",
+            )));
         }
 
-        // "files" will only be present if torrent info is multi-file
-        let files = match dict.contains_key(&b"files"[..]) {
-            true  => FileOrDir::Directory(unwrap!(Some, files::Directory::from_dict(dict))),
-            false => FileOrDir::File(unwrap!(Some, files::File::from_dict(dict))),
+        let name = info_name(dict, encoding)?;
+
+        // "length" is only present in single-file mode, and "files" only in multi-file mode
+        let files = match (
+            dict.contains_key(&b"length"[..]),
+            dict.contains_key(&b"files"[..]),
+        ) {
+            (true, false) => {
+                FileOrDir::File(files::File::from_info_with(dict, encoding, opts.sanitize)?)
+            }
+            (false, true) => FileOrDir::Directory(files::Directory::from_dict_with(
+                dict,
+                encoding,
+                opts.sanitize,
+            )?),
+            (true, true) => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Both single-file length and multi-file files present",
+                )))
+            }
+            (false, false) => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Neither single-file length nor multi-file files present",
+                )))
+            }
+        };
+
+        let piece_length = match bencode::take_int(dict, b"piece length")? {
+            l if l < 0 => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Negative \"piece length\"",
+                )))
+            }
+            0 => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Zero \"piece length\"",
+                )))
+            }
+            l if l as u64 > MAX_PIECE_LENGTH => {
+                return Err(error::Error::new(error::Error::Other(
+                    "\"piece length\" is larger than 512 MiB",
+                )))
+            }
+            l if opts.strict && !(l as u64).is_power_of_two() => {
+                return Err(error::Error::new(error::Error::Other(
+                    "\"piece length\" is not a power of two",
+                )))
+            }
+            l => l as u64,
+        };
+
+        let info = Info {
+            name,
+            piece_length,
+            pieces,
+            private: dict.remove(&b"private"[..]) == Some(Benc::Int(1)),
+            files,
         };
 
-        let piece_length = unwrap_opt!(Benc::Int, dict.remove(&b"piece length"[..]));
-        if piece_length < 0 {
-            return None
+        // every piece but the last is full, so there is one piece per started `piece_length`
+        let total = info.total_length();
+        let expect = match total % piece_length {
+            0 => total / piece_length,
+            _ => total / piece_length + 1,
+        };
+        if (info.pieces.len() / 20) as u64 != expect {
+            return Err(error::Error::new(error::Error::Other(
+                "Number of \"pieces\" does not match the total length",
+            )));
+        }
+        // piece indexes are 32 bits on the wire
+        if expect > u64::from(u32::MAX) {
+            return Err(error::Error::new(error::Error::Other("Too many pieces")));
         }
 
-        Some(Info {
-            piece_length: piece_length as u64,
-            pieces:       pieces,
-            private:      dict.remove(&b"private"[..]) == Some(Benc::Int(1)),
-            files:        files,
-        })
+        Ok(info)
+    }
+
+    /// Number of bytes in each piece
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    /// Total size in bytes of the torrent's data; the length of the file in single-file mode, or
+    /// the sum of all file lengths in multi-file mode. This is the `left` value of a fresh
+    /// announce.
+    pub fn total_length(&self) -> u64 {
+        match self.files {
+            FileOrDir::File(ref f) => f.length(),
+            FileOrDir::Directory(ref d) => d.total_size(),
+        }
+    }
+
+    /// Number of pieces the torrent's data is split into
+    pub fn piece_count(&self) -> u32 {
+        // at most `u32::MAX`, as checked when parsed
+        (self.pieces.len() / 20) as u32
+    }
+
+    /// SHA1 hash of the piece at `index`
+    pub fn piece_hash(&self, index: u32) -> Option<&[u8]> {
+        if index >= self.piece_count() {
+            return None;
+        }
+
+        let start = index as usize * 20;
+        Some(&self.pieces[start..start + 20])
+    }
+
+    /// Size in bytes of the piece at `index`. Every piece is `piece_length` bytes except the last,
+    /// which holds whatever remains.
+    pub fn piece_size(&self, index: u32) -> Option<u64> {
+        if index >= self.piece_count() {
+            return None;
+        }
+
+        let start = self.piece_length.checked_mul(u64::from(index))?;
+        let remaining = self.total_length().checked_sub(start)?;
+        Some(cmp::min(self.piece_length, remaining))
     }
 }
 
+#[derive(Debug)]
 pub struct Torrent {
     /// URL(s) to announce to. If only "announce" is present this is essentially `[[Tracker]]`
     trackers: Vec<AnnounceList>,
     info: Info,
+    /// The info dict's bytes as they appeared in the torrent file
+    info_bytes: Vec<u8>,
+    /// SHA1 hash of `info_bytes`
+    info_hash: [u8; 20],
+    /// HTTP/FTP servers which serve the torrent's data
+    web_seeds: Vec<String>,
+    /// DHT nodes to bootstrap from, as `(host, port)`
+    nodes: Vec<(String, u16)>,
+    /// Character set used for text in the torrent, if it is not UTF-8
+    encoding: Option<String>,
 
     /// Date the torrent file was created in UNIX epoch
-    creation_date: Option<time::Tm>,
+    creation_date: Option<time::OffsetDateTime>,
     /// Name and version of program used to create the torrent
     created_by: Option<String>,
     comment: Option<String>,
@@ -112,90 +353,1563 @@ impl Torrent {
         }
     }
 
-    /// Try to create a Torrent from a stream of Bytes
-    fn read<R: Read>(r: &mut R) -> error::Result<Torrent> {
-        match Benc::new(&mut r.bytes()) {
-            Ok(mut n) =>
-                if n.is_empty() {
-                    Err(error::Error::Other("No bencode nodes"))
-                } else {
-                    Torrent::from_benc(n.swap_remove(0))
-                },
-            Err(e) => Err(e),
+    /// Parse a torrent from any reader, such as a socket or an in-memory buffer. Only the first
+    /// bencoded value is used.
+    pub fn from_reader<R: Read>(r: &mut R) -> error::Result<Torrent> {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            return Err(error::Error::new(error::Error::Other("No bencode nodes")));
         }
+
+        let (node, spans) = Benc::new_with_spans(&buf)?;
+        Torrent::from_spans(node, &buf, &spans, &ParseOptions::default())
+    }
+
+    /// Parse a torrent held in memory. `buf` must hold exactly one bencoded dictionary; if
+    /// anything follows it `Error::TrailingData` is returned.
+    pub fn from_bytes(buf: &[u8]) -> error::Result<Torrent> {
+        Torrent::from_bytes_with(buf, &ParseOptions::default())
+    }
+
+    /// Parse a torrent held in memory, checked as set in `opts`. See `from_bytes`.
+    pub fn from_bytes_with(buf: &[u8], opts: &ParseOptions) -> error::Result<Torrent> {
+        let (node, spans) = Benc::new_with_spans(buf)?;
+        if spans.len < buf.len() {
+            return Err(error::Error::new(error::Error::TrailingData {
+                offset: spans.len,
+            }));
+        }
+
+        Torrent::from_spans(node, buf, &spans, opts)
+    }
+
+    /// Create a Torrent from `node`, decoded from `buf`. The bytes of the info dict are kept from
+    /// `buf`, rather than re-encoded, and hashed for the info-hash.
+    fn from_spans(
+        node: Benc,
+        buf: &[u8],
+        spans: &bencode::Spans,
+        opts: &ParseOptions,
+    ) -> error::Result<Torrent> {
+        let info_bytes = spans.info.clone().map(|r| buf[r].to_vec());
+
+        Torrent::from_benc_raw(node, info_bytes, opts)
     }
 
     /// Open and parse a local file to create a Torrent
     fn new_file(filename: &str) -> error::Result<Torrent> {
-        let mut f = try!(fs::File::open(filename));
+        let mut f = fs::File::open(filename)?;
 
-        Torrent::read(&mut f)
+        Torrent::from_reader(&mut f)
     }
 
     /// Open and parse a torrent file from a URL to create a Torrent
+    #[cfg(feature = "http")]
     fn new_url(url: &str) -> error::Result<Torrent> {
+        Torrent::from_url(url, &FetchOptions::default())
+    }
+
+    #[cfg(not(feature = "http"))]
+    fn new_url(_url: &str) -> error::Result<Torrent> {
+        Err(error::Error::new(error::Error::Other(
+            "Downloading torrents requires the `http` feature, see `Torrent::new_url_with`",
+        )))
+    }
+
+    /// Download and parse a torrent file with the built-in HTTP client
+    #[cfg(feature = "http")]
+    pub fn from_url(url: &str, opts: &FetchOptions) -> error::Result<Torrent> {
         // TODO - Consider using a global pool?
-        let client = hyper::client::Client::new();
-        let mut res = match client.get(url).send() {
-            Ok(r)  => r,
-            Err(e) => match e {
-                // TODO - Lossless errors?
-                hyper::error::Error::Io(e) => return Err(error::Error::from(e)),
-                _ => return Err(error::Error::Other("Could not download torrent")),
-            },
-        };
+        Torrent::new_url_with(url, &HttpFetcher::new(opts.clone())?)
+    }
+
+    /// Download a torrent file with `fetcher` and parse it
+    pub fn new_url_with(url: &str, fetcher: &dyn TorrentFetcher) -> error::Result<Torrent> {
+        let body = fetcher.fetch(url)?;
 
-        Torrent::read(&mut res)
+        Torrent::from_reader(&mut &body[..])
     }
 
     /// Open and parse a magnet link to create a Torrent
-    fn new_magnet(magnet: &str) -> error::Result<Torrent> {
+    fn new_magnet(_magnet: &str) -> error::Result<Torrent> {
         // TODO - Add magnet support
-        unimplemented!()
+        Err(error::Error::new(error::Error::Other(
+            "Magnet links are not supported",
+        )))
+    }
+
+    /// Create a Torrent from Benc nodes. The original bytes are gone, so the info-hash is found
+    /// by re-encoding the info dict; prefer `from_bytes` if the torrent may not be canonically
+    /// encoded.
+    pub fn from_benc(nodes: bencode::Benc) -> error::Result<Torrent> {
+        Torrent::from_benc_with(nodes, &ParseOptions::default())
     }
 
-    /// Create a Torrent from Benc nodes
-    fn from_benc(nodes: bencode::Benc) -> error::Result<Torrent> {
+    /// Create a Torrent from Benc nodes, checked as set in `opts`. See `from_benc`.
+    pub fn from_benc_with(nodes: bencode::Benc, opts: &ParseOptions) -> error::Result<Torrent> {
+        Torrent::from_benc_raw(nodes, None, opts)
+    }
+
+    /// Create a Torrent from Benc nodes. If the info dict's original bytes, `info_bytes`, are not
+    /// known it is re-encoded.
+    fn from_benc_raw(
+        nodes: bencode::Benc,
+        info_bytes: Option<Vec<u8>>,
+        opts: &ParseOptions,
+    ) -> error::Result<Torrent> {
         let mut dict = match nodes {
             Benc::Dict(d) => d,
-            _             => return Err(error::Error::Other("Dictionary not found")),
+            _ => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Dictionary not found",
+                )))
+            }
         };
 
+        // trackerless torrents list DHT nodes instead
+        let nodes = dht_nodes(&mut dict);
         let trackers = match announce_list(&mut dict) {
             Some(t) => t,
-            None    => return Err(error::Error::Other("Announcers not found")),
+            None if !nodes.is_empty() => Vec::new(),
+            None => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Announcers not found",
+                )))
+            }
         };
 
-        let info = match Info::from_dict(&mut dict) {
-            Some(t) => t,
-            None    => return Err(error::Error::Other("Info not found")),
-        };
+        let encoding = bencode::take_text(&mut dict, b"encoding");
 
-        let creation_date = match dict.remove(&b"creation_date"[..]) {
-            Some(Benc::Int(t)) => Some(time::at_utc(time::Timespec::new(t, 0))),
-            _                  => None,
+        let (info, info_bytes) = match dict.remove(&b"info"[..]) {
+            Some(info @ Benc::Dict(_)) => {
+                let info_bytes = match info_bytes {
+                    Some(b) => b,
+                    None => info.encode(),
+                };
+                let mut d = info.into_dict().unwrap_or_default();
+                let info =
+                    Info::from_dict(&mut d, encoding.as_deref(), opts).context("info dict")?;
+                (info, info_bytes)
+            }
+            _ => return Err(error::Error::new(error::Error::Other("Info not found"))),
         };
 
-        let created_by = match dict.remove(&b"created_by"[..]) {
-            Some(Benc::String(s)) => String::from_utf8(s).ok(),
-            _                     => None,
+        let creation_date = match dict.remove(&b"creation date"[..]) {
+            Some(Benc::Int(t)) => time::OffsetDateTime::from_unix_timestamp(t).ok(),
+            _ => None,
         };
 
-        let comment = match dict.remove(&b"comment"[..]) {
-            Some(Benc::String(s)) => String::from_utf8(s).ok(),
-            _                     => None,
-        };
+        let created_by = take_text(&mut dict, b"created by", encoding.as_deref());
+
+        let comment = take_text(&mut dict, b"comment", encoding.as_deref());
 
         Ok(Torrent {
-            trackers: trackers,
-            info:     info,
+            trackers,
+            info,
+            info_hash: util::sha1(&info_bytes),
+            info_bytes,
+            web_seeds: web_seeds(&mut dict),
+            nodes,
+            encoding,
 
-            creation_date: creation_date,
-            created_by:    created_by,
-            comment:       comment,
+            creation_date,
+            created_by,
+            comment,
         })
     }
+
+    /// SHA1 hash of the info dict, which identifies the torrent to trackers and peers
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// Encode the torrent as the contents of a .torrent file. The info dict is written as the
+    /// bytes it was parsed from, so the info-hash does not change; everything else is encoded
+    /// canonically from what was parsed. Keys this library does not read are not kept, and web
+    /// seeds are all written to "url-list".
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // values already encoded, in key order
+        let mut dict = collections::BTreeMap::new();
+        let mut put = |key: &str, val: Benc| dict.insert(key.as_bytes().to_vec(), val.encode());
+
+        if let Some(url) = self.trackers.iter().flatten().next() {
+            put("announce", Benc::from(url.as_str()));
+        }
+        if self.trackers.iter().flatten().nth(1).is_some() {
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|tier| Benc::List(tier.iter().map(|url| Benc::from(url.as_str())).collect()));
+            put("announce-list", Benc::List(tiers.collect()));
+        }
+        if !self.web_seeds.is_empty() {
+            let seeds = self.web_seeds.iter().map(|url| Benc::from(url.as_str()));
+            put("url-list", Benc::List(seeds.collect()));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self.nodes.iter().map(|(host, port)| {
+                Benc::List(vec![Benc::from(host.as_str()), Benc::Int(i64::from(*port))])
+            });
+            put("nodes", Benc::List(nodes.collect()));
+        }
+        if let Some(ref encoding) = self.encoding {
+            put("encoding", Benc::from(encoding.as_str()));
+        }
+        if let Some(date) = self.creation_date {
+            put("creation date", Benc::Int(date.unix_timestamp()));
+        }
+
+        // text was decoded to UTF-8, which readers prefer from a ".utf-8" key
+        let suffix = if self.encoding.is_some() {
+            ".utf-8"
+        } else {
+            ""
+        };
+        for (key, text) in [("created by", &self.created_by), ("comment", &self.comment)] {
+            if let Some(text) = text {
+                put(&format!("{}{}", key, suffix), Benc::from(text.as_str()));
+            }
+        }
+
+        dict.insert(b"info".to_vec(), self.info_bytes.clone());
+
+        let mut out = vec![b'd'];
+        for (key, val) in dict {
+            out.extend_from_slice(&Benc::from(key).encode());
+            out.extend_from_slice(&val);
+        }
+        out.push(b'e');
+        out
+    }
+
+    /// Write the torrent to `w` as a .torrent file. See `to_bytes`.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> error::Result<()> {
+        w.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Display name of the torrent, taken from the info dict's "name". This is the file name in
+    /// single-file torrents and the root directory name in multi-file torrents.
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
+
+    /// Total size in bytes of all files in the torrent
+    pub fn total_length(&self) -> u64 {
+        self.info.total_length()
+    }
+
+    /// Every file in the torrent, in the order they are declared. A single file torrent holds
+    /// just the one file. Padding files are left out; they still take up space in pieces, so
+    /// `total_length` counts them.
+    pub fn files(&self) -> Vec<&files::File> {
+        match self.info.files {
+            FileOrDir::File(ref f) => vec![f],
+            FileOrDir::Directory(ref d) => d.iter_visible().collect(),
+        }
+    }
+
+    /// Number of bytes in each piece
+    pub fn piece_length(&self) -> u64 {
+        self.info.piece_length
+    }
+
+    /// The torrent's "info" dict, e.g. to recheck files with `Directory::verify_all`
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    /// Number of pieces the torrent's data is split into
+    pub fn piece_count(&self) -> u32 {
+        self.info.piece_count()
+    }
+
+    /// SHA1 hash of the piece at `index`
+    pub fn piece_hash(&self, index: u32) -> Option<&[u8]> {
+        self.info.piece_hash(index)
+    }
+
+    /// Size in bytes of the piece at `index`; only the last piece may be shorter than
+    /// `piece_length`
+    pub fn piece_size(&self, index: u32) -> Option<u64> {
+        self.info.piece_size(index)
+    }
+
+    /// Returns true if the torrent is private ([BEP 027](
+    /// http://www.bittorrent.org/beps/bep_0027.html)). Only the integer `1` marks a torrent as
+    /// private, any other value or a missing "private" key does not.
+    ///
+    /// Peers of a private torrent must only be obtained from the trackers in the .torrent file; a
+    /// client must not use DHT, PEX, or LPD for it.
+    pub fn is_private(&self) -> bool {
+        self.info.private
+    }
+
+    /// Tiers of trackers to announce to
+    pub fn trackers(&self) -> &[AnnounceList] {
+        &self.trackers
+    }
+
+    /// Iterate over every tracker URL, tier by tier
+    pub fn iter_trackers(&self) -> impl Iterator<Item = &str> {
+        self.trackers.iter().flatten().map(|t| &t[..])
+    }
+
+    /// Add `url` to the tier at index `tier`, or to a new last tier if there is no such tier. A
+    /// URL already in any tier is not added again. Fails if `url` is not an http, https or udp
+    /// URL.
+    pub fn add_tracker(&mut self, url: String, tier: usize) -> error::Result<()> {
+        if !valid_tracker(&url) {
+            return Err(invalid_tracker());
+        }
+        if self.iter_trackers().any(|t| t == url) {
+            return Ok(());
+        }
+
+        match self.trackers.get_mut(tier) {
+            Some(t) => t.push(url),
+            None => self.trackers.push(vec![url]),
+        }
+        Ok(())
+    }
+
+    /// Replace every tracker with `trackers`. Empty tiers, and URLs already in an earlier tier,
+    /// are dropped. Fails without changing anything if any URL is not http, https or udp.
+    pub fn set_trackers(&mut self, trackers: Vec<AnnounceList>) -> error::Result<()> {
+        if !trackers.iter().flatten().all(|url| valid_tracker(url)) {
+            return Err(invalid_tracker());
+        }
+
+        let mut seen = collections::HashSet::new();
+        self.trackers = trackers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .filter(|url| seen.insert(url.clone()))
+                    .collect::<AnnounceList>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        Ok(())
+    }
+
+    /// Randomize the order of trackers within each tier, as described by [BEP 012](
+    /// http://www.bittorrent.org/beps/bep_0012.html). The order of the tiers is unchanged.
+    pub fn shuffle_tiers(&mut self) {
+        for tier in &mut self.trackers {
+            util::shuffle(tier);
+        }
+    }
+
+    /// URLs of HTTP servers which can serve the torrent's data, see BEP 017 and BEP 019
+    pub fn web_seeds(&self) -> &[String] {
+        &self.web_seeds
+    }
+
+    /// Download piece `piece_index` from the torrent's web seeds, see BEP 019. Seeds are tried
+    /// in order until one returns the whole piece and it matches its hash.
+    pub fn fetch_from_web_seed(
+        &self,
+        piece_index: u32,
+        fetcher: &dyn TorrentFetcher,
+    ) -> error::Result<Vec<u8>> {
+        let size = match self.piece_size(piece_index) {
+            Some(size) => size,
+            None => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Invalid piece index",
+                )))
+            }
+        };
+        // the piece is held in memory, which may not be possible on 32-bit platforms
+        if usize::try_from(size).is_err() {
+            return Err(error::Error::new(error::Error::Other(
+                "Piece is too large to fit in memory",
+            )));
+        }
+
+        let mut err = error::Error::new(error::Error::Other("No web seeds"));
+        for seed in &self.web_seeds {
+            match self.fetch_piece(seed, piece_index, fetcher) {
+                Ok(piece) => return Ok(piece),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+
+    /// Download a piece from a single web seed
+    fn fetch_piece(
+        &self,
+        seed: &str,
+        index: u32,
+        fetcher: &dyn TorrentFetcher,
+    ) -> error::Result<Vec<u8>> {
+        let mut piece = Vec::new();
+        for (url, range) in self.web_seed_ranges(seed, index) {
+            let len = range.end - range.start;
+            let url = match url {
+                Some(url) => url,
+                None => {
+                    piece.resize(piece.len() + len as usize, 0);
+                    continue;
+                }
+            };
+            let block = fetcher.fetch_range(&url, range)?;
+            if block.len() as u64 != len {
+                return Err(error::Error::new(error::Error::Other(
+                    "Web seed returned the wrong number of bytes",
+                )));
+            }
+            piece.extend_from_slice(&block);
+        }
+
+        match self.piece_hash(index) {
+            Some(hash) if hash == util::sha1(&piece) => Ok(piece),
+            _ => Err(error::Error::new(error::Error::Other(
+                "Piece from web seed does not match its hash",
+            ))),
+        }
+    }
+
+    /// URLs and byte ranges to request from `seed` for piece `index`. A seed ending in `/` is a
+    /// folder holding the torrent's files; otherwise a single file torrent's seed is the file.
+    /// URLs are built from the names in the torrent, not from where the files are stored.
+    /// Padding files are not on the seed; their ranges have no URL and hold zeros.
+    fn web_seed_ranges(&self, seed: &str, index: u32) -> Vec<(Option<String>, Range<u64>)> {
+        let mut base = seed.to_owned();
+        let piece_length = self.info.piece_length;
+
+        match self.info.files {
+            FileOrDir::File(ref f) => {
+                if base.ends_with('/') {
+                    base.push_str(&util::url_encode(&self.info.name));
+                }
+                let start = u64::from(index).saturating_mul(piece_length);
+                let end = cmp::min(start.saturating_add(piece_length), f.length());
+                vec![(Some(base), start..end)]
+            }
+            FileOrDir::Directory(ref d) => {
+                if !base.ends_with('/') {
+                    base.push('/');
+                }
+                base.push_str(&util::url_encode(&self.info.name));
+
+                d.piece_ranges(piece_length, self.total_length(), index)
+                    .into_iter()
+                    .filter_map(|s| {
+                        let f = d.file(s.file_index)?;
+                        let range = s.offset..s.offset + s.len;
+                        if f.is_padding() {
+                            return Some((None, range));
+                        }
+                        let mut url = base.clone();
+                        for c in f.torrent_path() {
+                            url.push('/');
+                            url.push_str(&util::url_encode(c));
+                        }
+                        Some((Some(url), range))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// DHT nodes to bootstrap from as `(host, port)`, see BEP 005. Trackerless torrents list
+    /// these in place of trackers.
+    pub fn nodes(&self) -> &[(String, u16)] {
+        &self.nodes
+    }
+
+    /// Character set declared by the "encoding" key. Names, "comment", and "created by" are
+    /// transcoded from it, which requires the `encoding` feature for charsets other than UTF-8.
+    pub fn encoding(&self) -> Option<&str> {
+        self.encoding.as_deref()
+    }
+
+    /// Date the torrent file was created
+    pub fn creation_date(&self) -> Option<time::OffsetDateTime> {
+        self.creation_date
+    }
+
+    /// Name and version of program used to create the torrent
+    pub fn created_by(&self) -> Option<&str> {
+        self.created_by.as_deref()
+    }
+
+    /// Free-form comment left by the torrent's author
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
 }
 
 // TODO - torrent::builder
-*/
+
+#[cfg(test)]
+mod test_torrent {
+    use std::borrow::ToOwned;
+    use std::io;
+
+    use sha1::{Digest, Sha1};
+
+    use super::{dht_nodes, web_seeds, Info, ParseOptions, Torrent};
+    use crate::bencode::Benc as B;
+    use crate::error::{Error, KeyProblem};
+
+    macro_rules! hashmap {
+        ($($k:expr => $v:expr),*) => ({
+            let mut d = ::std::collections::HashMap::new();
+            $(d.insert($k, $v);)*
+            d
+        });
+
+        ($($k:expr => $v:expr),+,) => (hashmap!($($k => $v),+));
+    }
+
+    macro_rules! bytes {
+        ($s:expr) => {
+            $s.to_owned().into_bytes()
+        };
+    }
+
+    #[test]
+    fn web_seeds_list() {
+        let mut dict = hashmap!(
+            bytes!("httpseeds") => B::List(vec![
+                B::String(bytes!("http://direct.example.com/mock1")),
+                B::String(bytes!("http://direct.example.com/mock2")),
+            ]),
+            bytes!("url-list") => B::List(vec![
+                B::String(bytes!("http://mirror.example.com/")),
+                B::Int(42),
+                B::String(vec![0xff, 0xfe]),
+            ]),
+        );
+
+        assert!(
+            web_seeds(&mut dict)
+                == vec![
+                    "http://direct.example.com/mock1",
+                    "http://direct.example.com/mock2",
+                    "http://mirror.example.com/",
+                ]
+        );
+        assert!(dict.is_empty());
+    }
+
+    #[test]
+    fn web_seeds_string() {
+        let mut dict = hashmap!(
+            bytes!("url-list") => B::String(bytes!("http://mirror.example.com/")),
+        );
+        assert!(web_seeds(&mut dict) == vec!["http://mirror.example.com/"]);
+
+        let mut dict = hashmap!(bytes!("url-list") => B::Int(42));
+        assert!(web_seeds(&mut dict).is_empty());
+
+        assert!(web_seeds(&mut ::std::collections::HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn nodes_list() {
+        let node = |host: &str, port: i64| B::List(vec![B::String(bytes!(host)), B::Int(port)]);
+        let mut dict = hashmap!(
+            bytes!("nodes") => B::List(vec![
+                node("router.example.com", 6881),
+                B::List(vec![B::Int(6881), B::String(bytes!("router.example.com"))]),
+                node("127.0.0.1", 65536),
+                B::List(vec![B::String(bytes!("127.0.0.1"))]),
+                B::String(bytes!("127.0.0.1")),
+            ]),
+        );
+
+        let nodes = dht_nodes(&mut dict);
+        assert!(
+            nodes == vec![(String::from("router.example.com"), 6881)],
+            "{:?}",
+            nodes
+        );
+        assert!(dict.is_empty());
+    }
+
+    #[test]
+    fn trackerless() {
+        let trackerless = || match torrent(vec![("files", B::List(vec![file(42)]))]) {
+            B::Dict(mut d) => {
+                d.remove(&bytes!("announce"));
+                d
+            }
+            _ => unreachable!(),
+        };
+        assert!(Torrent::from_benc(B::Dict(trackerless())).is_err());
+
+        let mut dict = trackerless();
+
+        dict.insert(
+            bytes!("nodes"),
+            B::List(vec![B::List(vec![
+                B::String(bytes!("127.0.0.1")),
+                B::Int(6881),
+            ])]),
+        );
+        let t = Torrent::from_benc(B::Dict(dict)).unwrap();
+        assert!(t.trackers().is_empty());
+        assert!(t.nodes() == [(String::from("127.0.0.1"), 6881)]);
+    }
+
+    fn torrent(info: Vec<(&str, B)>) -> B {
+        let mut dict = hashmap!(
+            bytes!("name")         => B::String(bytes!("mock")),
+            bytes!("piece length") => B::Int(16384),
+            bytes!("pieces")       => B::String(vec![0; 20]),
+        );
+        dict.extend(info.into_iter().map(|(k, v)| (bytes!(k), v)));
+
+        B::Dict(hashmap!(
+            bytes!("announce") => B::String(bytes!("http://tracker.example.com:8080/announce")),
+            bytes!("info")     => B::Dict(dict),
+        ))
+    }
+
+    fn file(len: i64) -> B {
+        B::Dict(hashmap!(
+            bytes!("path")   => B::List(vec![B::String(bytes!("file.ext"))]),
+            bytes!("length") => B::Int(len),
+        ))
+    }
+
+    /// Info of a single file torrent of 42 bytes, for `torrent`
+    fn single() -> Vec<(&'static str, B)> {
+        vec![
+            ("name", B::String(bytes!("file.ext"))),
+            ("length", B::Int(42)),
+        ]
+    }
+
+    #[test]
+    fn total_length() {
+        // 2^20 of the largest pieces
+        let single = torrent(vec![
+            ("name", B::String(bytes!("file.ext"))),
+            ("length", B::Int(562949953421312)),
+            ("piece length", B::Int(1 << 29)),
+            ("pieces", B::String(vec![0; 20 << 20])),
+        ]);
+        let t = Torrent::from_benc(single).unwrap();
+        assert!(t.total_length() == 562949953421312);
+
+        let multi = torrent(vec![("files", B::List(vec![file(3), file(0), file(10)]))]);
+        let t = Torrent::from_benc(multi).unwrap();
+        assert!(t.total_length() == 13, "{} == 13", t.total_length());
+    }
+
+    #[test]
+    fn files() {
+        let single = torrent(vec![
+            ("name", B::String(bytes!("file.ext"))),
+            ("length", B::Int(42)),
+        ]);
+        let t = Torrent::from_benc(single).unwrap();
+        let files = t.files();
+        assert!(files.len() == 1, "{} == 1", files.len());
+        assert!(
+            files[0].name() == "file.ext",
+            "{} == file.ext",
+            files[0].name()
+        );
+        assert!(files[0].length() == 42, "{} == 42", files[0].length());
+
+        let named = |name: &str, len| {
+            B::Dict(hashmap!(
+                bytes!("path")   => B::List(vec![
+                    B::String(bytes!("dir")),
+                    B::String(bytes!(name)),
+                ]),
+                bytes!("length") => B::Int(len),
+            ))
+        };
+        let multi = torrent(vec![(
+            "files",
+            B::List(vec![named("c", 3), named("a", 0), named("b", 10)]),
+        )]);
+        let t = Torrent::from_benc(multi).unwrap();
+        let files: Vec<_> = t.files().iter().map(|f| (f.name(), f.length())).collect();
+        let expected = vec![("dir/c", 3), ("dir/a", 0), ("dir/b", 10)];
+        assert!(files == expected, "{:?} == {:?}", files, expected);
+    }
+
+    #[test]
+    fn files_padding() {
+        let pad = B::Dict(hashmap!(
+            bytes!("path")   => B::List(vec![
+                B::String(bytes!(".pad")),
+                B::String(bytes!("16381")),
+            ]),
+            bytes!("length") => B::Int(16381),
+            bytes!("attr")   => B::String(bytes!("p")),
+        ));
+        let multi = torrent(vec![
+            ("files", B::List(vec![file(3), pad, file(10)])),
+            ("pieces", B::String(vec![0; 40])),
+        ]);
+        let t = Torrent::from_benc(multi).unwrap();
+        let lengths: Vec<_> = t.files().iter().map(|f| f.length()).collect();
+        assert!(lengths == vec![3, 10], "{:?} == [3, 10]", lengths);
+        // the padding still fills the rest of the first piece
+        assert!(t.total_length() == 16394, "{} == 16394", t.total_length());
+        assert!(t.piece_count() == 2);
+    }
+
+    #[test]
+    fn name() {
+        let single = torrent(vec![
+            ("name", B::String(bytes!("file.ext"))),
+            ("length", B::Int(42)),
+        ]);
+        let t = Torrent::from_benc(single).unwrap();
+        assert!(t.name() == "file.ext", "{} == file.ext", t.name());
+
+        let multi = torrent(vec![("files", B::List(vec![file(3)]))]);
+        let t = Torrent::from_benc(multi).unwrap();
+        assert!(t.name() == "mock", "{} == mock", t.name());
+
+        let utf8 = torrent(vec![
+            ("name", B::String(vec![0x82, 0xa0, 0xff])),
+            ("name.utf-8", B::String(bytes!("あいえおう"))),
+            ("files", B::List(vec![file(3)])),
+        ]);
+        let t = Torrent::from_benc(utf8).unwrap();
+        assert!(t.name() == "あいえおう", "{} == あいえおう", t.name());
+
+        let invalid = torrent(vec![
+            ("name", B::String(vec![0xff])),
+            ("files", B::List(vec![file(3)])),
+        ]);
+        assert!(Torrent::from_benc(invalid).is_err());
+    }
+
+    #[test]
+    fn pieces() {
+        let mut pieces = vec![1; 20];
+        pieces.extend(vec![2; 20]);
+        let t = Torrent::from_benc(torrent(vec![
+            ("name", B::String(bytes!("file.ext"))),
+            ("length", B::Int(16384 + 100)),
+            ("pieces", B::String(pieces)),
+        ]))
+        .unwrap();
+
+        assert!(t.piece_length() == 16384);
+        assert!(t.piece_count() == 2, "{} == 2", t.piece_count());
+        assert!(t.piece_hash(0) == Some(&[1; 20][..]));
+        assert!(t.piece_hash(1) == Some(&[2; 20][..]));
+        assert!(t.piece_hash(2).is_none());
+        assert!(t.piece_hash(u32::MAX).is_none());
+
+        assert!(t.piece_size(0) == Some(16384));
+        assert!(t.piece_size(1) == Some(100), "{:?}", t.piece_size(1));
+        assert!(t.piece_size(2).is_none());
+    }
+
+    #[test]
+    fn from_reader() {
+        let data = with_entries(single(), vec![("comment", B::String(bytes!("mock")))]).encode();
+
+        let t = Torrent::from_reader(&mut io::Cursor::new(&data)).unwrap();
+        assert!(t.total_length() == 42);
+        assert!(t.comment() == Some("mock"));
+        assert!(t
+            .iter_trackers()
+            .eq(vec!["http://tracker.example.com:8080/announce"]));
+
+        let t = Torrent::from_reader(&mut &data[..]).unwrap();
+        assert!(t.piece_count() == 1);
+
+        assert!(Torrent::from_reader(&mut io::Cursor::new("")).is_err());
+        assert!(Torrent::from_reader(&mut io::Cursor::new("i42e")).is_err());
+        assert!(Torrent::from_reader(&mut &data[..40]).is_err());
+    }
+
+    #[test]
+    fn info_hash() {
+        // "source" is not used by `Info`, but is still part of the hash
+        let info = concat!(
+            "d6:lengthi42e4:name8:file.ext12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa",
+            "7:privatei1e6:sourcei3ee"
+        );
+        let mut fields = single();
+        fields.extend(vec![
+            ("pieces", B::String(vec![b'a'; 20])),
+            ("private", B::Int(1)),
+            ("source", B::Int(3)),
+        ]);
+        let data = torrent(fields).encode();
+        let expect = [
+            0x6d, 0x1e, 0xe8, 0xc3, 0xb1, 0x35, 0xb2, 0x26, 0x92, 0x44, 0x60, 0x29, 0x56, 0x6e,
+            0x46, 0xc1, 0x04, 0x70, 0x12, 0x17,
+        ];
+
+        let (_, spans) = B::new_with_spans(&data).unwrap();
+        let span = spans.info.unwrap();
+        assert!(&data[span.clone()] == info.as_bytes(), "{:?}", span);
+        assert!(Sha1::digest(&data[span])[..] == expect[..]);
+
+        let t = Torrent::from_bytes(&data).unwrap();
+        assert!(
+            t.info_hash() == expect,
+            "{:?} == {:?}",
+            t.info_hash(),
+            expect
+        );
+        let t = Torrent::from_reader(&mut &data[..]).unwrap();
+        assert!(
+            t.info_hash() == expect,
+            "{:?} == {:?}",
+            t.info_hash(),
+            expect
+        );
+    }
+
+    #[test]
+    fn to_bytes() {
+        // canonical, and only keys which are kept
+        let mut info = single();
+        info.push(("source", B::Int(3)));
+        let tier = |urls: &[&str]| B::List(urls.iter().map(|u| B::String(bytes!(*u))).collect());
+        let data = with_entries(
+            info,
+            vec![
+                (
+                    "announce-list",
+                    B::List(vec![
+                        tier(&["http://tracker.example.com:8080/announce"]),
+                        tier(&["udp://backup.example.com:6969"]),
+                    ]),
+                ),
+                ("comment", B::String(bytes!("mock"))),
+                ("created by", B::String(bytes!("mock"))),
+                ("creation date", B::Int(1234567890)),
+                (
+                    "nodes",
+                    B::List(vec![B::List(vec![
+                        B::String(bytes!("127.0.0.1")),
+                        B::Int(6881),
+                    ])]),
+                ),
+                ("url-list", tier(&["http://seed.example.com/"])),
+            ],
+        )
+        .encode();
+        let t = Torrent::from_bytes(&data).unwrap();
+        let out = t.to_bytes();
+        assert!(out == data, "{}", String::from_utf8_lossy(&out));
+
+        let mut written = Vec::new();
+        t.write_to(&mut written).unwrap();
+        assert!(written == out);
+
+        // unknown keys are dropped, but the info dict is untouched
+        let data = with_entries(single(), vec![("unknown", B::Int(1))]).encode();
+        let t = Torrent::from_bytes(&data).unwrap();
+        let out = t.to_bytes();
+        assert!(out == torrent(single()).encode());
+        let t2 = Torrent::from_bytes(&out).unwrap();
+        assert!(t2.info_hash() == t.info_hash());
+
+        // text is written as UTF-8 when the torrent has another encoding
+        let data = with_entries(
+            single(),
+            vec![
+                ("comment", B::String(bytes!("mock"))),
+                ("encoding", B::String(bytes!("UTF-8"))),
+            ],
+        )
+        .encode();
+        let out = Torrent::from_bytes(&data).unwrap().to_bytes();
+        let t = Torrent::from_bytes(&out).unwrap();
+        assert!(t.comment() == Some("mock"), "{:?}", t.comment());
+        assert!(String::from_utf8_lossy(&out).contains("13:comment.utf-84:mock"));
+    }
+
+    #[test]
+    fn from_bytes() {
+        let data = torrent(single()).encode();
+
+        let t = Torrent::from_bytes(&data).unwrap();
+        assert!(t.total_length() == 42);
+
+        let mut trailing = data.clone();
+        trailing.extend_from_slice(b"i0e");
+        let err = Torrent::from_bytes(&trailing).unwrap_err();
+        let expect = Error::TrailingData { offset: data.len() };
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+
+        assert!(Torrent::from_bytes(b"").is_err());
+        assert!(Torrent::from_bytes(b"i42e").is_err());
+        assert!(Torrent::from_bytes(&data[..40]).is_err());
+    }
+
+    /// `torrent` with `entries` added to the top-level dict
+    fn with_entries(info: Vec<(&str, B)>, entries: Vec<(&str, B)>) -> B {
+        match torrent(info) {
+            B::Dict(mut d) => {
+                d.extend(entries.into_iter().map(|(k, v)| (bytes!(k), v)));
+                B::Dict(d)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn text_fields() {
+        let t = Torrent::from_benc(with_entries(
+            vec![("files", B::List(vec![file(3)]))],
+            vec![
+                ("comment", B::String(vec![0x82, 0xa0])),
+                ("comment.utf-8", B::String(bytes!("あ"))),
+                ("created by", B::String(b"mock\xff".to_vec())),
+            ],
+        ))
+        .unwrap();
+
+        // the UTF-8 copy is preferred, and invalid text keeps its bytes
+        assert!(t.comment() == Some("あ"), "{:?}", t.comment());
+        let created_by = t.created_by();
+        assert!(created_by == Some("mock\u{fffd}"), "{:?}", created_by);
+        assert!(t.encoding().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn shift_jis() {
+        // "あいえおう" in Shift_JIS
+        let sjis = b"\x82\xa0\x82\xa2\x82\xa6\x82\xa8\x82\xa4".to_vec();
+        let t = Torrent::from_benc(with_entries(
+            vec![
+                ("name", B::String(sjis.clone())),
+                ("files", B::List(vec![file(3)])),
+            ],
+            vec![
+                ("encoding", B::String(bytes!("Shift_JIS"))),
+                ("comment", B::String(sjis.clone())),
+                ("created by", B::String(sjis)),
+            ],
+        ))
+        .unwrap();
+
+        assert!(t.encoding() == Some("Shift_JIS"));
+        assert!(t.name() == "あいえおう", "{} == あいえおう", t.name());
+        assert!(t.comment() == Some("あいえおう"), "{:?}", t.comment());
+        assert!(t.created_by() == Some("あいえおう"), "{:?}", t.created_by());
+    }
+
+    #[test]
+    fn info_total_length() {
+        let info = |entries: Vec<(&str, B)>| {
+            let mut dict = hashmap!(
+                bytes!("piece length") => B::Int(16384),
+                bytes!("pieces")       => B::String(vec![0; 20]),
+            );
+            dict.extend(entries.into_iter().map(|(k, v)| (bytes!(k), v)));
+            Info::from_dict(&mut dict, None, &ParseOptions::default()).unwrap()
+        };
+
+        let single = info(vec![
+            ("name", B::String(bytes!("file.ext"))),
+            ("length", B::Int(42)),
+        ]);
+        assert!(
+            single.total_length() == 42,
+            "{} == 42",
+            single.total_length()
+        );
+
+        let multi = info(vec![
+            ("name", B::String(bytes!("mock"))),
+            ("files", B::List(vec![file(3), file(0), file(10)])),
+        ]);
+        assert!(multi.total_length() == 13, "{} == 13", multi.total_length());
+
+        let empty = info(vec![
+            ("name", B::String(bytes!("mock"))),
+            ("files", B::List(vec![])),
+            ("pieces", B::String(vec![])),
+        ]);
+        assert!(empty.total_length() == 0, "{} == 0", empty.total_length());
+    }
+
+    #[test]
+    fn piece_length() {
+        let t = |len: i64, pieces: usize, strict: bool| {
+            let t = torrent(vec![
+                ("name", B::String(bytes!("file.ext"))),
+                ("length", B::Int(100_000)),
+                ("piece length", B::Int(len)),
+                ("pieces", B::String(vec![0; pieces * 20])),
+            ]);
+            let opts = ParseOptions {
+                strict,
+                ..ParseOptions::default()
+            };
+            Torrent::from_benc_with(t, &opts).map(|t| t.piece_count())
+        };
+
+        assert!(t(16384, 7, true).ok() == Some(7));
+        assert!(t(1 << 29, 1, true).ok() == Some(1));
+        // only strict parsing requires a power of two
+        assert!(t(25000, 4, false).ok() == Some(4));
+        assert!(t(25000, 4, true).is_err());
+
+        assert!(t(0, 0, false).is_err());
+        assert!(t(-16384, 7, false).is_err());
+        assert!(t((1 << 29) + 1, 1, false).is_err());
+        assert!(t(1 << 30, 1, false).is_err());
+
+        // 100,000 bytes takes 7 pieces of 16 KiB
+        assert!(t(16384, 6, false).is_err());
+        assert!(t(16384, 8, false).is_err());
+    }
+
+    #[test]
+    fn info_mode() {
+        let info = |entries: Vec<(&str, B)>| {
+            let mut dict = hashmap!(
+                bytes!("name")         => B::String(bytes!("mock")),
+                bytes!("piece length") => B::Int(16384),
+                bytes!("pieces")       => B::String(vec![0; 20]),
+            );
+            dict.extend(entries.into_iter().map(|(k, v)| (bytes!(k), v)));
+            Info::from_dict(&mut dict, None, &ParseOptions::default())
+        };
+
+        let both = info(vec![
+            ("length", B::Int(42)),
+            ("files", B::List(vec![file(42)])),
+        ]);
+        let expect = Error::Other("Both single-file length and multi-file files present");
+        match both {
+            Err(ref e) => assert!(*e == expect, "{:?} == {:?}", e, expect),
+            Ok(ref i) => panic!("Expected an error, got {:?}", i),
+        }
+
+        let neither = info(vec![]);
+        let expect = Error::Other("Neither single-file length nor multi-file files present");
+        match neither {
+            Err(ref e) => assert!(*e == expect, "{:?} == {:?}", e, expect),
+            Ok(ref i) => panic!("Expected an error, got {:?}", i),
+        }
+    }
+
+    #[test]
+    fn add_tracker() {
+        let mut t = Torrent::from_bytes(&torrent(single()).encode()).unwrap();
+        let urls = |t: &Torrent| t.trackers().to_vec();
+
+        t.add_tracker("udp://backup.example.com:6969".to_owned(), 0)
+            .unwrap();
+        t.add_tracker("HTTPS://new.example.com/announce".to_owned(), 5)
+            .unwrap();
+        // already present, in any tier
+        t.add_tracker("http://tracker.example.com:8080/announce".to_owned(), 1)
+            .unwrap();
+        let expect = vec![
+            vec![
+                "http://tracker.example.com:8080/announce".to_owned(),
+                "udp://backup.example.com:6969".to_owned(),
+            ],
+            vec!["HTTPS://new.example.com/announce".to_owned()],
+        ];
+        assert!(urls(&t) == expect, "{:?} == {:?}", urls(&t), expect);
+
+        for url in [
+            "ftp://tracker.example.com",
+            "tracker.example.com",
+            "udp:/",
+            "",
+        ] {
+            assert!(t.add_tracker(url.to_owned(), 0).is_err(), "{}", url);
+        }
+        assert!(urls(&t) == expect, "{:?} == {:?}", urls(&t), expect);
+
+        // written out, and read back
+        let t2 = Torrent::from_bytes(&t.to_bytes()).unwrap();
+        assert!(urls(&t2) == expect, "{:?} == {:?}", urls(&t2), expect);
+    }
+
+    #[test]
+    fn set_trackers() {
+        let mut t = Torrent::from_bytes(&torrent(single()).encode()).unwrap();
+        let url = |s: &str| format!("http://{}.example.com/announce", s);
+
+        t.set_trackers(vec![
+            vec![url("a"), url("b"), url("a")],
+            vec![],
+            vec![url("b"), url("c")],
+        ])
+        .unwrap();
+        let expect = vec![vec![url("a"), url("b")], vec![url("c")]];
+        assert!(
+            t.trackers() == &expect[..],
+            "{:?} == {:?}",
+            t.trackers(),
+            expect
+        );
+
+        assert!(t
+            .set_trackers(vec![vec![url("d"), "mock".to_owned()]])
+            .is_err());
+        assert!(
+            t.trackers() == &expect[..],
+            "{:?} == {:?}",
+            t.trackers(),
+            expect
+        );
+
+        t.set_trackers(Vec::new()).unwrap();
+        assert!(t.iter_trackers().next().is_none());
+    }
+
+    #[test]
+    fn trackers() {
+        let tier =
+            |urls: Vec<String>| B::List(urls.into_iter().map(|u| B::String(bytes!(u))).collect());
+        let many = (0..16)
+            .map(|i| format!("http://{}.example.com", i))
+            .collect();
+
+        let mut dict = match torrent(vec![("files", B::List(vec![file(42)]))]) {
+            B::Dict(d) => d,
+            _ => unreachable!(),
+        };
+        dict.insert(
+            bytes!("announce-list"),
+            B::List(vec![
+                tier(vec![
+                    String::from("udp://a.example.com"),
+                    String::from("udp://b.example.com"),
+                ]),
+                tier(vec![String::from("http://c.example.com")]),
+                tier(many),
+            ]),
+        );
+
+        let mut t = Torrent::from_benc(B::Dict(dict)).unwrap();
+        let urls: Vec<_> = t.iter_trackers().map(|u| u.to_owned()).collect();
+        assert!(
+            urls[..3]
+                == [
+                    "udp://a.example.com",
+                    "udp://b.example.com",
+                    "http://c.example.com"
+                ]
+        );
+        assert!(urls.len() == 19);
+
+        let tiers = t.trackers().to_vec();
+        t.shuffle_tiers();
+        assert!(t.trackers().len() == tiers.len());
+
+        for (shuffled, mut tier) in t.trackers().iter().zip(tiers) {
+            let mut shuffled = shuffled.clone();
+            shuffled.sort();
+            tier.sort();
+            assert!(shuffled == tier, "{:?} == {:?}", shuffled, tier);
+        }
+    }
+
+    #[test]
+    fn is_private() {
+        let single = |private: Option<B>| {
+            let mut info = vec![
+                ("name", B::String(bytes!("file.ext"))),
+                ("length", B::Int(42)),
+            ];
+            info.extend(private.map(|p| ("private", p)));
+            Torrent::from_benc(torrent(info)).unwrap()
+        };
+
+        assert!(single(Some(B::Int(1))).is_private());
+        assert!(!single(Some(B::Int(0))).is_private());
+        assert!(!single(None).is_private());
+        // values other than `1` do not mark the torrent as private
+        assert!(!single(Some(B::Int(2))).is_private());
+        assert!(!single(Some(B::String(bytes!("1")))).is_private());
+
+        let multi = torrent(vec![
+            ("name", B::String(bytes!("dir"))),
+            ("files", B::List(vec![file(1), file(2)])),
+            ("private", B::Int(1)),
+        ]);
+        assert!(Torrent::from_benc(multi).unwrap().is_private());
+    }
+
+    #[cfg(feature = "http")]
+    fn serve_once(response: String) -> String {
+        serve(vec![Some(response)])
+    }
+
+    /// Serve one response per connection, in order. `None` holds the connection open without
+    /// responding.
+    #[cfg(feature = "http")]
+    fn serve(responses: Vec<Option<String>>) -> String {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/mock.torrent", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf);
+                    match response {
+                        Some(r) => drop(stream.write_all(r.as_bytes())),
+                        None => thread::sleep(Duration::from_secs(2)),
+                    }
+                });
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn new_magnet() {
+        let err = Torrent::new("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a")
+            .unwrap_err();
+        let expect = Error::Other("Magnet links are not supported");
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn new_url_status() {
+        use crate::error::Error;
+        use crate::fetch::FetchOptions;
+
+        for (response, status, retryable) in [
+            ("HTTP/1.1 404 Not Found", 404, false),
+            ("HTTP/1.1 503 Service Unavailable", 503, true),
+        ] {
+            let url = serve_once(format!(
+                "{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                response
+            ));
+
+            let opts = FetchOptions {
+                retries: 0,
+                ..FetchOptions::default()
+            };
+            let err = Torrent::from_url(&url, &opts).unwrap_err();
+            match err {
+                Error::Http {
+                    status: Some(s), ..
+                } => {
+                    assert!(s == status, "{} == {}", s, status);
+                    assert!(err.is_retryable() == retryable, "{:?}", err);
+                }
+                _ => panic!("Expected HTTP error, found {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn from_url_retry() {
+        use std::time::Duration;
+
+        use crate::fetch::FetchOptions;
+
+        let body = String::from_utf8(torrent(single()).encode()).unwrap();
+        let ok = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let opts = |retries| FetchOptions {
+            connect_timeout: Duration::from_secs(1),
+            timeout: Duration::from_millis(200),
+            retries,
+            backoff: Duration::from_millis(10),
+        };
+
+        // a host which never responds times out
+        let url = serve(vec![None]);
+        let err = Torrent::from_url(&url, &opts(0)).unwrap_err();
+        assert!(err.is_retryable(), "{:?}", err);
+
+        // stalls and server errors are retried
+        let unavailable = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+        let url = serve(vec![None, Some(unavailable.to_owned()), Some(ok)]);
+        let t = Torrent::from_url(&url, &opts(2)).unwrap();
+        assert!(t.total_length() == 42);
+    }
+
+    #[test]
+    fn new_url_with() {
+        let fetcher = |url: &str| -> crate::error::Result<Vec<u8>> {
+            match url {
+                "mock://example.com/file.torrent" => Ok(torrent(single()).encode()),
+                _ => Err(Error::Other("Not found")),
+            }
+        };
+
+        let t = Torrent::new_url_with("mock://example.com/file.torrent", &fetcher).unwrap();
+        assert!(t.total_length() == 42);
+
+        let err = Torrent::new_url_with("mock://example.com/none", &fetcher).unwrap_err();
+        assert!(err == Error::Other("Not found"), "{:?}", err);
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn http_fetch_range() {
+        use crate::fetch::{FetchOptions, HttpFetcher, TorrentFetcher};
+
+        let fetcher = HttpFetcher::new(FetchOptions::default()).unwrap();
+        let response = |status, body: &str| {
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+        };
+
+        let url = serve_once(response("206 Partial Content", "cdef"));
+        let body = fetcher.fetch_range(&url, 2..6).unwrap();
+        assert!(body == b"cdef", "{:?}", body);
+
+        // the range is cut out of the body if the server sends all of it
+        let url = serve_once(response("200 OK", "abcdefgh"));
+        let body = fetcher.fetch_range(&url, 2..6).unwrap();
+        assert!(body == b"cdef", "{:?}", body);
+
+        let url = serve_once(response("200 OK", "abc"));
+        assert!(fetcher.fetch_range(&url, 2..6).is_err());
+    }
+
+    #[test]
+    fn fetch_padding_from_web_seed() {
+        // a.ext is padded to the end of the first piece
+        let mut data: Vec<u8> = (1..=12).collect();
+        data.extend(vec![0; 4]);
+        data.extend(13..=32);
+        let pad = B::Dict(hashmap!(
+            bytes!("path")   => B::List(vec![B::String(bytes!(".pad")), B::String(bytes!("4"))]),
+            bytes!("length") => B::Int(4),
+            bytes!("attr")   => B::String(bytes!("p")),
+        ));
+        let file = |name: &str, len| {
+            B::Dict(hashmap!(
+                bytes!("path")   => B::List(vec![B::String(bytes!(name))]),
+                bytes!("length") => B::Int(len),
+            ))
+        };
+        let t = with_entries(
+            vec![
+                ("piece length", B::Int(16)),
+                (
+                    "pieces",
+                    B::String(
+                        data.chunks(16)
+                            .flat_map(|p| Sha1::digest(p).to_vec())
+                            .collect(),
+                    ),
+                ),
+                (
+                    "files",
+                    B::List(vec![file("a.ext", 12), pad, file("b.ext", 20)]),
+                ),
+            ],
+            vec![("url-list", B::String(bytes!("http://mirror.example.com/")))],
+        );
+        let t = Torrent::from_benc(t).unwrap();
+
+        let fetcher = |url: &str| -> crate::error::Result<Vec<u8>> {
+            match url {
+                "http://mirror.example.com/mock/a.ext" => Ok(data[..12].to_vec()),
+                "http://mirror.example.com/mock/b.ext" => Ok(data[16..].to_vec()),
+                _ => Err(Error::Other("Not found")),
+            }
+        };
+        for (index, piece) in data.chunks(16).enumerate() {
+            let fetched = t.fetch_from_web_seed(index as u32, &fetcher).unwrap();
+            assert!(fetched == piece, "{:?} == {:?}", fetched, piece);
+        }
+    }
+
+    #[test]
+    fn fetch_from_web_seed() {
+        use std::env;
+
+        use sha1::{Digest, Sha1};
+
+        use super::{FileOrDir, Info};
+        use crate::files::{Directory, File};
+
+        let data: Vec<u8> = (0..40).collect();
+        let torrent = |name: &str, files, seeds: &[&str]| Torrent {
+            trackers: Vec::new(),
+            info: Info {
+                name: name.to_owned(),
+                piece_length: 16,
+                pieces: data
+                    .chunks(16)
+                    .flat_map(|p| Sha1::digest(p).to_vec())
+                    .collect(),
+                private: false,
+                files,
+            },
+            info_bytes: Vec::new(),
+            info_hash: [0; 20],
+            web_seeds: seeds.iter().map(|s| s.to_string()).collect(),
+            nodes: Vec::new(),
+            encoding: None,
+            creation_date: None,
+            created_by: None,
+            comment: None,
+        };
+        let fetcher = |url: &str| -> crate::error::Result<Vec<u8>> {
+            match url {
+                "http://mirror.example.com/file.ext" => Ok(data.clone()),
+                "http://mirror.example.com/mock/a.ext" => Ok(data[..20].to_vec()),
+                "http://mirror.example.com/mock/sub/b.ext" => Ok(data[20..].to_vec()),
+                "http://bad.example.com/file.ext" => Ok(vec![0; 40]),
+                _ => Err(Error::Other("Not found")),
+            }
+        };
+
+        let file = File::new("file.ext".to_owned(), env::temp_dir().join("file.ext"), 40);
+        let t = torrent(
+            "file.ext",
+            FileOrDir::File(file),
+            &["http://mirror.example.com/"],
+        );
+        let piece = t.fetch_from_web_seed(1, &fetcher).unwrap();
+        assert!(piece == data[16..32], "{:?}", piece);
+        let piece = t.fetch_from_web_seed(2, &fetcher).unwrap();
+        assert!(piece == data[32..], "{:?}", piece);
+        assert!(t.fetch_from_web_seed(3, &fetcher).is_err());
+
+        // a piece spanning two files; urls use the torrent's names wherever the files are stored
+        for dir in ["mock", "elsewhere"] {
+            let root = env::temp_dir().join(dir);
+            let mut d = Directory::new(root.clone());
+            d.add_file(File::new("a.ext".to_owned(), root.join("a.ext"), 20));
+            d.add_file(File::new(
+                "sub/b.ext".to_owned(),
+                root.join("sub").join("b.ext"),
+                20,
+            ));
+            let t = torrent(
+                "mock",
+                FileOrDir::Directory(d),
+                &["http://mirror.example.com"],
+            );
+            let piece = t.fetch_from_web_seed(1, &fetcher).unwrap();
+            assert!(piece == data[16..32], "{:?}", piece);
+        }
+
+        // names that had to be sanitized are requested as the torrent spells them
+        let root = env::temp_dir().join("mock");
+        let mut d = Directory::new(root.clone());
+        d.add_file(File::new("a:b.ext".to_owned(), root.join("a_b.ext"), 40));
+        let t = torrent(
+            "mock",
+            FileOrDir::Directory(d),
+            &["http://mirror.example.com/"],
+        );
+        let ranges = t.web_seed_ranges("http://mirror.example.com/", 0);
+        let expect = vec![(
+            Some("http://mirror.example.com/mock/a%3Ab.ext".to_owned()),
+            0..16,
+        )];
+        assert!(ranges == expect, "{:?} == {:?}", ranges, expect);
+
+        // seeds which fail or send corrupt data are skipped
+        let file = File::new("file.ext".to_owned(), env::temp_dir().join("file.ext"), 40);
+        let seeds = [
+            "http://missing.example.com/",
+            "http://bad.example.com/file.ext",
+            "http://mirror.example.com/file.ext",
+        ];
+        let t = torrent("file.ext", FileOrDir::File(file), &seeds);
+        assert!(t.fetch_from_web_seed(0, &fetcher).unwrap() == data[..16]);
+
+        let file = File::new("file.ext".to_owned(), env::temp_dir().join("file.ext"), 40);
+        let t = torrent("file.ext", FileOrDir::File(file), &seeds[..2]);
+        let err = t.fetch_from_web_seed(0, &fetcher).unwrap_err();
+        let expect = Error::Other("Piece from web seed does not match its hash");
+        assert!(err == expect, "{:?} == {:?}", err, expect);
+    }
+
+    #[test]
+    fn from_benc_context() {
+        let multi = torrent(vec![(
+            "files",
+            B::List(vec![
+                file(42),
+                B::Dict(hashmap!(
+                    bytes!("path")   => B::List(vec![B::String(bytes!("file.ext"))]),
+                    bytes!("length") => B::String(bytes!("42")),
+                )),
+            ]),
+        )]);
+
+        let err = Torrent::from_benc(multi).unwrap_err();
+        let expect = "info dict: file 1: Unexpected string for key \"length\"";
+        assert!(err.to_string() == expect, "{} == {}", err, expect);
+    }
+
+    #[test]
+    fn from_benc_key() {
+        let info = |k: &str, v: Option<B>| {
+            let mut t = torrent(vec![
+                ("name", B::String(bytes!("file.ext"))),
+                ("length", B::Int(42)),
+            ]);
+            if let B::Dict(ref mut d) = t {
+                if let Some(B::Dict(info)) = d.get_mut(&bytes!("info")) {
+                    info.remove(k.as_bytes());
+                    info.extend(v.map(|v| (bytes!(k), v)));
+                }
+            }
+            t
+        };
+
+        for (t, key, problem) in [
+            (
+                info("piece length", None),
+                "piece length",
+                KeyProblem::Missing,
+            ),
+            (
+                info("pieces", Some(B::Int(0))),
+                "pieces",
+                KeyProblem::WrongType { found: "int" },
+            ),
+        ] {
+            let expect = Error::Key {
+                key: bytes!(key),
+                problem,
+            };
+
+            match Torrent::from_benc(t).unwrap_err() {
+                Error::Context { ref source, .. } => {
+                    assert!(**source == expect, "{:?} == {:?}", source, expect)
+                }
+                e => panic!("Expected context error, got {:?}", e),
+            }
+        }
+
+        let err = Torrent::from_benc(info("piece length", None)).unwrap_err();
+        let expect = "info dict: Missing key \"piece length\"";
+        assert!(err.to_string() == expect, "{} == {}", err, expect);
+    }
+}
@@ -1,5 +1,12 @@
 use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use sha1::Digest;
 
 #[cfg(target_os = "linux")]
 fn valid_byte(b: u8) -> bool {
@@ -13,28 +20,530 @@ fn valid_byte(b: u8) -> bool {
 
 #[cfg(target_os = "windows")]
 fn valid_byte(b: u8) -> bool {
-    match b {
-        b'\0' | b'/' | b'\\' | b':' | b'*' | b'?' | b'"' | b'<' | b'>' | b'|' => false,
-        _ => true,
-    }
+    windows_valid_byte(b)
+}
+
+/// Bytes which can be used in a filename on Windows, which forbids the control characters 0x00
+/// to 0x1F. This is checked on every platform when paths must also be usable from Windows.
+fn windows_valid_byte(b: u8) -> bool {
+    !matches!(
+        b,
+        0x00..=0x1f | b'/' | b'\\' | b':' | b'*' | b'?' | b'"' | b'<' | b'>' | b'|'
+    )
 }
 
 /// Naively try to sanitize paths. This assumes you are writing to NTFS on Windows, HFS+ on OS X,
 /// or Ext4/BTRFS on Linux
 crate fn sanitize_path(path: &[u8]) -> Cow<'_, [u8]> {
-    match path.iter().position(|c| valid_byte(*c)) {
+    strip(path, valid_byte)
+}
+
+/// Remove every byte of `path` which `valid` rejects
+fn strip(path: &[u8], valid: fn(u8) -> bool) -> Cow<'_, [u8]> {
+    match path.iter().position(|c| !valid(*c)) {
         None => Cow::Borrowed(path),
         Some(i) => {
             let mut p = path[..i].to_vec();
-            p.extend(path[i..].iter().cloned().filter(|c| valid_byte(*c)));
+            p.extend(path[i..].iter().cloned().filter(|c| valid(*c)));
 
             Cow::Owned(p)
         }
     }
 }
 
-/// Try to find a suitable default default download directory. The Path is not guaranteed to
-/// exist, but will be an absolute path.
-crate fn download_dir() -> Option<PathBuf> {
-    dirs::download_dir().filter(|p| p.is_absolute())
+/// Device names which can not be used as a filename on Windows, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Returns true if `component` is a reserved device name on Windows. Names are case-insensitive
+/// and an extension does not make a reserved name safe, e.g. `con.txt`.
+fn is_reserved(component: &[u8]) -> bool {
+    let stem = component.split(|&c| c == b'.').next().unwrap_or(component);
+
+    RESERVED_NAMES
+        .iter()
+        .any(|r| r.as_bytes().eq_ignore_ascii_case(stem))
+}
+
+/// Sanitize a single path component with `sanitize_path`. On Windows, or on any platform when
+/// `portable` is set, the bytes Windows forbids are removed as well and the component is made
+/// safe with `windows_component`.
+crate fn sanitize_component(component: &[u8], portable: bool) -> Cow<'_, [u8]> {
+    if portable || cfg!(target_os = "windows") {
+        windows_component(strip(component, windows_valid_byte))
+    } else {
+        sanitize_path(component)
+    }
+}
+
+/// Windows strips trailing dots and spaces from filenames, so distinct components such as `name`
+/// and `name.` would refer to the same file; they are trimmed here instead. A component which is
+/// empty after trimming is replaced with `_`, and reserved device names are prefixed with `_`.
+fn windows_component(c: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+    let len = c
+        .iter()
+        .rposition(|&b| b != b'.' && b != b' ')
+        .map_or(0, |i| i + 1);
+
+    let c = match c {
+        Cow::Borrowed(b) => Cow::Borrowed(&b[..len]),
+        Cow::Owned(mut b) => {
+            b.truncate(len);
+            Cow::Owned(b)
+        }
+    };
+
+    if c.is_empty() {
+        Cow::Borrowed(b"_")
+    } else if is_reserved(&c) {
+        let mut mangled = Vec::with_capacity(c.len() + 1);
+        mangled.push(b'_');
+        mangled.extend_from_slice(&c);
+
+        Cow::Owned(mangled)
+    } else {
+        c
+    }
+}
+
+/// Decode text from a torrent. Text is expected to be UTF-8 unless `encoding` names another
+/// character set, which is transcoded if the `encoding` feature is enabled. Text in an unknown
+/// character set is decoded as lossy UTF-8.
+crate fn decode(bytes: &[u8], encoding: Option<&str>) -> Option<String> {
+    match encoding {
+        Some(label) if !label.eq_ignore_ascii_case("utf-8") => Some(transcode(bytes, label)),
+        _ => String::from_utf8(bytes.to_vec()).ok(),
+    }
+}
+
+#[cfg(feature = "encoding")]
+fn transcode(bytes: &[u8], label: &str) -> String {
+    match encoding_rs::Encoding::for_label(label.as_bytes()) {
+        Some(enc) => enc.decode_without_bom_handling(bytes).0.into_owned(),
+        None => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+#[cfg(not(feature = "encoding"))]
+fn transcode(bytes: &[u8], _label: &str) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Percent-encode `s` for use as one segment of a URL path. Only RFC 3986 unreserved
+/// characters are kept as is.
+crate fn url_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Read a payload framed by a 4-byte big-endian length prefix, as sent by `write_frame`. Frames
+/// declaring more than `max` bytes are rejected before the payload is read.
+crate fn read_frame<R: io::Read>(r: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let mut prefix = [0; 4];
+    r.read_exact(&mut prefix)?;
+
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame is longer than the limit",
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Write `payload` preceded by its length as a 4-byte big-endian int
+crate fn write_frame<W: io::Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Frame is too long for its length prefix",
+        ));
+    }
+
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(payload)
+}
+
+/// SHA-1 hash of `data`, e.g. an info dict or a piece
+crate fn sha1(data: &[u8]) -> [u8; 20] {
+    ::sha1::Sha1::digest(data).into()
+}
+
+/// SHA-1 hash of data fed in parts, e.g. a piece read from several files
+#[derive(Debug, Clone, Default)]
+crate struct Sha1(::sha1::Sha1);
+
+impl Sha1 {
+    crate fn new() -> Sha1 {
+        Sha1::default()
+    }
+
+    crate fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    crate fn finish(self) -> [u8; 20] {
+        self.0.finalize().into()
+    }
+}
+
+/// Lowercase hex of `bytes`, two digits per byte
+crate fn hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for &b in bytes {
+        out.push(DIGITS[usize::from(b >> 4)] as char);
+        out.push(DIGITS[usize::from(b & 0xf)] as char);
+    }
+    out
+}
+
+/// Shuffle `items` in place. This is not cryptographically secure; the seed comes from the
+/// randomly keyed `RandomState` hasher.
+crate fn shuffle<T>(items: &mut [T]) {
+    let mut state = RandomState::new().build_hasher().finish() | 1;
+
+    // Fisher-Yates shuffle with a xorshift64 generator
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Directories `download_dir` chooses between
+struct DirEnv {
+    /// The platform's download directory
+    download: Option<PathBuf>,
+    /// The user's home directory
+    home: Option<PathBuf>,
+    /// The current working directory
+    current: Option<PathBuf>,
+}
+
+impl DirEnv {
+    fn system() -> DirEnv {
+        DirEnv {
+            download: dirs::download_dir(),
+            home: dirs::home_dir(),
+            current: env::current_dir().ok(),
+        }
+    }
+
+    /// Returns the first absolute path that is a directory or could be created as one, trying
+    /// the platform's download directory, then `$HOME/Downloads`, then the current directory.
+    /// Nothing is created; that is left to whatever first writes there.
+    fn download_dir(self) -> Option<PathBuf> {
+        let home = self.home.map(|h| h.join("Downloads"));
+
+        vec![self.download, home, self.current]
+            .into_iter()
+            .flatten()
+            .filter(|p| p.is_absolute())
+            .find(|p| matches!(p.ancestors().find(|a| a.exists()), Some(a) if a.is_dir()))
+    }
+}
+
+/// Find a suitable default download directory. The platform's download directory is preferred,
+/// followed by `$HOME/Downloads` and the current directory; the first of these which is an
+/// absolute path and exists or could be created is returned. If none are usable, the system's
+/// temporary directory is returned. The directory is chosen once and is not created.
+crate fn download_dir() -> PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+    DIR.get_or_init(|| {
+        DirEnv::system()
+            .download_dir()
+            .unwrap_or_else(env::temp_dir)
+    })
+    .clone()
+}
+
+#[cfg(test)]
+mod test_util {
+    use std::borrow::Cow;
+
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::{decode, hex, is_reserved, read_frame, sha1, shuffle, write_frame, DirEnv, Sha1};
+
+    #[test]
+    fn decode_utf8() {
+        let name = "あいえおう";
+
+        assert!(decode(name.as_bytes(), None) == Some(name.to_owned()));
+        assert!(decode(name.as_bytes(), Some("UTF-8")) == Some(name.to_owned()));
+        assert!(decode(b"\xff\xfe", None).is_none());
+        assert!(decode(b"\xff\xfe", Some("utf-8")).is_none());
+    }
+
+    #[test]
+    fn decode_unknown() {
+        assert!(decode(b"abc\xff", Some("x-mock-charset")) == Some("abc\u{fffd}".to_owned()));
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn decode_shift_jis() {
+        // "あいえおう" in Shift_JIS
+        let name = b"\x82\xa0\x82\xa2\x82\xa6\x82\xa8\x82\xa4";
+
+        assert!(decode(name, Some("Shift_JIS")) == Some("あいえおう".to_owned()));
+    }
+
+    #[test]
+    fn download_dir() {
+        let root = env::temp_dir().join("download_dir");
+        let _ = fs::remove_dir_all(&root);
+        let dir_env = |download: Option<PathBuf>, home: Option<PathBuf>| DirEnv {
+            download,
+            home,
+            current: Some(root.join("current")),
+        };
+
+        // platform directory is preferred, and not created if missing
+        let dir = dir_env(Some(root.join("download")), Some(root.join("home"))).download_dir();
+        assert!(dir == Some(root.join("download")), "{:?}", dir);
+        assert!(!root.exists());
+
+        // then $HOME/Downloads
+        let dir = dir_env(None, Some(root.join("home"))).download_dir();
+        assert!(
+            dir == Some(root.join("home").join("Downloads")),
+            "{:?}",
+            dir
+        );
+
+        // relative paths are skipped
+        let dir = dir_env(Some(PathBuf::from("download")), None).download_dir();
+        assert!(dir == Some(root.join("current")), "{:?}", dir);
+
+        // paths that can not be created are skipped
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("file"), b"").unwrap();
+        let dir = dir_env(Some(root.join("file").join("download")), None).download_dir();
+        assert!(dir == Some(root.join("current")), "{:?}", dir);
+
+        let none = DirEnv {
+            download: None,
+            home: None,
+            current: None,
+        };
+        assert!(none.download_dir().is_none());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn url_encode() {
+        assert!(super::url_encode("file-1_a.ext~") == "file-1_a.ext~");
+        let encoded = super::url_encode("a b/ü%");
+        assert!(encoded == "a%20b%2F%C3%BC%25", "{}", encoded);
+    }
+
+    #[test]
+    fn frame() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert!(buf == b"\0\0\0\x05hello\0\0\0\0", "{:?}", buf);
+
+        let mut r = &buf[..];
+        assert!(read_frame(&mut r, 5).unwrap() == b"hello");
+        assert!(read_frame(&mut r, 0).unwrap().is_empty());
+        assert!(r.is_empty());
+
+        // cut short in the prefix and in the payload
+        for end in [2, 7] {
+            let err = read_frame(&mut &buf[..end], 16).unwrap_err();
+            assert!(
+                err.kind() == io::ErrorKind::UnexpectedEof,
+                "{}: {:?}",
+                end,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &[0; 17]).unwrap();
+        let err = read_frame(&mut &buf[..], 16).unwrap_err();
+        assert!(err.kind() == io::ErrorKind::InvalidData, "{:?}", err);
+
+        // the limit is checked before the payload is read, so a huge prefix allocates nothing
+        let err = read_frame(&mut &[0xff; 4][..], 16).unwrap_err();
+        assert!(err.kind() == io::ErrorKind::InvalidData, "{:?}", err);
+    }
+
+    #[test]
+    fn sha1_abc() {
+        let expect = "a9993e364706816aba3e25717850c26c9cd0d89d";
+        assert!(
+            hex(&sha1(b"abc")) == expect,
+            "{} == {}",
+            hex(&sha1(b"abc")),
+            expect
+        );
+
+        let mut hasher = Sha1::new();
+        for part in [&b"a"[..], b"", b"bc"] {
+            hasher.update(part);
+        }
+        assert!(hasher.finish() == sha1(b"abc"));
+
+        let expect = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+        assert!(hex(&Sha1::new().finish()) == expect);
+    }
+
+    #[test]
+    fn hex_bytes() {
+        assert!(hex(&[]).is_empty());
+        assert!(
+            hex(&[0, 0x0f, 0xa0, 0xff]) == "000fa0ff",
+            "{}",
+            hex(&[0, 0x0f, 0xa0, 0xff])
+        );
+    }
+
+    #[test]
+    fn shuffle_permutes() {
+        let mut items: Vec<u32> = (0..64).collect();
+        shuffle(&mut items);
+
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert!(sorted == (0..64).collect::<Vec<_>>());
+
+        let mut empty: [u32; 0] = [];
+        shuffle(&mut empty);
+    }
+
+    #[test]
+    fn reserved_names() {
+        for name in ["con.txt", "LPT1", "Aux", "nul.tar.gz", "COM9"] {
+            assert!(is_reserved(name.as_bytes()), "{}", name);
+        }
+
+        for name in ["console", "LPT10", "com0", "xcon.txt", ".con", ""] {
+            assert!(!is_reserved(name.as_bytes()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn windows_component() {
+        for (name, expect) in [
+            ("name.", "name"),
+            ("name ", "name"),
+            ("name. . ", "name"),
+            ("na.me", "na.me"),
+            (" name", " name"),
+            ("con.txt", "_con.txt"),
+            ("LPT1", "_LPT1"),
+            ("con.", "_con"),
+            ("console", "console"),
+            ("..", "_"),
+            (" ", "_"),
+        ] {
+            let c = super::windows_component(Cow::Borrowed(name.as_bytes()));
+            assert!(&*c == expect.as_bytes(), "{:?} == {:?}", c, expect);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "windows")]
+    fn sanitize_component() {
+        assert!(&*super::sanitize_component(b"con.txt", false) == b"_con.txt");
+        assert!(&*super::sanitize_component(b"name.", false) == b"name");
+    }
+
+    #[test]
+    fn sanitize_component_portable() {
+        for (name, expect) in [
+            ("file.ext", "file.ext"),
+            ("a:b*c?.ext", "abc.ext"),
+            ("\"<>|\0", "_"),
+            ("a\\b", "ab"),
+            ("tab\there\n", "tabhere"),
+            ("\x01bell\x07\x1f", "bell"),
+            ("\x7f", "\x7f"),
+            ("report.", "report"),
+            ("report. ", "report"),
+            ("aux", "_aux"),
+            ("CON.txt", "_CON.txt"),
+            ("console.txt", "console.txt"),
+        ] {
+            let c = super::sanitize_component(name.as_bytes(), true);
+            assert!(
+                &*c == expect.as_bytes(),
+                "{:?}: {:?} == {:?}",
+                name,
+                c,
+                expect
+            );
+        }
+
+        // every reserved name is caught with and without an extension, in any case
+        for name in super::RESERVED_NAMES {
+            for c in [
+                name.to_string(),
+                name.to_lowercase(),
+                format!("{}.txt", name),
+                format!("{}.tar.gz", name.to_lowercase()),
+            ] {
+                let sanitized = super::sanitize_component(c.as_bytes(), true);
+                let expect = format!("_{}", c);
+                assert!(
+                    &*sanitized == expect.as_bytes(),
+                    "{:?} == {:?}",
+                    sanitized,
+                    expect
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn sanitize_path() {
+        for (path, expect) in [
+            (&b"name.ext"[..], &b"name.ext"[..]),
+            (b"\0name.ext", b"name.ext"),
+            (b"na\0me.ext\0", b"name.ext"),
+            (b"\0", b""),
+        ] {
+            let p = super::sanitize_path(path);
+            assert!(&*p == expect, "{:?} == {:?}", p, expect);
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn sanitize_component() {
+        for name in ["con.txt", "LPT1", "name.", "name ", "a:b"] {
+            let c = super::sanitize_component(name.as_bytes(), false);
+            assert!(&*c == name.as_bytes(), "{:?} == {:?}", c, name);
+        }
+    }
 }
@@ -0,0 +1,212 @@
+//! Check downloaded pieces against the SHA1 hashes in the torrent.
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error;
+use crate::torrent::Torrent;
+use crate::util;
+
+/// Result of adding a block to a piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The piece is still missing some of its data
+    Pending,
+    /// The piece is complete and matches its hash
+    Verified,
+    /// The piece is complete but does not match its hash. Its blocks are discarded so it can be
+    /// downloaded again.
+    Corrupt,
+}
+
+/// Verifies pieces as their blocks arrive, so a bad piece is found without reading it back from
+/// disk. Blocks may arrive in any order and may overlap.
+#[derive(Debug)]
+pub struct PieceVerifier<'a> {
+    torrent: &'a Torrent,
+    /// Blocks received for each incomplete piece, keyed by their offset into the piece
+    pieces: HashMap<u32, BTreeMap<u64, Vec<u8>>>,
+}
+
+impl<'a> PieceVerifier<'a> {
+    pub fn new(torrent: &'a Torrent) -> PieceVerifier<'a> {
+        PieceVerifier {
+            torrent,
+            pieces: HashMap::new(),
+        }
+    }
+
+    /// Add `block`, found `begin` bytes into the piece at `index`. Once every byte of the piece
+    /// has been added, it is hashed and the piece is forgotten.
+    pub fn add_block(
+        &mut self,
+        index: u32,
+        begin: u64,
+        block: &[u8],
+    ) -> error::Result<Verification> {
+        let size = match self.torrent.piece_size(index) {
+            Some(size) => size,
+            None => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Invalid piece index",
+                )))
+            }
+        };
+        match begin.checked_add(block.len() as u64) {
+            Some(end) if end <= size => (),
+            _ => {
+                return Err(error::Error::new(error::Error::Other(
+                    "Block extends past the end of the piece",
+                )))
+            }
+        }
+
+        let blocks = self.pieces.entry(index).or_default();
+        // a shorter block at the same offset must not replace data already received
+        let replace = match blocks.get(&begin) {
+            Some(b) => b.len() < block.len(),
+            None => true,
+        };
+        if replace {
+            blocks.insert(begin, block.to_vec());
+        }
+
+        if received(blocks) < size {
+            return Ok(Verification::Pending);
+        }
+
+        let blocks = self.pieces.remove(&index).unwrap_or_default();
+        let mut hasher = util::Sha1::new();
+        let mut pos: u64 = 0;
+        for (begin, block) in &blocks {
+            // skip bytes already hashed from an overlapping block
+            let skip = cmp::min(pos.saturating_sub(*begin), block.len() as u64) as usize;
+            hasher.update(&block[skip..]);
+            pos = cmp::max(pos, begin + block.len() as u64);
+        }
+
+        match self.torrent.piece_hash(index) {
+            Some(hash) if hash == hasher.finish() => Ok(Verification::Verified),
+            _ => Ok(Verification::Corrupt),
+        }
+    }
+
+    /// Number of bytes held for pieces which are not yet complete
+    pub fn buffered(&self) -> usize {
+        self.pieces
+            .values()
+            .flat_map(|b| b.values())
+            .map(|b| b.len())
+            .sum()
+    }
+}
+
+/// Number of contiguous bytes received from the start of a piece
+fn received(blocks: &BTreeMap<u64, Vec<u8>>) -> u64 {
+    let mut end = 0;
+    for (&begin, block) in blocks {
+        if begin > end {
+            break;
+        }
+        end = cmp::max(end, begin + block.len() as u64);
+    }
+    end
+}
+
+#[cfg(test)]
+mod test_verifier {
+    use std::borrow::ToOwned;
+
+    use sha1::{Digest, Sha1};
+
+    use super::{PieceVerifier, Verification};
+    use crate::bencode::Benc as B;
+    use crate::torrent::Torrent;
+
+    macro_rules! hashmap {
+        ($($k:expr => $v:expr),*) => ({
+            let mut d = ::std::collections::HashMap::new();
+            $(d.insert($k, $v);)*
+            d
+        });
+
+        ($($k:expr => $v:expr),+,) => (hashmap!($($k => $v),+));
+    }
+
+    macro_rules! bytes {
+        ($s:expr) => {
+            $s.to_owned().into_bytes()
+        };
+    }
+
+    static PIECE_LEN: usize = 16;
+
+    /// A single file torrent holding `data`
+    fn torrent(data: &[u8]) -> Torrent {
+        let pieces = data
+            .chunks(PIECE_LEN)
+            .flat_map(|p| Sha1::digest(p).to_vec())
+            .collect();
+
+        Torrent::from_benc(B::Dict(hashmap!(
+            bytes!("announce") => B::String(bytes!("http://tracker.example.com:8080/announce")),
+            bytes!("info")     => B::Dict(hashmap!(
+                bytes!("name")         => B::String(bytes!("file.ext")),
+                bytes!("length")       => B::Int(data.len() as i64),
+                bytes!("piece length") => B::Int(PIECE_LEN as i64),
+                bytes!("pieces")       => B::String(pieces),
+            )),
+        )))
+        .unwrap()
+    }
+
+    fn data() -> Vec<u8> {
+        (0..40).collect()
+    }
+
+    #[test]
+    fn verified() {
+        let data = data();
+        let t = torrent(&data);
+        let mut v = PieceVerifier::new(&t);
+
+        // out of order, and overlapping
+        assert!(v.add_block(0, 8, &data[8..16]).unwrap() == Verification::Pending);
+        assert!(v.add_block(0, 4, &data[4..10]).unwrap() == Verification::Pending);
+        assert!(v.buffered() == 14, "{} == 14", v.buffered());
+        assert!(v.add_block(0, 0, &data[..4]).unwrap() == Verification::Verified);
+        assert!(v.buffered() == 0, "{} == 0", v.buffered());
+
+        // the last piece is short
+        assert!(v.add_block(2, 0, &data[32..]).unwrap() == Verification::Verified);
+        assert!(v.add_block(1, 0, &data[16..32]).unwrap() == Verification::Verified);
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = data();
+        let t = torrent(&data);
+        let mut v = PieceVerifier::new(&t);
+
+        let mut bad = data[16..32].to_vec();
+        bad[3] ^= 0xff;
+        assert!(v.add_block(1, 0, &bad[..8]).unwrap() == Verification::Pending);
+        assert!(v.add_block(1, 8, &bad[8..]).unwrap() == Verification::Corrupt);
+        assert!(v.buffered() == 0);
+
+        // the piece can be downloaded again
+        assert!(v.add_block(1, 0, &data[16..32]).unwrap() == Verification::Verified);
+    }
+
+    #[test]
+    fn invalid_block() {
+        let data = data();
+        let t = torrent(&data);
+        let mut v = PieceVerifier::new(&t);
+
+        assert!(v.add_block(3, 0, &data[..8]).is_err());
+        assert!(v.add_block(0, 12, &data[..8]).is_err());
+        assert!(v.add_block(2, 4, &data[..8]).is_err());
+        assert!(v.add_block(0, u64::MAX, &data[..8]).is_err());
+        assert!(v.buffered() == 0);
+    }
+}